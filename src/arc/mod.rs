@@ -0,0 +1,620 @@
+/*
+ * Copyright 2021 Luca Fulchir <luca@fenrirproject.org>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! ARC (Adaptive Replacement Cache)
+//!
+//! Like the SLRU, ARC has two LRUs:
+//!  * T1: entries that have been seen once recently
+//!  * T2: entries that have been seen at least twice recently
+//!
+//! but their sizes are not fixed. ARC also tracks the keys of the entries
+//! recently evicted from T1 and T2 in two "ghost" lists, B1 and B2.  
+//! A hit in B1 means that T1 should have been bigger, and a hit in B2 that
+//! T2 should have been bigger, so ARC keeps adapting its target size for T1
+//! between recency and frequency.
+//!
+//! See the [original paper](https://www.usenix.org/conference/fast-03/arc-self-tuning-low-overhead-replacement-cache)
+
+use crate::ghost::Ghost;
+use crate::hashmap;
+use crate::hashmap::user;
+use crate::owned::Owned;
+use crate::results::{InsertResultShared, RemovalCause, Removed};
+
+/// cache id of the entries of the owning [`ARC`]
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ARCCid {
+    None,
+    T1,
+    T2,
+}
+impl Default for ARCCid {
+    fn default() -> Self {
+        ARCCid::None
+    }
+}
+impl user::Cid for ARCCid {}
+
+type ARCEntry<K, V, Umeta> = user::Entry<K, V, ARCCid, Umeta>;
+type HmapT<K, V, Umeta, HB> =
+    hashmap::SimpleHmap<ARCEntry<K, V, Umeta>, K, V, ARCCid, Umeta, HB>;
+
+/// [ARC](https://en.wikipedia.org/wiki/Adaptive_replacement_cache)
+/// implementation that wraps ARCShared, see [`Owned`]
+pub type ARC<'a, K, V, Umeta, HB> = Owned<
+    'a,
+    K,
+    V,
+    ARCCid,
+    Umeta,
+    HB,
+    ARCShared<
+        'a,
+        HmapT<K, V, Umeta, HB>,
+        ARCEntry<K, V, Umeta>,
+        K,
+        V,
+        ARCCid,
+        Umeta,
+        HB,
+    >,
+>;
+
+impl<
+        'a,
+        K: user::Hash,
+        V: user::Val,
+        Umeta: user::Meta<V>,
+        HB: ::std::hash::BuildHasher + Default,
+    > ARC<'a, K, V, Umeta, HB>
+{
+    /// new ARC with space for `entries` elements
+    pub fn new(
+        entries: usize,
+        extra_hashmap_capacity: usize,
+        hash_builder: HB,
+    ) -> Self {
+        Owned::from_shared(
+            ARCShared::new(entries, ARCCid::T1, ARCCid::T2, None),
+            extra_hashmap_capacity,
+            hash_builder,
+        )
+    }
+    /// get the current target size of T1
+    pub fn get_target(&self) -> usize {
+        self.shared().get_target()
+    }
+}
+
+#[derive(PartialEq, Eq)]
+enum ScanStatus {
+    Stopped,
+    RunningT1,
+    RunningT2,
+}
+
+/// Actual implementation of the ARC on a shared hashmap
+///
+/// T1 and T2 are two LRUs on the hashmap, with different cache ids.
+/// B1 and B2 only keep the keys of the evicted entries, outside of the
+/// hashmap.
+///
+/// Note that Insert/Remove do not actually insert anything in the hashmap.
+/// That must be done by the caller. We only fix all the pointers and ARC
+/// status
+pub struct ARCShared<'a, Hmap, E, K, V, CidT, Umeta, HB>
+where
+    Hmap: hashmap::HashMap<E, K, V, CidT, Umeta, HB>,
+    E: user::EntryT<K, V, CidT, Umeta>,
+    K: user::Hash,
+    V: user::Val,
+    CidT: user::Cid,
+    Umeta: user::Meta<V>,
+    HB: ::std::hash::BuildHasher + Default,
+{
+    // T1 and T2 can each grow up to the whole capacity, the evictions are
+    // decided by the ARC, not by the LRUs
+    _t1: crate::lru::LRUShared<'a, Hmap, E, K, V, CidT, Umeta, HB>,
+    _t2: crate::lru::LRUShared<'a, Hmap, E, K, V, CidT, Umeta, HB>,
+    _b1: Ghost<K, HB>,
+    _b2: Ghost<K, HB>,
+    // target size of T1
+    _p: usize,
+    _capacity: usize,
+    _scanstatus: ScanStatus,
+}
+
+impl<
+        'a,
+        Hmap: hashmap::HashMap<E, K, V, CidT, Umeta, HB>,
+        E: user::EntryT<K, V, CidT, Umeta>,
+        K: user::Hash,
+        V: user::Val,
+        CidT: user::Cid,
+        Umeta: user::Meta<V>,
+        HB: ::std::hash::BuildHasher + Default,
+    > ARCShared<'a, Hmap, E, K, V, CidT, Umeta, HB>
+{
+    /// new ARC with space for `entries` elements, the cache ids of T1 and T2
+    /// plus an optional callback for the lazy scan
+    pub fn new(
+        entries: usize,
+        t1_cid: CidT,
+        t2_cid: CidT,
+        access_scan: Option<&'a dyn Fn(::std::ptr::NonNull<E>)>,
+    ) -> Self {
+        ARCShared {
+            _t1: crate::lru::LRUShared::new(entries, t1_cid, access_scan),
+            _t2: crate::lru::LRUShared::new(entries, t2_cid, access_scan),
+            _b1: Ghost::new(),
+            _b2: Ghost::new(),
+            _p: 0,
+            _capacity: entries,
+            _scanstatus: ScanStatus::Stopped,
+        }
+    }
+    /// change the scan callback
+    pub fn set_scanf(
+        &mut self,
+        access_scan: Option<&'a dyn Fn(::std::ptr::NonNull<E>)>,
+    ) {
+        self._t1.set_scanf(access_scan);
+        self._t2.set_scanf(access_scan)
+    }
+    /// change what happens on a cache hit in T2.  
+    /// A hit in T1 always moves the entry to T2
+    pub fn set_hit_policy(&mut self, policy: crate::lru::HitPolicy) {
+        self._t1.set_hit_policy(policy);
+        self._t2.set_hit_policy(policy);
+    }
+    /// get the current hit policy
+    pub fn get_hit_policy(&self) -> crate::lru::HitPolicy {
+        self._t2.get_hit_policy()
+    }
+    /// An element has been added by the caller, fix the ARC
+    ///
+    /// `maybe_old_entry` must be `!= None` only if the clash happened in
+    /// this cache
+    pub fn insert_shared(
+        &mut self,
+        hmap: &mut Hmap,
+        maybe_old_entry: Option<&mut E>,
+        new_entry_idx: usize,
+    ) -> InsertResultShared {
+        let res = match maybe_old_entry {
+            None => self.miss(hmap, new_entry_idx, true),
+            Some(old_entry)
+                if old_entry.get_key()
+                    == hmap.get_index(new_entry_idx).unwrap().get_key() =>
+            {
+                // same key, just a new value: this is a hit, the entry
+                // goes in T2
+                if old_entry.get_cache_id() == self._t1.get_cache_id() {
+                    self._t1.remove_clashed(hmap, old_entry, new_entry_idx);
+                    self._t2.insert_shared(hmap, None, new_entry_idx)
+                } else {
+                    self._t2.insert_shared(hmap, Some(old_entry), new_entry_idx)
+                }
+            }
+            Some(old_entry) => {
+                // a different key took the place of the old entry, which is
+                // not in the cache anymore
+                if old_entry.get_cache_id() == self._t1.get_cache_id() {
+                    self._t1.remove_clashed(hmap, old_entry, new_entry_idx);
+                } else {
+                    self._t2.remove_clashed(hmap, old_entry, new_entry_idx);
+                }
+                self.miss(hmap, new_entry_idx, true)
+            }
+        };
+        self.update_scan_status();
+        res
+    }
+    /// An entry moved here from another cache on the same hashmap.
+    ///
+    /// Like a new insert, but without running the on-insert callback
+    pub fn move_in_shared(
+        &mut self,
+        hmap: &mut Hmap,
+        idx: usize,
+    ) -> InsertResultShared {
+        let res = self.miss(hmap, idx, false);
+        self.update_scan_status();
+        res
+    }
+    // the key at `idx` was not in the cache: adapt the target size of T1 if
+    // the key was in a ghost list, make room and add the entry in T1 or T2
+    fn miss(
+        &mut self,
+        hmap: &mut Hmap,
+        idx: usize,
+        run_callback: bool,
+    ) -> InsertResultShared {
+        let key = hmap.get_index(idx).unwrap().get_key();
+        let (in_b1, in_b2) = (self._b1.contains(key), self._b2.contains(key));
+        let evicted;
+        if in_b1 {
+            // T1 was too small
+            let delta = ::std::cmp::max(1, self._b2.len() / self._b1.len());
+            self._p = ::std::cmp::min(self._capacity, self._p + delta);
+            self._b1.remove(key);
+            evicted = self.replace(hmap, false);
+        } else if in_b2 {
+            // T2 was too small
+            let delta = ::std::cmp::max(1, self._b1.len() / self._b2.len());
+            self._p = self._p.saturating_sub(delta);
+            self._b2.remove(key);
+            evicted = self.replace(hmap, true);
+        } else if self._t1.len() + self._b1.len() >= self._capacity {
+            if self._t1.len() < self._capacity {
+                self._b1.pop_lru();
+                evicted = self.replace(hmap, false);
+            } else {
+                // B1 is empty and T1 is full: drop the T1 tail, without
+                // remembering it
+                evicted = self._t1.tail(hmap);
+                if let Some(tail) = evicted {
+                    self._t1.remove_shared(hmap, tail);
+                }
+            }
+        } else {
+            let ghosts = self._b1.len() + self._b2.len();
+            if self.len() + ghosts >= 2 * self._capacity {
+                self._b2.pop_lru();
+            }
+            evicted = self.replace(hmap, false);
+        }
+        let lru = if in_b1 || in_b2 {
+            &mut self._t2
+        } else {
+            &mut self._t1
+        };
+        match run_callback {
+            true => lru.insert_shared(hmap, None, idx),
+            false => lru.move_in_shared(hmap, idx),
+        };
+        match evicted {
+            None => Removed::new(),
+            Some(evicted) => Removed::single(evicted, RemovalCause::Capacity),
+        }
+    }
+    // if the cache is full, move the tail of T1 or T2 to its ghost list.
+    // The entry is still in the hashmap, its index is returned
+    fn replace(&mut self, hmap: &mut Hmap, hit_in_b2: bool) -> Option<usize> {
+        if self.len() < self._capacity {
+            return None;
+        }
+        let t1_len = self._t1.len();
+        let from_t1 = t1_len > 0
+            && (t1_len > self._p || (hit_in_b2 && t1_len == self._p));
+        let (lru, ghost) = if from_t1 {
+            (&mut self._t1, &mut self._b1)
+        } else {
+            (&mut self._t2, &mut self._b2)
+        };
+        let tail = lru.tail(hmap)?;
+        lru.remove_shared(hmap, tail);
+        ghost.push(hmap.get_index(tail).unwrap().get_key().clone());
+        Some(tail)
+    }
+    /// The index of the entry that will be evicted by the next insert of a
+    /// key that is not in the ghost lists, if the ARC is full
+    pub fn victim(&self, hmap: &Hmap) -> Option<usize> {
+        if self.len() < self._capacity {
+            return None;
+        }
+        let t1_len = self._t1.len();
+        if t1_len >= self._capacity
+            || (t1_len > 0 && t1_len > self._p)
+            || self._t2.len() == 0
+        {
+            self._t1.tail(hmap)
+        } else {
+            self._t2.tail(hmap)
+        }
+    }
+    /// Reset the ARC state, ghost lists included
+    pub fn clear_shared(&mut self) {
+        self._t1.clear_shared();
+        self._t2.clear_shared();
+        self._b1.clear();
+        self._b2.clear();
+        self._p = 0;
+        self._scanstatus = ScanStatus::Stopped;
+    }
+    /// do not actually remove the element at `idx`, just fix the ARC so that
+    /// it is not considered anymore and actual removal is safe
+    pub fn remove_shared(&mut self, hmap: &mut Hmap, idx: usize) {
+        let cid = hmap.get_index(idx).unwrap().get_cache_id();
+        if cid == self._t1.get_cache_id() {
+            self._t1.remove_shared(hmap, idx)
+        } else {
+            self._t2.remove_shared(hmap, idx)
+        };
+        self.update_scan_status();
+    }
+    /// `old_entry` was in the ARC, but the entry at `idx` clashed with it and
+    /// took its place in the hashmap. Forget about the old entry
+    pub fn remove_clashed(
+        &mut self,
+        hmap: &mut Hmap,
+        old_entry: &E,
+        idx: usize,
+    ) {
+        if old_entry.get_cache_id() == self._t1.get_cache_id() {
+            self._t1.remove_clashed(hmap, old_entry, idx)
+        } else {
+            self._t2.remove_clashed(hmap, old_entry, idx)
+        };
+        self.update_scan_status();
+    }
+    /// The hashmap has been resized and all the entries have moved.
+    ///
+    /// Fix both T1 and T2. The ghost lists only have keys
+    pub fn relocate(&mut self, reloc: &hashmap::Relocation<E>) {
+        self._t1.relocate(reloc);
+        self._t2.relocate(reloc);
+    }
+    /// return the cache ids for `(T1, T2)`
+    pub fn get_cache_ids(&self) -> (CidT, CidT) {
+        (self._t1.get_cache_id(), self._t2.get_cache_id())
+    }
+    /// Should be called only by the parent, run the on-get callback on the
+    /// correct LRU
+    ///
+    /// A hit in T1 moves the entry to the head of T2
+    pub fn on_get(&mut self, hmap: &mut Hmap, idx: usize) {
+        let cid = hmap.get_index(idx).unwrap().get_cache_id();
+        if cid == self._t1.get_cache_id() {
+            self._t1.remove_shared(hmap, idx);
+            // T2 can hold the whole cache, this never evicts
+            self._t2.move_in_shared(hmap, idx);
+            hmap.get_index_mut(idx).unwrap().user_on_get();
+        } else {
+            self._t2.on_get(hmap, idx);
+        }
+        self.update_scan_status();
+    }
+    /// start the scan callbacks on the ARC
+    /// scan will execute only once on the whole ARC
+    pub fn start_scan(&mut self) {
+        self._t1.start_scan();
+        if self._t1.is_scan_running() {
+            self._scanstatus = ScanStatus::RunningT1;
+            return;
+        }
+        self._t2.start_scan();
+        self._scanstatus = if self._t2.is_scan_running() {
+            ScanStatus::RunningT2
+        } else {
+            ScanStatus::Stopped
+        };
+    }
+    /// return scan status
+    pub fn is_scan_running(&self) -> bool {
+        self._scanstatus != ScanStatus::Stopped
+    }
+    fn update_scan_status(&mut self) {
+        match self._scanstatus {
+            ScanStatus::Stopped => {}
+            ScanStatus::RunningT1 => {
+                if !self._t1.is_scan_running() {
+                    self._t2.start_scan();
+                    self._scanstatus = ScanStatus::RunningT2;
+                }
+            }
+            ScanStatus::RunningT2 => {
+                if !self._t2.is_scan_running() {
+                    self._scanstatus = ScanStatus::Stopped;
+                }
+            }
+        }
+    }
+    /// Change the number of entries of the ARC
+    ///
+    /// The entries evicted while shrinking go to the ghost lists just like
+    /// on insert, and their indexes are returned: they are still in the
+    /// hashmap and must be removed by the caller
+    pub fn set_capacity(
+        &mut self,
+        hmap: &mut Hmap,
+        entries: usize,
+    ) -> Removed<usize> {
+        self._capacity = entries;
+        self._p = ::std::cmp::min(self._p, entries);
+        let mut evicted = Removed::new();
+        while self.len() > entries {
+            match self.replace(hmap, false) {
+                Some(idx) => evicted.push(idx, RemovalCause::Capacity),
+                None => break,
+            }
+        }
+        while self._t1.len() + self._b1.len() > entries
+            && self._b1.pop_lru().is_some()
+        {}
+        while self.len() + self._b1.len() + self._b2.len() > 2 * entries
+            && self._b2.pop_lru().is_some()
+        {}
+        // T1 and T2 are not over the capacity anymore, this does not evict
+        self._t1.set_capacity(hmap, entries);
+        self._t2.set_capacity(hmap, entries);
+        self.update_scan_status();
+        evicted
+    }
+    /// get the current target size of T1
+    pub fn get_target(&self) -> usize {
+        self._p
+    }
+    /// return the current size of `(T1, T2)`
+    pub fn get_lens(&self) -> (usize, usize) {
+        (self._t1.len(), self._t2.len())
+    }
+    /// return max ARC size
+    pub fn capacity(&self) -> usize {
+        self._capacity
+    }
+    /// return used ARC size
+    pub fn len(&self) -> usize {
+        self._t1.len() + self._t2.len()
+    }
+    /// check if the ARC is empty
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<
+        'a,
+        Hmap: hashmap::HashMap<E, K, V, CidT, Umeta, HB>,
+        E: user::EntryT<K, V, CidT, Umeta>,
+        K: user::Hash,
+        V: user::Val,
+        CidT: user::Cid,
+        Umeta: user::Meta<V>,
+        HB: ::std::hash::BuildHasher + Default,
+    > crate::cache::SharedCache<'a, Hmap, E, K, V, CidT, Umeta, HB>
+    for ARCShared<'a, Hmap, E, K, V, CidT, Umeta, HB>
+{
+    fn insert_shared(
+        &mut self,
+        hmap: &mut Hmap,
+        maybe_old_entry: Option<&mut E>,
+        new_entry_idx: usize,
+    ) -> InsertResultShared {
+        ARCShared::insert_shared(self, hmap, maybe_old_entry, new_entry_idx)
+    }
+    fn move_in_shared(
+        &mut self,
+        hmap: &mut Hmap,
+        idx: usize,
+    ) -> InsertResultShared {
+        ARCShared::move_in_shared(self, hmap, idx)
+    }
+    fn remove_shared(&mut self, hmap: &mut Hmap, idx: usize) {
+        ARCShared::remove_shared(self, hmap, idx)
+    }
+    fn remove_clashed(&mut self, hmap: &mut Hmap, old_entry: &E, idx: usize) {
+        ARCShared::remove_clashed(self, hmap, old_entry, idx)
+    }
+    fn clear_shared(&mut self) {
+        ARCShared::clear_shared(self)
+    }
+    fn on_get(&mut self, hmap: &mut Hmap, idx: usize) {
+        ARCShared::on_get(self, hmap, idx)
+    }
+    fn owns(&self, cid: CidT) -> bool {
+        cid == self._t1.get_cache_id() || cid == self._t2.get_cache_id()
+    }
+    fn victim(&self, hmap: &Hmap) -> Option<usize> {
+        ARCShared::victim(self, hmap)
+    }
+    fn relocate(&mut self, reloc: &hashmap::Relocation<E>) {
+        ARCShared::relocate(self, reloc)
+    }
+    fn set_scanf(
+        &mut self,
+        access_scan: Option<&'a dyn Fn(::std::ptr::NonNull<E>)>,
+    ) {
+        ARCShared::set_scanf(self, access_scan)
+    }
+    fn start_scan(&mut self) {
+        ARCShared::start_scan(self)
+    }
+    fn is_scan_running(&self) -> bool {
+        ARCShared::is_scan_running(self)
+    }
+    fn set_hit_policy(&mut self, policy: crate::lru::HitPolicy) {
+        ARCShared::set_hit_policy(self, policy)
+    }
+    fn get_hit_policy(&self) -> crate::lru::HitPolicy {
+        ARCShared::get_hit_policy(self)
+    }
+    fn set_capacity(
+        &mut self,
+        hmap: &mut Hmap,
+        entries: usize,
+    ) -> Removed<usize> {
+        ARCShared::set_capacity(self, hmap, entries)
+    }
+    fn capacity(&self) -> usize {
+        ARCShared::capacity(self)
+    }
+    fn len(&self) -> usize {
+        ARCShared::len(self)
+    }
+    // only counts entries
+    fn set_weighted(
+        &mut self,
+        _hmap: &Hmap,
+        weighted: bool,
+    ) -> Result<(), crate::results::Error> {
+        match weighted {
+            false => Ok(()),
+            true => Err(crate::results::Error::WeightUnsupported),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{Hasher, Key, Val};
+
+    type Cache<'a> = ARC<'a, Key, Val, user::ZeroMeta, Hasher>;
+
+    fn keys(res: Removed<(Key, Val, user::ZeroMeta)>) -> Vec<u32> {
+        res.iter().map(|(e, _)| e.0 .0).collect()
+    }
+
+    #[test]
+    fn ghost_hits_adapt_the_target() {
+        let mut cache = Cache::new(4, 0, Hasher::default());
+        for k in 0..2 {
+            cache.insert(Key(k), Val(k));
+            cache.get(&Key(k));
+        }
+        cache.insert(Key(2), Val(2));
+        cache.insert(Key(3), Val(3));
+        assert_eq!(cache.shared().get_lens(), (2, 2));
+        // T1 is over its target of 0: its tail goes to B1
+        assert_eq!(keys(cache.insert(Key(4), Val(4))), vec![2]);
+        assert_eq!(cache.get_target(), 0);
+        // a hit in B1 grows T1, and the key goes straight to T2
+        assert_eq!(keys(cache.insert(Key(2), Val(2))), vec![3]);
+        assert_eq!(cache.get_target(), 1);
+        assert_eq!(cache.shared().get_lens(), (1, 3));
+        // T1 is at its target: the T2 tail goes to B2
+        assert_eq!(keys(cache.insert(Key(5), Val(5))), vec![0]);
+        assert_eq!(cache.shared().get_lens(), (2, 2));
+        // a hit in B2 shrinks T1 again
+        assert_eq!(keys(cache.insert(Key(0), Val(0))), vec![4]);
+        assert_eq!(cache.get_target(), 0);
+        assert_eq!(cache.shared().get_lens(), (1, 3));
+        assert!(!cache.shared().is_empty());
+    }
+
+    #[test]
+    fn full_t1_is_dropped_without_ghosts() {
+        let mut cache = Cache::new(2, 0, Hasher::default());
+        cache.insert(Key(0), Val(0));
+        cache.insert(Key(1), Val(1));
+        assert_eq!(keys(cache.insert(Key(2), Val(2))), vec![0]);
+        // 0 was not remembered: inserting it again is a plain miss
+        assert_eq!(keys(cache.insert(Key(0), Val(0))), vec![1]);
+        assert_eq!(cache.get_target(), 0);
+        assert_eq!(cache.shared().get_lens(), (2, 0));
+    }
+}
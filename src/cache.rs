@@ -0,0 +1,289 @@
+/*
+ * Copyright 2021 Luca Fulchir <luker@fenrirproject.org>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use crate::hashmap;
+use crate::hashmap::user;
+use crate::results::{InsertResult, InsertResultShared, LoadResult, Removed};
+
+/// Common interface of the caches that own their hashmap
+///
+/// Lets you write code that does not care about the eviction policy
+pub trait Cache<K, V, Umeta>
+where
+    K: user::Hash,
+    V: user::Val,
+    Umeta: user::Meta<V>,
+{
+    /// insert a new element. Can return a clash or an evicted element
+    fn insert(&mut self, key: K, val: V) -> InsertResult<(K, V, Umeta)> {
+        self.insert_with_meta(key, val, Umeta::new())
+    }
+    /// insert a new element, but with metadata
+    fn insert_with_meta(
+        &mut self,
+        key: K,
+        val: V,
+        user_data: Umeta,
+    ) -> InsertResult<(K, V, Umeta)>;
+    /// get references to the element's data
+    fn get(&mut self, key: &K) -> Option<(&V, &Umeta)>;
+    /// get mutable references to the element's data
+    fn get_mut(&mut self, key: &K) -> Option<(&mut V, &mut Umeta)>;
+    /// get the element, or insert the value returned by `f` if it is missing
+    fn get_or_insert_with<F>(
+        &mut self,
+        key: K,
+        f: F,
+    ) -> LoadResult<'_, K, V, Umeta>
+    where
+        Self: Sized,
+        F: FnOnce() -> V;
+    /// like [`get_or_insert_with`](Self::get_or_insert_with), but `f` can
+    /// fail, and then nothing is inserted
+    fn try_get_or_insert_with<F, E>(
+        &mut self,
+        key: K,
+        f: F,
+    ) -> Result<LoadResult<'_, K, V, Umeta>, E>
+    where
+        Self: Sized,
+        F: FnOnce() -> Result<V, E>;
+    /// remove an element, returned with
+    /// [`RemovalCause::Explicit`](crate::results::RemovalCause::Explicit)
+    fn remove(&mut self, key: &K) -> Removed<(K, V, Umeta)>;
+    /// check if a key is in the cache
+    fn contains_key(&self, key: &K) -> bool;
+    /// remove all the elements
+    fn clear(&mut self);
+    /// get the current number of elements
+    fn len(&self) -> usize;
+    /// check if the cache is empty
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    /// get the max number of elements
+    fn capacity(&self) -> usize;
+}
+
+/// Common interface of the caches that work on a shared hashmap
+///
+/// The caller does the actual insert/remove in the hashmap, the shared caches
+/// only fix their own state. This lets a cache be composed of any other
+/// shared caches, as long as they all use different cache ids
+pub trait SharedCache<'a, Hmap, E, K, V, Cid, Umeta, HB>
+where
+    Hmap: hashmap::HashMap<E, K, V, Cid, Umeta, HB>,
+    E: user::EntryT<K, V, Cid, Umeta>,
+    K: user::Hash,
+    V: user::Val,
+    Cid: user::Cid,
+    Umeta: user::Meta<V>,
+    HB: ::std::hash::BuildHasher + Default,
+{
+    /// An element has been added by the caller at `new_entry_idx`
+    ///
+    /// `maybe_old_entry` is `!= None` only if the new entry clashed with
+    /// an entry of this cache and took its place in the hashmap
+    fn insert_shared(
+        &mut self,
+        hmap: &mut Hmap,
+        maybe_old_entry: Option<&mut E>,
+        new_entry_idx: usize,
+    ) -> InsertResultShared;
+    /// An entry moved here from another cache on the same hashmap.
+    ///
+    /// Like an insert without clashes, but without the on-insert callback
+    fn move_in_shared(
+        &mut self,
+        hmap: &mut Hmap,
+        idx: usize,
+    ) -> InsertResultShared;
+    /// forget the entry at `idx`, so that the caller can remove it
+    fn remove_shared(&mut self, hmap: &mut Hmap, idx: usize);
+    /// `old_entry` was in this cache, but the entry at `idx` clashed with it
+    /// and took its place in the hashmap. Forget about the old entry
+    fn remove_clashed(&mut self, hmap: &mut Hmap, old_entry: &E, idx: usize);
+    /// reset the cache
+    fn clear_shared(&mut self);
+    /// an entry of this cache has been accessed
+    fn on_get(&mut self, hmap: &mut Hmap, idx: usize);
+    /// check if the entries with this cache id belong to this cache
+    fn owns(&self, cid: Cid) -> bool;
+    /// The index of the entry that will be evicted by the next insert, if the
+    /// cache is full
+    fn victim(&self, hmap: &Hmap) -> Option<usize>;
+    /// The hashmap has been resized and all the entries have moved.
+    fn relocate(&mut self, reloc: &hashmap::Relocation<E>);
+    /// change the lazy scan callback
+    fn set_scanf(
+        &mut self,
+        access_scan: Option<&'a dyn Fn(::std::ptr::NonNull<E>)>,
+    );
+    /// start the lazy scan
+    fn start_scan(&mut self);
+    /// check if the lazy scan is running
+    fn is_scan_running(&self) -> bool;
+    /// change what happens on a cache hit
+    fn set_hit_policy(&mut self, policy: crate::lru::HitPolicy);
+    /// get the current hit policy
+    fn get_hit_policy(&self) -> crate::lru::HitPolicy;
+    /// Change the number of entries of the cache
+    ///
+    /// The evicted entries are returned, the caller must remove them
+    fn set_capacity(
+        &mut self,
+        hmap: &mut Hmap,
+        entries: usize,
+    ) -> Removed<usize>;
+    /// get the max number of entries
+    fn capacity(&self) -> usize;
+    /// get the current number of entries
+    fn len(&self) -> usize;
+    /// check if the cache is empty
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    /// Weigh each entry with [`user::Meta::weight`], so that the capacity is
+    /// the max total weight instead of the max number of entries
+    ///
+    /// The caches that only count entries return
+    /// [`Error::WeightUnsupported`](crate::results::Error::WeightUnsupported)
+    fn set_weighted(
+        &mut self,
+        hmap: &Hmap,
+        weighted: bool,
+    ) -> Result<(), crate::results::Error>;
+    /// get the total weight of the entries, the same as `len()` when not
+    /// weighted
+    fn weight(&self) -> usize {
+        self.len()
+    }
+    /// check if the capacity is a max weight instead of a number of entries
+    fn is_weighted(&self) -> bool {
+        false
+    }
+    /// the instant after which the entry at `idx` is idle, for the caches
+    /// with a time-to-idle. See [`IdleCache`]
+    fn idle_deadline(&self, _idx: usize) -> Option<u64> {
+        None
+    }
+    /// the time of the next inserts and hits, where their idle timers
+    /// start. Without it they start from the last
+    /// [`advance`](IdleCache::advance)
+    fn set_now(&mut self, _now: u64) {}
+}
+
+/// Shared caches that can expire the entries not accessed for some time, with
+/// a [`TimerWheel`](crate::expiry::wheel::TimerWheel)
+pub trait IdleCache<'a, Hmap, E, K, V, Cid, Umeta, HB>:
+    SharedCache<'a, Hmap, E, K, V, Cid, Umeta, HB>
+where
+    Hmap: hashmap::HashMap<E, K, V, Cid, Umeta, HB>,
+    E: user::EntryT<K, V, Cid, Umeta>,
+    K: user::Hash,
+    V: user::Val,
+    Cid: user::Cid,
+    Umeta: user::Meta<V>,
+    HB: ::std::hash::BuildHasher + Default,
+{
+    /// Expire the entries that are not accessed for `idle`, starting from
+    /// `now`. `None` disables it
+    fn set_time_to_idle(
+        &mut self,
+        hmap: &Hmap,
+        idle: Option<::std::time::Duration>,
+        now: u64,
+    );
+    /// get the current time-to-idle
+    fn get_time_to_idle(&self) -> Option<::std::time::Duration>;
+    /// Move the time to `now` and forget the idle entries
+    ///
+    /// The idle entries are returned, the caller must remove them
+    fn advance(&mut self, hmap: &mut Hmap, now: u64) -> Removed<usize>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::results::RemovalCause;
+    use crate::testing::{Hasher, Key, Val};
+
+    type Boxed<'a> = Box<dyn Cache<Key, Val, user::ZeroMeta> + 'a>;
+
+    // the policies behind the same interface, picked at runtime
+    fn caches<'a>() -> Vec<Boxed<'a>> {
+        vec![
+            Box::new(crate::lru::LRU::new(8, 0, Hasher::default())),
+            Box::new(crate::slru::SLRU::new(2, 6, 0, Hasher::default())),
+            Box::new(crate::swtlfu::SWTLFU::new(8, 0, Hasher::default())),
+            Box::new(crate::arc::ARC::new(8, 0, Hasher::default())),
+            Box::new(crate::twoq::TwoQ::new(8, 0, Hasher::default())),
+            Box::new(crate::lfu::LFU::new(8, 0, Hasher::default())),
+            Box::new(crate::sieve::Sieve::new(8, 0, Hasher::default())),
+        ]
+    }
+
+    #[test]
+    fn same_behaviour_through_the_trait() {
+        for (i, mut cache) in caches().into_iter().enumerate() {
+            let capacity = cache.capacity();
+            assert!(cache.is_empty());
+            // the hits promote out of the small admission segments of
+            // SLRU and SW-TLFU, so the whole capacity fills up
+            for k in 0..capacity as u32 {
+                assert!(cache.insert(Key(k), Val(k)).is_empty(), "cache {}", i);
+                for j in 0..=k {
+                    assert!(cache.get(&Key(j)).is_some(), "cache {}", i);
+                }
+            }
+            assert_eq!(cache.len(), capacity);
+            assert!(cache.contains_key(&Key(0)));
+            assert_eq!(cache.get(&Key(0)).unwrap().0, &Val(0));
+            cache.get_mut(&Key(1)).unwrap().0 .0 = 10;
+            assert_eq!(cache.get(&Key(1)).unwrap().0, &Val(10));
+            // full: one in, one out
+            let res = cache.insert(Key(100), Val(100));
+            let causes: Vec<_> = res.iter().map(|(_, c)| *c).collect();
+            assert_eq!(causes, vec![RemovalCause::Capacity]);
+            assert_eq!(cache.len(), capacity);
+            // the victim depends on the policy, remove any key left
+            let k = (0..capacity as u32)
+                .find(|k| cache.contains_key(&Key(*k)))
+                .unwrap();
+            let res = cache.remove(&Key(k));
+            let old: Vec<_> = res.iter().map(|(e, c)| (e.0, *c)).collect();
+            assert_eq!(old, vec![(Key(k), RemovalCause::Explicit)]);
+            assert!(!cache.contains_key(&Key(k)));
+            cache.clear();
+            assert!(cache.is_empty());
+            assert!(cache.get(&Key(0)).is_none());
+        }
+    }
+
+    // generic code sees the loaders too
+    fn load<C: Cache<Key, Val, user::ZeroMeta>>(cache: &mut C, k: u32) -> u32 {
+        let (val, _) = cache.get_or_insert_with(Key(k), || Val(k * 2));
+        val.unwrap().0 .0
+    }
+
+    #[test]
+    fn generic_loaders() {
+        let mut cache = crate::lru::LRU::new(8, 0, Hasher::default());
+        assert_eq!(load(&mut cache, 3), 6);
+        cache.get_mut(&Key(3)).unwrap().0 .0 = 7;
+        assert_eq!(load(&mut cache, 3), 7);
+        assert_eq!(cache.len(), 1);
+    }
+}
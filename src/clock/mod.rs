@@ -0,0 +1,615 @@
+/*
+ * Copyright 2021 Luca Fulchir <luca@fenrirproject.org>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! CLOCK cache
+//!
+//! The LRU moves an entry to the head on every hit, which means rewriting
+//! the links of three entries. CLOCK only sets a reference bit in the
+//! [`user::Cid`] on a hit.  
+//! On eviction a "hand" walks the bucket array of the hashmap: referenced
+//! entries get a second chance and lose their bit, the first entry without
+//! the bit is evicted.
+//!
+//! There are no links between the entries, so the hand skips the buckets
+//! that are empty or that belong to other caches.
+//!
+//! The [`pro`] module has CLOCK-Pro, which splits the entries in hot and cold
+//! ones.
+
+pub mod pro;
+
+use crate::hashmap;
+use crate::hashmap::user;
+use crate::owned::Owned;
+use crate::results::{InsertResultShared, RemovalCause, Removed};
+
+// Layout: cid 6..0, CLOCK reference bit 7
+::bitfield::bitfield! {
+    /// Cache id in 7 bits plus the CLOCK reference bit
+    #[derive(Copy, Clone, Default)]
+    pub struct ClockCid(u8);
+    impl Debug;
+    #[inline]
+    pub u8, g_cid, s_cid: 6, 0;
+    #[inline]
+    pub g_reference, s_reference: 7;
+}
+impl ClockCid {
+    /// new cache id. `0` is reserved for entries with no cache
+    pub fn new(cache: u8) -> Self {
+        let mut res = ClockCid::default();
+        res.s_cid(cache);
+        res
+    }
+}
+impl user::Cid for ClockCid {
+    fn get_reference(&self) -> bool {
+        self.g_reference()
+    }
+    fn set_reference(&mut self, referenced: bool) {
+        self.s_reference(referenced)
+    }
+    fn set_cache(&mut self, cache: Self) {
+        self.s_cid(cache.g_cid());
+        self.s_reference(false);
+    }
+}
+impl PartialEq for ClockCid {
+    fn eq(&self, other: &Self) -> bool {
+        self.g_cid() == other.g_cid()
+    }
+}
+impl Eq for ClockCid {}
+
+type ClockEntry<K, V, Umeta> = user::Entry<K, V, ClockCid, Umeta>;
+type HmapT<K, V, Umeta, HB> =
+    hashmap::SimpleHmap<ClockEntry<K, V, Umeta>, K, V, ClockCid, Umeta, HB>;
+
+/// [CLOCK](https://en.wikipedia.org/wiki/Page_replacement_algorithm#Clock)
+/// implementation that wraps ClockShared, see [`Owned`]
+pub type Clock<'a, K, V, Umeta, HB> = Owned<
+    'a,
+    K,
+    V,
+    ClockCid,
+    Umeta,
+    HB,
+    ClockShared<
+        'a,
+        HmapT<K, V, Umeta, HB>,
+        ClockEntry<K, V, Umeta>,
+        K,
+        V,
+        ClockCid,
+        Umeta,
+        HB,
+    >,
+>;
+
+impl<
+        'a,
+        K: user::Hash,
+        V: user::Val,
+        Umeta: user::Meta<V>,
+        HB: ::std::hash::BuildHasher + Default,
+    > Clock<'a, K, V, Umeta, HB>
+{
+    /// new CLOCK with space for `entries` elements
+    pub fn new(
+        entries: usize,
+        extra_hashmap_capacity: usize,
+        hash_builder: HB,
+    ) -> Self {
+        Owned::from_shared(
+            ClockShared::new(entries, ClockCid::new(1), None),
+            extra_hashmap_capacity,
+            hash_builder,
+        )
+    }
+}
+
+/// Lazy scan over the bucket array, for the caches that do not link their
+/// entries.
+///
+/// Every time `apply_next` is called, "f" is run on one more entry of the
+/// cache, in bucket order
+pub(crate) struct IdxScan<'a, E> {
+    next: Option<usize>,
+    f: Option<&'a dyn Fn(::std::ptr::NonNull<E>)>,
+}
+
+impl<'a, E> IdxScan<'a, E> {
+    pub fn new(f: Option<&'a dyn Fn(::std::ptr::NonNull<E>)>) -> Self {
+        IdxScan { next: None, f }
+    }
+    pub fn set_scanf(&mut self, f: Option<&'a dyn Fn(::std::ptr::NonNull<E>)>) {
+        self.f = f
+    }
+    pub fn is_running(&self) -> bool {
+        self.next.is_some()
+    }
+    /// start from the first bucket, if not already running
+    pub fn start_scan(&mut self) {
+        if self.f.is_some() && self.next.is_none() {
+            self.next = Some(0);
+        }
+    }
+    pub fn stop(&mut self) {
+        self.next = None;
+    }
+    pub fn apply_raw(&self, entry: ::std::ptr::NonNull<E>) {
+        if let Some(f) = self.f {
+            f(entry);
+        }
+    }
+    /// Apply "f" to the next entry for which `owned` is true
+    pub fn apply_next<Hmap, K, V, CidT, Umeta, HB>(
+        &mut self,
+        hmap: &mut Hmap,
+        owned: impl Fn(CidT) -> bool,
+    ) where
+        Hmap: hashmap::HashMap<E, K, V, CidT, Umeta, HB>,
+        E: user::EntryT<K, V, CidT, Umeta>,
+        K: user::Hash,
+        V: user::Val,
+        CidT: user::Cid,
+        Umeta: user::Meta<V>,
+        HB: ::std::hash::BuildHasher,
+    {
+        let (start, f) = match (self.next, self.f) {
+            (Some(next), Some(f)) => (next, f),
+            _ => {
+                self.next = None;
+                return;
+            }
+        };
+        for idx in start..hmap.buckets() {
+            if let Some(entry) = hmap.get_index_mut(idx) {
+                if owned(entry.get_cache_id()) {
+                    self.next = Some(idx + 1);
+                    f(entry.into());
+                    return;
+                }
+            }
+        }
+        self.next = None;
+    }
+    /// The hashmap has been resized and the bucket order is lost.  
+    /// Restart the scan from the beginning
+    pub fn relocate(&mut self) {
+        if self.next.is_some() {
+            self.next = Some(0);
+        }
+    }
+}
+
+/// Actual implementation of the CLOCK on a shared hashmap
+///
+/// Note that Insert/Remove do not actually insert anything in the hashmap.
+/// That must be done by the caller. We only fix the CLOCK status
+pub struct ClockShared<'a, Hmap, E, K, V, CidT, Umeta, HB>
+where
+    Hmap: hashmap::HashMap<E, K, V, CidT, Umeta, HB>,
+    E: user::EntryT<K, V, CidT, Umeta>,
+    K: user::Hash,
+    V: user::Val,
+    CidT: user::Cid,
+    Umeta: user::Meta<V>,
+    HB: ::std::hash::BuildHasher + Default,
+{
+    _cache_id: CidT,
+    // next bucket to check on eviction
+    _hand: usize,
+    _capacity: usize,
+    _used: usize,
+    _scan: IdxScan<'a, E>,
+    _hmap: ::std::marker::PhantomData<Hmap>,
+    _k: ::std::marker::PhantomData<K>,
+    _v: ::std::marker::PhantomData<V>,
+    _umeta: ::std::marker::PhantomData<Umeta>,
+    _hb: ::std::marker::PhantomData<HB>,
+}
+
+impl<
+        'a,
+        Hmap: hashmap::HashMap<E, K, V, CidT, Umeta, HB>,
+        E: user::EntryT<K, V, CidT, Umeta>,
+        K: user::Hash,
+        V: user::Val,
+        CidT: user::Cid,
+        Umeta: user::Meta<V>,
+        HB: ::std::hash::BuildHasher + Default,
+    > ClockShared<'a, Hmap, E, K, V, CidT, Umeta, HB>
+{
+    /// new CLOCK with space for `entries` elements, its cache id and an
+    /// optional callback for the lazy scan
+    ///
+    /// The cache id must be able to store the reference bit, see
+    /// [`user::Cid`]
+    pub fn new(
+        entries: usize,
+        cache_id: CidT,
+        access_scan: Option<&'a dyn Fn(::std::ptr::NonNull<E>)>,
+    ) -> Self {
+        ClockShared {
+            _cache_id: cache_id,
+            _hand: 0,
+            _capacity: entries,
+            _used: 0,
+            _scan: IdxScan::new(access_scan),
+            _hmap: ::std::marker::PhantomData,
+            _k: ::std::marker::PhantomData,
+            _v: ::std::marker::PhantomData,
+            _umeta: ::std::marker::PhantomData,
+            _hb: ::std::marker::PhantomData,
+        }
+    }
+    /// change the scan callback
+    pub fn set_scanf(
+        &mut self,
+        access_scan: Option<&'a dyn Fn(::std::ptr::NonNull<E>)>,
+    ) {
+        self._scan.set_scanf(access_scan)
+    }
+    /// An element has been added by the caller, fix the CLOCK
+    ///
+    /// `maybe_old_entry` must be `!= None` only if the clash happened in
+    /// this cache
+    pub fn insert_shared(
+        &mut self,
+        hmap: &mut Hmap,
+        maybe_old_entry: Option<&mut E>,
+        new_entry_idx: usize,
+    ) -> InsertResultShared {
+        match maybe_old_entry {
+            None => {
+                let res = self.push(hmap, new_entry_idx);
+                let just_inserted = hmap.get_index_mut(new_entry_idx).unwrap();
+                just_inserted.user_on_insert(None);
+                self._scan.apply_raw(just_inserted.into());
+                self.scan_next(hmap);
+                res
+            }
+            Some(old_entry) => {
+                // the new entry took the bucket of the old one, which was
+                // ours. A new value for the same key counts as a hit
+                let just_inserted = hmap.get_index_mut(new_entry_idx).unwrap();
+                let same_key = old_entry.get_key() == just_inserted.get_key();
+                let cid = just_inserted.get_cache_id_mut();
+                cid.set_cache(self._cache_id);
+                cid.set_reference(same_key);
+                just_inserted.user_on_insert(Some(old_entry));
+                self._scan.apply_raw(just_inserted.into());
+                self.scan_next(hmap);
+                Removed::new()
+            }
+        }
+    }
+    /// An entry moved here from another cache on the same hashmap
+    ///
+    /// Like [`insert_shared`](Self::insert_shared) without clashes, but the
+    /// on-insert callback is not run, since this is not a new insert
+    pub fn move_in_shared(
+        &mut self,
+        hmap: &mut Hmap,
+        idx: usize,
+    ) -> InsertResultShared {
+        let res = self.push(hmap, idx);
+        self.scan_next(hmap);
+        res
+    }
+    // make room if needed, then take the entry at `idx`
+    fn push(&mut self, hmap: &mut Hmap, idx: usize) -> InsertResultShared {
+        // evict first, so that the new entry is not considered
+        let maybe_evicted = if self._used >= self._capacity {
+            self.evict(hmap)
+        } else {
+            None
+        };
+        hmap.get_index_mut(idx)
+            .unwrap()
+            .get_cache_id_mut()
+            .set_cache(self._cache_id);
+        self._used += 1;
+        match maybe_evicted {
+            None => Removed::new(),
+            Some(evicted) => Removed::single(evicted, RemovalCause::Capacity),
+        }
+    }
+    // move the hand until an entry without the reference bit is found.
+    // The entry is still in the hashmap but does not belong to the CLOCK
+    // anymore
+    fn evict(&mut self, hmap: &mut Hmap) -> Option<usize> {
+        if self._used == 0 {
+            return None;
+        }
+        let buckets = hmap.buckets();
+        // the first turn might just clear all the reference bits
+        for _ in 0..(2 * buckets) {
+            let idx = self._hand;
+            self._hand = (self._hand + 1) % buckets;
+            let cid = match hmap.get_index_mut(idx) {
+                Some(entry) => entry.get_cache_id_mut(),
+                None => continue,
+            };
+            if *cid != self._cache_id {
+                continue;
+            }
+            if cid.get_reference() {
+                cid.set_reference(false);
+                continue;
+            }
+            cid.set_cache(CidT::default());
+            self._used -= 1;
+            return Some(idx);
+        }
+        None
+    }
+    // run the user scan on one more entry
+    fn scan_next(&mut self, hmap: &mut Hmap) {
+        let cache_id = self._cache_id;
+        self._scan.apply_next(hmap, |cid: CidT| cid == cache_id);
+    }
+    /// The index of the entry that will be evicted by the next insert, if
+    /// the CLOCK is full
+    pub fn victim(&self, hmap: &Hmap) -> Option<usize> {
+        if self._used < self._capacity || self._used == 0 {
+            return None;
+        }
+        let buckets = hmap.buckets();
+        let mut first = None;
+        for i in 0..buckets {
+            let idx = (self._hand + i) % buckets;
+            let cid = match hmap.get_index(idx) {
+                Some(entry) => entry.get_cache_id(),
+                None => continue,
+            };
+            if cid != self._cache_id {
+                continue;
+            }
+            if !cid.get_reference() {
+                return Some(idx);
+            }
+            first = first.or(Some(idx));
+        }
+        // all referenced: the hand will clear them all and come back
+        first
+    }
+    /// reset the CLOCK
+    pub fn clear_shared(&mut self) {
+        self._hand = 0;
+        self._used = 0;
+        self._scan.stop();
+    }
+    /// do not actually remove the element at `idx`, just fix the CLOCK so
+    /// that it is not considered anymore and actual removal is safe
+    pub fn remove_shared(&mut self, _hmap: &mut Hmap, _idx: usize) {
+        self._used -= 1;
+    }
+    /// `old_entry` was in the CLOCK, but the entry at `idx` clashed with it
+    /// and took its place in the hashmap. Forget about the old entry
+    pub fn remove_clashed(
+        &mut self,
+        _hmap: &mut Hmap,
+        _old_entry: &E,
+        _idx: usize,
+    ) {
+        self._used -= 1;
+    }
+    /// Change the number of entries of the CLOCK
+    ///
+    /// When shrinking, the evicted entries are returned.  
+    /// Just like with [`InsertResultShared`] they are still in the
+    /// hashmap and must be removed by the caller
+    pub fn set_capacity(
+        &mut self,
+        hmap: &mut Hmap,
+        entries: usize,
+    ) -> Removed<usize> {
+        self._capacity = entries;
+        let mut evicted = Removed::new();
+        while self._used > self._capacity {
+            match self.evict(hmap) {
+                Some(idx) => evicted.push(idx, RemovalCause::Capacity),
+                None => break,
+            }
+        }
+        evicted
+    }
+    /// The hashmap has been resized, all the entries moved.
+    ///
+    /// The hand keeps pointing to the same entry, if it was on one
+    pub fn relocate(&mut self, reloc: &hashmap::Relocation<E>) {
+        self._hand = reloc.index(self._hand).unwrap_or(0);
+        self._scan.relocate();
+    }
+    /// get the CLOCK cache id
+    pub fn get_cache_id(&self) -> CidT {
+        self._cache_id
+    }
+    /// Used when composing caches: set the reference bit and run the
+    /// callback on the entry at `idx`
+    pub fn on_get(&mut self, hmap: &mut Hmap, idx: usize) {
+        let entry = hmap.get_index_mut(idx).unwrap();
+        entry.get_cache_id_mut().set_reference(true);
+        entry.user_on_get();
+        self.scan_next(hmap);
+    }
+    /// start the scan on the entries of this CLOCK, in bucket order
+    pub fn start_scan(&mut self) {
+        if self._used > 0 {
+            self._scan.start_scan();
+        }
+    }
+    /// check if the scan is still running
+    pub fn is_scan_running(&self) -> bool {
+        self._scan.is_running()
+    }
+    /// get the CLOCK capacity
+    pub fn capacity(&self) -> usize {
+        self._capacity
+    }
+    /// get the CLOCK usage
+    pub fn len(&self) -> usize {
+        self._used
+    }
+    /// check if the CLOCK is empty
+    pub fn is_empty(&self) -> bool {
+        self._used == 0
+    }
+}
+
+impl<
+        'a,
+        Hmap: hashmap::HashMap<E, K, V, CidT, Umeta, HB>,
+        E: user::EntryT<K, V, CidT, Umeta>,
+        K: user::Hash,
+        V: user::Val,
+        CidT: user::Cid,
+        Umeta: user::Meta<V>,
+        HB: ::std::hash::BuildHasher + Default,
+    > crate::cache::SharedCache<'a, Hmap, E, K, V, CidT, Umeta, HB>
+    for ClockShared<'a, Hmap, E, K, V, CidT, Umeta, HB>
+{
+    fn insert_shared(
+        &mut self,
+        hmap: &mut Hmap,
+        maybe_old_entry: Option<&mut E>,
+        new_entry_idx: usize,
+    ) -> InsertResultShared {
+        ClockShared::insert_shared(self, hmap, maybe_old_entry, new_entry_idx)
+    }
+    fn move_in_shared(
+        &mut self,
+        hmap: &mut Hmap,
+        idx: usize,
+    ) -> InsertResultShared {
+        ClockShared::move_in_shared(self, hmap, idx)
+    }
+    fn remove_shared(&mut self, hmap: &mut Hmap, idx: usize) {
+        ClockShared::remove_shared(self, hmap, idx)
+    }
+    fn remove_clashed(&mut self, hmap: &mut Hmap, old_entry: &E, idx: usize) {
+        ClockShared::remove_clashed(self, hmap, old_entry, idx)
+    }
+    fn clear_shared(&mut self) {
+        ClockShared::clear_shared(self)
+    }
+    fn on_get(&mut self, hmap: &mut Hmap, idx: usize) {
+        ClockShared::on_get(self, hmap, idx)
+    }
+    fn owns(&self, cid: CidT) -> bool {
+        cid == self._cache_id
+    }
+    fn victim(&self, hmap: &Hmap) -> Option<usize> {
+        ClockShared::victim(self, hmap)
+    }
+    fn relocate(&mut self, reloc: &hashmap::Relocation<E>) {
+        ClockShared::relocate(self, reloc)
+    }
+    fn set_scanf(
+        &mut self,
+        access_scan: Option<&'a dyn Fn(::std::ptr::NonNull<E>)>,
+    ) {
+        ClockShared::set_scanf(self, access_scan)
+    }
+    fn start_scan(&mut self) {
+        ClockShared::start_scan(self)
+    }
+    fn is_scan_running(&self) -> bool {
+        ClockShared::is_scan_running(self)
+    }
+    // CLOCK always works with the reference bit
+    fn set_hit_policy(&mut self, _policy: crate::lru::HitPolicy) {}
+    fn get_hit_policy(&self) -> crate::lru::HitPolicy {
+        crate::lru::HitPolicy::Clock
+    }
+    fn set_capacity(
+        &mut self,
+        hmap: &mut Hmap,
+        entries: usize,
+    ) -> Removed<usize> {
+        ClockShared::set_capacity(self, hmap, entries)
+    }
+    fn capacity(&self) -> usize {
+        ClockShared::capacity(self)
+    }
+    fn len(&self) -> usize {
+        ClockShared::len(self)
+    }
+    // only counts entries
+    fn set_weighted(
+        &mut self,
+        _hmap: &Hmap,
+        weighted: bool,
+    ) -> Result<(), crate::results::Error> {
+        match weighted {
+            false => Ok(()),
+            true => Err(crate::results::Error::WeightUnsupported),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hashmap::user::EntryT;
+    use crate::testing::{Hasher, Key, Val};
+
+    type Cache<'a> = Clock<'a, Key, Val, user::ZeroMeta, Hasher>;
+
+    fn victim(cache: &Cache) -> Key {
+        let idx = cache.shared().victim(cache.hmap()).unwrap();
+        *cache.hmap().get_index(idx).unwrap().get_key()
+    }
+    fn evicted(res: Removed<(Key, Val, user::ZeroMeta)>) -> Vec<Key> {
+        res.iter().map(|(e, _)| e.0).collect()
+    }
+
+    #[test]
+    fn referenced_entries_get_a_second_chance() {
+        let mut cache = Cache::new(4, 0, Hasher::default());
+        for k in 0..4 {
+            cache.insert(Key(k), Val(k));
+        }
+        for k in [0, 1, 3] {
+            cache.get(&Key(k));
+        }
+        assert_eq!(victim(&cache), Key(2));
+        assert_eq!(evicted(cache.insert(Key(4), Val(4))), vec![Key(2)]);
+        assert_eq!(cache.len(), 4);
+        for k in [0, 1, 3, 4] {
+            assert!(cache.contains_key(&Key(k)));
+        }
+    }
+
+    #[test]
+    fn hand_wraps_around_when_all_are_referenced() {
+        let mut cache = Cache::new(4, 0, Hasher::default());
+        for k in 0..4 {
+            cache.insert(Key(k), Val(k));
+            cache.get(&Key(k));
+        }
+        // a full turn clears all the bits, then the first entry goes
+        for k in 4..12 {
+            let expected = victim(&cache);
+            assert_eq!(evicted(cache.insert(Key(k), Val(k))), vec![expected]);
+            assert_eq!(cache.len(), 4);
+            assert!(cache.contains_key(&Key(k)));
+        }
+        assert!(!cache.shared().is_empty());
+    }
+}
@@ -0,0 +1,582 @@
+/*
+ * Copyright 2021 Luca Fulchir <luca@fenrirproject.org>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! CLOCK-Pro cache
+//!
+//! Entries are either hot or cold. New entries are cold, and a cold entry
+//! that is hit before the cold hand reaches it becomes hot. Only cold
+//! entries are evicted, the hot hand turns unreferenced hot entries to cold
+//! when there are too many hot entries.
+//!
+//! The keys of evicted cold entries are kept in a non-resident list: if
+//! they come back they are inserted directly as hot and the target for the
+//! cold entries grows. If they are forgotten without coming back the target
+//! shrinks.
+//!
+//! Hot and cold are two different cache ids, the reference bit is in the
+//! [`user::Cid`] just like for [`ClockShared`](super::ClockShared).  
+//! Unlike the original paper, the non-resident keys are not in the clock
+//! but in their own FIFO list, as big as the cache, which works as the
+//! test period.
+//!
+//! See the [original paper](https://www.usenix.org/legacy/event/usenix05/tech/general/full_papers/jiang/jiang.pdf)
+
+use super::{ClockCid, IdxScan};
+use crate::ghost::Ghost;
+use crate::hashmap;
+use crate::hashmap::user;
+use crate::owned::Owned;
+use crate::results::{InsertResultShared, RemovalCause, Removed};
+
+type ClockProEntry<K, V, Umeta> = user::Entry<K, V, ClockCid, Umeta>;
+type HmapT<K, V, Umeta, HB> =
+    hashmap::SimpleHmap<ClockProEntry<K, V, Umeta>, K, V, ClockCid, Umeta, HB>;
+
+/// [CLOCK-Pro](https://en.wikipedia.org/wiki/Page_replacement_algorithm#Variants_on_clock)
+/// implementation that wraps ClockProShared, see [`Owned`]
+pub type ClockPro<'a, K, V, Umeta, HB> = Owned<
+    'a,
+    K,
+    V,
+    ClockCid,
+    Umeta,
+    HB,
+    ClockProShared<
+        'a,
+        HmapT<K, V, Umeta, HB>,
+        ClockProEntry<K, V, Umeta>,
+        K,
+        V,
+        ClockCid,
+        Umeta,
+        HB,
+    >,
+>;
+
+impl<
+        'a,
+        K: user::Hash,
+        V: user::Val,
+        Umeta: user::Meta<V>,
+        HB: ::std::hash::BuildHasher + Default,
+    > ClockPro<'a, K, V, Umeta, HB>
+{
+    /// new CLOCK-Pro with space for `entries` elements
+    pub fn new(
+        entries: usize,
+        extra_hashmap_capacity: usize,
+        hash_builder: HB,
+    ) -> Self {
+        Owned::from_shared(
+            ClockProShared::new(
+                entries,
+                ClockCid::new(1),
+                ClockCid::new(2),
+                None,
+            ),
+            extra_hashmap_capacity,
+            hash_builder,
+        )
+    }
+    /// get the current target number of cold entries
+    pub fn get_cold_target(&self) -> usize {
+        self.shared().get_cold_target()
+    }
+}
+
+/// Actual implementation of the CLOCK-Pro on a shared hashmap
+///
+/// Note that Insert/Remove do not actually insert anything in the hashmap.
+/// That must be done by the caller. We only fix the CLOCK-Pro status
+pub struct ClockProShared<'a, Hmap, E, K, V, CidT, Umeta, HB>
+where
+    Hmap: hashmap::HashMap<E, K, V, CidT, Umeta, HB>,
+    E: user::EntryT<K, V, CidT, Umeta>,
+    K: user::Hash,
+    V: user::Val,
+    CidT: user::Cid,
+    Umeta: user::Meta<V>,
+    HB: ::std::hash::BuildHasher + Default,
+{
+    _cold_cid: CidT,
+    _hot_cid: CidT,
+    _hand_cold: usize,
+    _hand_hot: usize,
+    _cold: usize,
+    _hot: usize,
+    _capacity: usize,
+    // target number of cold entries
+    _cold_target: usize,
+    // keys of the evicted cold entries
+    _non_resident: Ghost<K, HB>,
+    _scan: IdxScan<'a, E>,
+    _hmap: ::std::marker::PhantomData<Hmap>,
+    _v: ::std::marker::PhantomData<V>,
+    _umeta: ::std::marker::PhantomData<Umeta>,
+}
+
+impl<
+        'a,
+        Hmap: hashmap::HashMap<E, K, V, CidT, Umeta, HB>,
+        E: user::EntryT<K, V, CidT, Umeta>,
+        K: user::Hash,
+        V: user::Val,
+        CidT: user::Cid,
+        Umeta: user::Meta<V>,
+        HB: ::std::hash::BuildHasher + Default,
+    > ClockProShared<'a, Hmap, E, K, V, CidT, Umeta, HB>
+{
+    /// new CLOCK-Pro with space for `entries` elements, the cache ids of the
+    /// cold and hot entries and an optional callback for the lazy scan
+    ///
+    /// The cache ids must be able to store the reference bit, see
+    /// [`user::Cid`]
+    pub fn new(
+        entries: usize,
+        cold_cid: CidT,
+        hot_cid: CidT,
+        access_scan: Option<&'a dyn Fn(::std::ptr::NonNull<E>)>,
+    ) -> Self {
+        ClockProShared {
+            _cold_cid: cold_cid,
+            _hot_cid: hot_cid,
+            _hand_cold: 0,
+            _hand_hot: 0,
+            _cold: 0,
+            _hot: 0,
+            _capacity: entries,
+            _cold_target: 1,
+            _non_resident: Ghost::new(),
+            _scan: IdxScan::new(access_scan),
+            _hmap: ::std::marker::PhantomData,
+            _v: ::std::marker::PhantomData,
+            _umeta: ::std::marker::PhantomData,
+        }
+    }
+    /// change the scan callback
+    pub fn set_scanf(
+        &mut self,
+        access_scan: Option<&'a dyn Fn(::std::ptr::NonNull<E>)>,
+    ) {
+        self._scan.set_scanf(access_scan)
+    }
+    /// An element has been added by the caller, fix the CLOCK-Pro
+    ///
+    /// `maybe_old_entry` must be `!= None` only if the clash happened in
+    /// this cache
+    pub fn insert_shared(
+        &mut self,
+        hmap: &mut Hmap,
+        maybe_old_entry: Option<&mut E>,
+        new_entry_idx: usize,
+    ) -> InsertResultShared {
+        let res = match maybe_old_entry {
+            None => {
+                let res = self.push(hmap, new_entry_idx);
+                hmap.get_index_mut(new_entry_idx)
+                    .unwrap()
+                    .user_on_insert(None);
+                res
+            }
+            Some(old_entry)
+                if old_entry.get_key()
+                    == hmap.get_index(new_entry_idx).unwrap().get_key() =>
+            {
+                // same key, just a new value: keep it hot or cold, and
+                // count it as a hit
+                let just_inserted = hmap.get_index_mut(new_entry_idx).unwrap();
+                let cid = just_inserted.get_cache_id_mut();
+                cid.set_cache(old_entry.get_cache_id());
+                cid.set_reference(true);
+                just_inserted.user_on_insert(Some(old_entry));
+                Removed::new()
+            }
+            Some(old_entry) => {
+                // a different key took the bucket of the old entry
+                self.forget(old_entry.get_cache_id());
+                let res = self.push(hmap, new_entry_idx);
+                hmap.get_index_mut(new_entry_idx)
+                    .unwrap()
+                    .user_on_insert(Some(old_entry));
+                res
+            }
+        };
+        self._scan
+            .apply_raw(hmap.get_index_mut(new_entry_idx).unwrap().into());
+        self.scan_next(hmap);
+        res
+    }
+    /// An entry moved here from another cache on the same hashmap
+    ///
+    /// Like [`insert_shared`](Self::insert_shared) without clashes, but the
+    /// on-insert callback is not run, since this is not a new insert
+    pub fn move_in_shared(
+        &mut self,
+        hmap: &mut Hmap,
+        idx: usize,
+    ) -> InsertResultShared {
+        let res = self.push(hmap, idx);
+        self.scan_next(hmap);
+        res
+    }
+    // make room if needed, then take the entry at `idx`.
+    // Keys in their test period become hot, the others cold
+    fn push(&mut self, hmap: &mut Hmap, idx: usize) -> InsertResultShared {
+        // evict first, so that the new entry is not considered
+        let maybe_evicted = if self.len() >= self._capacity {
+            self.evict(hmap)
+        } else {
+            None
+        };
+        let key = hmap.get_index(idx).unwrap().get_key();
+        let hot = self._non_resident.remove(key);
+        let cid = hmap.get_index_mut(idx).unwrap().get_cache_id_mut();
+        if hot {
+            // the key came back during its test period: there should have
+            // been more space for cold entries
+            self._cold_target = ::std::cmp::min(
+                self._cold_target + 1,
+                ::std::cmp::max(1, self._capacity.saturating_sub(1)),
+            );
+            cid.set_cache(self._hot_cid);
+            self._hot += 1;
+            self.balance_hot(hmap);
+        } else {
+            cid.set_cache(self._cold_cid);
+            self._cold += 1;
+        }
+        match maybe_evicted {
+            None => Removed::new(),
+            Some(evicted) => Removed::single(evicted, RemovalCause::Capacity),
+        }
+    }
+    // an entry of this cache is gone
+    fn forget(&mut self, cid: CidT) {
+        if cid == self._hot_cid {
+            self._hot -= 1;
+        } else {
+            self._cold -= 1;
+        }
+    }
+    // next entry of this cache with the given cid, from the given hand
+    fn next_with(hmap: &Hmap, hand: usize, cid: CidT) -> Option<usize> {
+        let buckets = hmap.buckets();
+        (0..buckets).map(|i| (hand + i) % buckets).find(|&idx| {
+            match hmap.get_index(idx) {
+                Some(entry) => entry.get_cache_id() == cid,
+                None => false,
+            }
+        })
+    }
+    // move the cold hand until an unreferenced cold entry is found.
+    // Referenced cold entries become hot.
+    // The evicted entry is still in the hashmap but does not belong to the
+    // CLOCK-Pro anymore, and its key starts the test period
+    fn evict(&mut self, hmap: &mut Hmap) -> Option<usize> {
+        // each loop either evicts, or turns an entry from cold to hot or
+        // from hot to cold, clearing its reference bit
+        for _ in 0..(2 * self.len() + 1) {
+            if self._cold == 0 && !self.run_hot_hand(hmap) {
+                return None;
+            }
+            let idx = Self::next_with(hmap, self._hand_cold, self._cold_cid)?;
+            self._hand_cold = (idx + 1) % hmap.buckets();
+            let entry = hmap.get_index_mut(idx).unwrap();
+            let cid = entry.get_cache_id_mut();
+            if cid.get_reference() {
+                cid.set_cache(self._hot_cid);
+                self._cold -= 1;
+                self._hot += 1;
+                self.balance_hot(hmap);
+                continue;
+            }
+            cid.set_cache(CidT::default());
+            self._cold -= 1;
+            let key = entry.get_key().clone();
+            self._non_resident.push(key);
+            if self._non_resident.len() > self._capacity {
+                // a test period ended without a hit: less space for the
+                // cold entries
+                self._non_resident.pop_lru();
+                self._cold_target =
+                    ::std::cmp::max(1, self._cold_target.saturating_sub(1));
+            }
+            return Some(idx);
+        }
+        None
+    }
+    // turn hot entries to cold until there are not too many
+    fn balance_hot(&mut self, hmap: &mut Hmap) {
+        let hot_target = self._capacity.saturating_sub(self._cold_target);
+        while self._hot > hot_target {
+            if !self.run_hot_hand(hmap) {
+                return;
+            }
+        }
+    }
+    // move the hot hand until an unreferenced hot entry is found and turn
+    // it cold, clearing the reference bits on the way
+    fn run_hot_hand(&mut self, hmap: &mut Hmap) -> bool {
+        for _ in 0..(self._hot + 1) {
+            let idx = match Self::next_with(hmap, self._hand_hot, self._hot_cid)
+            {
+                None => return false,
+                Some(idx) => idx,
+            };
+            self._hand_hot = (idx + 1) % hmap.buckets();
+            let cid = hmap.get_index_mut(idx).unwrap().get_cache_id_mut();
+            if cid.get_reference() {
+                cid.set_reference(false);
+                continue;
+            }
+            cid.set_cache(self._cold_cid);
+            self._hot -= 1;
+            self._cold += 1;
+            return true;
+        }
+        false
+    }
+    // run the user scan on one more entry
+    fn scan_next(&mut self, hmap: &mut Hmap) {
+        let (cold, hot) = (self._cold_cid, self._hot_cid);
+        self._scan
+            .apply_next(hmap, |cid: CidT| cid == cold || cid == hot);
+    }
+    /// The index of the cold entry that will probably be evicted by the next
+    /// insert, if the CLOCK-Pro is full
+    pub fn victim(&self, hmap: &Hmap) -> Option<usize> {
+        if self.len() < self._capacity {
+            return None;
+        }
+        let buckets = hmap.buckets();
+        let mut first = None;
+        for i in 0..buckets {
+            let idx = (self._hand_cold + i) % buckets;
+            let cid = match hmap.get_index(idx) {
+                Some(entry) => entry.get_cache_id(),
+                None => continue,
+            };
+            if cid != self._cold_cid {
+                continue;
+            }
+            if !cid.get_reference() {
+                return Some(idx);
+            }
+            first = first.or(Some(idx));
+        }
+        first
+    }
+    /// reset the CLOCK-Pro, non-resident keys included
+    pub fn clear_shared(&mut self) {
+        self._hand_cold = 0;
+        self._hand_hot = 0;
+        self._cold = 0;
+        self._hot = 0;
+        self._cold_target = 1;
+        self._non_resident.clear();
+        self._scan.stop();
+    }
+    /// do not actually remove the element at `idx`, just fix the CLOCK-Pro
+    /// so that it is not considered anymore and actual removal is safe
+    pub fn remove_shared(&mut self, hmap: &mut Hmap, idx: usize) {
+        let cid = hmap.get_index(idx).unwrap().get_cache_id();
+        self.forget(cid);
+    }
+    /// `old_entry` was in the CLOCK-Pro, but the entry at `idx` clashed with
+    /// it and took its place in the hashmap. Forget about the old entry
+    pub fn remove_clashed(
+        &mut self,
+        _hmap: &mut Hmap,
+        old_entry: &E,
+        _idx: usize,
+    ) {
+        self.forget(old_entry.get_cache_id());
+    }
+    /// Change the number of entries of the CLOCK-Pro
+    ///
+    /// When shrinking, the evicted entries are returned.  
+    /// Just like with [`InsertResultShared`] they are still in the
+    /// hashmap and must be removed by the caller
+    pub fn set_capacity(
+        &mut self,
+        hmap: &mut Hmap,
+        entries: usize,
+    ) -> Removed<usize> {
+        self._capacity = entries;
+        self._cold_target = ::std::cmp::max(
+            1,
+            ::std::cmp::min(self._cold_target, entries.saturating_sub(1)),
+        );
+        let mut evicted = Removed::new();
+        while self.len() > self._capacity {
+            match self.evict(hmap) {
+                Some(idx) => evicted.push(idx, RemovalCause::Capacity),
+                None => break,
+            }
+        }
+        while self._non_resident.len() > self._capacity {
+            self._non_resident.pop_lru();
+        }
+        self.balance_hot(hmap);
+        evicted
+    }
+    /// The hashmap has been resized, all the entries moved.
+    ///
+    /// The hands keep pointing to the same entries, if they were on one
+    pub fn relocate(&mut self, reloc: &hashmap::Relocation<E>) {
+        self._hand_cold = reloc.index(self._hand_cold).unwrap_or(0);
+        self._hand_hot = reloc.index(self._hand_hot).unwrap_or(0);
+        self._scan.relocate();
+    }
+    /// return the cache ids for `(cold, hot)`
+    pub fn get_cache_ids(&self) -> (CidT, CidT) {
+        (self._cold_cid, self._hot_cid)
+    }
+    /// Used when composing caches: set the reference bit and run the
+    /// callback on the entry at `idx`
+    pub fn on_get(&mut self, hmap: &mut Hmap, idx: usize) {
+        let entry = hmap.get_index_mut(idx).unwrap();
+        entry.get_cache_id_mut().set_reference(true);
+        entry.user_on_get();
+        self.scan_next(hmap);
+    }
+    /// start the scan on the entries of this CLOCK-Pro, in bucket order
+    pub fn start_scan(&mut self) {
+        if !self.is_empty() {
+            self._scan.start_scan();
+        }
+    }
+    /// check if the scan is still running
+    pub fn is_scan_running(&self) -> bool {
+        self._scan.is_running()
+    }
+    /// get the current target number of cold entries
+    pub fn get_cold_target(&self) -> usize {
+        self._cold_target
+    }
+    /// return the current number of `(cold, hot)` entries
+    pub fn get_lens(&self) -> (usize, usize) {
+        (self._cold, self._hot)
+    }
+    /// get the CLOCK-Pro capacity
+    pub fn capacity(&self) -> usize {
+        self._capacity
+    }
+    /// get the CLOCK-Pro usage
+    pub fn len(&self) -> usize {
+        self._cold + self._hot
+    }
+    /// check if the CLOCK-Pro is empty
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<
+        'a,
+        Hmap: hashmap::HashMap<E, K, V, CidT, Umeta, HB>,
+        E: user::EntryT<K, V, CidT, Umeta>,
+        K: user::Hash,
+        V: user::Val,
+        CidT: user::Cid,
+        Umeta: user::Meta<V>,
+        HB: ::std::hash::BuildHasher + Default,
+    > crate::cache::SharedCache<'a, Hmap, E, K, V, CidT, Umeta, HB>
+    for ClockProShared<'a, Hmap, E, K, V, CidT, Umeta, HB>
+{
+    fn insert_shared(
+        &mut self,
+        hmap: &mut Hmap,
+        maybe_old_entry: Option<&mut E>,
+        new_entry_idx: usize,
+    ) -> InsertResultShared {
+        ClockProShared::insert_shared(
+            self,
+            hmap,
+            maybe_old_entry,
+            new_entry_idx,
+        )
+    }
+    fn move_in_shared(
+        &mut self,
+        hmap: &mut Hmap,
+        idx: usize,
+    ) -> InsertResultShared {
+        ClockProShared::move_in_shared(self, hmap, idx)
+    }
+    fn remove_shared(&mut self, hmap: &mut Hmap, idx: usize) {
+        ClockProShared::remove_shared(self, hmap, idx)
+    }
+    fn remove_clashed(&mut self, hmap: &mut Hmap, old_entry: &E, idx: usize) {
+        ClockProShared::remove_clashed(self, hmap, old_entry, idx)
+    }
+    fn clear_shared(&mut self) {
+        ClockProShared::clear_shared(self)
+    }
+    fn on_get(&mut self, hmap: &mut Hmap, idx: usize) {
+        ClockProShared::on_get(self, hmap, idx)
+    }
+    fn owns(&self, cid: CidT) -> bool {
+        cid == self._cold_cid || cid == self._hot_cid
+    }
+    fn victim(&self, hmap: &Hmap) -> Option<usize> {
+        ClockProShared::victim(self, hmap)
+    }
+    fn relocate(&mut self, reloc: &hashmap::Relocation<E>) {
+        ClockProShared::relocate(self, reloc)
+    }
+    fn set_scanf(
+        &mut self,
+        access_scan: Option<&'a dyn Fn(::std::ptr::NonNull<E>)>,
+    ) {
+        ClockProShared::set_scanf(self, access_scan)
+    }
+    fn start_scan(&mut self) {
+        ClockProShared::start_scan(self)
+    }
+    fn is_scan_running(&self) -> bool {
+        ClockProShared::is_scan_running(self)
+    }
+    // CLOCK-Pro always works with the reference bit
+    fn set_hit_policy(&mut self, _policy: crate::lru::HitPolicy) {}
+    fn get_hit_policy(&self) -> crate::lru::HitPolicy {
+        crate::lru::HitPolicy::Clock
+    }
+    fn set_capacity(
+        &mut self,
+        hmap: &mut Hmap,
+        entries: usize,
+    ) -> Removed<usize> {
+        ClockProShared::set_capacity(self, hmap, entries)
+    }
+    fn capacity(&self) -> usize {
+        ClockProShared::capacity(self)
+    }
+    fn len(&self) -> usize {
+        ClockProShared::len(self)
+    }
+    // only counts entries
+    fn set_weighted(
+        &mut self,
+        _hmap: &Hmap,
+        weighted: bool,
+    ) -> Result<(), crate::results::Error> {
+        match weighted {
+            false => Ok(()),
+            true => Err(crate::results::Error::WeightUnsupported),
+        }
+    }
+}
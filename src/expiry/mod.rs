@@ -0,0 +1,107 @@
+/*
+ * Copyright 2021 Luca Fulchir <luker@fenrirproject.org>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+//! Time sources for the entries that expire
+//!
+//! The owning caches can insert entries with a TTL (`insert_with_ttl`).
+//! The expiry instant is kept in the entry metadata, so `Umeta` needs space
+//! for it, like [`user::TtlMeta`](crate::hashmap::user::TtlMeta).  
+//! Expired entries are treated as misses by `get`, and are reclaimed lazily:
+//! each insert, get or remove checks one more bucket, so there is no
+//! background thread. The reclaimed entries are returned with
+//! [`RemovalCause::Expired`](crate::results::RemovalCause::Expired) by the
+//! next insert, remove or `advance`
+//!
+//! All instants are in nanoseconds from an arbitrary origin, chosen by the
+//! [`TimeSource`]
+//!
+//! Entries can also expire after some time without access, with the
+//! [`wheel::TimerWheel`] of the shared LRU, SLRU and SW-TLFU
+
+pub mod wheel;
+
+/// Where the caches read the current time
+///
+/// The time must never go back
+pub trait TimeSource {
+    /// nanoseconds since the origin of this time source
+    fn now(&self) -> u64;
+}
+
+impl<T: TimeSource + ?Sized> TimeSource for &T {
+    fn now(&self) -> u64 {
+        (**self).now()
+    }
+}
+
+/// The default time source, based on [`std::time::Instant`]
+///
+/// The origin is the creation of the time source
+pub struct MonotonicTime {
+    _start: ::std::time::Instant,
+}
+
+impl MonotonicTime {
+    pub fn new() -> Self {
+        MonotonicTime {
+            _start: ::std::time::Instant::now(),
+        }
+    }
+}
+
+impl Default for MonotonicTime {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TimeSource for MonotonicTime {
+    fn now(&self) -> u64 {
+        nanos(self._start.elapsed())
+    }
+}
+
+/// A time source that only moves when told to
+///
+/// Useful to advance time deterministically, like in tests
+#[derive(Default)]
+pub struct ManualTime {
+    _now: ::std::cell::Cell<u64>,
+}
+
+impl ManualTime {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// move the time forward by `step`
+    pub fn advance(&self, step: ::std::time::Duration) {
+        self._now.set(self._now.get().saturating_add(nanos(step)));
+    }
+    /// set the time since the origin. Must not go back
+    pub fn set(&self, now: ::std::time::Duration) {
+        self._now.set(nanos(now));
+    }
+}
+
+impl TimeSource for ManualTime {
+    fn now(&self) -> u64 {
+        self._now.get()
+    }
+}
+
+/// a duration in nanoseconds, saturating at `u64::MAX`
+pub fn nanos(duration: ::std::time::Duration) -> u64 {
+    ::std::convert::TryFrom::try_from(duration.as_nanos()).unwrap_or(u64::MAX)
+}
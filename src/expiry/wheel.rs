@@ -0,0 +1,290 @@
+/*
+ * Copyright 2021 Luca Fulchir <luker@fenrirproject.org>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+//! Hierarchical timer wheel for the time-to-idle of the entries
+//!
+//! Each level has [`SLOTS`] slots, and each slot of a level spans the whole
+//! level below. The first level has a resolution of `2^TICK_BITS`
+//! nanoseconds (about a millisecond), the last one covers about 13 days.
+//! Farther deadlines wait in the last level and are rescheduled.
+//!
+//! The slots only keep the indexes of the entries in the hashmap. The
+//! deadline and the current slot of each entry are kept by index, so
+//! rescheduling an entry is O(1) and just leaves a stale record in the old
+//! slot, dropped when that slot is drained.
+
+use crate::hashmap;
+
+/// number of slots for each level
+pub const SLOTS: usize = 64;
+const SLOT_BITS: u32 = 6;
+/// the first level has a resolution of `2^TICK_BITS` nanoseconds
+pub const TICK_BITS: u32 = 20;
+const LEVELS: usize = 5;
+// position of an entry that is not in the wheel
+const NONE: u16 = u16::MAX;
+
+fn shift(level: usize) -> u32 {
+    TICK_BITS + SLOT_BITS * level as u32
+}
+
+/// Timer wheel over the indexes of a hashmap
+///
+/// Time only moves forward with [`advance`](TimerWheel::advance), but the
+/// timers start from the `now` of the caller, which can be later
+pub struct TimerWheel {
+    _idle: u64,
+    _now: u64,
+    _deadlines: Vec<u64>,
+    _positions: Vec<u16>,
+    _slots: Vec<Vec<usize>>,
+}
+
+impl TimerWheel {
+    /// new wheel for entries that expire after `idle` without access.
+    /// `now` is in nanoseconds, like [`TimeSource`](super::TimeSource)
+    pub fn new(idle: ::std::time::Duration, now: u64) -> Self {
+        TimerWheel {
+            _idle: super::nanos(idle),
+            _now: now,
+            _deadlines: Vec::new(),
+            _positions: Vec::new(),
+            _slots: (0..(LEVELS * SLOTS)).map(|_| Vec::new()).collect(),
+        }
+    }
+    /// time of the last `advance`
+    pub fn now(&self) -> u64 {
+        self._now
+    }
+    /// the time-to-idle of the entries
+    pub fn idle(&self) -> ::std::time::Duration {
+        ::std::time::Duration::from_nanos(self._idle)
+    }
+    /// the entry at `idx` has been inserted or accessed at `now`, (re)start
+    /// its timer.  
+    /// A `now` before the last `advance` counts as the last `advance`
+    pub fn touch(&mut self, idx: usize, now: u64) {
+        if idx >= self._positions.len() {
+            self._deadlines.resize(idx + 1, 0);
+            self._positions.resize(idx + 1, NONE);
+        }
+        let start = ::std::cmp::max(now, self._now);
+        self._deadlines[idx] = start.saturating_add(self._idle);
+        self.schedule(idx);
+    }
+    /// the entry at `idx` moved in from another cache, start its timer only
+    /// if it is not running: a move is not an access
+    pub fn track(&mut self, idx: usize, now: u64) {
+        if self.deadline(idx).is_none() {
+            self.touch(idx, now);
+        }
+    }
+    /// the instant after which the entry at `idx` is idle, if it is tracked
+    pub fn deadline(&self, idx: usize) -> Option<u64> {
+        match self._positions.get(idx) {
+            None | Some(&NONE) => None,
+            Some(_) => Some(self._deadlines[idx]),
+        }
+    }
+    /// the entry at `idx` is not tracked anymore
+    pub fn cancel(&mut self, idx: usize) {
+        if let Some(pos) = self._positions.get_mut(idx) {
+            *pos = NONE;
+        }
+    }
+    /// cancel all the indexes in `removed`
+    pub fn cancel_removed(&mut self, removed: &crate::results::Removed<usize>) {
+        for (idx, _) in removed {
+            self.cancel(*idx);
+        }
+    }
+    /// Move the time to `now`, and return the indexes of the expired entries.
+    ///
+    /// They are not tracked anymore, and are still in the hashmap
+    pub fn advance(&mut self, now: u64) -> Vec<usize> {
+        let mut expired = Vec::new();
+        if now <= self._now {
+            return expired;
+        }
+        let then = ::std::mem::replace(&mut self._now, now);
+        for level in 0..LEVELS {
+            let (from, to) = (then >> shift(level), now >> shift(level));
+            if from == to {
+                // the upper levels did not move either
+                break;
+            }
+            let ticks = ::std::cmp::min(to - from, SLOTS as u64);
+            for tick in (to - ticks + 1)..=to {
+                let slot = level * SLOTS + (tick as usize & (SLOTS - 1));
+                let records = ::std::mem::take(&mut self._slots[slot]);
+                for idx in records {
+                    if self._positions[idx] != slot as u16 {
+                        // stale: rescheduled, cancelled or already handled
+                        continue;
+                    }
+                    self._positions[idx] = NONE;
+                    if self._deadlines[idx] <= now {
+                        expired.push(idx);
+                    } else {
+                        self.schedule(idx);
+                    }
+                }
+            }
+        }
+        expired
+    }
+    /// The hashmap has been resized and all the entries have moved.
+    ///
+    /// Reschedule everything at the new indexes
+    pub fn relocate<E>(&mut self, reloc: &hashmap::Relocation<E>) {
+        let deadlines = ::std::mem::take(&mut self._deadlines);
+        let positions = ::std::mem::take(&mut self._positions);
+        for slot in self._slots.iter_mut() {
+            slot.clear();
+        }
+        for (old_idx, pos) in positions.into_iter().enumerate() {
+            if pos == NONE {
+                continue;
+            }
+            if let Some(idx) = reloc.index(old_idx) {
+                if idx >= self._positions.len() {
+                    self._deadlines.resize(idx + 1, 0);
+                    self._positions.resize(idx + 1, NONE);
+                }
+                self._deadlines[idx] = deadlines[old_idx];
+                self.schedule(idx);
+            }
+        }
+    }
+    /// forget all the entries
+    pub fn clear(&mut self) {
+        self._deadlines.clear();
+        self._positions.clear();
+        for slot in self._slots.iter_mut() {
+            slot.clear();
+        }
+    }
+    // put the entry in the lowest level that can hold its deadline
+    fn schedule(&mut self, idx: usize) {
+        let deadline = self._deadlines[idx];
+        let mut placed = (LEVELS - 1, SLOTS as u64 - 1);
+        for level in 0..LEVELS {
+            let ticks = (deadline >> shift(level))
+                .saturating_sub(self._now >> shift(level));
+            if ticks < SLOTS as u64 {
+                // the current slot has already been drained, use the next
+                placed = (level, ::std::cmp::max(ticks, 1));
+                break;
+            }
+        }
+        let (level, ticks) = placed;
+        let tick = (self._now >> shift(level)) + ticks;
+        let slot = level * SLOTS + (tick as usize & (SLOTS - 1));
+        if self._positions[idx] == slot as u16 {
+            return;
+        }
+        self._positions[idx] = slot as u16;
+        self._slots[slot].push(idx);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ticks(n: u64) -> u64 {
+        n << TICK_BITS
+    }
+    fn idle(nanos: u64) -> ::std::time::Duration {
+        ::std::time::Duration::from_nanos(nanos)
+    }
+
+    #[test]
+    fn deadlines_cascade_down_the_levels() {
+        // level 1, then level 2
+        for deadline in [ticks(100), ticks(5000)] {
+            let mut wheel = TimerWheel::new(idle(deadline), 0);
+            wheel.touch(0, 0);
+            for step in 1..=ticks(10000) >> TICK_BITS {
+                let expired = wheel.advance(ticks(step));
+                match ticks(step) == deadline {
+                    true => assert_eq!(expired, vec![0]),
+                    false => assert!(expired.is_empty(), "{}", step),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn far_deadlines_wait_in_the_last_level() {
+        let deadline = ticks(1 << (SLOT_BITS * LEVELS as u32 + 2));
+        let mut wheel = TimerWheel::new(idle(deadline), 0);
+        wheel.touch(0, 0);
+        assert!(wheel.advance(deadline / 2).is_empty());
+        assert!(wheel.advance(deadline - 1).is_empty());
+        assert_eq!(wheel.advance(deadline), vec![0]);
+    }
+
+    #[test]
+    fn touch_restarts_and_track_keeps_the_timer() {
+        let mut wheel = TimerWheel::new(idle(ticks(10)), 0);
+        wheel.touch(0, 0);
+        wheel.touch(1, 0);
+        assert!(wheel.advance(ticks(5)).is_empty());
+        wheel.touch(0, 0);
+        wheel.track(1, 0);
+        wheel.track(2, 0);
+        assert_eq!(wheel.deadline(0), Some(ticks(15)));
+        assert_eq!(wheel.deadline(1), Some(ticks(10)));
+        assert_eq!(wheel.deadline(2), Some(ticks(15)));
+        assert_eq!(wheel.advance(ticks(10)), vec![1]);
+        assert_eq!(wheel.deadline(1), None);
+        wheel.cancel(2);
+        assert_eq!(wheel.advance(ticks(15)), vec![0]);
+    }
+
+    #[test]
+    fn timers_start_from_the_caller_time() {
+        let mut wheel = TimerWheel::new(idle(ticks(10)), 0);
+        assert!(wheel.advance(ticks(5)).is_empty());
+        wheel.touch(0, ticks(100));
+        // a time before the last advance counts as the last advance
+        wheel.touch(1, ticks(1));
+        assert_eq!(wheel.deadline(0), Some(ticks(110)));
+        assert_eq!(wheel.deadline(1), Some(ticks(15)));
+        assert_eq!(wheel.advance(ticks(100)), vec![1]);
+        assert!(wheel.advance(ticks(109)).is_empty());
+        assert_eq!(wheel.advance(ticks(110)), vec![0]);
+    }
+
+    #[test]
+    fn relocate_keeps_the_deadlines() {
+        let mut wheel = TimerWheel::new(idle(ticks(100)), 0);
+        wheel.touch(0, 0);
+        assert!(wheel.advance(ticks(50)).is_empty());
+        wheel.touch(2, 0);
+        let reloc = hashmap::Relocation::<u64>::new(
+            ::std::ptr::NonNull::dangling(),
+            ::std::ptr::NonNull::dangling(),
+            vec![Some(5), None, Some(1)],
+        );
+        wheel.relocate(&reloc);
+        assert_eq!(wheel.deadline(0), None);
+        assert_eq!(wheel.deadline(5), Some(ticks(100)));
+        assert_eq!(wheel.deadline(1), Some(ticks(150)));
+        assert_eq!(wheel.advance(ticks(100)), vec![5]);
+        assert_eq!(wheel.advance(ticks(150)), vec![1]);
+    }
+}
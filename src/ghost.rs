@@ -0,0 +1,98 @@
+/*
+ * Copyright 2021 Luca Fulchir <luker@fenrirproject.org>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+/// List of the keys of recently evicted entries, from the most to the least
+/// recent.
+///
+/// Ghost entries have no value and are not in the hashmap. Removal from the
+/// middle of the list is lazy: the stale key is skipped when it reaches the
+/// end of the list.  
+/// Pushing a key that is already there just makes it the most recent one,
+/// so this also works as a recency stack of keys, like the LIRS one
+pub(crate) struct Ghost<K, HB>
+where
+    K: ::std::hash::Hash + Eq + Clone,
+    HB: ::std::hash::BuildHasher + Default,
+{
+    // key -> sequence number of its position in `_order`
+    _keys: ::std::collections::HashMap<K, u64, HB>,
+    // front is the least recent
+    _order: ::std::collections::VecDeque<(K, u64)>,
+    _next: u64,
+}
+
+impl<K, HB> Ghost<K, HB>
+where
+    K: ::std::hash::Hash + Eq + Clone,
+    HB: ::std::hash::BuildHasher + Default,
+{
+    pub fn new() -> Self {
+        Ghost {
+            _keys: ::std::collections::HashMap::with_hasher(HB::default()),
+            _order: ::std::collections::VecDeque::new(),
+            _next: 0,
+        }
+    }
+    /// add a key as the most recent one
+    pub fn push(&mut self, key: K) {
+        self._next += 1;
+        self._keys.insert(key.clone(), self._next);
+        self._order.push_back((key, self._next));
+        self.compact();
+    }
+    /// remove and return the least recent key
+    pub fn pop_lru(&mut self) -> Option<K> {
+        while let Some((key, seq)) = self._order.pop_front() {
+            if self._keys.get(&key) == Some(&seq) {
+                self._keys.remove(&key);
+                return Some(key);
+            }
+        }
+        None
+    }
+    /// get the least recent key, without removing it
+    pub fn peek_lru(&mut self) -> Option<&K> {
+        while let Some((key, seq)) = self._order.front() {
+            if self._keys.get(key) == Some(seq) {
+                break;
+            }
+            self._order.pop_front();
+        }
+        self._order.front().map(|(key, _)| key)
+    }
+    /// remove a key, return true if it was there
+    pub fn remove(&mut self, key: &K) -> bool {
+        self._keys.remove(key).is_some()
+    }
+    pub fn contains(&self, key: &K) -> bool {
+        self._keys.contains_key(key)
+    }
+    pub fn len(&self) -> usize {
+        self._keys.len()
+    }
+    pub fn clear(&mut self) {
+        self._keys.clear();
+        self._order.clear();
+    }
+    // too many stale keys, drop them
+    fn compact(&mut self) {
+        if self._order.len() <= 2 * self._keys.len() + 16 {
+            return;
+        }
+        let keys = &self._keys;
+        self._order.retain(|(key, seq)| keys.get(key) == Some(seq));
+    }
+}
@@ -16,11 +16,81 @@
 
 /// user-reimplementable types for the hashmap
 pub mod user;
+use crate::results::Error;
 use std::hash::Hasher;
 
-/// Trait to reimplement to use an hashmap with [ccache](crate)
+/// Tracks where each entry has been moved after a [`HashMap::resize`]
+///
+/// The hashmap already fixes the links between its own entries, but everyone
+/// else that keeps a pointer or an index to an entry (e.g. the head and tail
+/// of the caches) must use this to find the new position
+///
+/// Entries are assumed to be stored backwards from the end of the data array,
+/// like in `::hashbrown::raw::RawTable`
+pub struct Relocation<E> {
+    old_end: usize,
+    new_end: ::std::ptr::NonNull<E>,
+    new_idx: Vec<Option<usize>>,
+}
+
+impl<E> Relocation<E> {
+    /// `old_end` and `new_end` are the end of the data of the old and new
+    /// tables. `new_idx[old_index]` is the index of the entry after the move,
+    /// if there was an entry
+    pub fn new(
+        old_end: ::std::ptr::NonNull<E>,
+        new_end: ::std::ptr::NonNull<E>,
+        new_idx: Vec<Option<usize>>,
+    ) -> Self {
+        Relocation {
+            old_end: old_end.as_ptr() as usize,
+            new_end,
+            new_idx,
+        }
+    }
+    /// new index of the entry that was at `old_idx`, if any
+    pub fn index(&self, old_idx: usize) -> Option<usize> {
+        match self.new_idx.get(old_idx) {
+            None => None,
+            Some(idx) => *idx,
+        }
+    }
+    // index of a pointer in the old table.
+    // The old memory has already been freed, we only use the address
+    fn old_index(&self, old: ::std::ptr::NonNull<E>) -> usize {
+        let size = ::std::mem::size_of::<E>();
+        let old_addr = old.as_ptr() as usize;
+        if size == 0 || old_addr >= self.old_end {
+            return usize::MAX;
+        }
+        // basically copied from the ::hashbrown::raw::Bucket implementation
+        ((self.old_end - old_addr) / size) - 1
+    }
+    /// new position of the entry that was pointed by `old`, if any
+    pub fn ptr(
+        &self,
+        old: ::std::ptr::NonNull<E>,
+    ) -> Option<::std::ptr::NonNull<E>> {
+        let new_idx = self.index(self.old_index(old))?;
+        unsafe {
+            Some(::std::ptr::NonNull::new_unchecked(
+                self.new_end.as_ptr().sub(new_idx + 1),
+            ))
+        }
+    }
+    /// new link to the entry that was linked by `old`, if any
+    pub fn link<L: user::Link<E>>(&self, old: L) -> Option<L> {
+        let new_idx = self.index(old.index(|p| self.old_index(p)))?;
+        let new_ptr = unsafe {
+            ::std::ptr::NonNull::new_unchecked(
+                self.new_end.as_ptr().sub(new_idx + 1),
+            )
+        };
+        Some(L::new(new_idx, new_ptr))
+    }
+}
 
-// TODO: resizing
+/// Trait to reimplement to use an hashmap with [ccache](crate)
 pub trait HashMap<Entry, Key, Val, Cid, Umeta, BuildHasher>
 where
     Entry: user::EntryT<Key, Val, Cid, Umeta>,
@@ -39,12 +109,24 @@ where
     ) -> Self;
     /// return the current hashmap max capacity
     fn capacity(&self) -> usize;
+    /// number of buckets. All the indexes are in `0..buckets()`, but only
+    /// some of them have an entry
+    fn buckets(&self) -> usize;
     /// Returns the current number of elements in the hashmap
     fn len(&self) -> usize;
+    /// Returns true if there are no elements in the hashmap
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
     /// Get the index and the reference to an element if present
     fn get_full(&self, key: &Key) -> Option<(usize, &Entry)>;
     /// Get the index and a mutable reference to an element if present
     fn get_full_mut(&mut self, key: &Key) -> Option<(usize, &mut Entry)>;
+    /// Hash of a key, to look it up and insert it with a single hashing
+    fn hash_key(&self, key: &Key) -> u64;
+    /// Like [`get_full`](Self::get_full), with `hash` from
+    /// [`hash_key`](Self::hash_key)
+    fn get_full_hashed(&self, hash: u64, key: &Key) -> Option<(usize, &Entry)>;
     /// Return a reference to the object at the given index, if any
     fn get_index(&self, idx: usize) -> Option<&Entry>;
     /// Return a reference to the object at the given index, if any
@@ -66,30 +148,89 @@ where
     /// Returns a pair with a possible clash `Option<V>` plus the index and the
     /// reference to the object just inserted
     /// Must not reshuffle or reallocate
-    fn insert(&mut self, entry: Entry) -> (Option<Entry>, usize, &Entry);
+    ///
+    /// If the hashmap is full the entry is given back as `Err`: make room
+    /// with [`resize`](Self::resize) and try again
+    fn insert(
+        &mut self,
+        entry: Entry,
+    ) -> Result<(Option<Entry>, usize, &Entry), Entry>;
     /// Insert a new element in the hashmap.
     /// Returns a pair with a possible clash `Option<V>` plus the index and the
     /// mutable reference to the object just inserted
     /// Must not reshuffle or reallocate
+    ///
+    /// If the hashmap is full the entry is given back as `Err`, like
+    /// [`insert`](Self::insert)
     fn insert_mut(
         &mut self,
         entry: Entry,
-    ) -> (Option<Entry>, usize, &mut Entry);
+    ) -> Result<(Option<Entry>, usize, &mut Entry), Entry>;
+    /// Insert an element whose key is known not to be in the hashmap,
+    /// without looking for it again. `hash` is from
+    /// [`hash_key`](Self::hash_key)
+    ///
+    /// If the hashmap is full the entry is given back as `Err`, like
+    /// [`insert`](Self::insert)
+    fn insert_new_hashed(
+        &mut self,
+        hash: u64,
+        entry: Entry,
+    ) -> Result<(usize, &mut Entry), Entry>;
     /// returns a reference to the current hasher
     fn hasher(&self) -> &BuildHasher;
+    /// Follow a link to the entry
+    ///
+    /// Panics if the link does not point to an entry in the hashmap
+    fn get_link(&self, link: Entry::Link) -> &Entry {
+        use user::Link;
+        let ptr = link.resolve(|idx| self.get_index(idx).map(|e| e.into()));
+        unsafe { &*ptr.as_ptr() }
+    }
+    /// Follow a link to the entry
+    ///
+    /// Panics if the link does not point to an entry in the hashmap
+    fn get_link_mut(&mut self, link: Entry::Link) -> &mut Entry {
+        use user::Link;
+        let ptr = link.resolve(|idx| self.get_index_mut(idx).map(|e| e.into()));
+        unsafe { &mut *ptr.as_ptr() }
+    }
+    /// Get the link to the entry at the given index
+    ///
+    /// Panics if there is no entry at that index
+    fn link_from_index(&mut self, idx: usize) -> Entry::Link {
+        use user::Link;
+        let ptr = self.get_index_mut(idx).unwrap().into();
+        Entry::Link::new(idx, ptr)
+    }
+    /// Get the index of a linked entry
+    fn index_from_link(&self, link: Entry::Link) -> usize {
+        use user::Link;
+        link.index(|p| unsafe { self.index_from_entry(p.as_ref()) })
+    }
+    /// Grow or shrink the hashmap so that it can hold at least `capacity`
+    /// elements.
+    ///
+    /// This is the only operation allowed to move the entries around.
+    /// The links between entries must be fixed by the hashmap itself, any
+    /// other reference to the entries must be fixed by the caller through the
+    /// returned [`Relocation`]
+    ///
+    /// Returns `Err` if `capacity` can not hold the current elements
+    fn resize(&mut self, capacity: usize) -> Result<Relocation<Entry>, Error>;
 }
 
 /// Simple, stable hashmap with indexes
 ///
 /// This simple hashmap has some limitations:
-/// * It will not resize
-/// * It always has the same maximum size
+/// * It will not grow on its own: use [`SimpleHmap::resize`]
 /// * Should not be used in multithread
 ///
 /// But it should be efficient enugh, and stable
 ///
 /// So if you add or remove elements, the other will not be reshuffled at any
-/// time
+/// time. The only exception is [`SimpleHmap::resize`], which moves everything
+/// and returns a [`Relocation`] so that the caches can fix their pointers
 ///
 /// It also supports O(1) access via indexes
 ///
@@ -98,10 +239,9 @@ where
 /// multiple caches and distinguish them via the [`user::Cid`]
 ///
 /// In reimplementing the needed types, remember that:
-/// * [`user::EntryT`] must have a default type that will be used as
-///   "empty-space" marker in the hash_map
-/// * [`user::Cid`] needs the default type which is used by EntryT to mark
-///   "empty-space"
+/// * [`user::EntryT`] must have a default type
+/// * [`user::Cid`] needs the default type, which is used for entries that are
+///   not (yet) part of any cache
 // TODO: add allocator
 pub struct SimpleHmap<
     Entry,
@@ -118,7 +258,6 @@ pub struct SimpleHmap<
     Umeta: user::Meta<Val>,
     BuildHasher: ::std::hash::BuildHasher + Default,
 {
-    usage: usize,
     table: ::hashbrown::raw::RawTable<Entry>,
     hash_builder: BuildHasher,
     _k: ::std::marker::PhantomData<Key>,
@@ -138,70 +277,62 @@ where
     BuildHasher: ::std::hash::BuildHasher + Default,
 {
     pub fn with_capacity(capacity: usize) -> Self {
-        let mut res = SimpleHmap {
-            usage: 0,
-            table: ::hashbrown::raw::RawTable::<Entry>::with_capacity(capacity),
-            hash_builder: BuildHasher::default(),
-            _k: ::std::marker::PhantomData,
-            _v: ::std::marker::PhantomData,
-            _c: ::std::marker::PhantomData,
-            _u: ::std::marker::PhantomData,
-        };
-        res.init_all_default(false);
-        res
+        SimpleHmap::with_capacity_and_hasher(capacity, BuildHasher::default())
     }
     pub fn with_capacity_and_hasher(
         capacity: usize,
         hash_builder: BuildHasher,
     ) -> Self {
-        let mut res = SimpleHmap {
-            usage: 0,
+        SimpleHmap {
             table: ::hashbrown::raw::RawTable::<Entry>::with_capacity(capacity),
             hash_builder: hash_builder,
             _k: ::std::marker::PhantomData,
             _v: ::std::marker::PhantomData,
             _c: ::std::marker::PhantomData,
             _u: ::std::marker::PhantomData,
-        };
-        res.init_all_default(false);
-        res
-    }
-    fn init_all_default(&mut self, quick: bool) {
-        match quick {
-            false => {
-                for idx in 0..(self.table.len()) {
-                    unsafe {
-                        let bucket = self.table.bucket(idx);
-                        let default_el = Entry::default();
-                        bucket.write(default_el);
-                    }
-                }
-            }
-            true => {
-                for idx in 0..(self.table.len()) {
-                    unsafe {
-                        let bucket = self.table.bucket(idx);
-                        let cid = bucket.as_mut().get_cache_id_mut();
-                        *cid = Cid::default();
-                    }
-                }
-            }
         }
-        self.usage = 0;
     }
+    /// Max number of elements that can be inserted without a resize
+    ///
+    /// Note that removals can leave tombstones, which lower the capacity until
+    /// the next [`SimpleHmap::resize`]
     pub fn capacity(&self) -> usize {
         self.table.capacity()
     }
     pub fn len(&self) -> usize {
-        self.usage
+        self.table.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.table.is_empty()
+    }
+    /// number of buckets, see [`HashMap::buckets`]
+    pub fn buckets(&self) -> usize {
+        self.table.buckets()
     }
     fn hash(&self, key: &Key) -> u64 {
         let mut hasher = self.hash_builder.build_hasher();
         key.hash(&mut hasher);
         hasher.finish()
     }
+    // check if there is an entry in the bucket
+    fn is_full(&self, idx: usize) -> bool {
+        if idx >= self.table.buckets() {
+            return false;
+        }
+        unsafe { self.table.is_bucket_full(idx) }
+    }
     pub fn get_full(&self, key: &Key) -> Option<(usize, &Entry)> {
-        let hash = self.hash(key);
+        self.get_full_hashed(self.hash(key), key)
+    }
+    /// hash of the key, see [`HashMap::hash_key`]
+    pub fn hash_key(&self, key: &Key) -> u64 {
+        self.hash(key)
+    }
+    pub fn get_full_hashed(
+        &self,
+        hash: u64,
+        key: &Key,
+    ) -> Option<(usize, &Entry)> {
         match self.table.find(hash, move |x| key.eq(x.get_key())) {
             None => None,
             Some(bucket) => {
@@ -223,24 +354,16 @@ where
         }
     }
     pub fn get_index(&self, idx: usize) -> Option<&Entry> {
-        if idx >= self.len() {
-            return None;
-        }
-        let bucket = unsafe { self.table.bucket(idx) };
-        if unsafe { bucket.as_ref() }.get_cache_id() == Cid::default() {
+        if !self.is_full(idx) {
             return None;
         }
-        Some(unsafe { bucket.as_ref() })
+        Some(unsafe { self.table.bucket(idx).as_ref() })
     }
     pub fn get_index_mut(&mut self, idx: usize) -> Option<&mut Entry> {
-        if idx >= self.len() {
+        if !self.is_full(idx) {
             return None;
         }
-        let bucket = unsafe { self.table.bucket(idx) };
-        if unsafe { bucket.as_ref() }.get_cache_id() == Cid::default() {
-            return None;
-        }
-        Some(unsafe { bucket.as_mut() })
+        Some(unsafe { self.table.bucket(idx).as_mut() })
     }
     unsafe fn index_from_entry(&self, e: &Entry) -> usize {
         self.entry_to_idx(e)
@@ -248,108 +371,117 @@ where
     fn entry_to_idx(&self, e: &Entry) -> usize {
         unsafe {
             // basically copied from the ::hashbrown::raw::Bucket implementation
+            // the bucket pointer is one past the actual data
             let ep = e as *const Entry;
             let end = self.table.data_end();
-            end.as_ptr().offset_from(ep) as usize
+            (end.as_ptr().offset_from(ep) as usize) - 1
         }
     }
-    /// just mark the entry as not part of a `Cid` and return a copy
-    /// don't bother actually deleting the data
+    /// remove the entry from the hashmap and return it
     pub fn remove(&mut self, item: &Entry) -> Entry {
         let idx = self.entry_to_idx(item);
-        self.remove_idx_unsafe(idx)
+        self.remove_idx(idx)
     }
-    /// just mark the entry as not part of a `Cid` and return a copy
-    /// don't bother actually deleting the data
+    /// remove the entry from the hashmap and return it.
+    /// Other entries are not moved.
     /// If not present, return a default `Entry`
     pub fn remove_idx(&mut self, idx: usize) -> Entry {
-        if idx >= self.capacity() {
+        if !self.is_full(idx) {
             return Entry::default();
         }
-        self.remove_idx_unsafe(idx)
-    }
-    fn remove_idx_unsafe(&mut self, idx: usize) -> Entry {
         unsafe {
             let bucket = self.table.bucket(idx);
-            if bucket.as_ref().get_cache_id() == Cid::default() {
-                return Entry::default();
-            }
-            self.usage -= 1;
-            let res: Entry = bucket.read();
-            let old_e_cid = bucket.as_mut().get_cache_id_mut();
-            *old_e_cid = Cid::default();
-            res
+            self.table.remove(bucket)
         }
     }
     pub fn clear(&mut self) {
-        self.init_all_default(true)
+        self.table.clear()
     }
     /// returns: any eventual clash in `Option<Entry>` plus the index and a ref
     /// to the actual entry.
-    /// If the hashmap is full and we can not insert the element, returns Err
-    pub fn insert(&mut self, entry: Entry) -> (Option<Entry>, usize, &Entry) {
-        let (clash, idx, entry) = self.insert_mut(entry);
-        (clash, idx, entry)
+    ///
+    /// If the hashmap is full, the entry is returned as `Err`
+    pub fn insert(
+        &mut self,
+        entry: Entry,
+    ) -> Result<(Option<Entry>, usize, &Entry), Entry> {
+        let (clash, idx, entry) = self.insert_mut(entry)?;
+        Ok((clash, idx, entry))
     }
     pub fn insert_mut(
         &mut self,
         entry: Entry,
-    ) -> (Option<Entry>, usize, &mut Entry) {
-        self.usage += 1;
+    ) -> Result<(Option<Entry>, usize, &mut Entry), Entry> {
         let hash = self.hash(entry.get_key());
-        let non_mut_self = &*self;
-        let opt_clash_strong = self.table.find(hash, move |x| {
-            if (&x).get_cache_id() == Cid::default() {
-                return true;
-            }
-            let k = x.get_key();
-            let h2 = non_mut_self.hash(k);
-            hash.eq(&h2)
-        });
-        let opt_clash_real =
-            if opt_clash_strong.is_none() && (self.usage == self.capacity()) {
-                // hashmap is full, but we did not find a clash.
-                // since we always guarantee an insert, let's weaken the hash
-                // and force a clash
-                let weak_hash = hash % (self.capacity() as u64);
-                self.table.find(weak_hash, move |x| {
-                    if (&x).get_cache_id() == Cid::default() {
-                        return true;
-                    }
-                    let k = x.get_key();
-                    let h2 =
-                        non_mut_self.hash(k) % (non_mut_self.capacity() as u64);
-                    weak_hash.eq(&h2)
-                })
-            } else {
-                opt_clash_strong
-            };
-        let (opt_clash_copy, bucket) = match opt_clash_real {
+        let key = entry.get_key();
+        let bucket = match self.table.find(hash, |x| key.eq(x.get_key())) {
+            Some(bucket) => bucket,
             None => {
-                let bucket = self.table.insert_no_grow(hash, entry);
-                let bucket_idx = unsafe { self.table.bucket_index(&bucket) };
-                return (None, bucket_idx, unsafe { bucket.as_mut() });
-            }
-            Some(bucket) => {
-                let old_element = if unsafe { bucket.as_ref() }.get_cache_id()
-                    == Cid::default()
-                {
-                    None
-                } else {
-                    Some(unsafe { bucket.read() })
-                };
-                (old_element, bucket)
+                let (idx, entry) = self.insert_new_hashed(hash, entry)?;
+                return Ok((None, idx, entry));
             }
         };
-        unsafe {
-            bucket.write(entry);
-        }
+        // same key, overwrite it
+        let old_element =
+            ::std::mem::replace(unsafe { bucket.as_mut() }, entry);
         let bucket_idx = unsafe { self.table.bucket_index(&bucket) };
-        (opt_clash_copy, bucket_idx, unsafe { bucket.as_mut() })
+        Ok((Some(old_element), bucket_idx, unsafe { bucket.as_mut() }))
+    }
+    /// insert an entry with a key that is not in the hashmap, see
+    /// [`HashMap::insert_new_hashed`]
+    pub fn insert_new_hashed(
+        &mut self,
+        hash: u64,
+        entry: Entry,
+    ) -> Result<(usize, &mut Entry), Entry> {
+        let bucket = self.table.try_insert_no_grow(hash, entry)?;
+        let bucket_idx = unsafe { self.table.bucket_index(&bucket) };
+        Ok((bucket_idx, unsafe { bucket.as_mut() }))
     }
     pub fn hasher(&self) -> &BuildHasher {
         &self.hash_builder
     }
+    /// Grow or shrink the hashmap.
+    ///
+    /// All the entries will be moved, and their head/tail links fixed.  
+    /// The caches working on this hashmap must be fixed with the returned
+    /// [`Relocation`]
+    pub fn resize(
+        &mut self,
+        capacity: usize,
+    ) -> Result<Relocation<Entry>, Error> {
+        if capacity < self.len() {
+            return Err(Error::CapacityTooSmall);
+        }
+        let mut new_table =
+            ::hashbrown::raw::RawTable::<Entry>::with_capacity(capacity);
+        let mut new_idx = vec![None; self.table.buckets()];
+        unsafe {
+            for bucket in self.table.iter() {
+                let old_idx = self.table.bucket_index(&bucket);
+                let entry = ::std::ptr::read(bucket.as_ptr());
+                let hash = self.hash(entry.get_key());
+                let new_bucket = new_table.insert_no_grow(hash, entry);
+                new_idx[old_idx] = Some(new_table.bucket_index(&new_bucket));
+            }
+            // everything has been moved, don't drop anything
+            self.table.clear_no_drop();
+        }
+        let old_end = unsafe { self.table.data_end() };
+        let new_end = unsafe { new_table.data_end() };
+        self.table = new_table;
+        let reloc = Relocation::new(old_end, new_end, new_idx);
+        unsafe {
+            for bucket in self.table.iter() {
+                let entry = bucket.as_mut();
+                let head = entry.get_head().and_then(|h| reloc.link(h));
+                let tail = entry.get_tail().and_then(|t| reloc.link(t));
+                entry.set_head(head);
+                entry.set_tail(tail);
+            }
+        }
+        Ok(reloc)
+    }
 }
 impl<Entry, Key, Val, Cid, Umeta, BuildHasher>
     HashMap<Entry, Key, Val, Cid, Umeta, BuildHasher>
@@ -374,15 +506,27 @@ where
     fn capacity(&self) -> usize {
         SimpleHmap::capacity(self)
     }
+    fn buckets(&self) -> usize {
+        SimpleHmap::buckets(self)
+    }
     fn len(&self) -> usize {
         SimpleHmap::len(self)
     }
+    fn is_empty(&self) -> bool {
+        SimpleHmap::is_empty(self)
+    }
     fn get_full(&self, key: &Key) -> Option<(usize, &Entry)> {
         SimpleHmap::get_full(self, key)
     }
     fn get_full_mut(&mut self, key: &Key) -> Option<(usize, &mut Entry)> {
         SimpleHmap::get_full_mut(self, key)
     }
+    fn hash_key(&self, key: &Key) -> u64 {
+        SimpleHmap::hash_key(self, key)
+    }
+    fn get_full_hashed(&self, hash: u64, key: &Key) -> Option<(usize, &Entry)> {
+        SimpleHmap::get_full_hashed(self, hash, key)
+    }
     fn get_index(&self, idx: usize) -> Option<&Entry> {
         SimpleHmap::get_index(self, idx)
     }
@@ -401,16 +545,190 @@ where
     fn clear(&mut self) {
         SimpleHmap::clear(self)
     }
-    fn insert(&mut self, entry: Entry) -> (Option<Entry>, usize, &Entry) {
+    fn insert(
+        &mut self,
+        entry: Entry,
+    ) -> Result<(Option<Entry>, usize, &Entry), Entry> {
         SimpleHmap::insert(self, entry)
     }
     fn insert_mut(
         &mut self,
         entry: Entry,
-    ) -> (Option<Entry>, usize, &mut Entry) {
+    ) -> Result<(Option<Entry>, usize, &mut Entry), Entry> {
         SimpleHmap::insert_mut(self, entry)
     }
+    fn insert_new_hashed(
+        &mut self,
+        hash: u64,
+        entry: Entry,
+    ) -> Result<(usize, &mut Entry), Entry> {
+        SimpleHmap::insert_new_hashed(self, hash, entry)
+    }
     fn hasher(&self) -> &BuildHasher {
         SimpleHmap::hasher(self)
     }
+    fn resize(&mut self, capacity: usize) -> Result<Relocation<Entry>, Error> {
+        SimpleHmap::resize(self, capacity)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::user::EntryT;
+    use super::*;
+    use crate::testing::{Hasher, Key, Val};
+
+    type Cid = ::std::marker::PhantomData<Key>;
+    type Map<En> = SimpleHmap<En, Key, Val, Cid, user::ZeroMeta, Hasher>;
+    type E = user::Entry<Key, Val, Cid, user::ZeroMeta>;
+    type IdxE = user::IdxEntry<Key, Val, Cid, user::ZeroMeta>;
+    type Hmap = Map<E>;
+
+    fn entry<En: EntryT<Key, Val, Cid, user::ZeroMeta>>(k: u32) -> En {
+        En::new_entry(
+            None,
+            None,
+            Key(k),
+            Val(k),
+            ::std::marker::PhantomData,
+            user::ZeroMeta::default(),
+        )
+    }
+
+    #[test]
+    fn insert_when_full_gives_the_entry_back() {
+        let mut h = Hmap::with_capacity(3);
+        let cap = h.capacity();
+        for k in 0..cap as u32 {
+            assert!(h.insert(entry(k)).is_ok());
+        }
+        let back = match h.insert(entry(100)) {
+            Ok(_) => panic!("inserted in a full hashmap"),
+            Err(back) => back,
+        };
+        assert_eq!(back.get_key(), &Key(100));
+        assert_eq!(h.len(), cap);
+        for k in 0..cap as u32 {
+            assert_eq!(h.get_full(&Key(k)).unwrap().1.get_val(), &Val(k));
+        }
+        // the same key only replaces the entry, even when full
+        let (old, _, _) = h.insert(entry(0)).ok().unwrap();
+        assert_eq!(old.unwrap().get_key(), &Key(0));
+        assert_eq!(h.len(), cap);
+    }
+
+    #[test]
+    fn index_access() {
+        let mut h = Hmap::with_capacity(8);
+        assert!(h.is_empty());
+        assert!(h.get_index(0).is_none());
+        assert!(h.get_index(h.buckets()).is_none());
+        let (_, idx, _) = h.insert(entry(7)).ok().unwrap();
+        assert_eq!(h.get_index(idx).unwrap().get_key(), &Key(7));
+        let e = h.get_index(idx).unwrap();
+        assert_eq!(unsafe { h.index_from_entry(e) }, idx);
+        assert!(!h.is_empty());
+        assert_eq!(h.remove_idx(idx).get_key(), &Key(7));
+        assert!(h.is_empty());
+        assert!(h.get_index(idx).is_none());
+        // removing an empty bucket gives a default entry
+        assert_eq!(h.remove_idx(idx).get_key(), &Key::default());
+    }
+
+    // link the entries of `keys` as a list, from head to tail, and return
+    // the index of the head
+    fn chain<En: EntryT<Key, Val, Cid, user::ZeroMeta>>(
+        h: &mut Map<En>,
+        keys: &[u32],
+    ) -> usize {
+        let idxs: Vec<usize> = keys
+            .iter()
+            .map(|k| h.get_full(&Key(*k)).unwrap().0)
+            .collect();
+        for (pos, idx) in idxs.iter().enumerate() {
+            let head = match pos {
+                0 => None,
+                _ => Some(h.link_from_index(idxs[pos - 1])),
+            };
+            let tail = idxs.get(pos + 1).map(|t| h.link_from_index(*t));
+            let e = h.get_index_mut(*idx).unwrap();
+            e.set_head(head);
+            e.set_tail(tail);
+        }
+        idxs[0]
+    }
+    // follow the tail links from `idx`, checking the head links on the way
+    fn walk<En: EntryT<Key, Val, Cid, user::ZeroMeta>>(
+        h: &Map<En>,
+        idx: usize,
+    ) -> Vec<u32> {
+        assert!(h.get_index(idx).unwrap().get_head().is_none());
+        let mut keys = vec![h.get_index(idx).unwrap().get_key().0];
+        let mut cur = idx;
+        while let Some(link) = h.get_index(cur).unwrap().get_tail() {
+            let next = h.index_from_link(link);
+            let head = h.get_index(next).unwrap().get_head().unwrap();
+            assert_eq!(h.index_from_link(head), cur);
+            keys.push(h.get_index(next).unwrap().get_key().0);
+            cur = next;
+        }
+        keys
+    }
+    // grow and shrink a hashmap with linked entries
+    fn check_resize<En: EntryT<Key, Val, Cid, user::ZeroMeta>>() {
+        let mut h = Map::<En>::with_capacity(8);
+        for k in 0..8 {
+            assert!(h.insert(entry(k)).is_ok());
+        }
+        let order = [3, 0, 7, 1, 6, 2, 5, 4];
+        let head = chain(&mut h, &order);
+        let old_link = h.link_from_index(head);
+        let buckets = h.buckets();
+        let reloc = h.resize(64).ok().unwrap();
+        assert!(h.buckets() > buckets);
+        let head = reloc.index(head).unwrap();
+        assert_eq!(walk(&h, head), order);
+        // the links of the caches are moved to the new table
+        assert!(reloc.link(old_link) == Some(h.link_from_index(head)));
+        // shrinking moves everything again
+        let buckets = h.buckets();
+        let reloc = h.resize(8).ok().unwrap();
+        assert!(h.buckets() < buckets);
+        let head = reloc.index(head).unwrap();
+        assert_eq!(walk(&h, head), order);
+        assert!(h.resize(4).is_err());
+    }
+
+    #[test]
+    fn resize_fixes_the_links() {
+        check_resize::<E>();
+    }
+
+    #[test]
+    fn resize_fixes_the_idx_links() {
+        check_resize::<IdxE>();
+    }
+
+    #[test]
+    fn idx_and_pointer_links_agree() {
+        let mut ptrs = Hmap::with_capacity(8);
+        let mut idxs = Map::<IdxE>::with_capacity(8);
+        for k in 0..4 {
+            let (_, p, _) = ptrs.insert(entry(k)).ok().unwrap();
+            let (_, i, _) = idxs.insert(entry(k)).ok().unwrap();
+            // same hasher, same buckets
+            assert_eq!(p, i);
+            let p_link = ptrs.link_from_index(p);
+            let i_link = idxs.link_from_index(i);
+            assert_eq!(ptrs.index_from_link(p_link), p);
+            assert_eq!(idxs.index_from_link(i_link), i);
+            assert_eq!(ptrs.get_link(p_link).get_key(), &Key(k));
+            assert_eq!(idxs.get_link(i_link).get_key(), &Key(k));
+        }
+        let p_head = chain(&mut ptrs, &[2, 0, 3, 1]);
+        let i_head = chain(&mut idxs, &[2, 0, 3, 1]);
+        assert_eq!(walk(&ptrs, p_head), walk(&idxs, i_head));
+        // an index link is half a pointer, and None is free
+        assert_eq!(::std::mem::size_of::<Option<user::Idx>>(), 4);
+    }
 }
@@ -21,7 +21,26 @@ pub trait Val: Sized + Default {}
 /// The Cache-Id, which will tell to which cache an element belongs to
 ///
 /// `Eq`, `Copy`, `Clone`, `Default`
-pub trait Cid: Eq + Copy + Clone + Default {}
+///
+/// The Cid can also hide the reference bit used by
+/// [`HitPolicy::Clock`](crate::lru::HitPolicy::Clock). The bit must not be
+/// part of the comparison between Cids.  
+/// By default there is no space for it, and entries are never referenced
+pub trait Cid: Eq + Copy + Clone + Default {
+    /// get the CLOCK reference bit
+    fn get_reference(&self) -> bool {
+        false
+    }
+    /// set the CLOCK reference bit
+    fn set_reference(&mut self, _referenced: bool) {}
+    /// move the entry to the cache identified by `cache`
+    ///
+    /// Cids that hide other data should only copy the cache id and reset the
+    /// reference bit
+    fn set_cache(&mut self, cache: Self) {
+        *self = cache;
+    }
+}
 
 impl<T> Cid for ::std::marker::PhantomData<T> {}
 
@@ -47,6 +66,23 @@ pub trait Meta<V>: Default {
     );
     /// run every time the key is requested
     fn on_get(&mut self, val: &mut V);
+    /// get the instant after which the entry is expired, in nanoseconds of
+    /// the cache [`TimeSource`](crate::expiry::TimeSource)
+    ///
+    /// By default there is no space for it, and entries never expire
+    fn get_expiry(&self) -> Option<u64> {
+        None
+    }
+    /// set the instant after which the entry is expired
+    fn set_expiry(&mut self, _expiry: Option<u64>) {}
+    /// get the weight of the entry, used by the caches with a weighted
+    /// capacity
+    ///
+    /// It must not change while the entry is in the cache, so it should be
+    /// computed in `on_insert` and stored. By default every entry weighs `1`
+    fn weight(&self) -> usize {
+        1
+    }
 }
 
 /// The simplest of implementation for metadata:
@@ -66,8 +102,131 @@ impl<V> Meta<V> for ZeroMeta {
     }
     fn on_get(&mut self, _val: &mut V) {}
 }
-// TODO: make 'head' and 'tail' typesafe.
-// Does this require a full reimplementation of all pointer operations?
+
+/// Metadata with only space for the expiry instant, used for entries
+/// inserted with a TTL
+#[derive(Default)]
+pub struct TtlMeta {
+    expiry: Option<u64>,
+}
+
+impl<V> Meta<V> for TtlMeta {
+    fn new() -> Self {
+        TtlMeta { expiry: None }
+    }
+    fn on_insert(
+        &mut self,
+        _current_val: &mut V,
+        _old_entry: Option<(&Self, &mut V)>,
+    ) {
+    }
+    fn on_get(&mut self, _val: &mut V) {}
+    fn get_expiry(&self) -> Option<u64> {
+        self.expiry
+    }
+    fn set_expiry(&mut self, expiry: Option<u64>) {
+        self.expiry = expiry;
+    }
+}
+/// Values that know their own weight, see [`WeightMeta`]
+pub trait Weigher {
+    /// the weight of the value
+    fn weight(&self) -> usize;
+}
+
+/// Metadata with only space for the weight of the value, computed on insert
+///
+/// A `get_mut` on the value does not change the weight until the next insert
+#[derive(Default)]
+pub struct WeightMeta {
+    weight: usize,
+}
+
+impl<V: Weigher> Meta<V> for WeightMeta {
+    fn new() -> Self {
+        WeightMeta { weight: 0 }
+    }
+    fn on_insert(
+        &mut self,
+        current_val: &mut V,
+        _old_entry: Option<(&Self, &mut V)>,
+    ) {
+        self.weight = current_val.weight();
+    }
+    fn on_get(&mut self, _val: &mut V) {}
+    fn weight(&self) -> usize {
+        self.weight
+    }
+}
+/// A link between two entries of the same hashmap, used by the caches to
+/// build their linked lists
+///
+/// The hashmap knows how to follow the link, so the same cache can work
+/// with pointers ([`std::ptr::NonNull`]) or indexes ([`Idx`])
+pub trait Link<E>: Copy + Eq {
+    /// build a link to the entry at index `idx`, whose address is `ptr`
+    fn new(idx: usize, ptr: ::std::ptr::NonNull<E>) -> Self;
+    /// follow the link.
+    ///
+    /// `from_idx` returns the entry at the given index in the hashmap
+    fn resolve(
+        self,
+        from_idx: impl FnOnce(usize) -> Option<::std::ptr::NonNull<E>>,
+    ) -> ::std::ptr::NonNull<E>;
+    /// get the index of the linked entry
+    ///
+    /// `from_ptr` translates the address of an entry to its index
+    fn index(
+        self,
+        from_ptr: impl FnOnce(::std::ptr::NonNull<E>) -> usize,
+    ) -> usize;
+}
+
+impl<E> Link<E> for ::std::ptr::NonNull<E> {
+    fn new(_idx: usize, ptr: ::std::ptr::NonNull<E>) -> Self {
+        ptr
+    }
+    fn resolve(
+        self,
+        _from_idx: impl FnOnce(usize) -> Option<::std::ptr::NonNull<E>>,
+    ) -> ::std::ptr::NonNull<E> {
+        self
+    }
+    fn index(
+        self,
+        from_ptr: impl FnOnce(::std::ptr::NonNull<E>) -> usize,
+    ) -> usize {
+        from_ptr(self)
+    }
+}
+
+/// Link entries via their `u32` index in the hashmap
+///
+/// Half the size of a pointer on 64-bit targets, and `Option<Idx>` is still
+/// 32 bits. Limits the hashmap to `u32::MAX - 1` buckets
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub struct Idx(::std::num::NonZeroU32);
+
+impl<E> Link<E> for Idx {
+    fn new(idx: usize, _ptr: ::std::ptr::NonNull<E>) -> Self {
+        let raw = ::std::convert::TryFrom::try_from(idx + 1)
+            .expect("Idx link only supports u32 indexes");
+        Idx(::std::num::NonZeroU32::new(raw).unwrap())
+    }
+    fn resolve(
+        self,
+        from_idx: impl FnOnce(usize) -> Option<::std::ptr::NonNull<E>>,
+    ) -> ::std::ptr::NonNull<E> {
+        from_idx((self.0.get() - 1) as usize)
+            .expect("Idx link to an empty bucket")
+    }
+    fn index(
+        self,
+        _from_ptr: impl FnOnce(::std::ptr::NonNull<E>) -> usize,
+    ) -> usize {
+        (self.0.get() - 1) as usize
+    }
+}
 
 /// Trait to reimplement for the hashmap Entry
 
@@ -79,24 +238,26 @@ where
     Umeta: Meta<V>,
     Self: Sized,
 {
+    /// How the entry links to the others in the same cache
+    type Link: Link<Self>;
     /// returns an entry with the given values
     fn new_entry(
-        head: Option<::std::ptr::NonNull<Self>>,
-        tail: Option<::std::ptr::NonNull<Self>>,
+        head: Option<Self::Link>,
+        tail: Option<Self::Link>,
         key: K,
         val: V,
         cache_id: Cid,
         user_data: Umeta,
     ) -> Self;
-    /// Get the pointer to a value higher in the cache
-    fn get_head_ptr(&self) -> Option<::std::ptr::NonNull<Self>>;
-    /// Set the pointer to a value higher in the cacheì
-    fn set_head_ptr(&mut self, head: Option<::std::ptr::NonNull<Self>>);
+    /// Get the link to a value higher in the cache
+    fn get_head(&self) -> Option<Self::Link>;
+    /// Set the link to a value higher in the cache
+    fn set_head(&mut self, head: Option<Self::Link>);
 
-    /// Get the pointer to a value lower in the cache
-    fn get_tail_ptr(&self) -> Option<::std::ptr::NonNull<Self>>;
-    /// Set the pointer to a value lower in the cacheì
-    fn set_tail_ptr(&mut self, tail: Option<::std::ptr::NonNull<Self>>);
+    /// Get the link to a value lower in the cache
+    fn get_tail(&self) -> Option<Self::Link>;
+    /// Set the link to a value lower in the cache
+    fn set_tail(&mut self, tail: Option<Self::Link>);
 
     /// get a reference to the key
     fn get_key(&self) -> &K;
@@ -177,6 +338,7 @@ where
     V: Val,
     Cid: crate::hashmap::user::Cid,
 {
+    type Link = ::std::ptr::NonNull<Self>;
     fn new_entry(
         head: Option<::std::ptr::NonNull<Self>>,
         tail: Option<::std::ptr::NonNull<Self>>,
@@ -194,16 +356,132 @@ where
             user_data: user_data,
         }
     }
-    fn get_head_ptr(&self) -> Option<::std::ptr::NonNull<Self>> {
+    fn get_head(&self) -> Option<::std::ptr::NonNull<Self>> {
+        self.ll_head
+    }
+    fn set_head(&mut self, head: Option<::std::ptr::NonNull<Self>>) {
+        self.ll_head = head;
+    }
+    fn get_tail(&self) -> Option<::std::ptr::NonNull<Self>> {
+        self.ll_tail
+    }
+    fn set_tail(&mut self, tail: Option<::std::ptr::NonNull<Self>>) {
+        self.ll_tail = tail;
+    }
+    fn get_key(&self) -> &K {
+        &self.key
+    }
+
+    fn get_val(&self) -> &V {
+        &self.val
+    }
+    fn get_val_mut(&mut self) -> &mut V {
+        &mut self.val
+    }
+    fn get_cache_id(&self) -> Cid {
+        self.cache_id
+    }
+    fn get_cache_id_mut(&mut self) -> &mut Cid {
+        &mut self.cache_id
+    }
+    fn get_user(&self) -> &Umeta {
+        &self.user_data
+    }
+    fn get_user_mut(&mut self) -> &mut Umeta {
+        &mut self.user_data
+    }
+    fn get_val_user_mut(&mut self) -> (&mut V, &mut Umeta) {
+        (&mut self.val, &mut self.user_data)
+    }
+    fn deconstruct(self) -> (K, V, Umeta) {
+        (self.key, self.val, self.user_data)
+    }
+    fn user_on_insert(&mut self, old_entry: Option<&mut Self>) {
+        match old_entry {
+            None => self.user_data.on_insert(&mut self.val, None),
+            Some(old_meta) => self.user_data.on_insert(
+                &mut self.val,
+                Some((&mut old_meta.user_data, &mut old_meta.val)),
+            ),
+        }
+    }
+    fn user_on_get(&mut self) {
+        self.user_data.on_get(&mut self.val)
+    }
+}
+
+/// Same as [`Entry`], but the caches link the elements via their [`Idx`] in
+/// the hashmap instead of pointers
+///
+/// Each link is half the size, but has to go through
+/// [`HashMap::get_index`](crate::hashmap::HashMap::get_index)
+pub struct IdxEntry<K, V, Cid, Umeta>
+where
+    Umeta: Meta<V>,
+    Cid: Copy,
+{
+    cache_id: Cid,
+    // linked list towards head
+    ll_head: Option<Idx>,
+    // linked list towards tail
+    ll_tail: Option<Idx>,
+    key: K,
+    val: V,
+    user_data: Umeta,
+}
+impl<K, V, Cid, Umeta: Meta<V>> Default for IdxEntry<K, V, Cid, Umeta>
+where
+    K: Hash,
+    V: Val,
+    Cid: crate::hashmap::user::Cid,
+{
+    fn default() -> Self {
+        IdxEntry {
+            cache_id: Cid::default(),
+            ll_head: None,
+            ll_tail: None,
+            key: K::default(),
+            val: V::default(),
+            user_data: Umeta::default(),
+        }
+    }
+}
+
+impl<K, V, Cid, Umeta: Meta<V>> EntryT<K, V, Cid, Umeta>
+    for IdxEntry<K, V, Cid, Umeta>
+where
+    K: Hash,
+    V: Val,
+    Cid: crate::hashmap::user::Cid,
+{
+    type Link = Idx;
+    fn new_entry(
+        head: Option<Idx>,
+        tail: Option<Idx>,
+        key: K,
+        val: V,
+        cache_id: Cid,
+        user_data: Umeta,
+    ) -> Self {
+        IdxEntry {
+            cache_id,
+            ll_head: head,
+            ll_tail: tail,
+            key,
+            val,
+            user_data,
+        }
+    }
+    fn get_head(&self) -> Option<Idx> {
         self.ll_head
     }
-    fn set_head_ptr(&mut self, head: Option<::std::ptr::NonNull<Self>>) {
+    fn set_head(&mut self, head: Option<Idx>) {
         self.ll_head = head;
     }
-    fn get_tail_ptr(&self) -> Option<::std::ptr::NonNull<Self>> {
+    fn get_tail(&self) -> Option<Idx> {
         self.ll_tail
     }
-    fn set_tail_ptr(&mut self, tail: Option<::std::ptr::NonNull<Self>>) {
+    fn set_tail(&mut self, tail: Option<Idx>) {
         self.ll_tail = tail;
     }
     fn get_key(&self) -> &K {
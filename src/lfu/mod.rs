@@ -0,0 +1,810 @@
+/*
+ * Copyright 2021 Luca Fulchir <luca@fenrirproject.org>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! LFU (Least Frequently Used) cache
+//!
+//! An exact LFU: the entry with the lowest number of accesses is evicted,
+//! and between entries with the same frequency the least recent one.  
+//! The entries are kept in per-frequency buckets, so get, insert and evict
+//! are all O(1).
+//!
+//! A pure LFU never forgets: entries that were popular a long time ago stay
+//! in the cache. Optional aging halves all the frequencies from time to time,
+//! just like the counters of the [SW-TinyLFU](crate::swtlfu).
+//!
+//! See the [O(1) LFU paper](http://dhruvbird.com/lfu.pdf)
+
+use crate::hashmap;
+use crate::hashmap::user;
+use crate::owned::Owned;
+use crate::results::{InsertResultShared, RemovalCause, Removed};
+use crate::swtlfu::counter;
+
+/// Cache id of the [`LFU`]: either in the cache or not
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LFUCid {
+    None,
+    Lfu,
+}
+impl Default for LFUCid {
+    fn default() -> Self {
+        LFUCid::None
+    }
+}
+impl user::Cid for LFUCid {}
+
+// This is a Cid, but it hides generation and counters inside
+// Layout: cid 0, generation 1, counter 31..2
+::bitfield::bitfield! {
+    /// [`LFUCid`] plus generation and a 30 bit counter, all in 32 bits
+    #[derive(Copy, Clone, Default)]
+    pub struct LFUCounter(u32);
+    impl Debug;
+    #[inline]
+    pub g_cid, s_cid: 0;
+    #[inline]
+    pub into counter::Generation, g_generation, s_generation: 1;
+    #[inline]
+    pub u32, g_counter, s_counter: 31, 2;
+}
+impl user::Cid for LFUCounter {
+    fn set_cache(&mut self, cache: Self) {
+        self.s_cid(cache.g_cid());
+    }
+}
+impl PartialEq for LFUCounter {
+    fn eq(&self, other: &Self) -> bool {
+        self.g_cid() == other.g_cid()
+    }
+}
+impl Eq for LFUCounter {}
+
+impl counter::CidCounter<LFUCid> for LFUCounter {
+    fn new(cid: LFUCid) -> Self {
+        let mut res = LFUCounter::default();
+        res.set_cid(cid);
+        res
+    }
+    fn get_cid(&self) -> LFUCid {
+        match self.g_cid() {
+            false => LFUCid::None,
+            true => LFUCid::Lfu,
+        }
+    }
+    fn set_cid(&mut self, cid: LFUCid) {
+        self.s_cid(cid == LFUCid::Lfu)
+    }
+
+    fn get_generation(&self) -> counter::Generation {
+        self.g_generation().into()
+    }
+    fn flip_generation(&mut self) {
+        self.s_generation((!self.get_generation()).into())
+    }
+
+    fn get_counter(&self) -> u32 {
+        self.g_counter()
+    }
+    fn add(&mut self) {
+        let tmp = self.g_counter();
+        // saturate, the counter is only 30 bits
+        if tmp < (1 << 30) - 1 {
+            self.s_counter(tmp + 1);
+        }
+    }
+    fn halve(&mut self) {
+        let tmp = self.g_counter();
+        self.s_counter(tmp / 2);
+    }
+}
+
+type LFUEntry<K, V, Umeta> = user::Entry<K, V, LFUCounter, Umeta>;
+type HmapT<K, V, Umeta, HB> =
+    hashmap::SimpleHmap<LFUEntry<K, V, Umeta>, K, V, LFUCounter, Umeta, HB>;
+type SharedT<'a, K, V, Umeta, HB> = LFUShared<
+    'a,
+    HmapT<K, V, Umeta, HB>,
+    LFUEntry<K, V, Umeta>,
+    K,
+    V,
+    LFUCid,
+    LFUCounter,
+    Umeta,
+    HB,
+>;
+
+/// [LFU](https://en.wikipedia.org/wiki/Least_frequently_used)
+/// implementation that wraps LFUShared, see [`Owned`]
+pub type LFU<'a, K, V, Umeta, HB> =
+    Owned<'a, K, V, LFUCounter, Umeta, HB, SharedT<'a, K, V, Umeta, HB>>;
+
+impl<
+        'a,
+        K: user::Hash,
+        V: user::Val,
+        Umeta: user::Meta<V>,
+        HB: ::std::hash::BuildHasher + Default,
+    > LFU<'a, K, V, Umeta, HB>
+{
+    /// new LFU with space for `entries` elements
+    pub fn new(
+        entries: usize,
+        extra_hashmap_capacity: usize,
+        hash_builder: HB,
+    ) -> Self {
+        Owned::from_shared(
+            LFUShared::new(entries, LFUCid::Lfu, None),
+            extra_hashmap_capacity,
+            hash_builder,
+        )
+    }
+    /// Enable or disable aging of the frequencies, disabled by default
+    pub fn set_aging(&mut self, enabled: bool) {
+        self.shared_mut().set_aging(enabled)
+    }
+    /// check if aging is enabled
+    pub fn is_aging(&self) -> bool {
+        self.shared().is_aging()
+    }
+    /// get the current frequency of an element, without counting an access
+    pub fn frequency(&self, key: &K) -> Option<u32> {
+        let idx = self.index_of(key)?;
+        Some(self.shared().frequency(self.hmap(), idx))
+    }
+}
+
+// first and last entry with the same frequency.
+// The head is the most recent one
+#[derive(Clone, Copy)]
+struct Bucket<L> {
+    head: L,
+    tail: L,
+}
+
+/// Actual implementation of the LFU on a shared hashmap
+///
+/// All the entries are in one list linked via [`user::EntryT::Link`],
+/// ordered by frequency and then by recency: the tail has the lowest
+/// frequency and is the least recent, so it is the next one to be evicted.  
+/// Each frequency is a contiguous bucket of the list, and we only track the
+/// first and last entry of each bucket. Moving an entry to the next bucket,
+/// inserting and evicting are all O(1).
+///
+/// The frequency is kept in the `CidCtr` counter. Aging is optional: when
+/// enabled the generation flips every `capacity()` get/insert, and all the
+/// frequencies are halved. The buckets are halved immediately, while the
+/// counters are halved lazily with [`counter::CidCounter::age`], a couple of
+/// entries at every get/insert.
+///
+/// Note that Insert/Remove do not actually insert anything in the hashmap.
+/// That must be done by the caller. We only fix all the pointers and LFU
+/// status
+pub struct LFUShared<'a, Hmap, E, K, V, CidT, CidCtr, Umeta, HB>
+where
+    Hmap: hashmap::HashMap<E, K, V, CidCtr, Umeta, HB>,
+    E: user::EntryT<K, V, CidCtr, Umeta>,
+    K: user::Hash,
+    V: user::Val,
+    CidT: user::Cid,
+    CidCtr: counter::CidCounter<CidT>,
+    Umeta: user::Meta<V>,
+    HB: ::std::hash::BuildHasher + Default,
+{
+    _capacity: usize,
+    _used: usize,
+    _head: Option<E::Link>,
+    _tail: Option<E::Link>,
+    _buckets: ::std::collections::HashMap<u32, Bucket<E::Link>>,
+    _cache_id: CidT,
+    // aging: current generation, get/insert since the last flip and next
+    // entry to age
+    _aging: bool,
+    _generation: counter::Generation,
+    _ops: usize,
+    _age_next: Option<E::Link>,
+    _hmap: ::std::marker::PhantomData<Hmap>,
+    _k: ::std::marker::PhantomData<K>,
+    _v: ::std::marker::PhantomData<V>,
+    _umeta: ::std::marker::PhantomData<Umeta>,
+    _hb: ::std::marker::PhantomData<HB>,
+    _scan: crate::scan::Scan<'a, Hmap, E, K, V, CidCtr, Umeta, HB>,
+}
+
+impl<
+        'a,
+        Hmap: hashmap::HashMap<E, K, V, CidCtr, Umeta, HB>,
+        E: user::EntryT<K, V, CidCtr, Umeta>,
+        K: user::Hash,
+        V: user::Val,
+        CidT: user::Cid,
+        CidCtr: counter::CidCounter<CidT>,
+        Umeta: user::Meta<V>,
+        HB: ::std::hash::BuildHasher + Default,
+    > LFUShared<'a, Hmap, E, K, V, CidT, CidCtr, Umeta, HB>
+{
+    /// new LFU with space for `entries` elements, its cache id and an
+    /// optional callback for the lazy scan
+    pub fn new(
+        entries: usize,
+        cache_id: CidT,
+        access_scan: Option<&'a dyn Fn(::std::ptr::NonNull<E>)>,
+    ) -> Self {
+        LFUShared {
+            _capacity: entries,
+            _used: 0,
+            _head: None,
+            _tail: None,
+            _buckets: ::std::collections::HashMap::new(),
+            _cache_id: cache_id,
+            _aging: false,
+            _generation: counter::Generation::default(),
+            _ops: 0,
+            _age_next: None,
+            _hmap: ::std::marker::PhantomData,
+            _k: ::std::marker::PhantomData,
+            _v: ::std::marker::PhantomData,
+            _umeta: ::std::marker::PhantomData,
+            _hb: ::std::marker::PhantomData,
+            _scan: crate::scan::Scan::new(access_scan),
+        }
+    }
+    /// change the scan callback
+    pub fn set_scanf(
+        &mut self,
+        access_scan: Option<&'a dyn Fn(::std::ptr::NonNull<E>)>,
+    ) {
+        self._scan.set_scanf(access_scan)
+    }
+    /// Enable or disable aging.
+    ///
+    /// With aging all the frequencies are halved every `capacity()`
+    /// get/insert, so old entries that are not used anymore can be evicted
+    pub fn set_aging(&mut self, enabled: bool) {
+        // without aging the generation does not flip anymore, and the
+        // counters that were not aged yet are aged when used
+        self._aging = enabled;
+        self._ops = 0;
+    }
+    /// check if aging is enabled
+    pub fn is_aging(&self) -> bool {
+        self._aging
+    }
+    /// An element has been added by the caller, fix the LFU
+    ///
+    /// `maybe_old_entry` must be `!= None` only if the clash happened in
+    /// this cache
+    pub fn insert_shared(
+        &mut self,
+        hmap: &mut Hmap,
+        maybe_old_entry: Option<&mut E>,
+        new_entry_idx: usize,
+    ) -> InsertResultShared {
+        let res = match maybe_old_entry {
+            None => {
+                let res = self.push(hmap, new_entry_idx);
+                hmap.get_index_mut(new_entry_idx)
+                    .unwrap()
+                    .user_on_insert(None);
+                res
+            }
+            Some(old_entry)
+                if old_entry.get_key()
+                    == hmap.get_index(new_entry_idx).unwrap().get_key() =>
+            {
+                // same key, just a new value. The new entry took the bucket
+                // of the old one, take over its place and count an access
+                let just_inserted = hmap.get_index_mut(new_entry_idx).unwrap();
+                just_inserted.set_head(old_entry.get_head());
+                just_inserted.set_tail(old_entry.get_tail());
+                *just_inserted.get_cache_id_mut() = old_entry.get_cache_id();
+                just_inserted.user_on_insert(Some(old_entry));
+                self.hit(hmap, new_entry_idx);
+                Removed::new()
+            }
+            Some(old_entry) => {
+                // a different key took the place of the old entry, which is
+                // not in the cache anymore
+                self.remove_clashed(hmap, old_entry, new_entry_idx);
+                let res = self.push(hmap, new_entry_idx);
+                hmap.get_index_mut(new_entry_idx)
+                    .unwrap()
+                    .user_on_insert(Some(old_entry));
+                res
+            }
+        };
+        self._scan
+            .apply_raw(hmap.get_index_mut(new_entry_idx).unwrap().into());
+        self._scan.apply_next(hmap);
+        res
+    }
+    /// An entry moved here from another cache on the same hashmap
+    ///
+    /// Like [`insert_shared`](Self::insert_shared) without clashes, but the
+    /// on-insert callback is not run, since this is not a new insert.  
+    /// The entry starts again from a frequency of one
+    pub fn move_in_shared(
+        &mut self,
+        hmap: &mut Hmap,
+        idx: usize,
+    ) -> InsertResultShared {
+        let res = self.push(hmap, idx);
+        self._scan.apply_next(hmap);
+        res
+    }
+    // evict if full, then add the entry with a frequency of one
+    fn push(&mut self, hmap: &mut Hmap, idx: usize) -> InsertResultShared {
+        let maybe_evicted = if self._used >= self._capacity {
+            self.evict(hmap)
+        } else {
+            None
+        };
+        let cid = hmap.get_index_mut(idx).unwrap().get_cache_id_mut();
+        *cid = CidCtr::new(self._cache_id);
+        if cid.get_generation() != self._generation {
+            cid.flip_generation();
+        }
+        cid.add();
+        let freq = cid.get_counter();
+        // there is no lower frequency, a new bucket goes to the tail
+        self.push_bucket(hmap, idx, freq, None);
+        self._used += 1;
+        self.tick(hmap);
+        match maybe_evicted {
+            None => Removed::new(),
+            Some(evicted) => Removed::single(evicted, RemovalCause::Capacity),
+        }
+    }
+    // count one more access to the entry at `idx`, moving it to the head of
+    // the next bucket
+    fn hit(&mut self, hmap: &mut Hmap, idx: usize) {
+        let freq = self.aged_frequency(hmap, idx);
+        let anchor = self.pop_bucket(hmap, idx, freq);
+        let cid = hmap.get_index_mut(idx).unwrap().get_cache_id_mut();
+        cid.add();
+        let freq = cid.get_counter();
+        self.push_bucket(hmap, idx, freq, anchor);
+        self.tick(hmap);
+    }
+    // bring the counter of the entry at `idx` to the current generation and
+    // return it
+    fn aged_frequency(&self, hmap: &mut Hmap, idx: usize) -> u32 {
+        let cid = hmap.get_index_mut(idx).unwrap().get_cache_id_mut();
+        Self::age(cid, self._generation);
+        cid.get_counter()
+    }
+    // like `counter::CidCounter::age`, but entries in the cache never go
+    // below one, just like the buckets
+    fn age(cid: &mut CidCtr, generation: counter::Generation) {
+        cid.age(generation);
+        if cid.get_counter() == 0 {
+            cid.add();
+        }
+    }
+    // detach the entry at `idx` from its bucket and from the list.
+    // Return the entry that should be right after a new bucket with the
+    // next frequency, `None` for the tail
+    fn pop_bucket(
+        &mut self,
+        hmap: &mut Hmap,
+        idx: usize,
+        freq: u32,
+    ) -> Option<E::Link> {
+        let link = hmap.link_from_index(idx);
+        self._scan.check_and_next(hmap, link);
+        let entry = hmap.get_index(idx).unwrap();
+        let (head, tail) = (entry.get_head(), entry.get_tail());
+        if self._age_next == Some(link) {
+            self._age_next = tail;
+        }
+        let anchor = match self._buckets.get_mut(&freq) {
+            Some(bucket) if bucket.head == link && bucket.tail == link => {
+                self._buckets.remove(&freq);
+                tail
+            }
+            Some(bucket) => {
+                if bucket.head == link {
+                    bucket.head = tail.unwrap();
+                } else if bucket.tail == link {
+                    bucket.tail = head.unwrap();
+                }
+                Some(bucket.head)
+            }
+            None => ::std::panic!("LFU: entry not in its bucket"),
+        };
+        match head {
+            None => self._head = tail,
+            Some(entry_head) => hmap.get_link_mut(entry_head).set_tail(tail),
+        }
+        match tail {
+            None => self._tail = head,
+            Some(entry_tail) => hmap.get_link_mut(entry_tail).set_head(head),
+        }
+        anchor
+    }
+    // put the detached entry at `idx` at the head of the bucket of `freq`.
+    // If there is no such bucket, it is created right before `anchor`, or at
+    // the tail if `anchor` is `None`
+    fn push_bucket(
+        &mut self,
+        hmap: &mut Hmap,
+        idx: usize,
+        freq: u32,
+        anchor: Option<E::Link>,
+    ) {
+        let link = hmap.link_from_index(idx);
+        let anchor = match self._buckets.get_mut(&freq) {
+            Some(bucket) => {
+                let anchor = bucket.head;
+                bucket.head = link;
+                Some(anchor)
+            }
+            None => {
+                self._buckets.insert(
+                    freq,
+                    Bucket {
+                        head: link,
+                        tail: link,
+                    },
+                );
+                anchor
+            }
+        };
+        let head = match anchor {
+            None => self._tail,
+            Some(anchor) => hmap.get_link(anchor).get_head(),
+        };
+        let entry = hmap.get_index_mut(idx).unwrap();
+        entry.set_head(head);
+        entry.set_tail(anchor);
+        match head {
+            None => self._head = Some(link),
+            Some(head) => hmap.get_link_mut(head).set_tail(Some(link)),
+        }
+        match anchor {
+            None => self._tail = Some(link),
+            Some(anchor) => hmap.get_link_mut(anchor).set_head(Some(link)),
+        }
+    }
+    // count one more get/insert. With aging, age a couple of counters and
+    // every `capacity()` operations flip the generation
+    fn tick(&mut self, hmap: &mut Hmap) {
+        if !self._aging {
+            return;
+        }
+        self.age_next(hmap);
+        self.age_next(hmap);
+        self._ops += 1;
+        if self._ops < self._capacity {
+            return;
+        }
+        self._ops = 0;
+        // the counters must all be from the current generation before the
+        // next flip. This normally does nothing
+        while self._age_next.is_some() {
+            self.age_next(hmap);
+        }
+        self._generation = !self._generation;
+        self.halve_buckets();
+        self._age_next = self._head;
+    }
+    // age the counter of the next entry
+    fn age_next(&mut self, hmap: &mut Hmap) {
+        if let Some(next) = self._age_next {
+            let entry = hmap.get_link_mut(next);
+            Self::age(entry.get_cache_id_mut(), self._generation);
+            self._age_next = entry.get_tail();
+        }
+    }
+    // halve the frequency of all the buckets.
+    // The buckets that end up with the same frequency are next to each
+    // other, so they are just merged
+    fn halve_buckets(&mut self) {
+        let mut freqs: Vec<u32> = self._buckets.keys().copied().collect();
+        freqs.sort_unstable();
+        let mut halved =
+            ::std::collections::HashMap::with_capacity(self._buckets.len());
+        for freq in freqs {
+            let bucket = self._buckets.remove(&freq).unwrap();
+            let new_freq = ::std::cmp::max(1, freq / 2);
+            match halved.get_mut(&new_freq) {
+                None => {
+                    halved.insert(new_freq, bucket);
+                }
+                Some(lower) => lower.head = bucket.head,
+            }
+        }
+        self._buckets = halved;
+    }
+    // detach the tail, the least recent entry with the lowest frequency.
+    // The entry is still in the hashmap, its index is returned
+    fn evict(&mut self, hmap: &mut Hmap) -> Option<usize> {
+        let idx = hmap.index_from_link(self._tail?);
+        let freq = self.aged_frequency(hmap, idx);
+        self.pop_bucket(hmap, idx, freq);
+        self._used -= 1;
+        Some(idx)
+    }
+    /// The index of the entry that will be evicted by the next insert, if
+    /// the LFU is full
+    pub fn victim(&self, hmap: &Hmap) -> Option<usize> {
+        if self._used < self._capacity {
+            return None;
+        }
+        self._tail.map(|t| hmap.index_from_link(t))
+    }
+    /// reset the LFU
+    pub fn clear_shared(&mut self) {
+        self._head = None;
+        self._tail = None;
+        self._buckets.clear();
+        self._used = 0;
+        self._ops = 0;
+        self._age_next = None;
+        self._scan.stop();
+    }
+    /// do not actually remove the element at `idx`, just fix the LFU so that
+    /// it is not considered anymore and actual removal is safe
+    pub fn remove_shared(&mut self, hmap: &mut Hmap, idx: usize) {
+        let freq = self.aged_frequency(hmap, idx);
+        self.pop_bucket(hmap, idx, freq);
+        self._used -= 1;
+    }
+    /// `old_entry` was in the LFU, but the entry at `idx` clashed with it and
+    /// took its place in the hashmap. Forget about the old entry.
+    ///
+    /// The links and cache id of the entry at `idx` are overwritten, the
+    /// caller will have to insert it somewhere
+    pub fn remove_clashed(
+        &mut self,
+        hmap: &mut Hmap,
+        old_entry: &E,
+        idx: usize,
+    ) {
+        let entry = hmap.get_index_mut(idx).unwrap();
+        entry.set_head(old_entry.get_head());
+        entry.set_tail(old_entry.get_tail());
+        *entry.get_cache_id_mut() = old_entry.get_cache_id();
+        self.remove_shared(hmap, idx);
+    }
+    /// Change the number of entries of the LFU
+    ///
+    /// When shrinking, the least frequently used entries are evicted and
+    /// their indexes returned.  
+    /// Just like with [`InsertResultShared`] they are still in the
+    /// hashmap and must be removed by the caller
+    pub fn set_capacity(
+        &mut self,
+        hmap: &mut Hmap,
+        entries: usize,
+    ) -> Removed<usize> {
+        self._capacity = entries;
+        let mut evicted = Removed::new();
+        while self._used > self._capacity {
+            match self.evict(hmap) {
+                None => break,
+                Some(to_remove) => {
+                    evicted.push(to_remove, RemovalCause::Capacity)
+                }
+            }
+        }
+        evicted
+    }
+    /// The hashmap has been resized and all the entries have moved.
+    ///
+    /// Fix the list, the buckets and the lazy scans
+    pub fn relocate(&mut self, reloc: &hashmap::Relocation<E>) {
+        self._head = self._head.and_then(|h| reloc.link(h));
+        self._tail = self._tail.and_then(|t| reloc.link(t));
+        self._age_next = self._age_next.and_then(|n| reloc.link(n));
+        for bucket in self._buckets.values_mut() {
+            bucket.head = reloc.link(bucket.head).unwrap();
+            bucket.tail = reloc.link(bucket.tail).unwrap();
+        }
+        self._scan.relocate(reloc);
+    }
+    /// get the LFU cache id
+    pub fn get_cache_id(&self) -> CidT {
+        self._cache_id
+    }
+    /// get the current frequency of the entry at `idx`
+    pub fn frequency(&self, hmap: &Hmap, idx: usize) -> u32 {
+        let mut cid = hmap.get_index(idx).unwrap().get_cache_id();
+        Self::age(&mut cid, self._generation);
+        cid.get_counter()
+    }
+    /// Used when composing caches: count one more access and run the
+    /// callback on the entry at `idx`
+    pub fn on_get(&mut self, hmap: &mut Hmap, idx: usize) {
+        self.hit(hmap, idx);
+        hmap.get_index_mut(idx).unwrap().user_on_get();
+        self._scan.apply_next(hmap);
+    }
+    /// start the lazy scan, from the most frequently used entry  
+    /// The scan will execute on the whole LFU but only once
+    pub fn start_scan(&mut self) {
+        if let (false, Some(head)) = (self._scan.is_running(), self._head) {
+            self._scan.start_scan(head);
+        }
+    }
+    /// check if the scan is still running
+    pub fn is_scan_running(&self) -> bool {
+        self._scan.is_running()
+    }
+    /// get the LFU capacity
+    pub fn capacity(&self) -> usize {
+        self._capacity
+    }
+    /// get the LFU usage
+    pub fn len(&self) -> usize {
+        self._used
+    }
+    /// check if the LFU is empty
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<
+        'a,
+        Hmap: hashmap::HashMap<E, K, V, CidCtr, Umeta, HB>,
+        E: user::EntryT<K, V, CidCtr, Umeta>,
+        K: user::Hash,
+        V: user::Val,
+        CidT: user::Cid,
+        CidCtr: counter::CidCounter<CidT>,
+        Umeta: user::Meta<V>,
+        HB: ::std::hash::BuildHasher + Default,
+    > crate::cache::SharedCache<'a, Hmap, E, K, V, CidCtr, Umeta, HB>
+    for LFUShared<'a, Hmap, E, K, V, CidT, CidCtr, Umeta, HB>
+{
+    fn insert_shared(
+        &mut self,
+        hmap: &mut Hmap,
+        maybe_old_entry: Option<&mut E>,
+        new_entry_idx: usize,
+    ) -> InsertResultShared {
+        LFUShared::insert_shared(self, hmap, maybe_old_entry, new_entry_idx)
+    }
+    fn move_in_shared(
+        &mut self,
+        hmap: &mut Hmap,
+        idx: usize,
+    ) -> InsertResultShared {
+        LFUShared::move_in_shared(self, hmap, idx)
+    }
+    fn remove_shared(&mut self, hmap: &mut Hmap, idx: usize) {
+        LFUShared::remove_shared(self, hmap, idx)
+    }
+    fn remove_clashed(&mut self, hmap: &mut Hmap, old_entry: &E, idx: usize) {
+        LFUShared::remove_clashed(self, hmap, old_entry, idx)
+    }
+    fn clear_shared(&mut self) {
+        LFUShared::clear_shared(self)
+    }
+    fn on_get(&mut self, hmap: &mut Hmap, idx: usize) {
+        LFUShared::on_get(self, hmap, idx)
+    }
+    fn owns(&self, cid: CidCtr) -> bool {
+        cid.get_cid() == self._cache_id
+    }
+    fn victim(&self, hmap: &Hmap) -> Option<usize> {
+        LFUShared::victim(self, hmap)
+    }
+    fn relocate(&mut self, reloc: &hashmap::Relocation<E>) {
+        LFUShared::relocate(self, reloc)
+    }
+    fn set_scanf(
+        &mut self,
+        access_scan: Option<&'a dyn Fn(::std::ptr::NonNull<E>)>,
+    ) {
+        LFUShared::set_scanf(self, access_scan)
+    }
+    fn start_scan(&mut self) {
+        LFUShared::start_scan(self)
+    }
+    fn is_scan_running(&self) -> bool {
+        LFUShared::is_scan_running(self)
+    }
+    // the LFU always moves the entries to the next frequency
+    fn set_hit_policy(&mut self, _policy: crate::lru::HitPolicy) {}
+    fn get_hit_policy(&self) -> crate::lru::HitPolicy {
+        crate::lru::HitPolicy::MoveToHead
+    }
+    fn set_capacity(
+        &mut self,
+        hmap: &mut Hmap,
+        entries: usize,
+    ) -> Removed<usize> {
+        LFUShared::set_capacity(self, hmap, entries)
+    }
+    fn capacity(&self) -> usize {
+        LFUShared::capacity(self)
+    }
+    fn len(&self) -> usize {
+        LFUShared::len(self)
+    }
+    // only counts entries
+    fn set_weighted(
+        &mut self,
+        _hmap: &Hmap,
+        weighted: bool,
+    ) -> Result<(), crate::results::Error> {
+        match weighted {
+            false => Ok(()),
+            true => Err(crate::results::Error::WeightUnsupported),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hashmap::user::EntryT;
+    use crate::testing::{Hasher, Key, Val};
+
+    type Cache<'a> = LFU<'a, Key, Val, user::ZeroMeta, Hasher>;
+
+    fn victim(cache: &Cache) -> Key {
+        let idx = cache.shared().victim(cache.hmap()).unwrap();
+        *cache.hmap().get_index(idx).unwrap().get_key()
+    }
+    fn evicted(res: Removed<(Key, Val, user::ZeroMeta)>) -> Vec<Key> {
+        res.iter().map(|(e, _)| e.0).collect()
+    }
+
+    #[test]
+    fn ties_evict_the_least_recent() {
+        let mut cache = Cache::new(3, 0, Hasher::default());
+        for k in 0..3 {
+            cache.insert(Key(k), Val(k));
+        }
+        assert_eq!(victim(&cache), Key(0));
+        cache.get(&Key(0));
+        assert_eq!(cache.frequency(&Key(0)), Some(2));
+        assert_eq!(victim(&cache), Key(1));
+        assert_eq!(evicted(cache.insert(Key(3), Val(3))), vec![Key(1)]);
+        // 2 and 3 are both used once, 2 is older
+        assert_eq!(victim(&cache), Key(2));
+        assert_eq!(evicted(cache.insert(Key(4), Val(4))), vec![Key(2)]);
+        assert!(cache.contains_key(&Key(0)));
+    }
+
+    #[test]
+    fn aging_merges_the_buckets_in_order() {
+        let mut cache = Cache::new(8, 0, Hasher::default());
+        cache.set_aging(true);
+        for k in 0..4 {
+            cache.insert(Key(k), Val(k));
+        }
+        cache.get(&Key(0));
+        cache.get(&Key(0));
+        cache.get(&Key(1));
+        assert_eq!(cache.frequency(&Key(0)), Some(3));
+        assert_eq!(cache.frequency(&Key(1)), Some(2));
+        // the 8th operation halves everything down to one
+        cache.get(&Key(2));
+        for k in 0..4 {
+            assert_eq!(cache.frequency(&Key(k)), Some(1));
+        }
+        // the merged buckets keep the old order: lower frequencies first
+        assert_eq!(
+            evicted(cache.set_capacity(1)),
+            vec![Key(3), Key(1), Key(2)]
+        );
+        assert!(cache.contains_key(&Key(0)));
+        assert!(!cache.shared().is_empty());
+    }
+}
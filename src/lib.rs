@@ -35,16 +35,40 @@
 //! # Composable caches
 //! Each cache is usable on the same hashmap, so they must coordinate a bit
 //! through its The project currently implements:
+//! * [ARC](arc)
+//! * [CLOCK](clock) and [CLOCK-Pro](clock::pro)
+//! * [LFU](lfu)
+//! * [LIRS](lirs)
 //! * [LRU](lru)
+//! * [S3-FIFO](s3fifo)
+//! * [Sampled](sampled), approximated LRU/LFU like Redis
+//! * [SIEVE](sieve)
 //! * [SLRU](slru)
 //! * [Scan-W-TLFU](swtlfu), a W-TLFU variant
+//! * [2Q](twoq)
 
+pub mod arc;
+/// common traits for all the caches
+pub mod cache;
+pub mod clock;
+pub mod expiry;
+// not public, lists of the keys of evicted entries
+mod ghost;
 /// stable hashmap implementation, based on `hashbrown::raw::RawTable`
 pub mod hashmap;
+pub mod lfu;
+pub mod lirs;
 pub mod lru;
+pub mod owned;
 /// common result for insert/get operations
 pub mod results;
+pub mod s3fifo;
+pub mod sampled;
 // not public, wrapper to scan each entry
 mod scan;
+pub mod sieve;
 pub mod slru;
 pub mod swtlfu;
+#[cfg(test)]
+mod testing;
+pub mod twoq;
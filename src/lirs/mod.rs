@@ -0,0 +1,652 @@
+/*
+ * Copyright 2021 Luca Fulchir <luca@fenrirproject.org>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! LIRS (Low Inter-reference Recency Set) cache
+//!
+//! LIRS looks at the reuse distance of the entries, not only at their
+//! recency. The entries are either:
+//!  * LIR: entries with a short reuse distance, most of the cache
+//!  * HIR: entries with a long reuse distance, or seen only once. Only 1% of
+//!    the cache is kept for the resident HIR entries, in the queue Q
+//!
+//! The stack S keeps the recency of the LIR entries and of the HIR entries
+//! more recent than the oldest LIR one, even if they are not resident
+//! anymore. A HIR entry that is hit while still in the stack has a shorter
+//! reuse distance than the oldest LIR entry, so they switch status.  
+//! Only the resident HIR entries are evicted, so loops slightly bigger than
+//! the cache and long scans do not flush the LIR entries like in an LRU.
+//!
+//! See the [original paper](https://dl.acm.org/doi/10.1145/511399.511340)
+
+use crate::ghost::Ghost;
+use crate::hashmap;
+use crate::hashmap::user;
+use crate::owned::Owned;
+use crate::results::{InsertResultShared, RemovalCause, Removed};
+
+/// cache id of the entries of the owning [`LIRS`]
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum LIRSCid {
+    None,
+    Lir,
+    Hir,
+}
+impl Default for LIRSCid {
+    fn default() -> Self {
+        LIRSCid::None
+    }
+}
+impl user::Cid for LIRSCid {}
+
+type LIRSEntry<K, V, Umeta> = user::Entry<K, V, LIRSCid, Umeta>;
+type HmapT<K, V, Umeta, HB> =
+    hashmap::SimpleHmap<LIRSEntry<K, V, Umeta>, K, V, LIRSCid, Umeta, HB>;
+
+/// [LIRS](https://en.wikipedia.org/wiki/LIRS_caching_algorithm)
+/// implementation that wraps LIRSShared, see [`Owned`]
+pub type LIRS<'a, K, V, Umeta, HB> = Owned<
+    'a,
+    K,
+    V,
+    LIRSCid,
+    Umeta,
+    HB,
+    LIRSShared<
+        'a,
+        HmapT<K, V, Umeta, HB>,
+        LIRSEntry<K, V, Umeta>,
+        K,
+        V,
+        LIRSCid,
+        Umeta,
+        HB,
+    >,
+>;
+
+impl<
+        'a,
+        K: user::Hash,
+        V: user::Val,
+        Umeta: user::Meta<V>,
+        HB: ::std::hash::BuildHasher + Default,
+    > LIRS<'a, K, V, Umeta, HB>
+{
+    /// new LIRS with space for `entries` elements
+    pub fn new(
+        entries: usize,
+        extra_hashmap_capacity: usize,
+        hash_builder: HB,
+    ) -> Self {
+        Owned::from_shared(
+            LIRSShared::new(entries, LIRSCid::Lir, LIRSCid::Hir, None),
+            extra_hashmap_capacity,
+            hash_builder,
+        )
+    }
+    /// return the current number of `(LIR, resident HIR)` entries
+    pub fn get_lens(&self) -> (usize, usize) {
+        self.shared().get_lens()
+    }
+}
+
+#[derive(PartialEq, Eq)]
+enum ScanStatus {
+    Stopped,
+    RunningLir,
+    RunningHir,
+}
+
+// the resident HIR entries get 1% of the entries
+fn hir_size(entries: usize) -> usize {
+    ::std::cmp::max(1, entries / 100)
+}
+
+/// Actual implementation of the LIRS on a shared hashmap
+///
+/// The LIR entries and the resident HIR entries (queue Q) are two LRUs on the
+/// hashmap, with different cache ids.  
+/// The stack S only has the keys, since it also tracks non-resident HIR
+/// entries, which are not in the hashmap.
+///
+/// Note that Insert/Remove do not actually insert anything in the hashmap.
+/// That must be done by the caller. We only fix all the pointers and LIRS
+/// status
+pub struct LIRSShared<'a, Hmap, E, K, V, CidT, Umeta, HB>
+where
+    Hmap: hashmap::HashMap<E, K, V, CidT, Umeta, HB>,
+    E: user::EntryT<K, V, CidT, Umeta>,
+    K: user::Hash,
+    V: user::Val,
+    CidT: user::Cid,
+    Umeta: user::Meta<V>,
+    HB: ::std::hash::BuildHasher + Default,
+{
+    // the order of the LIR entries does not matter, the recency is in the
+    // stack. Both LRUs can grow up to the whole capacity, the evictions are
+    // decided by the LIRS
+    _lir: crate::lru::LRUShared<'a, Hmap, E, K, V, CidT, Umeta, HB>,
+    _hir: crate::lru::LRUShared<'a, Hmap, E, K, V, CidT, Umeta, HB>,
+    // stack S. The least recent key is always a LIR one
+    _stack: Ghost<K, HB>,
+    // non-resident HIR keys still in the stack, oldest first
+    _non_resident: Ghost<K, HB>,
+    _capacity: usize,
+    _scanstatus: ScanStatus,
+}
+
+impl<
+        'a,
+        Hmap: hashmap::HashMap<E, K, V, CidT, Umeta, HB>,
+        E: user::EntryT<K, V, CidT, Umeta>,
+        K: user::Hash,
+        V: user::Val,
+        CidT: user::Cid,
+        Umeta: user::Meta<V>,
+        HB: ::std::hash::BuildHasher + Default,
+    > LIRSShared<'a, Hmap, E, K, V, CidT, Umeta, HB>
+{
+    /// new LIRS with space for `entries` elements, the cache ids of the LIR
+    /// and the resident HIR entries plus an optional callback for the lazy
+    /// scan
+    pub fn new(
+        entries: usize,
+        lir_cid: CidT,
+        hir_cid: CidT,
+        access_scan: Option<&'a dyn Fn(::std::ptr::NonNull<E>)>,
+    ) -> Self {
+        LIRSShared {
+            _lir: crate::lru::LRUShared::new(entries, lir_cid, access_scan),
+            _hir: crate::lru::LRUShared::new(entries, hir_cid, access_scan),
+            _stack: Ghost::new(),
+            _non_resident: Ghost::new(),
+            _capacity: entries,
+            _scanstatus: ScanStatus::Stopped,
+        }
+    }
+    /// change the scan callback
+    pub fn set_scanf(
+        &mut self,
+        access_scan: Option<&'a dyn Fn(::std::ptr::NonNull<E>)>,
+    ) {
+        self._lir.set_scanf(access_scan);
+        self._hir.set_scanf(access_scan)
+    }
+    /// An element has been added by the caller, fix the LIRS
+    ///
+    /// `maybe_old_entry` must be `!= None` only if the clash happened in
+    /// this cache
+    pub fn insert_shared(
+        &mut self,
+        hmap: &mut Hmap,
+        maybe_old_entry: Option<&mut E>,
+        new_entry_idx: usize,
+    ) -> InsertResultShared {
+        let res = match maybe_old_entry {
+            None => self.miss(hmap, new_entry_idx, true),
+            Some(old_entry)
+                if old_entry.get_key()
+                    == hmap.get_index(new_entry_idx).unwrap().get_key() =>
+            {
+                // same key, just a new value: keep the LIR/HIR status, but
+                // the key is the most recent one now
+                self._stack.push(old_entry.get_key().clone());
+                let res =
+                    if old_entry.get_cache_id() == self._lir.get_cache_id() {
+                        self._lir.insert_shared(
+                            hmap,
+                            Some(old_entry),
+                            new_entry_idx,
+                        )
+                    } else {
+                        self._hir.insert_shared(
+                            hmap,
+                            Some(old_entry),
+                            new_entry_idx,
+                        )
+                    };
+                self.prune(hmap);
+                res
+            }
+            Some(old_entry) => {
+                // a different key took the place of the old entry, which is
+                // not in the cache anymore
+                self.remove_clashed(hmap, old_entry, new_entry_idx);
+                self.miss(hmap, new_entry_idx, true)
+            }
+        };
+        self.update_scan_status();
+        res
+    }
+    /// An entry moved here from another cache on the same hashmap.
+    ///
+    /// Like a new insert, but without running the on-insert callback
+    pub fn move_in_shared(
+        &mut self,
+        hmap: &mut Hmap,
+        idx: usize,
+    ) -> InsertResultShared {
+        let res = self.miss(hmap, idx, false);
+        self.update_scan_status();
+        res
+    }
+    // the key at `idx` was not resident: make room, then add the entry as
+    // LIR if the LIR entries are still too few or the key was a
+    // non-resident HIR one, as a resident HIR entry otherwise
+    fn miss(
+        &mut self,
+        hmap: &mut Hmap,
+        idx: usize,
+        run_callback: bool,
+    ) -> InsertResultShared {
+        let evicted = self.reclaim(hmap);
+        let key = hmap.get_index(idx).unwrap().get_key().clone();
+        let was_non_resident = self._non_resident.remove(&key);
+        let warming_up = self._lir.len() < self.lir_size();
+        self._stack.push(key);
+        let lru = if warming_up || was_non_resident {
+            &mut self._lir
+        } else {
+            &mut self._hir
+        };
+        match run_callback {
+            true => lru.insert_shared(hmap, None, idx),
+            false => lru.move_in_shared(hmap, idx),
+        };
+        if was_non_resident && !warming_up {
+            // its reuse distance is shorter than the one of the oldest LIR
+            self.demote_bottom(hmap);
+        }
+        match evicted {
+            None => Removed::new(),
+            Some(evicted) => Removed::single(evicted, RemovalCause::Capacity),
+        }
+    }
+    // max number of LIR entries
+    fn lir_size(&self) -> usize {
+        self._capacity.saturating_sub(hir_size(self._capacity))
+    }
+    // if the cache is full, evict the oldest resident HIR entry.
+    // If it is still in the stack, its key stays as non-resident.
+    // The entry is still in the hashmap, its index is returned
+    fn reclaim(&mut self, hmap: &mut Hmap) -> Option<usize> {
+        if self.len() < self._capacity {
+            return None;
+        }
+        if self._hir.len() == 0 {
+            self.demote_bottom(hmap);
+        }
+        let tail = self._hir.tail(hmap)?;
+        self._hir.remove_shared(hmap, tail);
+        let key = hmap.get_index(tail).unwrap().get_key();
+        if self._stack.contains(key) {
+            self._non_resident.push(key.clone());
+            // do not let the non-resident keys grow forever
+            while self._non_resident.len() > self._capacity {
+                if let Some(old) = self._non_resident.pop_lru() {
+                    self._stack.remove(&old);
+                }
+            }
+        }
+        self.prune(hmap);
+        Some(tail)
+    }
+    // the oldest LIR entry becomes the newest resident HIR one.
+    // Return false if there was no LIR entry
+    fn demote_bottom(&mut self, hmap: &mut Hmap) -> bool {
+        let key = match self._stack.pop_lru() {
+            None => return false,
+            Some(key) => key,
+        };
+        if let Some((idx, _)) = hmap.get_full(&key) {
+            self._lir.remove_shared(hmap, idx);
+            // Q can hold the whole cache, this never evicts
+            self._hir.move_in_shared(hmap, idx);
+        }
+        self.prune(hmap);
+        true
+    }
+    // remove HIR keys from the bottom of the stack, so that the bottom is
+    // always the least recent LIR entry.
+    // Every key is pushed once per access, so this is amortized O(1)
+    fn prune(&mut self, hmap: &Hmap) {
+        let lir_cid = self._lir.get_cache_id();
+        while let Some(key) = self._stack.peek_lru() {
+            if let Some((_, entry)) = hmap.get_full(key) {
+                if entry.get_cache_id() == lir_cid {
+                    return;
+                }
+            }
+            let key = self._stack.pop_lru().unwrap();
+            self._non_resident.remove(&key);
+        }
+    }
+    /// The index of the entry that will be evicted by the next insert, if
+    /// the LIRS is full
+    pub fn victim(&self, hmap: &Hmap) -> Option<usize> {
+        if self.len() < self._capacity {
+            return None;
+        }
+        self._hir.tail(hmap)
+    }
+    /// Reset the LIRS state, non-resident keys included
+    pub fn clear_shared(&mut self) {
+        self._lir.clear_shared();
+        self._hir.clear_shared();
+        self._stack.clear();
+        self._non_resident.clear();
+        self._scanstatus = ScanStatus::Stopped;
+    }
+    /// do not actually remove the element at `idx`, just fix the LIRS so
+    /// that it is not considered anymore and actual removal is safe
+    pub fn remove_shared(&mut self, hmap: &mut Hmap, idx: usize) {
+        let entry = hmap.get_index(idx).unwrap();
+        self._stack.remove(entry.get_key());
+        if entry.get_cache_id() == self._lir.get_cache_id() {
+            self._lir.remove_shared(hmap, idx)
+        } else {
+            self._hir.remove_shared(hmap, idx)
+        };
+        self.update_scan_status();
+    }
+    /// `old_entry` was in the LIRS, but the entry at `idx` clashed with it
+    /// and took its place in the hashmap. Forget about the old entry
+    pub fn remove_clashed(
+        &mut self,
+        hmap: &mut Hmap,
+        old_entry: &E,
+        idx: usize,
+    ) {
+        self._stack.remove(old_entry.get_key());
+        if old_entry.get_cache_id() == self._lir.get_cache_id() {
+            self._lir.remove_clashed(hmap, old_entry, idx)
+        } else {
+            self._hir.remove_clashed(hmap, old_entry, idx)
+        };
+        self.update_scan_status();
+    }
+    /// The hashmap has been resized and all the entries have moved.
+    ///
+    /// Fix both the LIR and HIR lists. The stack only has keys
+    pub fn relocate(&mut self, reloc: &hashmap::Relocation<E>) {
+        self._lir.relocate(reloc);
+        self._hir.relocate(reloc);
+    }
+    /// return the cache ids for `(LIR, HIR)`
+    pub fn get_cache_ids(&self) -> (CidT, CidT) {
+        (self._lir.get_cache_id(), self._hir.get_cache_id())
+    }
+    /// Should be called only by the parent, run the on-get callback on the
+    /// correct LRU
+    ///
+    /// A hit on a resident HIR entry that is still in the stack makes it
+    /// LIR, and the oldest LIR entry becomes HIR
+    pub fn on_get(&mut self, hmap: &mut Hmap, idx: usize) {
+        let entry = hmap.get_index(idx).unwrap();
+        let cid = entry.get_cache_id();
+        let key = entry.get_key().clone();
+        if cid == self._lir.get_cache_id() {
+            self._stack.push(key);
+            self._lir.on_get(hmap, idx);
+            self.prune(hmap);
+        } else if self._stack.contains(&key) {
+            self._stack.push(key);
+            self._hir.remove_shared(hmap, idx);
+            // the LIR list can hold the whole cache, this never evicts
+            self._lir.move_in_shared(hmap, idx);
+            hmap.get_index_mut(idx).unwrap().user_on_get();
+            self.demote_bottom(hmap);
+        } else {
+            self._stack.push(key);
+            self._hir.on_get(hmap, idx);
+        }
+        self.update_scan_status();
+    }
+    /// start the scan callbacks on the LIRS
+    /// scan will execute only once on the whole LIRS
+    pub fn start_scan(&mut self) {
+        self._lir.start_scan();
+        if self._lir.is_scan_running() {
+            self._scanstatus = ScanStatus::RunningLir;
+            return;
+        }
+        self._hir.start_scan();
+        self._scanstatus = if self._hir.is_scan_running() {
+            ScanStatus::RunningHir
+        } else {
+            ScanStatus::Stopped
+        };
+    }
+    /// return scan status
+    pub fn is_scan_running(&self) -> bool {
+        self._scanstatus != ScanStatus::Stopped
+    }
+    fn update_scan_status(&mut self) {
+        match self._scanstatus {
+            ScanStatus::Stopped => {}
+            ScanStatus::RunningLir => {
+                if !self._lir.is_scan_running() {
+                    self._hir.start_scan();
+                    self._scanstatus = ScanStatus::RunningHir;
+                }
+            }
+            ScanStatus::RunningHir => {
+                if !self._hir.is_scan_running() {
+                    self._scanstatus = ScanStatus::Stopped;
+                }
+            }
+        }
+    }
+    /// Change the number of entries of the LIRS
+    ///
+    /// The entries evicted while shrinking are chosen just like on insert,
+    /// and their indexes are returned: they are still in the hashmap and
+    /// must be removed by the caller
+    pub fn set_capacity(
+        &mut self,
+        hmap: &mut Hmap,
+        entries: usize,
+    ) -> Removed<usize> {
+        self._capacity = entries;
+        let mut evicted = Removed::new();
+        while self.len() > entries {
+            match self.reclaim(hmap) {
+                Some(idx) => evicted.push(idx, RemovalCause::Capacity),
+                None => break,
+            }
+        }
+        while self._lir.len() > self.lir_size() && self.demote_bottom(hmap) {}
+        while self._non_resident.len() > entries {
+            if let Some(old) = self._non_resident.pop_lru() {
+                self._stack.remove(&old);
+            }
+        }
+        self.prune(hmap);
+        // the LRUs are not over the capacity anymore, this does not evict
+        self._lir.set_capacity(hmap, entries);
+        self._hir.set_capacity(hmap, entries);
+        self.update_scan_status();
+        evicted
+    }
+    /// return the current number of `(LIR, resident HIR)` entries
+    pub fn get_lens(&self) -> (usize, usize) {
+        (self._lir.len(), self._hir.len())
+    }
+    /// return max LIRS size
+    pub fn capacity(&self) -> usize {
+        self._capacity
+    }
+    /// return used LIRS size
+    pub fn len(&self) -> usize {
+        self._lir.len() + self._hir.len()
+    }
+    /// check if the LIRS is empty
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<
+        'a,
+        Hmap: hashmap::HashMap<E, K, V, CidT, Umeta, HB>,
+        E: user::EntryT<K, V, CidT, Umeta>,
+        K: user::Hash,
+        V: user::Val,
+        CidT: user::Cid,
+        Umeta: user::Meta<V>,
+        HB: ::std::hash::BuildHasher + Default,
+    > crate::cache::SharedCache<'a, Hmap, E, K, V, CidT, Umeta, HB>
+    for LIRSShared<'a, Hmap, E, K, V, CidT, Umeta, HB>
+{
+    fn insert_shared(
+        &mut self,
+        hmap: &mut Hmap,
+        maybe_old_entry: Option<&mut E>,
+        new_entry_idx: usize,
+    ) -> InsertResultShared {
+        LIRSShared::insert_shared(self, hmap, maybe_old_entry, new_entry_idx)
+    }
+    fn move_in_shared(
+        &mut self,
+        hmap: &mut Hmap,
+        idx: usize,
+    ) -> InsertResultShared {
+        LIRSShared::move_in_shared(self, hmap, idx)
+    }
+    fn remove_shared(&mut self, hmap: &mut Hmap, idx: usize) {
+        LIRSShared::remove_shared(self, hmap, idx)
+    }
+    fn remove_clashed(&mut self, hmap: &mut Hmap, old_entry: &E, idx: usize) {
+        LIRSShared::remove_clashed(self, hmap, old_entry, idx)
+    }
+    fn clear_shared(&mut self) {
+        LIRSShared::clear_shared(self)
+    }
+    fn on_get(&mut self, hmap: &mut Hmap, idx: usize) {
+        LIRSShared::on_get(self, hmap, idx)
+    }
+    fn owns(&self, cid: CidT) -> bool {
+        cid == self._lir.get_cache_id() || cid == self._hir.get_cache_id()
+    }
+    fn victim(&self, hmap: &Hmap) -> Option<usize> {
+        LIRSShared::victim(self, hmap)
+    }
+    fn relocate(&mut self, reloc: &hashmap::Relocation<E>) {
+        LIRSShared::relocate(self, reloc)
+    }
+    fn set_scanf(
+        &mut self,
+        access_scan: Option<&'a dyn Fn(::std::ptr::NonNull<E>)>,
+    ) {
+        LIRSShared::set_scanf(self, access_scan)
+    }
+    fn start_scan(&mut self) {
+        LIRSShared::start_scan(self)
+    }
+    fn is_scan_running(&self) -> bool {
+        LIRSShared::is_scan_running(self)
+    }
+    // the recency is tracked by the stack, there is no hit policy
+    fn set_hit_policy(&mut self, _policy: crate::lru::HitPolicy) {}
+    fn get_hit_policy(&self) -> crate::lru::HitPolicy {
+        crate::lru::HitPolicy::MoveToHead
+    }
+    fn set_capacity(
+        &mut self,
+        hmap: &mut Hmap,
+        entries: usize,
+    ) -> Removed<usize> {
+        LIRSShared::set_capacity(self, hmap, entries)
+    }
+    fn capacity(&self) -> usize {
+        LIRSShared::capacity(self)
+    }
+    fn len(&self) -> usize {
+        LIRSShared::len(self)
+    }
+    // only counts entries
+    fn set_weighted(
+        &mut self,
+        _hmap: &Hmap,
+        weighted: bool,
+    ) -> Result<(), crate::results::Error> {
+        match weighted {
+            false => Ok(()),
+            true => Err(crate::results::Error::WeightUnsupported),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{Hasher, Key, Val};
+
+    type Cache<'a> = LIRS<'a, Key, Val, user::ZeroMeta, Hasher>;
+
+    fn bottom(cache: &mut Cache) -> Option<Key> {
+        cache.shared_mut()._stack.peek_lru().copied()
+    }
+
+    #[test]
+    fn stack_bottom_is_always_lir() {
+        let mut cache = Cache::new(4, 0, Hasher::default());
+        for k in 0..4 {
+            cache.insert(Key(k), Val(k));
+        }
+        // 3 LIR entries, then a resident HIR one
+        assert_eq!(cache.get_lens(), (3, 1));
+        cache.get(&Key(0));
+        cache.get(&Key(1));
+        assert_eq!(bottom(&mut cache), Some(Key(2)));
+        // 3 is HIR: once 2 leaves the bottom it is pruned
+        cache.get(&Key(2));
+        assert_eq!(bottom(&mut cache), Some(Key(0)));
+        assert!(!cache.shared()._stack.contains(&Key(3)));
+        assert_eq!(cache.shared()._stack.len(), 3);
+        // out of the stack: the first hit keeps it HIR
+        cache.get(&Key(3));
+        assert_eq!(cache.get_lens(), (3, 1));
+        // in the stack: the second makes it LIR and demotes the bottom
+        cache.get(&Key(3));
+        assert_eq!(cache.get_lens(), (3, 1));
+        assert_eq!(bottom(&mut cache), Some(Key(1)));
+        assert!(!cache.shared()._stack.contains(&Key(0)));
+        // 0 is the HIR entry now, and not in the stack: not remembered
+        let res = cache.insert(Key(4), Val(4));
+        let evicted: Vec<_> = res.iter().map(|(e, _)| e.0).collect();
+        assert_eq!(evicted, vec![Key(0)]);
+        assert_eq!(cache.shared()._non_resident.len(), 0);
+        assert!(!cache.shared().is_empty());
+    }
+
+    #[test]
+    fn non_resident_hit_becomes_lir() {
+        let mut cache = Cache::new(4, 0, Hasher::default());
+        for k in 0..5 {
+            cache.insert(Key(k), Val(k));
+        }
+        // 3 was HIR and still in the stack: now non-resident
+        assert!(!cache.contains_key(&Key(3)));
+        assert!(cache.shared()._non_resident.contains(&Key(3)));
+        let res = cache.insert(Key(3), Val(3));
+        let evicted: Vec<_> = res.iter().map(|(e, _)| e.0).collect();
+        assert_eq!(evicted, vec![Key(4)]);
+        assert_eq!(cache.get_lens(), (3, 1));
+        // the oldest LIR entry made room for it
+        assert_eq!(bottom(&mut cache), Some(Key(1)));
+        assert!(!cache.shared()._non_resident.contains(&Key(3)));
+    }
+}
@@ -15,16 +15,19 @@
  */
 //! Basic LRU cache
 //!
-//! Extra features: callbacks on get/insert, lazy scan callback
+//! Extra features: callbacks on get/insert, lazy scan callback, configurable
+//! [`HitPolicy`] (move to head, FIFO or CLOCK)
 //!
 //! The lazy scan is a scan that only runs on one more element after each
 //! get/insert  
 //! This means that X elements will be fully scanned only after X get/insert
 
+use crate::expiry::wheel;
 use crate::hashmap;
 use crate::hashmap::user;
 use crate::hashmap::user::EntryT;
-use crate::results::{InsertResult, InsertResultShared};
+use crate::owned::Owned;
+use crate::results::{InsertResultShared, RemovalCause, Removed};
 
 type LRUEntry<K, V, Umeta> =
     user::Entry<K, V, ::std::marker::PhantomData<K>, Umeta>;
@@ -36,24 +39,18 @@ type HmapT<K, V, Umeta, HB> = hashmap::SimpleHmap<
     Umeta,
     HB,
 >;
-/// LRU implementation that wraps LRUShared
-///
-/// note that we store the value as-is and we have pointers to those.
-///
-/// **If you need to grow the LRU dynamically, make sure to use `Box<V>`
-/// as the value** (resize currently not supported)
+/// LRU implementation that wraps LRUShared, see [`Owned`]
 // TODO: generalize: K in the first Hashmap template parameter is not
 // necessarily the same K in the user::Entry<K>
 // (e.g: could be a pointer to user::Entry<K>.key)
-pub struct LRU<'a, K, V, Umeta, HB>
-where
-    K: user::Hash,
-    V: user::Val,
-    Umeta: user::Meta<V>,
-    HB: ::std::hash::BuildHasher + Default,
-{
-    _hmap: HmapT<K, V, Umeta, HB>,
-    _lru: LRUShared<
+pub type LRU<'a, K, V, Umeta, HB> = Owned<
+    'a,
+    K,
+    V,
+    ::std::marker::PhantomData<K>,
+    Umeta,
+    HB,
+    LRUShared<
         'a,
         HmapT<K, V, Umeta, HB>,
         LRUEntry<K, V, Umeta>,
@@ -63,7 +60,8 @@ where
         Umeta,
         HB,
     >,
-}
+>;
+
 impl<
         'a,
         K: user::Hash,
@@ -78,141 +76,46 @@ impl<
         extra_hashmap_capacity: usize,
         hash_builder: HB,
     ) -> LRU<'a, K, V, Umeta, HB> {
-        LRU {
-            _hmap: HmapT::<K, V, Umeta, HB>::with_capacity_and_hasher(
-                1 + entries + extra_hashmap_capacity,
-                hash_builder,
-            ),
-            _lru: LRUShared::<
-                '_,
-                HmapT<K, V, Umeta, HB>,
-                LRUEntry<K, V, Umeta>,
-                K,
-                V,
-                ::std::marker::PhantomData<K>,
-                Umeta,
-                HB,
-            >::new(entries, ::std::marker::PhantomData, None),
-        }
-    }
-    /// insert a new entry in the LRU
-    pub fn insert(&mut self, key: K, val: V) -> InsertResult<(K, V, Umeta)> {
-        self.insert_with_meta(key, val, Umeta::new())
-    }
-    /// The same insert, but with metadata
-    pub fn insert_with_meta(
-        &mut self,
-        key: K,
-        val: V,
-        user_data: Umeta,
-    ) -> InsertResult<(K, V, Umeta)> {
-        let e =
-            user::Entry::<K, V, ::std::marker::PhantomData<K>, Umeta>::new_entry(
-                None,
-                None,
-                key.clone(),
-                val,
-                ::std::marker::PhantomData,
-                user_data,
-            );
-        // insert and get length and a ref to the value just inserted
-        // we will use this ref to fix the linked lists in ll_tail/ll_head
-        // of the various elements
-        let (mut maybe_clash, new_entry_idx, _new_entry) = self._hmap.insert(e);
-        let opt_ref_clash = maybe_clash.as_mut();
-        match self._lru.insert_shared(
-            &mut self._hmap,
-            opt_ref_clash,
-            new_entry_idx,
-        ) {
-            InsertResultShared::OldEntry { evicted } => {
-                let c = match maybe_clash {
-                    None => None,
-                    Some(x) => Some(x.deconstruct()),
-                };
-                let e = match evicted {
-                    None => None,
-                    Some(x) => Some(x.deconstruct()),
-                };
-                InsertResult::OldEntry {
-                    clash: c,
-                    evicted: e,
-                }
-            }
-            InsertResultShared::OldTailPtr { evicted } => {
-                let c = match maybe_clash {
-                    None => None,
-                    Some(x) => Some(x.deconstruct()),
-                };
-                let removed = self._hmap.remove(unsafe { &*evicted.as_ptr() });
-                InsertResult::OldTail {
-                    clash: c,
-                    evicted: removed.deconstruct(),
-                }
-            }
-            InsertResultShared::Success => match maybe_clash {
-                None => InsertResult::Success,
-                Some(clash) => InsertResult::OldEntry {
-                    clash: Some(clash.deconstruct()),
-                    evicted: None,
-                },
-            },
-        }
-    }
-    /// empty the whole LRU
-    pub fn clear(&mut self) {
-        self._hmap.clear();
-        self._lru.clear_shared()
-    }
-    /// remove a single element from the lru
-    pub fn remove(&mut self, key: &K) -> Option<(V, Umeta)> {
-        let (idx, entry) = match self._hmap.get_full(key) {
-            None => return None,
-            Some((idx, entry)) => (idx, entry),
-        };
-        self._lru.remove_shared(entry);
-        let (_, val, meta) = self._hmap.remove_idx(idx).deconstruct();
-        Some((val, meta))
-    }
-    /// chech if a key exists in the LRU
-    pub fn contains_key(&self, key: &K) -> bool {
-        self._hmap.get_full(&key).is_some()
+        Owned::from_shared(
+            LRUShared::new(entries, ::std::marker::PhantomData, None),
+            extra_hashmap_capacity,
+            hash_builder,
+        )
     }
     /// If present, make the entry the head of the LRU, and return references to
     /// the values
     pub fn make_head(&mut self, key: &K) -> Option<(&V, &Umeta)> {
-        match self._hmap.get_full_mut(key) {
-            None => None,
-            Some((_, mut entry)) => {
-                self._lru.make_head(&mut entry);
-                Some((entry.get_val(), entry.get_user()))
-            }
-        }
+        let idx = self.find(key)?;
+        let (hmap, lru) = self.parts_mut();
+        lru.make_head(hmap, idx);
+        let entry = hmap.get_index(idx).unwrap();
+        Some((entry.get_val(), entry.get_user()))
     }
-    /// get references to an entry
-    pub fn get(&mut self, key: &K) -> Option<(&V, &Umeta)> {
-        match self._hmap.get_full_mut(key) {
-            None => None,
-            Some((_, mut entry)) => {
-                self._lru.on_get(&mut entry);
-                Some((entry.get_val(), entry.get_user()))
-            }
-        }
-    }
-    /// get a mutable reference to the entry
-    pub fn get_mut(&mut self, key: &K) -> Option<(&mut V, &mut Umeta)> {
-        match self._hmap.get_full_mut(key) {
-            None => None,
-            //Some(mut entry) => Some((entry.get_val(), entry.get_user())),
-            Some((_, mut entry)) => {
-                self._lru.on_get(&mut entry);
-                Some(entry.get_val_user_mut())
-            }
-        }
+}
+
+/// What the LRU does with an entry on a cache hit
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum HitPolicy {
+    /// move the entry to the head. Classic LRU
+    MoveToHead,
+    /// leave the entry where it is. The LRU becomes a FIFO queue
+    Fifo,
+    /// set the reference bit in the [`user::Cid`]. A referenced entry that
+    /// reaches the tail is not evicted but gets a second chance at the head
+    ///
+    /// If the Cid has no space for the bit, this is the same as `Fifo`
+    Clock,
+}
+impl Default for HitPolicy {
+    fn default() -> Self {
+        HitPolicy::MoveToHead
     }
 }
 
 /// Actual implementation of the LRU on a shared hashmap
+///
+/// The entries are linked via [`user::EntryT::Link`], so this works both with
+/// pointers and indexes
 pub struct LRUShared<'a, Hmap, E, K, V, CidT, Umeta, HB>
 where
     Hmap: hashmap::HashMap<E, K, V, CidT, Umeta, HB>,
@@ -225,16 +128,23 @@ where
 {
     _capacity: usize,
     _used: usize,
+    // total weight of the entries, same as `_used` when not weighted
+    _weight: usize,
+    _weighted: bool,
 
-    _head: Option<::std::ptr::NonNull<E>>,
-    _tail: Option<::std::ptr::NonNull<E>>,
+    _head: Option<E::Link>,
+    _tail: Option<E::Link>,
     _cache_id: CidT,
+    _hit_policy: HitPolicy,
     _hmap: ::std::marker::PhantomData<Hmap>,
     _key: ::std::marker::PhantomData<K>,
     _val: ::std::marker::PhantomData<V>,
     _meta: ::std::marker::PhantomData<Umeta>,
     _hashbuilder: ::std::marker::PhantomData<HB>,
-    _scan: crate::scan::Scan<'a, E, K, V, CidT, Umeta>,
+    _scan: crate::scan::Scan<'a, Hmap, E, K, V, CidT, Umeta, HB>,
+    _idle: Option<wheel::TimerWheel>,
+    // time of the current operation, where the idle timers start
+    _now: u64,
 }
 
 impl<
@@ -261,15 +171,20 @@ impl<
         LRUShared {
             _capacity: entries,
             _used: 0,
+            _weight: 0,
+            _weighted: false,
             _head: None,
             _tail: None,
             _cache_id: cache_id,
+            _hit_policy: HitPolicy::default(),
             _hmap: ::std::marker::PhantomData,
             _key: ::std::marker::PhantomData,
             _val: ::std::marker::PhantomData,
             _meta: ::std::marker::PhantomData,
             _hashbuilder: std::marker::PhantomData,
             _scan: crate::scan::Scan::new(access_scan),
+            _idle: None,
+            _now: 0,
         }
     }
     /// change the scan callback
@@ -279,277 +194,385 @@ impl<
     ) {
         self._scan.set_scanf(access_scan)
     }
-
+    /// change what happens to an entry on a cache hit
+    pub fn set_hit_policy(&mut self, policy: HitPolicy) {
+        self._hit_policy = policy;
+    }
+    /// get the current hit policy
+    pub fn get_hit_policy(&self) -> HitPolicy {
+        self._hit_policy
+    }
+    /// Weigh each entry with [`user::Meta::weight`]. The capacity becomes the
+    /// max total weight instead of the max number of entries, and an insert
+    /// evicts as many entries as needed
+    ///
+    /// An entry heavier than the whole capacity is evicted right away
+    pub fn set_weighted(&mut self, hmap: &Hmap, weighted: bool) {
+        self._weighted = weighted;
+        self._weight = 0;
+        let mut next = self.tail(hmap);
+        while let Some(idx) = next {
+            self._weight += self.entry_weight(hmap.get_index(idx).unwrap());
+            next = self.next_to_head(hmap, idx);
+        }
+    }
+    /// check if the entries are weighted
+    pub fn is_weighted(&self) -> bool {
+        self._weighted
+    }
+    /// the weight of an entry, `1` when not weighted
+    pub fn entry_weight(&self, entry: &E) -> usize {
+        match self._weighted {
+            false => 1,
+            true => entry.get_user().weight(),
+        }
+    }
+    /// Expire the entries that are not accessed for `idle`, or never with
+    /// `None`
+    ///
+    /// Time only moves with [`advance`](Self::advance), starting from `now`:
+    /// the idle time of the inserted or accessed entries starts from the
+    /// time given to [`set_now`](Self::set_now), or from the last `advance`
+    /// if that is later
+    pub fn set_time_to_idle(
+        &mut self,
+        hmap: &Hmap,
+        idle: Option<::std::time::Duration>,
+        now: u64,
+    ) {
+        let mut wheel = match idle {
+            None => {
+                self._idle = None;
+                return;
+            }
+            Some(idle) => wheel::TimerWheel::new(idle, now),
+        };
+        let mut next = self.tail(hmap);
+        while let Some(idx) = next {
+            wheel.touch(idx, now);
+            next = self.next_to_head(hmap, idx);
+        }
+        self._idle = Some(wheel);
+    }
+    /// get the time-to-idle of the entries, if any
+    pub fn get_time_to_idle(&self) -> Option<::std::time::Duration> {
+        self._idle.as_ref().map(|wheel| wheel.idle())
+    }
+    /// Move the time to `now`, and remove the entries that have not been
+    /// accessed for the time-to-idle
+    ///
+    /// Just like with [`InsertResultShared`] the expired entries are still
+    /// in the hashmap and must be removed by the caller
+    pub fn advance(&mut self, hmap: &mut Hmap, now: u64) -> Removed<usize> {
+        let expired = match self._idle.as_mut() {
+            None => return Removed::new(),
+            Some(wheel) => wheel.advance(now),
+        };
+        let mut removed = Removed::new();
+        for idx in expired {
+            self.remove_shared(hmap, idx);
+            removed.push(idx, RemovalCause::Expired);
+        }
+        removed
+    }
+    // (re)start the idle timer of `idx`, and stop the ones of the evicted
+    fn touch_idle(&mut self, idx: usize, evicted: &InsertResultShared) {
+        if let Some(wheel) = self._idle.as_mut() {
+            wheel.touch(idx, self._now);
+            wheel.cancel_removed(evicted);
+        }
+    }
+    // like `touch_idle` for an entry moved in from another cache, which
+    // keeps its timer
+    fn track_idle(&mut self, idx: usize, evicted: &InsertResultShared) {
+        if let Some(wheel) = self._idle.as_mut() {
+            wheel.track(idx, self._now);
+            wheel.cancel_removed(evicted);
+        }
+    }
+    /// the instant after which the entry at `idx` is idle, if there is a
+    /// time-to-idle
+    pub fn idle_deadline(&self, idx: usize) -> Option<u64> {
+        self._idle.as_ref().and_then(|wheel| wheel.deadline(idx))
+    }
+    /// the time of the next inserts and hits, where their idle timers start
+    pub fn set_now(&mut self, now: u64) {
+        self._now = now;
+    }
     /// `insert_shared` does not actually insert anything.
     ///
     /// It will only fix the LRU linked lists after something has been inserted
     /// by the parent.
     ///
     /// Note that `maybe_old_entry` is != `None` if and only if the
-    /// ols entry is part of the same cache
+    /// ols entry is part of the same cache, and that the new entry must have
+    /// taken the place of the old one in the hashmap
     pub fn insert_shared(
         &mut self,
         hmap: &mut Hmap,
         maybe_old_entry: Option<&mut E>,
         new_entry_idx: usize,
-    ) -> InsertResultShared<E> {
+    ) -> InsertResultShared {
         let just_inserted = hmap.get_index_mut(new_entry_idx).unwrap();
-        self._used += 1;
         self._scan.apply_raw(just_inserted.into());
-        *just_inserted.get_cache_id_mut() = self._cache_id;
+        just_inserted.get_cache_id_mut().set_cache(self._cache_id);
 
         match maybe_old_entry {
             None => {
                 just_inserted.user_on_insert(None);
                 // we did not clash with anything, but we might still be over
                 // capacity
-                if self._used >= self._capacity {
-                    // reset head & tail to correct values, returen old tail
-                    unsafe {
-                        self._head
-                            .unwrap()
-                            .as_mut()
-                            .set_head_ptr(Some(just_inserted.into()));
-                    }
-                    self._head = Some(just_inserted.into());
-                    let mut to_remove = self._tail.unwrap();
-                    self._scan.check_and_next(to_remove);
-                    self._scan.apply_next();
-                    unsafe {
-                        let mut to_rm_head =
-                            to_remove.as_mut().get_head_ptr().unwrap();
-                        to_rm_head.as_mut().set_tail_ptr(None);
-                        self._tail = Some(to_rm_head);
-                        return InsertResultShared::OldTailPtr {
-                            evicted: to_remove,
-                        };
-                    }
-                }
-                match self._head {
-                    None => {
-                        // first entry in the LRU, both head and tail
-                        // since it's the first entry, we don't need to start
-                        // scanning anything
-                        self._head = Some(just_inserted.into());
-                        self._tail = Some(just_inserted.into());
-                    }
-                    Some(mut old_head) => {
-                        // just a new entry on a non-filled LRU
-                        unsafe {
-                            old_head
-                                .as_mut()
-                                .set_head_ptr(Some(just_inserted.into()))
-                        };
-                        self._head = Some(just_inserted.into());
-                        self._scan.apply_next();
-                    }
-                }
-                return InsertResultShared::Success;
+                let res = self.push_and_evict(hmap, new_entry_idx);
+                self.touch_idle(new_entry_idx, &res);
+                self._scan.apply_next(hmap);
+                res
             }
             Some(old_entry) => {
                 // the callee has added an element to the hashmap, but it
-                // clashed with something. We'll have to keep track of it and
-                // we should fix it
-                //
-                // By definition, we know that we get the 'old_entry' only if it
-                // is in the same cache_id as us
-                // Also, we don't have to check the LRU size since here the
-                // number of elements remains the same.
-                // TL;DR: we had a clash, there can be no eviction
-
+                // clashed with something in our own cache.
+                // The new entry took the bucket of the old one, so the
+                // neighbours of the old entry already link to it.
+                // Just take over the old links and move it to the head
+                // TL;DR: we had a clash, there can only be an eviction if
+                // the new entry weighs more than the old one
                 just_inserted.user_on_insert(Some(old_entry));
-                // The clash was on something in our own cache.
-                // In this case the head or tail might need to be changed
-
-                self._scan.check_and_next((old_entry).into());
-                self._scan.apply_next();
-                match old_entry.get_head_ptr() {
-                    None => {
-                        // we removed the old head with the hash clash
-                        just_inserted.set_tail_ptr(old_entry.get_tail_ptr());
-                        match old_entry.get_tail_ptr() {
-                            None => {
-                                // None    == old_entry.head
-                                // None    == old_entry.tail
-                                // basically the only element in the LRU
-                                // both head and tail
-                                self._tail = Some(just_inserted.into());
-                            }
-                            Some(mut old_entry_tail) => {
-                                unsafe {
-                                    // None    == old_entry.head
-                                    // Some(_) == old_entry.tail
-                                    // the head of the LRU
-                                    old_entry_tail.as_mut().set_head_ptr(Some(
-                                        just_inserted.into(),
-                                    ));
-                                }
-                            }
-                        }
-                        self._head = Some(just_inserted.into());
-                        return InsertResultShared::OldEntry { evicted: None };
-                    }
-                    Some(mut old_entry_head) => {
-                        match old_entry.get_tail_ptr() {
-                            None => {
-                                // Some(_) == old_entry.head
-                                // None    == old_entry.tail
-                                // we removed the old tail, with a hash clash
-                                // the new tail is the prev of the old tail.
-                                unsafe {
-                                    old_entry_head.as_mut().set_tail_ptr(None);
-                                    self._head.unwrap().as_mut().set_head_ptr(
-                                        Some(just_inserted.into()),
-                                    );
-                                }
-                                self._head = Some(just_inserted.into());
-                                self._tail = Some(old_entry_head);
-                                return InsertResultShared::OldEntry {
-                                    evicted: None,
-                                };
-                            }
-                            Some(mut old_entry_tail) => {
-                                // Some(_) == old_entry.head
-                                // Some(_) == old_entry.tail
-                                // we removed something in the middle
-                                unsafe {
-                                    old_entry_tail
-                                        .as_mut()
-                                        .set_head_ptr(old_entry.get_head_ptr());
-                                    old_entry
-                                        .get_head_ptr()
-                                        .unwrap()
-                                        .as_mut()
-                                        .set_tail_ptr(old_entry.get_tail_ptr());
-                                    self._head.unwrap().as_mut().set_head_ptr(
-                                        Some(just_inserted.into()),
-                                    );
-                                }
-                                self._head = Some(just_inserted.into());
-                                return InsertResultShared::OldEntry {
-                                    evicted: None,
-                                };
-                            }
-                        }
-                    }
+                just_inserted.set_head(old_entry.get_head());
+                just_inserted.set_tail(old_entry.get_tail());
+                let old_weight = self.entry_weight(old_entry);
+                let new_weight =
+                    self.entry_weight(hmap.get_index(new_entry_idx).unwrap());
+                self._weight = self._weight - old_weight + new_weight;
+                self.make_head(hmap, new_entry_idx);
+                let res = if self._weighted && new_weight > self._capacity {
+                    self.remove_shared(hmap, new_entry_idx);
+                    Removed::single(new_entry_idx, RemovalCause::Capacity)
+                } else {
+                    self.evict_overweight(hmap)
+                };
+                self.touch_idle(new_entry_idx, &res);
+                self._scan.apply_next(hmap);
+                res
+            }
+        }
+    }
+    /// An entry moved here from another cache on the same hashmap
+    ///
+    /// Like [`insert_shared`](Self::insert_shared) without clashes, but the
+    /// on-insert callback is not run, since this is not a new insert
+    pub fn move_in_shared(
+        &mut self,
+        hmap: &mut Hmap,
+        idx: usize,
+    ) -> InsertResultShared {
+        hmap.get_index_mut(idx)
+            .unwrap()
+            .get_cache_id_mut()
+            .set_cache(self._cache_id);
+        let res = self.push_and_evict(hmap, idx);
+        self.track_idle(idx, &res);
+        self._scan.apply_next(hmap);
+        res
+    }
+    // Make room before pushing the new entry, so that CLOCK never picks it
+    fn push_and_evict(
+        &mut self,
+        hmap: &mut Hmap,
+        idx: usize,
+    ) -> InsertResultShared {
+        let weight = self.entry_weight(hmap.get_index(idx).unwrap());
+        if self._weighted && weight > self._capacity {
+            // it would flush the whole LRU and still not fit
+            return Removed::single(idx, RemovalCause::Capacity);
+        }
+        let mut evicted = Removed::new();
+        while self._weight + weight > self._capacity {
+            match self.evict_tail(hmap) {
+                None => break,
+                Some(to_remove) => {
+                    evicted.push(to_remove, RemovalCause::Capacity)
                 }
             }
         }
+        self._used += 1;
+        self._weight += weight;
+        self.push_head(hmap, idx);
+        evicted
+    }
+    // evict the tail until the total weight fits the capacity
+    fn evict_overweight(&mut self, hmap: &mut Hmap) -> InsertResultShared {
+        let mut evicted = Removed::new();
+        while self._weight > self._capacity {
+            match self.evict_tail(hmap) {
+                None => break,
+                Some(to_remove) => {
+                    evicted.push(to_remove, RemovalCause::Capacity)
+                }
+            }
+        }
+        evicted
     }
     /// reset the LRU
     pub fn clear_shared(&mut self) {
         self._head = None;
         self._tail = None;
+        self._used = 0;
+        self._weight = 0;
         self._scan.stop();
+        if let Some(wheel) = self._idle.as_mut() {
+            wheel.clear();
+        }
     }
-    /// remove the pointers to this element in the LRU.
+    /// remove the links to the element at `idx` in the LRU.
     ///
     /// Does not actually remove the element, that is done by the caller
-    pub fn remove_shared(&mut self, entry: &E) {
-        self._scan.check_and_next(entry.into());
-        if None == entry.get_head_ptr() {
+    pub fn remove_shared(&mut self, hmap: &mut Hmap, idx: usize) {
+        let weight = self.entry_weight(hmap.get_index(idx).unwrap());
+        self.forget(hmap, idx, weight);
+    }
+    // unlink the entry at `idx`, which weighed `weight`
+    fn forget(&mut self, hmap: &mut Hmap, idx: usize, weight: usize) {
+        let link = hmap.link_from_index(idx);
+        self._scan.check_and_next(hmap, link);
+        self.unlink(hmap, idx);
+        self._used -= 1;
+        self._weight -= weight;
+        if let Some(wheel) = self._idle.as_mut() {
+            wheel.cancel(idx);
+        }
+    }
+    /// `old_entry` was in the LRU, but the entry at `idx` clashed with it and
+    /// took its place in the hashmap. Forget about the old entry.
+    ///
+    /// The links of the entry at `idx` are overwritten, the caller will have
+    /// to insert it somewhere
+    pub fn remove_clashed(
+        &mut self,
+        hmap: &mut Hmap,
+        old_entry: &E,
+        idx: usize,
+    ) {
+        let entry = hmap.get_index_mut(idx).unwrap();
+        entry.set_head(old_entry.get_head());
+        entry.set_tail(old_entry.get_tail());
+        let weight = self.entry_weight(old_entry);
+        self.forget(hmap, idx, weight);
+    }
+    // detach the entry from its neighbours, fixing head and tail
+    fn unlink(&mut self, hmap: &mut Hmap, idx: usize) {
+        let entry = hmap.get_index(idx).unwrap();
+        let (head, tail) = (entry.get_head(), entry.get_tail());
+        match head {
             // we removed the head
-            match entry.get_tail_ptr() {
-                None => {
-                    // None == entry.head
-                    // None == entry.tail
-                    // we had only one element, we removed it
-                    self._head = None;
-                    self._tail = None;
-                }
-                Some(mut entry_tail) => {
-                    // None == entry.head
-                    // Some(_) == entry.tail
-                    // we removed the head
-                    unsafe {
-                        entry_tail.as_mut().set_head_ptr(None);
-                    }
-                    self._head = Some(entry_tail);
-                }
-            }
-        } else {
-            match entry.get_tail_ptr() {
-                None => {
-                    // Some(_) == entry.head
-                    // None == entry.tail
-                    // we removed the tail
-                    unsafe {
-                        entry
-                            .get_head_ptr()
-                            .unwrap()
-                            .as_mut()
-                            .set_tail_ptr(None);
-                    }
-                    self._tail = entry.get_head_ptr();
-                }
-                Some(mut entry_tail) => {
-                    // Some(_) == entry.head
-                    // Some(_) == entry.tail
-                    // we removed an intermediate entry
-                    unsafe {
-                        entry_tail.as_mut().set_head_ptr(entry.get_head_ptr());
-                        entry
-                            .get_head_ptr()
-                            .unwrap()
-                            .as_mut()
-                            .set_tail_ptr(entry.get_tail_ptr());
-                    }
-                }
-            }
+            None => self._head = tail,
+            Some(entry_head) => hmap.get_link_mut(entry_head).set_tail(tail),
+        }
+        match tail {
+            // we removed the tail
+            None => self._tail = head,
+            Some(entry_tail) => hmap.get_link_mut(entry_tail).set_head(head),
         }
     }
-    /// make the key the head of the LRU.
-    pub fn make_head(&mut self, entry: &mut E) {
-        self._scan.check_and_next(entry.into());
-        match entry.get_head_ptr() {
-            None => {
-                // already the head, nothing to do
-            }
-            Some(mut entry_head) => {
-                unsafe {
-                    entry_head.as_mut().set_tail_ptr(entry.get_tail_ptr());
-                }
-                match entry.get_tail_ptr() {
-                    None => {
-                        // we moved the tail to the head.
-                        unsafe {
-                            self._head
-                                .unwrap()
-                                .as_mut()
-                                .set_head_ptr(Some(entry.into()));
-                            entry.set_tail_ptr(self._head);
-                        }
-                        self._head = Some(entry.into());
-                        self._tail = Some(entry_head);
-                    }
-                    Some(mut entry_tail) => {
-                        // we promoted to head something in the middle
-                        // of the linked list
-                        unsafe {
-                            entry_tail.as_mut().set_head_ptr(Some(entry_head));
-                            entry_head.as_mut().set_tail_ptr(Some(entry_tail));
-                            self._head
-                                .unwrap()
-                                .as_mut()
-                                .set_head_ptr(Some(entry.into()));
-                        }
-                        self._head = Some(entry.into());
-                    }
+    // put a detached entry in front of the head
+    fn push_head(&mut self, hmap: &mut Hmap, idx: usize) {
+        let link = hmap.link_from_index(idx);
+        let entry = hmap.get_index_mut(idx).unwrap();
+        entry.set_head(None);
+        entry.set_tail(self._head);
+        match self._head {
+            // first entry in the LRU, both head and tail
+            None => self._tail = Some(link),
+            Some(old_head) => hmap.get_link_mut(old_head).set_head(Some(link)),
+        }
+        self._head = Some(link);
+    }
+    // detach the tail and return its index.
+    // The entry is still in the hashmap, the caller must deal with it
+    fn pop_tail(&mut self, hmap: &mut Hmap) -> Option<usize> {
+        let to_remove = self._tail?;
+        let idx = hmap.index_from_link(to_remove);
+        let weight = self.entry_weight(hmap.get_index(idx).unwrap());
+        self.forget(hmap, idx, weight);
+        hmap.get_index_mut(idx).unwrap().set_head(None);
+        Some(idx)
+    }
+    // choose the tail to evict and detach it.
+    // With CLOCK the referenced entries lose their bit and are moved to the
+    // head instead, and after a full round no entry is referenced anymore
+    fn evict_tail(&mut self, hmap: &mut Hmap) -> Option<usize> {
+        if self._hit_policy == HitPolicy::Clock {
+            for _ in 0..self._used {
+                let tail = hmap.index_from_link(self._tail?);
+                let cid = hmap.get_index_mut(tail).unwrap().get_cache_id_mut();
+                if !cid.get_reference() {
+                    break;
                 }
+                cid.set_reference(false);
+                self.make_head(hmap, tail);
             }
         }
+        self.pop_tail(hmap)
+    }
+    /// make the entry at `idx` the head of the LRU.
+    pub fn make_head(&mut self, hmap: &mut Hmap, idx: usize) {
+        let link = hmap.link_from_index(idx);
+        self._scan.check_and_next(hmap, link);
+        if hmap.get_index(idx).unwrap().get_head().is_none() {
+            // already the head, nothing to do
+            return;
+        }
+        self.unlink(hmap, idx);
+        self.push_head(hmap, idx);
+    }
+    /// Change the number of entries of the LRU, or its max weight when
+    /// weighted
+    ///
+    /// When shrinking, the entries in the tail are evicted and their indexes
+    /// returned.  
+    /// Just like with [`InsertResultShared`] they are still in the
+    /// hashmap and must be removed by the caller
+    pub fn set_capacity(
+        &mut self,
+        hmap: &mut Hmap,
+        entries: usize,
+    ) -> Removed<usize> {
+        self._capacity = entries;
+        self.evict_overweight(hmap)
+    }
+    /// The hashmap has been resized and all the entries have moved.
+    ///
+    /// Fix our head/tail and the lazy scan
+    pub fn relocate(&mut self, reloc: &hashmap::Relocation<E>) {
+        self._head = self._head.and_then(|h| reloc.link(h));
+        self._tail = self._tail.and_then(|t| reloc.link(t));
+        self._scan.relocate(reloc);
+        if let Some(wheel) = self._idle.as_mut() {
+            wheel.relocate(reloc);
+        }
     }
     /// get the LRU cache id
     pub fn get_cache_id(&self) -> CidT {
         self._cache_id
     }
-    /// Used when composing caches, run the callback on the entry
+    /// Used when composing caches, apply the [`HitPolicy`] and run the
+    /// callback on the entry at `idx`
     ///
     /// This method should be passed down between parent/child cache
     /// and only the final cache which owns the element should execute it
-    pub fn on_get(&mut self, entry: &mut E) {
-        entry.user_on_get();
-        self._scan.apply_next();
+    pub fn on_get(&mut self, hmap: &mut Hmap, idx: usize) {
+        match self._hit_policy {
+            HitPolicy::MoveToHead => self.make_head(hmap, idx),
+            HitPolicy::Fifo => {}
+            HitPolicy::Clock => hmap
+                .get_index_mut(idx)
+                .unwrap()
+                .get_cache_id_mut()
+                .set_reference(true),
+        }
+        hmap.get_index_mut(idx).unwrap().user_on_get();
+        if let Some(wheel) = self._idle.as_mut() {
+            wheel.touch(idx, self._now);
+        }
+        self._scan.apply_next(hmap);
     }
     /// start the lazy scan  
     /// The scan will execute on the whole LRU but only once
@@ -562,6 +585,18 @@ impl<
             true => {}
         }
     }
+    /// index of the tail, the next entry to be evicted
+    pub fn tail(&self, hmap: &Hmap) -> Option<usize> {
+        self._tail.map(|t| hmap.index_from_link(t))
+    }
+    /// index of the entry after `idx`, going from the tail to the head.
+    /// `None` if `idx` is the head
+    pub fn next_to_head(&self, hmap: &Hmap, idx: usize) -> Option<usize> {
+        hmap.get_index(idx)
+            .unwrap()
+            .get_head()
+            .map(|h| hmap.index_from_link(h))
+    }
     /// check if the scan is still running
     pub fn is_scan_running(&self) -> bool {
         self._scan.is_running()
@@ -574,4 +609,365 @@ impl<
     pub fn len(&self) -> usize {
         self._used
     }
+    /// get the total weight of the entries, the same as `len()` when not
+    /// weighted
+    pub fn weight(&self) -> usize {
+        self._weight
+    }
+}
+
+impl<
+        'a,
+        Hmap: hashmap::HashMap<E, K, V, CidT, Umeta, HB>,
+        E: user::EntryT<K, V, CidT, Umeta>,
+        K: user::Hash,
+        V: user::Val,
+        CidT: user::Cid,
+        Umeta: user::Meta<V>,
+        HB: ::std::hash::BuildHasher + Default,
+    > crate::cache::SharedCache<'a, Hmap, E, K, V, CidT, Umeta, HB>
+    for LRUShared<'a, Hmap, E, K, V, CidT, Umeta, HB>
+{
+    fn insert_shared(
+        &mut self,
+        hmap: &mut Hmap,
+        maybe_old_entry: Option<&mut E>,
+        new_entry_idx: usize,
+    ) -> InsertResultShared {
+        LRUShared::insert_shared(self, hmap, maybe_old_entry, new_entry_idx)
+    }
+    fn move_in_shared(
+        &mut self,
+        hmap: &mut Hmap,
+        idx: usize,
+    ) -> InsertResultShared {
+        LRUShared::move_in_shared(self, hmap, idx)
+    }
+    fn remove_shared(&mut self, hmap: &mut Hmap, idx: usize) {
+        LRUShared::remove_shared(self, hmap, idx)
+    }
+    fn remove_clashed(&mut self, hmap: &mut Hmap, old_entry: &E, idx: usize) {
+        LRUShared::remove_clashed(self, hmap, old_entry, idx)
+    }
+    fn clear_shared(&mut self) {
+        LRUShared::clear_shared(self)
+    }
+    fn on_get(&mut self, hmap: &mut Hmap, idx: usize) {
+        LRUShared::on_get(self, hmap, idx)
+    }
+    fn owns(&self, cid: CidT) -> bool {
+        cid == self._cache_id
+    }
+    fn victim(&self, hmap: &Hmap) -> Option<usize> {
+        if self._weight < self._capacity {
+            return None;
+        }
+        self.tail(hmap)
+    }
+    fn relocate(&mut self, reloc: &hashmap::Relocation<E>) {
+        LRUShared::relocate(self, reloc)
+    }
+    fn set_scanf(
+        &mut self,
+        access_scan: Option<&'a dyn Fn(::std::ptr::NonNull<E>)>,
+    ) {
+        LRUShared::set_scanf(self, access_scan)
+    }
+    fn start_scan(&mut self) {
+        LRUShared::start_scan(self)
+    }
+    fn is_scan_running(&self) -> bool {
+        LRUShared::is_scan_running(self)
+    }
+    fn set_hit_policy(&mut self, policy: HitPolicy) {
+        LRUShared::set_hit_policy(self, policy)
+    }
+    fn get_hit_policy(&self) -> HitPolicy {
+        LRUShared::get_hit_policy(self)
+    }
+    fn set_capacity(
+        &mut self,
+        hmap: &mut Hmap,
+        entries: usize,
+    ) -> Removed<usize> {
+        LRUShared::set_capacity(self, hmap, entries)
+    }
+    fn capacity(&self) -> usize {
+        LRUShared::capacity(self)
+    }
+    fn len(&self) -> usize {
+        LRUShared::len(self)
+    }
+    fn set_weighted(
+        &mut self,
+        hmap: &Hmap,
+        weighted: bool,
+    ) -> Result<(), crate::results::Error> {
+        LRUShared::set_weighted(self, hmap, weighted);
+        Ok(())
+    }
+    fn weight(&self) -> usize {
+        LRUShared::weight(self)
+    }
+    fn is_weighted(&self) -> bool {
+        LRUShared::is_weighted(self)
+    }
+    fn idle_deadline(&self, idx: usize) -> Option<u64> {
+        LRUShared::idle_deadline(self, idx)
+    }
+    fn set_now(&mut self, now: u64) {
+        LRUShared::set_now(self, now)
+    }
+}
+
+impl<
+        'a,
+        Hmap: hashmap::HashMap<E, K, V, CidT, Umeta, HB>,
+        E: user::EntryT<K, V, CidT, Umeta>,
+        K: user::Hash,
+        V: user::Val,
+        CidT: user::Cid,
+        Umeta: user::Meta<V>,
+        HB: ::std::hash::BuildHasher + Default,
+    > crate::cache::IdleCache<'a, Hmap, E, K, V, CidT, Umeta, HB>
+    for LRUShared<'a, Hmap, E, K, V, CidT, Umeta, HB>
+{
+    fn set_time_to_idle(
+        &mut self,
+        hmap: &Hmap,
+        idle: Option<::std::time::Duration>,
+        now: u64,
+    ) {
+        LRUShared::set_time_to_idle(self, hmap, idle, now)
+    }
+    fn get_time_to_idle(&self) -> Option<::std::time::Duration> {
+        LRUShared::get_time_to_idle(self)
+    }
+    fn advance(&mut self, hmap: &mut Hmap, now: u64) -> Removed<usize> {
+        LRUShared::advance(self, hmap, now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::results::Error;
+    use crate::testing::{Hasher, Key, Val};
+
+    type Cache<'a> = LRU<'a, Key, Val, user::WeightMeta, Hasher>;
+    type Plain<'a> = LRU<'a, Key, Val, user::ZeroMeta, Hasher>;
+
+    fn keys<M>(res: &Removed<(Key, Val, M)>) -> Vec<u32> {
+        res.iter().map(|(e, _)| e.0 .0).collect()
+    }
+
+    #[test]
+    fn weight_is_kept_from_insert() {
+        let mut cache = Cache::new(10, 0, Hasher::default());
+        cache.set_weighted(true).unwrap();
+        cache.insert(Key(0), Val(4));
+        cache.insert(Key(1), Val(4));
+        assert_eq!(cache.weight(), 8);
+        // a heavier value does not change the weight counted on insert
+        *cache.get_mut(&Key(0)).unwrap().0 = Val(9);
+        assert_eq!(cache.weight(), 8);
+        cache.remove(&Key(0));
+        assert_eq!(cache.weight(), 4);
+        // the update is weighed again
+        cache.insert(Key(1), Val(7));
+        assert_eq!(cache.weight(), 7);
+    }
+
+    #[test]
+    fn heavy_insert_evicts_many() {
+        let mut cache = Cache::new(10, 0, Hasher::default());
+        cache.set_weighted(true).unwrap();
+        for k in 0..3 {
+            assert!(cache.insert(Key(k), Val(3)).is_empty());
+        }
+        let res = cache.insert(Key(3), Val(8));
+        assert_eq!(keys(&res), vec![0, 1, 2]);
+        assert_eq!((cache.len(), cache.weight()), (1, 8));
+        // heavier than the whole cache: evicted right away
+        let res = cache.insert(Key(4), Val(11));
+        assert_eq!(keys(&res), vec![4]);
+        assert_eq!((cache.len(), cache.weight()), (1, 8));
+    }
+
+    #[test]
+    fn enabling_weights_evicts_the_overweight() {
+        let mut cache = Cache::new(10, 0, Hasher::default());
+        for k in 0..4 {
+            cache.insert(Key(k), Val(4));
+        }
+        assert_eq!(cache.weight(), 4);
+        let res = cache.set_weighted(true).unwrap();
+        assert_eq!(keys(&res), vec![0, 1]);
+        assert_eq!(cache.weight(), 8);
+    }
+
+    #[test]
+    fn unweighted_policies_reject_weights() {
+        let mut cache =
+            crate::lfu::LFU::<Key, Val, user::WeightMeta, Hasher>::new(
+                10,
+                0,
+                Hasher::default(),
+            );
+        assert!(cache.set_weighted(false).is_ok());
+        assert!(matches!(
+            cache.set_weighted(true),
+            Err(Error::WeightUnsupported)
+        ));
+    }
+
+    #[test]
+    fn resize_keeps_the_list_order() {
+        let mut cache = Plain::new(4, 0, Hasher::default());
+        for k in 0..4 {
+            cache.insert(Key(k), Val(k));
+        }
+        cache.get(&Key(0));
+        let buckets = cache.hmap().buckets();
+        assert!(cache.set_capacity(64).is_empty());
+        assert!(cache.hmap().buckets() > buckets);
+        for k in 4..8 {
+            assert!(cache.insert(Key(k), Val(k)).is_empty());
+        }
+        let buckets = cache.hmap().buckets();
+        let res = cache.set_capacity(2);
+        assert!(cache.hmap().buckets() < buckets);
+        assert_eq!(keys(&res), vec![1, 2, 3, 0, 4, 5]);
+        // head and tail point to the moved entries
+        assert_eq!(keys(&cache.insert(Key(8), Val(8))), vec![6]);
+        cache.get(&Key(7));
+        assert_eq!(keys(&cache.insert(Key(9), Val(9))), vec![8]);
+    }
+
+    #[test]
+    fn scan_continues_after_resize() {
+        let seen = ::std::cell::RefCell::new(Vec::new());
+        let scanf =
+            |e: ::std::ptr::NonNull<LRUEntry<Key, Val, user::ZeroMeta>>| {
+                seen.borrow_mut().push(unsafe { e.as_ref() }.get_key().0)
+            };
+        let mut cache = Plain::new(8, 0, Hasher::default());
+        for k in 0..8 {
+            cache.insert(Key(k), Val(k));
+        }
+        cache.shared_mut().set_scanf(Some(&scanf));
+        cache.shared_mut().start_scan();
+        // each hit scans one more entry. The head is moved by the first
+        // hit, so it is skipped
+        cache.get(&Key(7));
+        cache.set_capacity(64);
+        for _ in 0..3 {
+            cache.get(&Key(7));
+        }
+        cache.set_capacity(8);
+        for _ in 0..3 {
+            cache.get(&Key(7));
+        }
+        assert_eq!(*seen.borrow(), vec![6, 5, 4, 3, 2, 1, 0]);
+        assert!(!cache.shared().is_scan_running());
+    }
+
+    #[test]
+    fn idle_timers_follow_the_resize() {
+        let time = crate::expiry::ManualTime::new();
+        let mut cache = Plain::new(4, 0, Hasher::default());
+        cache.set_time_source(Some(&time));
+        cache.set_time_to_idle(Some(::std::time::Duration::from_secs(10)));
+        for k in 0..4 {
+            cache.insert(Key(k), Val(k));
+        }
+        time.advance(::std::time::Duration::from_secs(5));
+        assert!(cache.advance().is_empty());
+        cache.get(&Key(0));
+        cache.set_capacity(64);
+        time.advance(::std::time::Duration::from_secs(7));
+        let mut expired = keys(&cache.advance());
+        expired.sort_unstable();
+        assert_eq!(expired, vec![1, 2, 3]);
+        cache.set_capacity(2);
+        time.advance(::std::time::Duration::from_secs(8));
+        assert_eq!(keys(&cache.advance()), vec![0]);
+        assert!(cache.is_empty());
+    }
+
+    // an LRU with space for the reference bit in the cache id
+    type ClockLru<'a> = Owned<
+        'a,
+        Key,
+        Val,
+        crate::clock::ClockCid,
+        user::ZeroMeta,
+        Hasher,
+        LRUShared<
+            'a,
+            crate::owned::OwnedHmap<
+                Key,
+                Val,
+                crate::clock::ClockCid,
+                user::ZeroMeta,
+                Hasher,
+            >,
+            crate::owned::OwnedEntry<
+                Key,
+                Val,
+                crate::clock::ClockCid,
+                user::ZeroMeta,
+            >,
+            Key,
+            Val,
+            crate::clock::ClockCid,
+            user::ZeroMeta,
+            Hasher,
+        >,
+    >;
+
+    // insert 0..3 in a cache of 3, hit 0, then insert 3
+    fn evicted_after_a_hit<C>(cache: &mut C) -> Vec<u32>
+    where
+        C: crate::cache::Cache<Key, Val, user::ZeroMeta>,
+    {
+        for k in 0..3 {
+            cache.insert(Key(k), Val(k));
+        }
+        cache.get(&Key(0));
+        keys(&cache.insert(Key(3), Val(3)))
+    }
+
+    #[test]
+    fn hits_move_to_head_by_default() {
+        let mut cache = Plain::new(3, 0, Hasher::default());
+        assert_eq!(cache.shared().get_hit_policy(), HitPolicy::MoveToHead);
+        assert_eq!(evicted_after_a_hit(&mut cache), vec![1]);
+        assert_eq!(keys(&cache.insert(Key(4), Val(4))), vec![2]);
+    }
+
+    #[test]
+    fn fifo_hits_keep_the_order() {
+        let mut cache = Plain::new(3, 0, Hasher::default());
+        cache.shared_mut().set_hit_policy(HitPolicy::Fifo);
+        assert_eq!(evicted_after_a_hit(&mut cache), vec![0]);
+        // without the reference bit, CLOCK is a FIFO too
+        let mut cache = Plain::new(3, 0, Hasher::default());
+        cache.shared_mut().set_hit_policy(HitPolicy::Clock);
+        assert_eq!(evicted_after_a_hit(&mut cache), vec![0]);
+    }
+
+    #[test]
+    fn clock_hits_get_a_second_chance() {
+        let mut cache = ClockLru::from_shared(
+            LRUShared::new(3, crate::clock::ClockCid::new(1), None),
+            0,
+            Hasher::default(),
+        );
+        cache.shared_mut().set_hit_policy(HitPolicy::Clock);
+        assert_eq!(evicted_after_a_hit(&mut cache), vec![1]);
+        // 0 went back to the head without its bit, and is evicted in turn
+        assert_eq!(keys(&cache.insert(Key(4), Val(4))), vec![2]);
+        assert_eq!(keys(&cache.insert(Key(5), Val(5))), vec![0]);
+    }
 }
@@ -0,0 +1,287 @@
+/*
+ * Copyright 2021 Luca Fulchir <luker@fenrirproject.org>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+//! Entry API of the [`Owned`] caches, like the one of
+//! [`std::collections::HashMap`]
+//!
+//! The key is looked up only once, then the entry can be read, updated,
+//! removed or inserted
+
+use super::{Owned, OwnedEntry, OwnedHmap};
+use crate::cache::SharedCache;
+use crate::hashmap::user;
+use crate::hashmap::user::EntryT;
+use crate::results::{EntryResult, Removed};
+
+/// A key of the cache, that can be there or not. See [`Owned::entry`]
+pub enum Entry<'c, 'a, K, V, CidT, Umeta, HB, C>
+where
+    K: user::Hash,
+    V: user::Val,
+    CidT: user::Cid,
+    Umeta: user::Meta<V>,
+    HB: ::std::hash::BuildHasher + Default,
+    C: SharedCache<
+        'a,
+        OwnedHmap<K, V, CidT, Umeta, HB>,
+        OwnedEntry<K, V, CidT, Umeta>,
+        K,
+        V,
+        CidT,
+        Umeta,
+        HB,
+    >,
+{
+    Occupied(OccupiedEntry<'c, 'a, K, V, CidT, Umeta, HB, C>),
+    Vacant(VacantEntry<'c, 'a, K, V, CidT, Umeta, HB, C>),
+}
+
+/// The key is in the cache
+pub struct OccupiedEntry<'c, 'a, K, V, CidT, Umeta, HB, C>
+where
+    K: user::Hash,
+    V: user::Val,
+    CidT: user::Cid,
+    Umeta: user::Meta<V>,
+    HB: ::std::hash::BuildHasher + Default,
+    C: SharedCache<
+        'a,
+        OwnedHmap<K, V, CidT, Umeta, HB>,
+        OwnedEntry<K, V, CidT, Umeta>,
+        K,
+        V,
+        CidT,
+        Umeta,
+        HB,
+    >,
+{
+    _cache: &'c mut Owned<'a, K, V, CidT, Umeta, HB, C>,
+    _idx: usize,
+}
+
+/// The key is not in the cache
+pub struct VacantEntry<'c, 'a, K, V, CidT, Umeta, HB, C>
+where
+    K: user::Hash,
+    V: user::Val,
+    CidT: user::Cid,
+    Umeta: user::Meta<V>,
+    HB: ::std::hash::BuildHasher + Default,
+    C: SharedCache<
+        'a,
+        OwnedHmap<K, V, CidT, Umeta, HB>,
+        OwnedEntry<K, V, CidT, Umeta>,
+        K,
+        V,
+        CidT,
+        Umeta,
+        HB,
+    >,
+{
+    _cache: &'c mut Owned<'a, K, V, CidT, Umeta, HB, C>,
+    _key: K,
+    _hash: u64,
+    // the expired entry with the same key, removed and returned on insert
+    _expired: Option<usize>,
+}
+
+impl<
+        'c,
+        'a,
+        K: user::Hash,
+        V: user::Val,
+        CidT: user::Cid,
+        Umeta: user::Meta<V>,
+        HB: ::std::hash::BuildHasher + Default,
+        C: SharedCache<
+            'a,
+            OwnedHmap<K, V, CidT, Umeta, HB>,
+            OwnedEntry<K, V, CidT, Umeta>,
+            K,
+            V,
+            CidT,
+            Umeta,
+            HB,
+        >,
+    > Entry<'c, 'a, K, V, CidT, Umeta, HB, C>
+{
+    pub fn key(&self) -> &K {
+        match self {
+            Entry::Occupied(entry) => entry.key(),
+            Entry::Vacant(entry) => entry.key(),
+        }
+    }
+    /// insert `default` if the key is not there, and get references to the
+    /// entry
+    pub fn or_insert(self, default: V) -> EntryResult<'c, K, V, Umeta> {
+        self.or_insert_with(|| default)
+    }
+    /// insert the value returned by `f` if the key is not there, and get
+    /// references to the entry
+    pub fn or_insert_with<F>(self, f: F) -> EntryResult<'c, K, V, Umeta>
+    where
+        F: FnOnce() -> V,
+    {
+        match self {
+            Entry::Occupied(entry) => (Some(entry.into_mut()), Removed::new()),
+            Entry::Vacant(entry) => entry.insert(f()),
+        }
+    }
+    /// update the value if the key is there
+    pub fn and_modify<F>(self, f: F) -> Self
+    where
+        F: FnOnce(&mut V),
+    {
+        match self {
+            Entry::Occupied(mut entry) => {
+                f(entry.get_mut().0);
+                Entry::Occupied(entry)
+            }
+            Entry::Vacant(entry) => Entry::Vacant(entry),
+        }
+    }
+}
+
+impl<
+        'c,
+        'a,
+        K: user::Hash,
+        V: user::Val,
+        CidT: user::Cid,
+        Umeta: user::Meta<V>,
+        HB: ::std::hash::BuildHasher + Default,
+        C: SharedCache<
+            'a,
+            OwnedHmap<K, V, CidT, Umeta, HB>,
+            OwnedEntry<K, V, CidT, Umeta>,
+            K,
+            V,
+            CidT,
+            Umeta,
+            HB,
+        >,
+    > OccupiedEntry<'c, 'a, K, V, CidT, Umeta, HB, C>
+{
+    pub(super) fn new(
+        cache: &'c mut Owned<'a, K, V, CidT, Umeta, HB, C>,
+        idx: usize,
+    ) -> Self {
+        OccupiedEntry {
+            _cache: cache,
+            _idx: idx,
+        }
+    }
+    pub fn key(&self) -> &K {
+        self._cache._hmap.get_index(self._idx).unwrap().get_key()
+    }
+    pub fn get(&self) -> (&V, &Umeta) {
+        let entry = self._cache._hmap.get_index(self._idx).unwrap();
+        (entry.get_val(), entry.get_user())
+    }
+    pub fn get_mut(&mut self) -> (&mut V, &mut Umeta) {
+        self._cache
+            ._hmap
+            .get_index_mut(self._idx)
+            .unwrap()
+            .get_val_user_mut()
+    }
+    /// like `get_mut`, but the references live as long as the entry borrow
+    pub fn into_mut(self) -> (&'c mut V, &'c mut Umeta) {
+        self._cache
+            ._hmap
+            .get_index_mut(self._idx)
+            .unwrap()
+            .get_val_user_mut()
+    }
+    /// replace the value, and return the old one
+    pub fn insert(&mut self, val: V) -> V {
+        ::std::mem::replace(self.get_mut().0, val)
+    }
+    /// remove the entry from the cache
+    pub fn remove(self) -> (V, Umeta) {
+        let cache = self._cache;
+        cache._cache.remove_shared(&mut cache._hmap, self._idx);
+        let (_, val, meta) = cache._hmap.remove_idx(self._idx).deconstruct();
+        (val, meta)
+    }
+}
+
+impl<
+        'c,
+        'a,
+        K: user::Hash,
+        V: user::Val,
+        CidT: user::Cid,
+        Umeta: user::Meta<V>,
+        HB: ::std::hash::BuildHasher + Default,
+        C: SharedCache<
+            'a,
+            OwnedHmap<K, V, CidT, Umeta, HB>,
+            OwnedEntry<K, V, CidT, Umeta>,
+            K,
+            V,
+            CidT,
+            Umeta,
+            HB,
+        >,
+    > VacantEntry<'c, 'a, K, V, CidT, Umeta, HB, C>
+{
+    pub(super) fn new(
+        cache: &'c mut Owned<'a, K, V, CidT, Umeta, HB, C>,
+        key: K,
+        hash: u64,
+        expired: Option<usize>,
+    ) -> Self {
+        VacantEntry {
+            _cache: cache,
+            _key: key,
+            _hash: hash,
+            _expired: expired,
+        }
+    }
+    pub fn key(&self) -> &K {
+        &self._key
+    }
+    pub fn into_key(self) -> K {
+        self._key
+    }
+    /// insert the value, see [`insert_with_meta`](Self::insert_with_meta)
+    pub fn insert(self, val: V) -> EntryResult<'c, K, V, Umeta> {
+        self.insert_with_meta(val, Umeta::new())
+    }
+    /// insert the value with its metadata, without looking for the key again
+    ///
+    /// The references are `None` only if the new entry could not stay in the
+    /// cache, and is then in the returned entries
+    pub fn insert_with_meta(
+        self,
+        val: V,
+        user_data: Umeta,
+    ) -> EntryResult<'c, K, V, Umeta> {
+        let cache = self._cache;
+        let (idx, res) = cache.insert_hashed(
+            self._hash,
+            self._key,
+            val,
+            user_data,
+            self._expired,
+        );
+        let entry = match idx {
+            Some(idx) => cache._hmap.get_index_mut(idx),
+            None => None,
+        };
+        (entry.map(|e| e.get_val_user_mut()), res)
+    }
+}
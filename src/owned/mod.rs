@@ -0,0 +1,951 @@
+/*
+ * Copyright 2021 Luca Fulchir <luker@fenrirproject.org>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+//! Caches that own their hashmap
+//!
+//! [`Owned`] wraps any [`SharedCache`] together with the hashmap, the lazy
+//! reclaim of the expired entries and the entry API.  
+//! The caches of each module, like [`LRU`](crate::lru::LRU), are only
+//! [`Owned`] with a specific shared cache
+
+pub mod entry;
+
+use crate::cache::{IdleCache, SharedCache};
+use crate::hashmap;
+use crate::hashmap::user;
+use crate::hashmap::user::EntryT;
+use crate::results::{Error, InsertResult, LoadResult, RemovalCause, Removed};
+
+/// The entries of the owning caches
+pub type OwnedEntry<K, V, CidT, Umeta> = user::Entry<K, V, CidT, Umeta>;
+/// The hashmap of the owning caches
+pub type OwnedHmap<K, V, CidT, Umeta, HB> =
+    hashmap::SimpleHmap<OwnedEntry<K, V, CidT, Umeta>, K, V, CidT, Umeta, HB>;
+
+/// A cache that owns its hashmap, with the eviction policy of the shared
+/// cache `C`
+///
+/// note that we store the value as-is and we have pointers to those.
+///
+/// The hashmap is rehashed from time to time to reclaim the space left by
+/// removed entries, and this moves all the values. If `V` is big, consider
+/// using `Box<V>` as the value
+pub struct Owned<'a, K, V, CidT, Umeta, HB, C>
+where
+    K: user::Hash,
+    V: user::Val,
+    CidT: user::Cid,
+    Umeta: user::Meta<V>,
+    HB: ::std::hash::BuildHasher + Default,
+    C: SharedCache<
+        'a,
+        OwnedHmap<K, V, CidT, Umeta, HB>,
+        OwnedEntry<K, V, CidT, Umeta>,
+        K,
+        V,
+        CidT,
+        Umeta,
+        HB,
+    >,
+{
+    _hmap: OwnedHmap<K, V, CidT, Umeta, HB>,
+    _extra_hashmap_capacity: usize,
+    _expiry: crate::scan::ExpiryScan<'a>,
+    // the cache has a time-to-idle, and needs the time of each operation
+    _idle: bool,
+    // reclaimed by the gets, returned by the next insert, remove or advance
+    _expired: Removed<(K, V, Umeta)>,
+    _cache: C,
+}
+
+impl<
+        'a,
+        K: user::Hash,
+        V: user::Val,
+        CidT: user::Cid,
+        Umeta: user::Meta<V>,
+        HB: ::std::hash::BuildHasher + Default,
+        C: SharedCache<
+            'a,
+            OwnedHmap<K, V, CidT, Umeta, HB>,
+            OwnedEntry<K, V, CidT, Umeta>,
+            K,
+            V,
+            CidT,
+            Umeta,
+            HB,
+        >,
+    > Owned<'a, K, V, CidT, Umeta, HB, C>
+{
+    /// Wrap an empty shared cache
+    ///
+    /// The hashmap has space for the capacity of the cache plus
+    /// `extra_hashmap_capacity` entries. The extra space delays the rehash
+    /// needed to reclaim the space of the removed entries.  
+    /// With weights the capacity is not a number of entries, and the
+    /// hashmap grows with the entries instead
+    pub fn from_shared(
+        cache: C,
+        extra_hashmap_capacity: usize,
+        hash_builder: HB,
+    ) -> Self {
+        Owned {
+            _hmap: OwnedHmap::<K, V, CidT, Umeta, HB>::with_capacity_and_hasher(
+                1 + Self::hashmap_entries(&cache, 0) + extra_hashmap_capacity,
+                hash_builder,
+            ),
+            _extra_hashmap_capacity: extra_hashmap_capacity,
+            _expiry: crate::scan::ExpiryScan::new(),
+            _idle: false,
+            _expired: Removed::new(),
+            _cache: cache,
+        }
+    }
+    /// insert a new entry. Can return a clash or the evicted entries
+    pub fn insert(&mut self, key: K, val: V) -> InsertResult<(K, V, Umeta)> {
+        self.insert_with_meta(key, val, Umeta::new())
+    }
+    /// The same insert, but with metadata
+    pub fn insert_with_meta(
+        &mut self,
+        key: K,
+        val: V,
+        user_data: Umeta,
+    ) -> InsertResult<(K, V, Umeta)> {
+        self.set_now();
+        self._expiry.watch(user_data.get_expiry());
+        self.reclaim();
+        let e = OwnedEntry::<K, V, CidT, Umeta>::new_entry(
+            None,
+            None,
+            key,
+            val,
+            CidT::default(),
+            user_data,
+        );
+        // insert and get the index of the value just inserted
+        // we will use it to fix the linked lists in ll_tail/ll_head
+        // of the various elements
+        let (mut maybe_clash, new_entry_idx) = match self._hmap.insert(e) {
+            Ok((clash, idx, _)) => (clash, idx),
+            Err(e) => {
+                self.resize_hashmap();
+                match self._hmap.insert(e) {
+                    Ok((clash, idx, _)) => (clash, idx),
+                    Err(_) => unreachable!("the hashmap has just grown"),
+                }
+            }
+        };
+        let opt_ref_clash = maybe_clash.as_mut();
+        let mut res = self
+            ._cache
+            .insert_shared(&mut self._hmap, opt_ref_clash, new_entry_idx)
+            .take_from(&mut self._hmap, maybe_clash);
+        res.append(self.take_expired());
+        res
+    }
+    /// insert an entry that expires after `ttl`
+    ///
+    /// `Umeta` needs space for the expiry, like [`user::TtlMeta`], or the
+    /// entry will never expire.  
+    /// Expired entries are misses for `get`, and are reclaimed lazily, see
+    /// [`expiry`](crate::expiry)
+    pub fn insert_with_ttl(
+        &mut self,
+        key: K,
+        val: V,
+        ttl: ::std::time::Duration,
+    ) -> InsertResult<(K, V, Umeta)> {
+        let mut user_data = Umeta::new();
+        user_data.set_expiry(Some(self._expiry.deadline(ttl)));
+        self.insert_with_meta(key, val, user_data)
+    }
+    /// Read the time from `time` instead of the monotonic clock, see
+    /// [`expiry`](crate::expiry)
+    ///
+    /// Should be set before inserting entries with a TTL
+    pub fn set_time_source(
+        &mut self,
+        time: Option<&'a dyn crate::expiry::TimeSource>,
+    ) {
+        self._expiry.set_time_source(time)
+    }
+    // resize the hashmap to follow the cache capacity.
+    // Removals leave tombstones in the hashmap, which slowly eat up all the
+    // free space: when there is no more space, this rehashes everything,
+    // and there is always room for one more entry
+    fn resize_hashmap(&mut self) {
+        let capacity = ::std::cmp::max(
+            1 + Self::hashmap_entries(&self._cache, self._hmap.len())
+                + self._extra_hashmap_capacity,
+            1 + self._hmap.len(),
+        );
+        if let Ok(reloc) = self._hmap.resize(capacity) {
+            self._cache.relocate(&reloc);
+            self._expiry.relocate();
+        }
+    }
+    // number of entries the hashmap is sized for.
+    // A weighted capacity says nothing about the number of entries, which
+    // might be far less (or more, with weights of zero): then the hashmap
+    // doubles the current entries, so the rehash is amortized like a Vec
+    fn hashmap_entries(cache: &C, len: usize) -> usize {
+        match cache.is_weighted() {
+            false => cache.capacity(),
+            true => 2 * len,
+        }
+    }
+    /// Change the number of entries in the cache
+    ///
+    /// If the cache shrinks, the evicted entries are returned
+    pub fn set_capacity(&mut self, entries: usize) -> Removed<(K, V, Umeta)> {
+        self.resize_with(|hmap, cache| cache.set_capacity(hmap, entries))
+    }
+    /// Weigh each entry with [`user::Meta::weight`], so that the capacity is
+    /// the max total weight instead of the max number of entries
+    ///
+    /// The entries over the capacity are evicted and returned.  
+    /// Fails if the cache can only count entries
+    pub fn set_weighted(
+        &mut self,
+        weighted: bool,
+    ) -> Result<Removed<(K, V, Umeta)>, Error> {
+        self._cache.set_weighted(&self._hmap, weighted)?;
+        let entries = self.capacity();
+        Ok(self.set_capacity(entries))
+    }
+    /// get the total weight of the entries, the same as `len()` when not
+    /// weighted
+    pub fn weight(&self) -> usize {
+        self._cache.weight()
+    }
+    // change the capacity with `f`, then follow it with the hashmap
+    pub(crate) fn resize_with<F>(&mut self, f: F) -> Removed<(K, V, Umeta)>
+    where
+        F: FnOnce(
+            &mut OwnedHmap<K, V, CidT, Umeta, HB>,
+            &mut C,
+        ) -> Removed<usize>,
+    {
+        let evicted = f(&mut self._hmap, &mut self._cache)
+            .take_from(&mut self._hmap, None);
+        self.resize_hashmap();
+        evicted
+    }
+    /// get the max number of entries in the cache
+    pub fn capacity(&self) -> usize {
+        self._cache.capacity()
+    }
+    /// get the current number of entries in the cache
+    pub fn len(&self) -> usize {
+        self._cache.len()
+    }
+    /// check if the cache is empty
+    pub fn is_empty(&self) -> bool {
+        self._cache.is_empty()
+    }
+    /// empty the whole cache
+    pub fn clear(&mut self) {
+        self._expired = Removed::new();
+        self._hmap.clear();
+        self._cache.clear_shared()
+    }
+    /// remove a single entry
+    ///
+    /// The entry is returned with [`RemovalCause::Explicit`], or with
+    /// [`RemovalCause::Expired`] if it had already expired, together with
+    /// the expired entries reclaimed since the last insert or remove
+    pub fn remove(&mut self, key: &K) -> Removed<(K, V, Umeta)> {
+        self.reclaim();
+        let idx = match self._hmap.get_full(key) {
+            None => return self.take_expired(),
+            Some((idx, _)) => idx,
+        };
+        let cause = match self.is_expired(idx) {
+            true => RemovalCause::Expired,
+            false => RemovalCause::Explicit,
+        };
+        self._cache.remove_shared(&mut self._hmap, idx);
+        let mut res =
+            Removed::single(self._hmap.remove_idx(idx).deconstruct(), cause);
+        res.append(self.take_expired());
+        res
+    }
+    /// check if a key is in the cache
+    pub fn contains_key(&self, key: &K) -> bool {
+        match self._hmap.get_full(key) {
+            None => false,
+            Some((idx, _)) => !self.is_expired(idx),
+        }
+    }
+    /// get references to an entry
+    ///
+    /// This is a hit for the eviction policy
+    pub fn get(&mut self, key: &K) -> Option<(&V, &Umeta)> {
+        self.reclaim();
+        let idx = self.find(key)?;
+        self.on_get(idx);
+        let entry = self._hmap.get_index(idx).unwrap();
+        Some((entry.get_val(), entry.get_user()))
+    }
+    /// get mutable references to an entry
+    pub fn get_mut(&mut self, key: &K) -> Option<(&mut V, &mut Umeta)> {
+        self.reclaim();
+        let idx = self.find(key)?;
+        self.on_get(idx);
+        Some(self._hmap.get_index_mut(idx).unwrap().get_val_user_mut())
+    }
+    // get the index of `key` if it is still alive.
+    // Nothing is removed here: an expired key is left for the reclaim or
+    // for the insert that replaces it, which return it
+    pub(crate) fn find(&self, key: &K) -> Option<usize> {
+        let (idx, _) = self._hmap.get_full(key)?;
+        match self.is_expired(idx) {
+            true => None,
+            false => Some(idx),
+        }
+    }
+    // check one more bucket for an expired entry. It is kept until the
+    // next insert, remove or advance can return it
+    fn reclaim(&mut self) {
+        let expired = self._expiry.reclaim(&mut self._hmap, &mut self._cache);
+        self._expired.append(expired);
+    }
+    fn take_expired(&mut self) -> Removed<(K, V, Umeta)> {
+        ::std::mem::replace(&mut self._expired, Removed::new())
+    }
+    // a hit for the eviction policy
+    fn on_get(&mut self, idx: usize) {
+        self.set_now();
+        self._cache.on_get(&mut self._hmap, idx);
+    }
+    // give the time of this operation to the idle timers, if any
+    fn set_now(&mut self) {
+        if self._idle {
+            let now = self._expiry.now();
+            self._cache.set_now(now);
+        }
+    }
+    // check both the TTL and the time-to-idle of the entry at `idx`
+    fn is_expired(&self, idx: usize) -> bool {
+        let entry = self._hmap.get_index(idx).unwrap();
+        self._expiry.is_expired(entry.get_user().get_expiry())
+            || self._expiry.is_expired(self._cache.idle_deadline(idx))
+    }
+    /// get the entry, or insert the value returned by `f` if it is missing
+    ///
+    /// The key is looked up only once. The references are `None` only if
+    /// the new entry could not stay in the cache (e.g. it weighs more than
+    /// the whole capacity), and is then in the returned entries
+    pub fn get_or_insert_with<F>(
+        &mut self,
+        key: K,
+        f: F,
+    ) -> LoadResult<'_, K, V, Umeta>
+    where
+        F: FnOnce() -> V,
+    {
+        match self.try_get_or_insert_with(key, || {
+            Ok::<V, ::std::convert::Infallible>(f())
+        }) {
+            Ok(res) => res,
+            Err(never) => match never {},
+        }
+    }
+    /// like [`get_or_insert_with`](Self::get_or_insert_with), but `f` can
+    /// fail, and then nothing is inserted
+    pub fn try_get_or_insert_with<F, E>(
+        &mut self,
+        key: K,
+        f: F,
+    ) -> Result<LoadResult<'_, K, V, Umeta>, E>
+    where
+        F: FnOnce() -> Result<V, E>,
+    {
+        self.reclaim();
+        let (hash, found, expired) = self.lookup(&key);
+        if let Some(idx) = found {
+            self.on_get(idx);
+            let entry = self._hmap.get_index(idx).unwrap();
+            return Ok((
+                Some((entry.get_val(), entry.get_user())),
+                Removed::new(),
+            ));
+        }
+        let (idx, res) =
+            self.insert_hashed(hash, key, f()?, Umeta::new(), expired);
+        let hmap = &self._hmap;
+        let entry = idx.and_then(|idx| hmap.get_index(idx));
+        Ok((entry.map(|e| (e.get_val(), e.get_user())), res))
+    }
+    /// Get the entry of `key`, to read, update, remove or insert it with a
+    /// single lookup
+    ///
+    /// Finding the key counts as a hit, like [`get`](Self::get)
+    pub fn entry(
+        &mut self,
+        key: K,
+    ) -> entry::Entry<'_, 'a, K, V, CidT, Umeta, HB, C> {
+        self.reclaim();
+        let (hash, found, expired) = self.lookup(&key);
+        match found {
+            Some(idx) => {
+                self.on_get(idx);
+                entry::Entry::Occupied(entry::OccupiedEntry::new(self, idx))
+            }
+            None => entry::Entry::Vacant(entry::VacantEntry::new(
+                self, key, hash, expired,
+            )),
+        }
+    }
+    // find `key`, as `(hash, alive, expired)`. The hash can be reused to
+    // insert the key.
+    // An expired entry is not removed here, only by the insert that replaces
+    // it, so that it is always returned
+    fn lookup(&self, key: &K) -> (u64, Option<usize>, Option<usize>) {
+        let hash = self._hmap.hash_key(key);
+        match self._hmap.get_full_hashed(hash, key) {
+            None => (hash, None, None),
+            Some((idx, _)) => match self.is_expired(idx) {
+                true => (hash, None, Some(idx)),
+                false => (hash, Some(idx), None),
+            },
+        }
+    }
+    // insert a key that is not alive in the hashmap, with the hash and the
+    // expired entry from `lookup`. The caller reclaims before the `lookup`,
+    // since that might remove the expired entry.
+    // Returns the index of the new entry, if it stayed in the cache
+    fn insert_hashed(
+        &mut self,
+        hash: u64,
+        key: K,
+        val: V,
+        user_data: Umeta,
+        expired_idx: Option<usize>,
+    ) -> (Option<usize>, InsertResult<(K, V, Umeta)>) {
+        self.set_now();
+        let mut expired = match expired_idx {
+            None => Removed::new(),
+            Some(idx) => {
+                self._cache.remove_shared(&mut self._hmap, idx);
+                let old = self._hmap.remove_idx(idx).deconstruct();
+                Removed::single(old, RemovalCause::Expired)
+            }
+        };
+        expired.append(self.take_expired());
+        self._expiry.watch(user_data.get_expiry());
+        let e = OwnedEntry::<K, V, CidT, Umeta>::new_entry(
+            None,
+            None,
+            key,
+            val,
+            CidT::default(),
+            user_data,
+        );
+        let new_entry_idx = match self._hmap.insert_new_hashed(hash, e) {
+            Ok((idx, _)) => idx,
+            Err(e) => {
+                self.resize_hashmap();
+                match self._hmap.insert_new_hashed(hash, e) {
+                    Ok((idx, _)) => idx,
+                    Err(_) => unreachable!("the hashmap has just grown"),
+                }
+            }
+        };
+        let evicted =
+            self._cache
+                .insert_shared(&mut self._hmap, None, new_entry_idx);
+        let resident = evicted.iter().all(|(idx, _)| *idx != new_entry_idx);
+        let mut res = evicted.take_from(&mut self._hmap, None);
+        res.append(expired);
+        if resident {
+            (Some(new_entry_idx), res)
+        } else {
+            (None, res)
+        }
+    }
+    // the index of `key`, without counting an access
+    pub(crate) fn index_of(&self, key: &K) -> Option<usize> {
+        self._hmap.get_full(key).map(|(idx, _)| idx)
+    }
+    pub(crate) fn hmap(&self) -> &OwnedHmap<K, V, CidT, Umeta, HB> {
+        &self._hmap
+    }
+    pub(crate) fn shared(&self) -> &C {
+        &self._cache
+    }
+    pub(crate) fn shared_mut(&mut self) -> &mut C {
+        &mut self._cache
+    }
+    pub(crate) fn parts_mut(
+        &mut self,
+    ) -> (&mut OwnedHmap<K, V, CidT, Umeta, HB>, &mut C) {
+        (&mut self._hmap, &mut self._cache)
+    }
+}
+
+impl<
+        'a,
+        K: user::Hash,
+        V: user::Val,
+        CidT: user::Cid,
+        Umeta: user::Meta<V>,
+        HB: ::std::hash::BuildHasher + Default,
+        C: IdleCache<
+            'a,
+            OwnedHmap<K, V, CidT, Umeta, HB>,
+            OwnedEntry<K, V, CidT, Umeta>,
+            K,
+            V,
+            CidT,
+            Umeta,
+            HB,
+        >,
+    > Owned<'a, K, V, CidT, Umeta, HB, C>
+{
+    /// Expire the entries that are not accessed for `idle`. `None` disables
+    /// it. The time comes from the same source as the TTLs
+    ///
+    /// Idle entries are misses for `get` as soon as their time is up, and
+    /// are removed by [`advance`](Self::advance)
+    pub fn set_time_to_idle(&mut self, idle: Option<::std::time::Duration>) {
+        let now = self._expiry.now();
+        self._idle = idle.is_some();
+        self._cache.set_time_to_idle(&self._hmap, idle, now);
+    }
+    /// get the current time-to-idle
+    pub fn get_time_to_idle(&self) -> Option<::std::time::Duration> {
+        self._cache.get_time_to_idle()
+    }
+    /// Remove and return the entries that have not been accessed for the
+    /// time-to-idle, and the expired entries reclaimed by the gets
+    pub fn advance(&mut self) -> Removed<(K, V, Umeta)> {
+        let now = self._expiry.now();
+        let mut res = self
+            ._cache
+            .advance(&mut self._hmap, now)
+            .take_from(&mut self._hmap, None);
+        res.append(self.take_expired());
+        res
+    }
+}
+
+impl<
+        'a,
+        K: user::Hash,
+        V: user::Val,
+        CidT: user::Cid,
+        Umeta: user::Meta<V>,
+        HB: ::std::hash::BuildHasher + Default,
+        C: SharedCache<
+            'a,
+            OwnedHmap<K, V, CidT, Umeta, HB>,
+            OwnedEntry<K, V, CidT, Umeta>,
+            K,
+            V,
+            CidT,
+            Umeta,
+            HB,
+        >,
+    > crate::cache::Cache<K, V, Umeta> for Owned<'a, K, V, CidT, Umeta, HB, C>
+{
+    fn insert_with_meta(
+        &mut self,
+        key: K,
+        val: V,
+        user_data: Umeta,
+    ) -> InsertResult<(K, V, Umeta)> {
+        Owned::insert_with_meta(self, key, val, user_data)
+    }
+    fn get(&mut self, key: &K) -> Option<(&V, &Umeta)> {
+        Owned::get(self, key)
+    }
+    fn get_mut(&mut self, key: &K) -> Option<(&mut V, &mut Umeta)> {
+        Owned::get_mut(self, key)
+    }
+    fn get_or_insert_with<F>(
+        &mut self,
+        key: K,
+        f: F,
+    ) -> LoadResult<'_, K, V, Umeta>
+    where
+        F: FnOnce() -> V,
+    {
+        Owned::get_or_insert_with(self, key, f)
+    }
+    fn try_get_or_insert_with<F, E>(
+        &mut self,
+        key: K,
+        f: F,
+    ) -> Result<LoadResult<'_, K, V, Umeta>, E>
+    where
+        F: FnOnce() -> Result<V, E>,
+    {
+        Owned::try_get_or_insert_with(self, key, f)
+    }
+    fn remove(&mut self, key: &K) -> Removed<(K, V, Umeta)> {
+        Owned::remove(self, key)
+    }
+    fn contains_key(&self, key: &K) -> bool {
+        Owned::contains_key(self, key)
+    }
+    fn clear(&mut self) {
+        Owned::clear(self)
+    }
+    fn len(&self) -> usize {
+        Owned::len(self)
+    }
+    fn capacity(&self) -> usize {
+        Owned::capacity(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::entry::Entry;
+    use crate::expiry::ManualTime;
+    use crate::hashmap::user;
+    use crate::lru::LRU;
+    use crate::results::{RemovalCause, Removed};
+    use crate::testing::{Hasher, Key, Val};
+
+    type TtlCache<'a> = LRU<'a, Key, Val, user::TtlMeta, Hasher>;
+
+    #[test]
+    fn full_hashmap_is_rehashed_on_insert() {
+        let mut lru = LRU::<Key, Val, user::ZeroMeta, Hasher>::new(
+            4,
+            0,
+            Hasher::default(),
+        );
+        // removals leave tombstones until there is no space left
+        for k in 0..1000 {
+            assert!(lru.insert(Key(k), Val(k)).is_empty());
+            let removed: Vec<_> = lru
+                .remove(&Key(k))
+                .iter()
+                .map(|(e, c)| (e.1 .0, *c))
+                .collect();
+            assert_eq!(removed, vec![(k, RemovalCause::Explicit)]);
+        }
+        for k in 0..4 {
+            assert!(lru.insert(Key(k), Val(k)).is_empty());
+        }
+        let res = lru.insert(Key(4), Val(4));
+        let evicted: Vec<_> =
+            res.into_entries().into_iter().map(|(k, _, _)| k).collect();
+        assert_eq!(evicted, vec![Key(0)]);
+        for k in 1..5 {
+            assert_eq!(lru.get(&Key(k)).unwrap().0, &Val(k));
+        }
+    }
+
+    #[test]
+    fn weighted_hashmap_follows_the_entries() {
+        let mut lru = LRU::<Key, Val, user::WeightMeta, Hasher>::new(
+            10,
+            0,
+            Hasher::default(),
+        );
+        lru.set_weighted(true).unwrap();
+        // a big weight budget must not allocate a bucket per unit of weight
+        assert!(lru.set_capacity(256 * 1024 * 1024).is_empty());
+        assert!(lru.hmap().buckets() < 64);
+        for k in 0..1000 {
+            assert!(lru.insert(Key(k), Val(1 + k)).is_empty());
+        }
+        assert_eq!(lru.len(), 1000);
+        assert!(lru.hmap().buckets() < 4096);
+        for k in 0..1000 {
+            assert_eq!(lru.get(&Key(k)).unwrap().0, &Val(1 + k));
+        }
+    }
+
+    #[test]
+    fn expired_entries_are_returned_on_insert() {
+        let time = ManualTime::new();
+        let mut cache = TtlCache::new(10, 0, Hasher::default());
+        cache.set_time_source(Some(&time));
+        cache.insert_with_ttl(
+            Key(0),
+            Val(0),
+            ::std::time::Duration::from_secs(1),
+        );
+        time.advance(::std::time::Duration::from_secs(2));
+        // a miss. The gets might reclaim the entry, but it is not dropped
+        assert!(cache.get(&Key(0)).is_none());
+        assert!(cache.get_mut(&Key(0)).is_none());
+        assert!(cache.make_head(&Key(0)).is_none());
+        assert!(!cache.contains_key(&Key(0)));
+        let (val, res) = cache.get_or_insert_with(Key(0), || Val(1));
+        assert_eq!(val.unwrap().0, &Val(1));
+        let removed: Vec<_> = res.iter().map(|(e, c)| (e.0, *c)).collect();
+        assert_eq!(removed, vec![(Key(0), RemovalCause::Expired)]);
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn reclaim_steps_one_bucket_per_insert() {
+        let time = ManualTime::new();
+        let mut cache = TtlCache::new(100, 0, Hasher::default());
+        cache.set_time_source(Some(&time));
+        for k in 0..10 {
+            cache.insert_with_ttl(
+                Key(k),
+                Val(k),
+                ::std::time::Duration::from_secs(1),
+            );
+        }
+        time.advance(::std::time::Duration::from_secs(2));
+        let mut reclaimed = Vec::new();
+        for k in 0..cache.hmap().buckets() {
+            // the same key each time, so the hashmap does not grow
+            let res = cache.insert(Key(1000), Val(k as u32));
+            let expired: Vec<_> = res
+                .iter()
+                .filter(|(_, c)| *c == RemovalCause::Expired)
+                .map(|(e, _)| e.0)
+                .collect();
+            assert!(expired.len() <= 1);
+            reclaimed.extend(expired);
+        }
+        reclaimed.sort_by_key(|k| k.0);
+        assert_eq!(reclaimed, (0..10).map(Key).collect::<Vec<_>>());
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn gets_reclaim_and_remove_returns() {
+        let time = ManualTime::new();
+        let mut cache = TtlCache::new(100, 0, Hasher::default());
+        cache.set_time_source(Some(&time));
+        for k in 0..10 {
+            // no `insert_with_ttl`: the expiry comes with the metadata
+            let mut meta = <user::TtlMeta as user::Meta<Val>>::new();
+            user::Meta::<Val>::set_expiry(&mut meta, Some(1_000_000_000));
+            assert!(cache.insert_with_meta(Key(k), Val(k), meta).is_empty());
+        }
+        time.advance(::std::time::Duration::from_secs(2));
+        for _ in 0..cache.hmap().buckets() {
+            assert!(cache.get(&Key(1000)).is_none());
+        }
+        // the gets freed the space, but they can not return the entries
+        assert!(cache.hmap().is_empty());
+        let mut reclaimed: Vec<_> = cache
+            .remove(&Key(1000))
+            .iter()
+            .map(|(e, c)| (e.0, *c))
+            .collect();
+        reclaimed.sort_by_key(|(k, _)| k.0);
+        let expected: Vec<_> =
+            (0..10).map(|k| (Key(k), RemovalCause::Expired)).collect();
+        assert_eq!(reclaimed, expected);
+        assert!(cache.remove(&Key(1000)).is_empty());
+    }
+
+    #[test]
+    fn remove_reports_the_cause() {
+        let time = ManualTime::new();
+        let mut cache = TtlCache::new(10, 0, Hasher::default());
+        cache.set_time_source(Some(&time));
+        cache.insert(Key(0), Val(0));
+        cache.insert_with_ttl(
+            Key(1),
+            Val(1),
+            ::std::time::Duration::from_secs(1),
+        );
+        time.advance(::std::time::Duration::from_secs(2));
+        let causes = |res: Removed<(Key, Val, user::TtlMeta)>| -> Vec<_> {
+            res.iter().map(|(e, c)| (e.0, *c)).collect()
+        };
+        // already reclaimed or not, 1 is returned as expired
+        assert_eq!(
+            causes(cache.remove(&Key(1))),
+            vec![(Key(1), RemovalCause::Expired)]
+        );
+        assert_eq!(
+            causes(cache.remove(&Key(0))),
+            vec![(Key(0), RemovalCause::Explicit)]
+        );
+        assert!(cache.remove(&Key(2)).is_empty());
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn idle_entries_are_misses_before_advance() {
+        let time = ManualTime::new();
+        let mut cache = TtlCache::new(10, 0, Hasher::default());
+        cache.set_time_source(Some(&time));
+        cache.set_time_to_idle(Some(::std::time::Duration::from_secs(10)));
+        cache.insert(Key(0), Val(0));
+        cache.insert(Key(1), Val(1));
+        time.advance(::std::time::Duration::from_secs(20));
+        assert!(cache.get(&Key(0)).is_none());
+        assert!(!cache.contains_key(&Key(1)));
+        let causes = |res: Removed<(Key, Val, user::TtlMeta)>| -> Vec<_> {
+            res.iter().map(|(e, c)| (e.0, *c)).collect()
+        };
+        assert_eq!(
+            causes(cache.remove(&Key(1))),
+            vec![(Key(1), RemovalCause::Expired)]
+        );
+        assert_eq!(
+            causes(cache.advance()),
+            vec![(Key(0), RemovalCause::Expired)]
+        );
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn idle_timers_start_at_the_operation() {
+        let time = ManualTime::new();
+        let mut cache = TtlCache::new(10, 0, Hasher::default());
+        cache.set_time_source(Some(&time));
+        cache.set_time_to_idle(Some(::std::time::Duration::from_secs(10)));
+        // long after the last advance
+        time.advance(::std::time::Duration::from_secs(100));
+        cache.insert(Key(0), Val(0));
+        cache.insert(Key(1), Val(1));
+        assert!(cache.get(&Key(0)).is_some());
+        assert!(cache.advance().is_empty());
+        time.advance(::std::time::Duration::from_secs(5));
+        // the hit restarts the timer of 0
+        assert!(cache.get(&Key(0)).is_some());
+        time.advance(::std::time::Duration::from_secs(6));
+        assert!(cache.get(&Key(0)).is_some());
+        let expired: Vec<_> =
+            cache.advance().iter().map(|(e, _)| e.0).collect();
+        assert_eq!(expired, vec![Key(1)]);
+    }
+
+    #[test]
+    fn entry_vacant_then_occupied() {
+        let mut cache = LRU::<Key, Val, user::ZeroMeta, Hasher>::new(
+            2,
+            0,
+            Hasher::default(),
+        );
+        let (refs, res) = match cache.entry(Key(0)) {
+            Entry::Vacant(entry) => entry.insert(Val(0)),
+            Entry::Occupied(_) => panic!("empty cache"),
+        };
+        assert_eq!(*refs.unwrap().0, Val(0));
+        assert!(res.is_empty());
+        cache.insert(Key(1), Val(1));
+        match cache.entry(Key(0)) {
+            Entry::Occupied(mut entry) => {
+                assert_eq!(entry.insert(Val(10)), Val(0));
+                assert_eq!(*entry.get().0, Val(10));
+            }
+            Entry::Vacant(_) => panic!("key not found"),
+        }
+        // the lookup was a hit: 1 is now the tail
+        let (refs, res) = cache.entry(Key(2)).or_insert(Val(2));
+        assert_eq!(*refs.unwrap().0, Val(2));
+        let evicted: Vec<_> = res.iter().map(|(e, _)| e.0).collect();
+        assert_eq!(evicted, vec![Key(1)]);
+        let (refs, _) = cache
+            .entry(Key(0))
+            .and_modify(|v| v.0 += 1)
+            .or_insert(Val(0));
+        assert_eq!(*refs.unwrap().0, Val(11));
+        match cache.entry(Key(0)) {
+            Entry::Occupied(entry) => assert_eq!(entry.remove().0, Val(11)),
+            Entry::Vacant(_) => panic!("key not found"),
+        }
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn entry_insert_can_evict_itself() {
+        let mut cache = LRU::<Key, Val, user::WeightMeta, Hasher>::new(
+            10,
+            0,
+            Hasher::default(),
+        );
+        cache.set_weighted(true).unwrap();
+        cache.insert(Key(0), Val(4));
+        // heavier than the whole cache: no references, the entry is returned
+        let (refs, res) = cache.entry(Key(1)).or_insert(Val(11));
+        assert!(refs.is_none());
+        let evicted: Vec<_> =
+            res.iter().map(|(e, c)| (e.0, e.1 .0, *c)).collect();
+        assert_eq!(evicted, vec![(Key(1), 11, RemovalCause::Capacity)]);
+        assert!(!cache.contains_key(&Key(1)));
+        assert_eq!((cache.len(), cache.weight()), (1, 4));
+    }
+
+    #[test]
+    fn entry_replaces_the_expired() {
+        let time = ManualTime::new();
+        let mut cache = TtlCache::new(10, 0, Hasher::default());
+        cache.set_time_source(Some(&time));
+        cache.insert_with_ttl(
+            Key(0),
+            Val(0),
+            ::std::time::Duration::from_secs(1),
+        );
+        time.advance(::std::time::Duration::from_secs(2));
+        let (refs, res) = match cache.entry(Key(0)) {
+            Entry::Vacant(entry) => entry.insert(Val(1)),
+            Entry::Occupied(_) => panic!("expired entry found"),
+        };
+        assert_eq!(*refs.unwrap().0, Val(1));
+        let removed: Vec<_> =
+            res.iter().map(|(e, c)| (e.0, e.1 .0, *c)).collect();
+        assert_eq!(removed, vec![(Key(0), 0, RemovalCause::Expired)]);
+        assert_eq!(cache.len(), 1);
+    }
+
+    type ZeroCache<'a> = LRU<'a, Key, Val, user::ZeroMeta, Hasher>;
+
+    #[test]
+    fn loader_runs_only_on_a_miss() {
+        let mut cache = ZeroCache::new(2, 0, Hasher::default());
+        let (val, res) = cache.get_or_insert_with(Key(0), || Val(0));
+        assert_eq!(val.unwrap().0, &Val(0));
+        assert!(res.is_empty());
+        cache.insert(Key(1), Val(1));
+        // a hit, and Key(0) is now the most recent
+        let (val, res) =
+            cache.get_or_insert_with(Key(0), || panic!("loaded on a hit"));
+        assert_eq!(val.unwrap().0, &Val(0));
+        assert!(res.is_empty());
+        let (val, res) = cache.get_or_insert_with(Key(2), || Val(2));
+        assert_eq!(val.unwrap().0, &Val(2));
+        let removed: Vec<_> = res.iter().map(|(e, c)| (e.0, *c)).collect();
+        assert_eq!(removed, vec![(Key(1), RemovalCause::Capacity)]);
+        assert_eq!(cache.len(), 2);
+    }
+
+    #[test]
+    fn failed_loader_inserts_nothing() {
+        let mut cache = ZeroCache::new(2, 0, Hasher::default());
+        cache.insert(Key(0), Val(0));
+        cache.insert(Key(1), Val(1));
+        let res = cache.try_get_or_insert_with(Key(2), || Err("failed"));
+        assert_eq!(res.err(), Some("failed"));
+        assert_eq!(cache.len(), 2);
+        assert!(!cache.contains_key(&Key(2)));
+        assert!(cache.contains_key(&Key(0)) && cache.contains_key(&Key(1)));
+        // the error is not seen on a hit
+        let (val, res) = cache
+            .try_get_or_insert_with(Key(0), || Err("failed"))
+            .unwrap();
+        assert_eq!(val.unwrap().0, &Val(0));
+        assert!(res.is_empty());
+    }
+}
@@ -13,25 +13,194 @@
  * See the License for the specific language governing permissions and
  * limitations under the License.
  */
+use crate::hashmap::user;
+
 #[derive(::thiserror::Error, Debug)]
 pub enum Error {
     #[error("Key not found in lru")]
     KeyNotFound,
+    #[error("Capacity too small for the current elements")]
+    CapacityTooSmall,
+    #[error("The cache can only count entries, not weigh them")]
+    WeightUnsupported,
 }
 
-pub enum InsertResult<E> {
-    OldEntry {
-        clash: Option<E>,
-        evicted: Option<E>,
-    },
-    OldTail {
+/// Why an entry left the cache
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RemovalCause {
+    /// replaced by an entry with the same key, or by one that took its place
+    Clash,
+    /// evicted to make room for other entries, or after a shrink
+    Capacity,
+    /// its time ran out
+    Expired,
+    /// removed on request of the user
+    Explicit,
+}
+
+/// Entries that left the cache, each tagged with its `RemovalCause`
+///
+/// The order is the removal order. An empty result does not allocate
+#[derive(Debug, Clone)]
+pub struct Removed<T> {
+    _entries: Vec<(T, RemovalCause)>,
+}
+
+/// Result of an insert in the owning caches: the entries that left the cache,
+/// with the clash (if any) first
+pub type InsertResult<E> = Removed<E>;
+/// Result of an insert in the shared caches: the indexes of the evicted
+/// entries, still in the hashmap. The caller must remove them
+pub type InsertResultShared = Removed<usize>;
+/// Result of a `get_or_insert_with` in the owning caches: references to the
+/// resident entry, plus the entries that left the cache on the way.
+///
+/// The references are `None` only if the new entry was evicted right away,
+/// and is then in the [`Removed`] entries
+pub type LoadResult<'c, K, V, Umeta> =
+    (Option<(&'c V, &'c Umeta)>, InsertResult<(K, V, Umeta)>);
+/// Like [`LoadResult`], but with mutable references, for the inserts through
+/// the entry API
+pub type EntryResult<'c, K, V, Umeta> = (
+    Option<(&'c mut V, &'c mut Umeta)>,
+    InsertResult<(K, V, Umeta)>,
+);
+
+impl<T> Default for Removed<T> {
+    fn default() -> Self {
+        Removed {
+            _entries: Vec::new(),
+        }
+    }
+}
+
+impl<T> Removed<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// result with only one removed entry
+    pub fn single(entry: T, cause: RemovalCause) -> Self {
+        Removed {
+            _entries: vec![(entry, cause)],
+        }
+    }
+    /// result with all the entries removed for the same cause
+    pub fn from_vec(entries: Vec<T>, cause: RemovalCause) -> Self {
+        Removed {
+            _entries: entries.into_iter().map(|e| (e, cause)).collect(),
+        }
+    }
+    pub fn push(&mut self, entry: T, cause: RemovalCause) {
+        self._entries.push((entry, cause));
+    }
+    /// add all the entries of `other` after ours
+    pub fn append(&mut self, mut other: Removed<T>) {
+        self._entries.append(&mut other._entries);
+    }
+    pub fn len(&self) -> usize {
+        self._entries.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self._entries.is_empty()
+    }
+    pub fn iter(&self) -> ::std::slice::Iter<'_, (T, RemovalCause)> {
+        self._entries.iter()
+    }
+    /// the entry that clashed with the insert, if any
+    pub fn clash(&self) -> Option<&T> {
+        self._entries
+            .iter()
+            .find(|(_, cause)| *cause == RemovalCause::Clash)
+            .map(|(e, _)| e)
+    }
+    /// the entries removed for `cause`
+    pub fn by_cause(
+        &self,
+        cause: RemovalCause,
+    ) -> impl Iterator<Item = &T> + '_ {
+        self._entries
+            .iter()
+            .filter(move |(_, c)| *c == cause)
+            .map(|(e, _)| e)
+    }
+    /// drop the causes and keep only the entries
+    pub fn into_entries(self) -> Vec<T> {
+        self._entries.into_iter().map(|(e, _)| e).collect()
+    }
+    pub fn map<U, F>(self, mut f: F) -> Removed<U>
+    where
+        F: FnMut(T) -> U,
+    {
+        Removed {
+            _entries: self
+                ._entries
+                .into_iter()
+                .map(|(e, cause)| (f(e), cause))
+                .collect(),
+        }
+    }
+}
+
+impl Removed<usize> {
+    /// Take the removed indexes out of the hashmap, after the `clash` entry
+    ///
+    /// Used by the owning caches to turn the result of the shared ones into
+    /// owned entries
+    pub fn take_from<Hmap, E, K, V, CidT, Umeta, HB>(
+        self,
+        hmap: &mut Hmap,
         clash: Option<E>,
-        evicted: E,
-    },
-    Success,
-}
-pub enum InsertResultShared<E> {
-    OldEntry { evicted: Option<E> },
-    OldTailPtr { evicted: ::std::ptr::NonNull<E> },
-    Success,
+    ) -> Removed<(K, V, Umeta)>
+    where
+        Hmap: crate::hashmap::HashMap<E, K, V, CidT, Umeta, HB>,
+        E: user::EntryT<K, V, CidT, Umeta>,
+        K: user::Hash,
+        V: user::Val,
+        CidT: user::Cid,
+        Umeta: user::Meta<V>,
+        HB: ::std::hash::BuildHasher,
+    {
+        let mut res = Removed {
+            _entries: Vec::with_capacity(
+                self._entries.len() + clash.is_some() as usize,
+            ),
+        };
+        if let Some(clash) = clash {
+            res.push(clash.deconstruct(), RemovalCause::Clash);
+        }
+        for (idx, cause) in self._entries {
+            res.push(hmap.remove_idx(idx).deconstruct(), cause);
+        }
+        res
+    }
+}
+
+impl<T> IntoIterator for Removed<T> {
+    type Item = (T, RemovalCause);
+    type IntoIter = ::std::vec::IntoIter<(T, RemovalCause)>;
+    fn into_iter(self) -> Self::IntoIter {
+        self._entries.into_iter()
+    }
+}
+
+impl<'r, T> IntoIterator for &'r Removed<T> {
+    type Item = &'r (T, RemovalCause);
+    type IntoIter = ::std::slice::Iter<'r, (T, RemovalCause)>;
+    fn into_iter(self) -> Self::IntoIter {
+        self._entries.iter()
+    }
+}
+
+impl<T> Extend<(T, RemovalCause)> for Removed<T> {
+    fn extend<I: IntoIterator<Item = (T, RemovalCause)>>(&mut self, iter: I) {
+        self._entries.extend(iter);
+    }
+}
+
+impl<T> ::std::iter::FromIterator<(T, RemovalCause)> for Removed<T> {
+    fn from_iter<I: IntoIterator<Item = (T, RemovalCause)>>(iter: I) -> Self {
+        Removed {
+            _entries: iter.into_iter().collect(),
+        }
+    }
 }
@@ -0,0 +1,661 @@
+/*
+ * Copyright 2021 Luca Fulchir <luca@fenrirproject.org>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! S3-FIFO cache
+//!
+//! Three FIFO queues:
+//!  * small: where the new entries go, 10% of the cache
+//!  * main: entries that were accessed while in the small FIFO
+//!  * ghost: keys of the entries evicted from the small FIFO
+//!
+//! A hit never moves the entry, it only increases its 2-bit access counter,
+//! kept in the [`user::Cid`] (see [`CidFreq`]).  
+//! Most entries are seen only once, and they are evicted quickly from the
+//! small FIFO. The tail of the small FIFO goes to the main one if it was
+//! accessed, and the keys that come back while still in the ghost FIFO go
+//! directly to the main one. The tail of the main FIFO is reinserted with a
+//! lower counter if it was accessed, evicted otherwise.
+//!
+//! See the [original paper](https://dl.acm.org/doi/10.1145/3600006.3613147)
+
+use crate::ghost::Ghost;
+use crate::hashmap;
+use crate::hashmap::user;
+use crate::owned::Owned;
+use crate::results::{InsertResultShared, RemovalCause, Removed};
+
+// Layout: cid 5..0, access counter 7..6
+::bitfield::bitfield! {
+    /// Cache id in 6 bits plus the S3-FIFO access counter
+    #[derive(Copy, Clone, Default)]
+    pub struct S3FifoCid(u8);
+    impl Debug;
+    #[inline]
+    pub u8, g_cid, s_cid: 5, 0;
+    #[inline]
+    pub u8, g_freq, s_freq: 7, 6;
+}
+impl S3FifoCid {
+    /// new cache id. `0` is reserved for entries with no cache
+    pub fn new(cache: u8) -> Self {
+        let mut res = S3FifoCid::default();
+        res.s_cid(cache);
+        res
+    }
+}
+impl user::Cid for S3FifoCid {
+    fn set_cache(&mut self, cache: Self) {
+        self.s_cid(cache.g_cid());
+        self.s_freq(0);
+    }
+}
+impl CidFreq for S3FifoCid {
+    fn get_freq(&self) -> u8 {
+        self.g_freq()
+    }
+    fn set_freq(&mut self, freq: u8) {
+        self.s_freq(freq)
+    }
+}
+impl PartialEq for S3FifoCid {
+    fn eq(&self, other: &Self) -> bool {
+        self.g_cid() == other.g_cid()
+    }
+}
+impl Eq for S3FifoCid {}
+
+type S3FifoEntry<K, V, Umeta> = user::Entry<K, V, S3FifoCid, Umeta>;
+type HmapT<K, V, Umeta, HB> =
+    hashmap::SimpleHmap<S3FifoEntry<K, V, Umeta>, K, V, S3FifoCid, Umeta, HB>;
+
+/// [S3-FIFO](https://s3fifo.com)
+/// implementation that wraps S3FifoShared, see [`Owned`]
+pub type S3Fifo<'a, K, V, Umeta, HB> = Owned<
+    'a,
+    K,
+    V,
+    S3FifoCid,
+    Umeta,
+    HB,
+    S3FifoShared<
+        'a,
+        HmapT<K, V, Umeta, HB>,
+        S3FifoEntry<K, V, Umeta>,
+        K,
+        V,
+        S3FifoCid,
+        Umeta,
+        HB,
+    >,
+>;
+
+impl<
+        'a,
+        K: user::Hash,
+        V: user::Val,
+        Umeta: user::Meta<V>,
+        HB: ::std::hash::BuildHasher + Default,
+    > S3Fifo<'a, K, V, Umeta, HB>
+{
+    /// new S3-FIFO with space for `entries` elements
+    pub fn new(
+        entries: usize,
+        extra_hashmap_capacity: usize,
+        hash_builder: HB,
+    ) -> Self {
+        Owned::from_shared(
+            S3FifoShared::new(
+                entries,
+                S3FifoCid::new(1),
+                S3FifoCid::new(2),
+                None,
+            ),
+            extra_hashmap_capacity,
+            hash_builder,
+        )
+    }
+    /// return the current size of `(small, main)`
+    pub fn get_lens(&self) -> (usize, usize) {
+        self.shared().get_lens()
+    }
+}
+
+/// A [`user::Cid`] that also hides a small access counter, `0..=3`
+///
+/// The counter must not be part of the comparison between Cids, and
+/// [`set_cache`](user::Cid::set_cache) resets it
+pub trait CidFreq: user::Cid {
+    /// get the access counter
+    fn get_freq(&self) -> u8;
+    /// set the access counter
+    fn set_freq(&mut self, freq: u8);
+}
+
+/// max value of the access counter
+pub const MAX_FREQ: u8 = 3;
+
+#[derive(PartialEq, Eq)]
+enum ScanStatus {
+    Stopped,
+    RunningSmall,
+    RunningMain,
+}
+
+// the small FIFO gets 10% of the entries
+fn small_size(entries: usize) -> usize {
+    ::std::cmp::max(1, entries / 10)
+}
+
+/// Actual implementation of S3-FIFO on a shared hashmap
+///
+/// The small and main FIFOs are two [`LRUShared`](crate::lru::LRUShared)
+/// with [`HitPolicy::Fifo`](crate::lru::HitPolicy::Fifo), with different
+/// cache ids. The ghost FIFO only keeps the keys of the evicted entries,
+/// outside of the hashmap.
+///
+/// Note that Insert/Remove do not actually insert anything in the hashmap.
+/// That must be done by the caller. We only fix all the pointers and S3-FIFO
+/// status
+pub struct S3FifoShared<'a, Hmap, E, K, V, CidT, Umeta, HB>
+where
+    Hmap: hashmap::HashMap<E, K, V, CidT, Umeta, HB>,
+    E: user::EntryT<K, V, CidT, Umeta>,
+    K: user::Hash,
+    V: user::Val,
+    CidT: CidFreq,
+    Umeta: user::Meta<V>,
+    HB: ::std::hash::BuildHasher + Default,
+{
+    // both FIFOs can grow up to the whole capacity, the evictions are
+    // decided by the S3-FIFO, not by the FIFOs
+    _small: crate::lru::LRUShared<'a, Hmap, E, K, V, CidT, Umeta, HB>,
+    _main: crate::lru::LRUShared<'a, Hmap, E, K, V, CidT, Umeta, HB>,
+    _ghost: Ghost<K, HB>,
+    _capacity: usize,
+    _scanstatus: ScanStatus,
+}
+
+impl<
+        'a,
+        Hmap: hashmap::HashMap<E, K, V, CidT, Umeta, HB>,
+        E: user::EntryT<K, V, CidT, Umeta>,
+        K: user::Hash,
+        V: user::Val,
+        CidT: CidFreq,
+        Umeta: user::Meta<V>,
+        HB: ::std::hash::BuildHasher + Default,
+    > S3FifoShared<'a, Hmap, E, K, V, CidT, Umeta, HB>
+{
+    /// new S3-FIFO with space for `entries` elements, the cache ids of the
+    /// small and main FIFOs plus an optional callback for the lazy scan
+    pub fn new(
+        entries: usize,
+        small_cid: CidT,
+        main_cid: CidT,
+        access_scan: Option<&'a dyn Fn(::std::ptr::NonNull<E>)>,
+    ) -> Self {
+        let mut small =
+            crate::lru::LRUShared::new(entries, small_cid, access_scan);
+        small.set_hit_policy(crate::lru::HitPolicy::Fifo);
+        let mut main =
+            crate::lru::LRUShared::new(entries, main_cid, access_scan);
+        main.set_hit_policy(crate::lru::HitPolicy::Fifo);
+        S3FifoShared {
+            _small: small,
+            _main: main,
+            _ghost: Ghost::new(),
+            _capacity: entries,
+            _scanstatus: ScanStatus::Stopped,
+        }
+    }
+    /// change the scan callback
+    pub fn set_scanf(
+        &mut self,
+        access_scan: Option<&'a dyn Fn(::std::ptr::NonNull<E>)>,
+    ) {
+        self._small.set_scanf(access_scan);
+        self._main.set_scanf(access_scan)
+    }
+    /// An element has been added by the caller, fix the S3-FIFO
+    ///
+    /// `maybe_old_entry` must be `!= None` only if the clash happened in
+    /// this cache
+    pub fn insert_shared(
+        &mut self,
+        hmap: &mut Hmap,
+        maybe_old_entry: Option<&mut E>,
+        new_entry_idx: usize,
+    ) -> InsertResultShared {
+        let res = match maybe_old_entry {
+            None => self.miss(hmap, new_entry_idx, true),
+            Some(old_entry)
+                if old_entry.get_key()
+                    == hmap.get_index(new_entry_idx).unwrap().get_key() =>
+            {
+                // same key, just a new value: count it as an access
+                let old_cid = old_entry.get_cache_id();
+                let res = if old_cid == self._small.get_cache_id() {
+                    self._small.insert_shared(
+                        hmap,
+                        Some(old_entry),
+                        new_entry_idx,
+                    )
+                } else {
+                    self._main.insert_shared(
+                        hmap,
+                        Some(old_entry),
+                        new_entry_idx,
+                    )
+                };
+                hmap.get_index_mut(new_entry_idx)
+                    .unwrap()
+                    .get_cache_id_mut()
+                    .set_freq(::std::cmp::min(
+                        old_cid.get_freq() + 1,
+                        MAX_FREQ,
+                    ));
+                res
+            }
+            Some(old_entry) => {
+                // a different key took the place of the old entry, which is
+                // not in the cache anymore
+                self.remove_clashed(hmap, old_entry, new_entry_idx);
+                self.miss(hmap, new_entry_idx, true)
+            }
+        };
+        self.update_scan_status();
+        res
+    }
+    /// An entry moved here from another cache on the same hashmap.
+    ///
+    /// Like a new insert, but without running the on-insert callback
+    pub fn move_in_shared(
+        &mut self,
+        hmap: &mut Hmap,
+        idx: usize,
+    ) -> InsertResultShared {
+        let res = self.miss(hmap, idx, false);
+        self.update_scan_status();
+        res
+    }
+    // the key at `idx` was not in the cache: make room, then add the entry
+    // to the main FIFO if the key was in the ghost FIFO, or to the small one
+    fn miss(
+        &mut self,
+        hmap: &mut Hmap,
+        idx: usize,
+        run_callback: bool,
+    ) -> InsertResultShared {
+        let evicted = if self.len() >= self._capacity {
+            self.evict(hmap)
+        } else {
+            None
+        };
+        let key = hmap.get_index(idx).unwrap().get_key();
+        let fifo = match self._ghost.remove(key) {
+            true => &mut self._main,
+            false => &mut self._small,
+        };
+        match run_callback {
+            true => fifo.insert_shared(hmap, None, idx),
+            false => fifo.move_in_shared(hmap, idx),
+        };
+        match evicted {
+            None => Removed::new(),
+            Some(evicted) => Removed::single(evicted, RemovalCause::Capacity),
+        }
+    }
+    // evict one entry:
+    //  * from the small FIFO if it is too big: the tail goes to the main
+    //    FIFO if it was accessed, else its key goes to the ghost FIFO
+    //  * from the main FIFO otherwise: the accessed entries are reinserted
+    //    with a lower counter
+    // The entry is still in the hashmap, its index is returned
+    fn evict(&mut self, hmap: &mut Hmap) -> Option<usize> {
+        loop {
+            if self._small.len() >= small_size(self._capacity)
+                || self._main.len() == 0
+            {
+                let tail = self._small.tail(hmap)?;
+                self._small.remove_shared(hmap, tail);
+                let entry = hmap.get_index(tail).unwrap();
+                if entry.get_cache_id().get_freq() > 0 {
+                    // the main FIFO can hold the whole cache, this never
+                    // evicts
+                    self._main.move_in_shared(hmap, tail);
+                    continue;
+                }
+                self._ghost.push(entry.get_key().clone());
+                while self._ghost.len() > self._main.capacity() {
+                    self._ghost.pop_lru();
+                }
+                return Some(tail);
+            }
+            let tail = self._main.tail(hmap)?;
+            let cid = hmap.get_index_mut(tail).unwrap().get_cache_id_mut();
+            let freq = cid.get_freq();
+            if freq > 0 {
+                cid.set_freq(freq - 1);
+                self._main.make_head(hmap, tail);
+                continue;
+            }
+            self._main.remove_shared(hmap, tail);
+            return Some(tail);
+        }
+    }
+    /// The index of the entry that will probably be evicted by the next
+    /// insert, if the S3-FIFO is full
+    pub fn victim(&self, hmap: &Hmap) -> Option<usize> {
+        if self.len() < self._capacity {
+            return None;
+        }
+        if self._small.len() >= small_size(self._capacity)
+            || self._main.len() == 0
+        {
+            // the accessed entries move to the main FIFO
+            let mut maybe_idx = self._small.tail(hmap);
+            while let Some(idx) = maybe_idx {
+                if hmap.get_index(idx).unwrap().get_cache_id().get_freq() == 0 {
+                    return Some(idx);
+                }
+                maybe_idx = self._small.next_to_head(hmap, idx);
+            }
+        }
+        // the first entry with the lowest counter will reach zero first
+        let mut maybe_idx = self._main.tail(hmap);
+        let mut victim = None;
+        let mut lowest = MAX_FREQ + 1;
+        while let Some(idx) = maybe_idx {
+            let freq = hmap.get_index(idx).unwrap().get_cache_id().get_freq();
+            if freq < lowest {
+                lowest = freq;
+                victim = Some(idx);
+                if freq == 0 {
+                    break;
+                }
+            }
+            maybe_idx = self._main.next_to_head(hmap, idx);
+        }
+        victim.or_else(|| self._small.tail(hmap))
+    }
+    /// Reset the S3-FIFO state, ghost FIFO included
+    pub fn clear_shared(&mut self) {
+        self._small.clear_shared();
+        self._main.clear_shared();
+        self._ghost.clear();
+        self._scanstatus = ScanStatus::Stopped;
+    }
+    /// do not actually remove the element at `idx`, just fix the S3-FIFO so
+    /// that it is not considered anymore and actual removal is safe
+    pub fn remove_shared(&mut self, hmap: &mut Hmap, idx: usize) {
+        let cid = hmap.get_index(idx).unwrap().get_cache_id();
+        if cid == self._small.get_cache_id() {
+            self._small.remove_shared(hmap, idx)
+        } else {
+            self._main.remove_shared(hmap, idx)
+        };
+        self.update_scan_status();
+    }
+    /// `old_entry` was in the S3-FIFO, but the entry at `idx` clashed with it
+    /// and took its place in the hashmap. Forget about the old entry
+    pub fn remove_clashed(
+        &mut self,
+        hmap: &mut Hmap,
+        old_entry: &E,
+        idx: usize,
+    ) {
+        if old_entry.get_cache_id() == self._small.get_cache_id() {
+            self._small.remove_clashed(hmap, old_entry, idx)
+        } else {
+            self._main.remove_clashed(hmap, old_entry, idx)
+        };
+        self.update_scan_status();
+    }
+    /// The hashmap has been resized and all the entries have moved.
+    ///
+    /// Fix both FIFOs. The ghost FIFO only has keys
+    pub fn relocate(&mut self, reloc: &hashmap::Relocation<E>) {
+        self._small.relocate(reloc);
+        self._main.relocate(reloc);
+    }
+    /// return the cache ids for `(small, main)`
+    pub fn get_cache_ids(&self) -> (CidT, CidT) {
+        (self._small.get_cache_id(), self._main.get_cache_id())
+    }
+    /// Should be called only by the parent: increase the access counter and
+    /// run the on-get callback. The entry is never moved
+    pub fn on_get(&mut self, hmap: &mut Hmap, idx: usize) {
+        let cid = hmap.get_index_mut(idx).unwrap().get_cache_id_mut();
+        cid.set_freq(::std::cmp::min(cid.get_freq() + 1, MAX_FREQ));
+        if *cid == self._small.get_cache_id() {
+            self._small.on_get(hmap, idx);
+        } else {
+            self._main.on_get(hmap, idx);
+        }
+        self.update_scan_status();
+    }
+    /// start the scan callbacks on the S3-FIFO
+    /// scan will execute only once on the whole S3-FIFO
+    pub fn start_scan(&mut self) {
+        self._small.start_scan();
+        if self._small.is_scan_running() {
+            self._scanstatus = ScanStatus::RunningSmall;
+            return;
+        }
+        self._main.start_scan();
+        self._scanstatus = if self._main.is_scan_running() {
+            ScanStatus::RunningMain
+        } else {
+            ScanStatus::Stopped
+        };
+    }
+    /// return scan status
+    pub fn is_scan_running(&self) -> bool {
+        self._scanstatus != ScanStatus::Stopped
+    }
+    fn update_scan_status(&mut self) {
+        match self._scanstatus {
+            ScanStatus::Stopped => {}
+            ScanStatus::RunningSmall => {
+                if !self._small.is_scan_running() {
+                    self._main.start_scan();
+                    self._scanstatus = ScanStatus::RunningMain;
+                }
+            }
+            ScanStatus::RunningMain => {
+                if !self._main.is_scan_running() {
+                    self._scanstatus = ScanStatus::Stopped;
+                }
+            }
+        }
+    }
+    /// Change the number of entries of the S3-FIFO
+    ///
+    /// The entries evicted while shrinking are chosen just like on insert,
+    /// and their indexes are returned: they are still in the hashmap and
+    /// must be removed by the caller
+    pub fn set_capacity(
+        &mut self,
+        hmap: &mut Hmap,
+        entries: usize,
+    ) -> Removed<usize> {
+        self._capacity = entries;
+        let mut evicted = Removed::new();
+        while self.len() > entries {
+            match self.evict(hmap) {
+                Some(idx) => evicted.push(idx, RemovalCause::Capacity),
+                None => break,
+            }
+        }
+        // the FIFOs are not over the capacity anymore, this does not evict
+        self._small.set_capacity(hmap, entries);
+        self._main.set_capacity(hmap, entries);
+        while self._ghost.len() > entries {
+            self._ghost.pop_lru();
+        }
+        self.update_scan_status();
+        evicted
+    }
+    /// return the current size of `(small, main)`
+    pub fn get_lens(&self) -> (usize, usize) {
+        (self._small.len(), self._main.len())
+    }
+    /// return max S3-FIFO size
+    pub fn capacity(&self) -> usize {
+        self._capacity
+    }
+    /// return used S3-FIFO size
+    pub fn len(&self) -> usize {
+        self._small.len() + self._main.len()
+    }
+    /// check if the S3-FIFO is empty
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<
+        'a,
+        Hmap: hashmap::HashMap<E, K, V, CidT, Umeta, HB>,
+        E: user::EntryT<K, V, CidT, Umeta>,
+        K: user::Hash,
+        V: user::Val,
+        CidT: CidFreq,
+        Umeta: user::Meta<V>,
+        HB: ::std::hash::BuildHasher + Default,
+    > crate::cache::SharedCache<'a, Hmap, E, K, V, CidT, Umeta, HB>
+    for S3FifoShared<'a, Hmap, E, K, V, CidT, Umeta, HB>
+{
+    fn insert_shared(
+        &mut self,
+        hmap: &mut Hmap,
+        maybe_old_entry: Option<&mut E>,
+        new_entry_idx: usize,
+    ) -> InsertResultShared {
+        S3FifoShared::insert_shared(self, hmap, maybe_old_entry, new_entry_idx)
+    }
+    fn move_in_shared(
+        &mut self,
+        hmap: &mut Hmap,
+        idx: usize,
+    ) -> InsertResultShared {
+        S3FifoShared::move_in_shared(self, hmap, idx)
+    }
+    fn remove_shared(&mut self, hmap: &mut Hmap, idx: usize) {
+        S3FifoShared::remove_shared(self, hmap, idx)
+    }
+    fn remove_clashed(&mut self, hmap: &mut Hmap, old_entry: &E, idx: usize) {
+        S3FifoShared::remove_clashed(self, hmap, old_entry, idx)
+    }
+    fn clear_shared(&mut self) {
+        S3FifoShared::clear_shared(self)
+    }
+    fn on_get(&mut self, hmap: &mut Hmap, idx: usize) {
+        S3FifoShared::on_get(self, hmap, idx)
+    }
+    fn owns(&self, cid: CidT) -> bool {
+        cid == self._small.get_cache_id() || cid == self._main.get_cache_id()
+    }
+    fn victim(&self, hmap: &Hmap) -> Option<usize> {
+        S3FifoShared::victim(self, hmap)
+    }
+    fn relocate(&mut self, reloc: &hashmap::Relocation<E>) {
+        S3FifoShared::relocate(self, reloc)
+    }
+    fn set_scanf(
+        &mut self,
+        access_scan: Option<&'a dyn Fn(::std::ptr::NonNull<E>)>,
+    ) {
+        S3FifoShared::set_scanf(self, access_scan)
+    }
+    fn start_scan(&mut self) {
+        S3FifoShared::start_scan(self)
+    }
+    fn is_scan_running(&self) -> bool {
+        S3FifoShared::is_scan_running(self)
+    }
+    // S3-FIFO never moves the entries on a hit
+    fn set_hit_policy(&mut self, _policy: crate::lru::HitPolicy) {}
+    fn get_hit_policy(&self) -> crate::lru::HitPolicy {
+        crate::lru::HitPolicy::Fifo
+    }
+    fn set_capacity(
+        &mut self,
+        hmap: &mut Hmap,
+        entries: usize,
+    ) -> Removed<usize> {
+        S3FifoShared::set_capacity(self, hmap, entries)
+    }
+    fn capacity(&self) -> usize {
+        S3FifoShared::capacity(self)
+    }
+    fn len(&self) -> usize {
+        S3FifoShared::len(self)
+    }
+    // only counts entries
+    fn set_weighted(
+        &mut self,
+        _hmap: &Hmap,
+        weighted: bool,
+    ) -> Result<(), crate::results::Error> {
+        match weighted {
+            false => Ok(()),
+            true => Err(crate::results::Error::WeightUnsupported),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{Hasher, Key, Val};
+
+    type Cache<'a> = S3Fifo<'a, Key, Val, user::ZeroMeta, Hasher>;
+
+    fn evicted(res: Removed<(Key, Val, user::ZeroMeta)>) -> Vec<Key> {
+        res.iter().map(|(e, _)| e.0).collect()
+    }
+
+    #[test]
+    fn accessed_small_tail_goes_to_main() {
+        let mut cache = Cache::new(10, 0, Hasher::default());
+        for k in 0..10 {
+            cache.insert(Key(k), Val(k));
+        }
+        assert_eq!(cache.get_lens(), (10, 0));
+        cache.get(&Key(0));
+        // the small FIFO is too big: 0 was accessed and moves to main
+        assert_eq!(evicted(cache.insert(Key(10), Val(10))), vec![Key(1)]);
+        assert_eq!(cache.get_lens(), (9, 1));
+        assert!(cache.contains_key(&Key(0)));
+        assert!(!cache.contains_key(&Key(1)));
+    }
+
+    #[test]
+    fn ghost_hit_goes_to_main() {
+        let mut cache = Cache::new(10, 0, Hasher::default());
+        for k in 0..11 {
+            cache.insert(Key(k), Val(k));
+        }
+        assert_eq!(cache.get_lens(), (10, 0));
+        // 0 was evicted from small without hits, its key is a ghost
+        assert!(!cache.contains_key(&Key(0)));
+        assert_eq!(evicted(cache.insert(Key(0), Val(0))), vec![Key(1)]);
+        assert_eq!(cache.get_lens(), (9, 1));
+        assert!(cache.contains_key(&Key(0)));
+        assert!(!cache.shared().is_empty());
+    }
+}
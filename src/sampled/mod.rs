@@ -0,0 +1,715 @@
+/*
+ * Copyright 2021 Luca Fulchir <luca@fenrirproject.org>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Sampled eviction cache
+//!
+//! Approximated LRU/LFU in the style of Redis: the entries are not linked,
+//! every entry only keeps a small stamp in its [`user::Cid`], which is
+//! either the last access time or an access counter.  
+//! On eviction a few random buckets of the hashmap are sampled and the
+//! entry with the worst score is evicted.
+//!
+//! The number of samples and the scoring ([`Scoring`]) are configurable.
+//! With [`Scoring::TtlFirst`] the entry that expires first is evicted,
+//! according to a user function on the entry.
+//!
+//! Since nothing points to the entries, hits never touch the links and a
+//! hashmap resize needs no fixups.
+
+use crate::clock::IdxScan;
+use crate::hashmap;
+use crate::hashmap::user;
+use crate::owned::Owned;
+use crate::results::{InsertResultShared, RemovalCause, Removed};
+
+/// A [`user::Cid`] that also hides a stamp of at least [`STAMP_BITS`] bits:
+/// the time of the last access or the access counter, depending on the
+/// [`Scoring`]
+///
+/// The stamp must not be part of the comparison between Cids
+pub trait CidStamp: user::Cid {
+    /// get the stamp
+    fn get_stamp(&self) -> u32;
+    /// set the stamp
+    fn set_stamp(&mut self, stamp: u32);
+}
+
+/// number of bits used in the stamps.
+/// Like Redis, the access time is a clock that wraps around
+pub const STAMP_BITS: u32 = 28;
+const STAMP_MASK: u32 = (1 << STAMP_BITS) - 1;
+
+/// How the sampled entries are compared: the one with the lowest score is
+/// evicted
+pub enum Scoring<'a, E> {
+    /// evict the least recently used entry
+    Lru,
+    /// evict the least frequently used entry
+    Lfu,
+    /// evict the entry that expires first. The function must return the
+    /// expiration time of the entry, for example from its user metadata
+    TtlFirst(&'a dyn Fn(&E) -> u64),
+}
+// derive would require `E: Clone`
+impl<'a, E> Clone for Scoring<'a, E> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+impl<'a, E> Copy for Scoring<'a, E> {}
+
+/// default number of entries sampled for each eviction, same as Redis
+pub const DEFAULT_SAMPLES: usize = 5;
+
+// splitmix64, we only need something fast and predictable, so that
+// `victim()` can know which entries the next eviction will sample
+fn next_random(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+// Layout: cid 3..0, stamp 31..4
+::bitfield::bitfield! {
+    /// Cache id in 4 bits plus the access time or counter in 28 bits
+    #[derive(Copy, Clone, Default)]
+    pub struct SampledCid(u32);
+    impl Debug;
+    #[inline]
+    pub u8, g_cid, s_cid: 3, 0;
+    #[inline]
+    pub u32, g_stamp, s_stamp: 31, 4;
+}
+impl SampledCid {
+    /// new cache id. `0` is reserved for entries with no cache
+    pub fn new(cache: u8) -> Self {
+        let mut res = SampledCid::default();
+        res.s_cid(cache);
+        res
+    }
+}
+impl user::Cid for SampledCid {
+    fn set_cache(&mut self, cache: Self) {
+        self.s_cid(cache.g_cid());
+    }
+}
+impl CidStamp for SampledCid {
+    fn get_stamp(&self) -> u32 {
+        self.g_stamp()
+    }
+    fn set_stamp(&mut self, stamp: u32) {
+        self.s_stamp(stamp)
+    }
+}
+impl PartialEq for SampledCid {
+    fn eq(&self, other: &Self) -> bool {
+        self.g_cid() == other.g_cid()
+    }
+}
+impl Eq for SampledCid {}
+
+type SampledEntry<K, V, Umeta> = user::Entry<K, V, SampledCid, Umeta>;
+type HmapT<K, V, Umeta, HB> =
+    hashmap::SimpleHmap<SampledEntry<K, V, Umeta>, K, V, SampledCid, Umeta, HB>;
+
+/// Sampled eviction cache that wraps SampledShared, see [`Owned`]
+pub type Sampled<'a, K, V, Umeta, HB> = Owned<
+    'a,
+    K,
+    V,
+    SampledCid,
+    Umeta,
+    HB,
+    SampledShared<
+        'a,
+        HmapT<K, V, Umeta, HB>,
+        SampledEntry<K, V, Umeta>,
+        K,
+        V,
+        SampledCid,
+        Umeta,
+        HB,
+    >,
+>;
+
+impl<
+        'a,
+        K: user::Hash,
+        V: user::Val,
+        Umeta: user::Meta<V>,
+        HB: ::std::hash::BuildHasher + Default,
+    > Sampled<'a, K, V, Umeta, HB>
+{
+    /// new cache with space for `entries` elements
+    pub fn new(
+        entries: usize,
+        extra_hashmap_capacity: usize,
+        hash_builder: HB,
+    ) -> Self {
+        Owned::from_shared(
+            SampledShared::new(entries, SampledCid::new(1), None),
+            extra_hashmap_capacity,
+            hash_builder,
+        )
+    }
+    /// change the number of entries sampled for each eviction
+    pub fn set_samples(&mut self, samples: usize) {
+        self.shared_mut().set_samples(samples)
+    }
+    /// get the number of entries sampled for each eviction
+    pub fn get_samples(&self) -> usize {
+        self.shared().get_samples()
+    }
+    /// change how the sampled entries are compared
+    pub fn set_scoring(
+        &mut self,
+        scoring: Scoring<'a, SampledEntry<K, V, Umeta>>,
+    ) {
+        self.shared_mut().set_scoring(scoring)
+    }
+}
+
+/// Actual implementation of the sampled eviction on a shared hashmap
+///
+/// The entries are not linked. On eviction a few random buckets of the
+/// hashmap are sampled, and the entry of this cache with the lowest score
+/// is evicted
+///
+/// Note that Insert/Remove do not actually insert anything in the hashmap.
+/// That must be done by the caller
+pub struct SampledShared<'a, Hmap, E, K, V, CidT, Umeta, HB>
+where
+    Hmap: hashmap::HashMap<E, K, V, CidT, Umeta, HB>,
+    E: user::EntryT<K, V, CidT, Umeta>,
+    K: user::Hash,
+    V: user::Val,
+    CidT: CidStamp,
+    Umeta: user::Meta<V>,
+    HB: ::std::hash::BuildHasher + Default,
+{
+    _cache_id: CidT,
+    _capacity: usize,
+    _used: usize,
+    _samples: usize,
+    _scoring: Scoring<'a, E>,
+    // logical time, one tick every get/insert
+    _now: u32,
+    _rng: u64,
+    _scan: IdxScan<'a, E>,
+    _hmap: ::std::marker::PhantomData<Hmap>,
+    _k: ::std::marker::PhantomData<K>,
+    _v: ::std::marker::PhantomData<V>,
+    _umeta: ::std::marker::PhantomData<Umeta>,
+    _hb: ::std::marker::PhantomData<HB>,
+}
+
+impl<
+        'a,
+        Hmap: hashmap::HashMap<E, K, V, CidT, Umeta, HB>,
+        E: user::EntryT<K, V, CidT, Umeta>,
+        K: user::Hash,
+        V: user::Val,
+        CidT: CidStamp,
+        Umeta: user::Meta<V>,
+        HB: ::std::hash::BuildHasher + Default,
+    > SampledShared<'a, Hmap, E, K, V, CidT, Umeta, HB>
+{
+    /// new sampled cache with space for `entries` elements, its cache id and
+    /// an optional callback for the lazy scan.
+    ///
+    /// Starts with [`Scoring::Lru`] and [`DEFAULT_SAMPLES`]
+    pub fn new(
+        entries: usize,
+        cache_id: CidT,
+        access_scan: Option<&'a dyn Fn(::std::ptr::NonNull<E>)>,
+    ) -> Self {
+        SampledShared {
+            _cache_id: cache_id,
+            _capacity: entries,
+            _used: 0,
+            _samples: DEFAULT_SAMPLES,
+            _scoring: Scoring::Lru,
+            _now: 0,
+            _rng: ::rand::random(),
+            _scan: IdxScan::new(access_scan),
+            _hmap: ::std::marker::PhantomData,
+            _k: ::std::marker::PhantomData,
+            _v: ::std::marker::PhantomData,
+            _umeta: ::std::marker::PhantomData,
+            _hb: ::std::marker::PhantomData,
+        }
+    }
+    /// change the scan callback
+    pub fn set_scanf(
+        &mut self,
+        access_scan: Option<&'a dyn Fn(::std::ptr::NonNull<E>)>,
+    ) {
+        self._scan.set_scanf(access_scan)
+    }
+    /// change the number of entries sampled for each eviction.
+    ///
+    /// More samples get closer to the exact policy, but make the eviction
+    /// slower
+    pub fn set_samples(&mut self, samples: usize) {
+        self._samples = ::std::cmp::max(1, samples);
+    }
+    /// get the number of entries sampled for each eviction
+    pub fn get_samples(&self) -> usize {
+        self._samples
+    }
+    /// change how the sampled entries are compared.
+    ///
+    /// The stamps of the entries already in the cache are not converted, so
+    /// switching between LRU and LFU takes a while to settle
+    pub fn set_scoring(&mut self, scoring: Scoring<'a, E>) {
+        self._scoring = scoring;
+    }
+    /// get how the sampled entries are compared
+    pub fn get_scoring(&self) -> Scoring<'a, E> {
+        self._scoring
+    }
+    /// An element has been added by the caller, fix the cache
+    ///
+    /// `maybe_old_entry` must be `!= None` only if the clash happened in
+    /// this cache
+    pub fn insert_shared(
+        &mut self,
+        hmap: &mut Hmap,
+        maybe_old_entry: Option<&mut E>,
+        new_entry_idx: usize,
+    ) -> InsertResultShared {
+        match maybe_old_entry {
+            None => {
+                let res = self.push(hmap, new_entry_idx);
+                let just_inserted = hmap.get_index_mut(new_entry_idx).unwrap();
+                just_inserted.user_on_insert(None);
+                self._scan.apply_raw(just_inserted.into());
+                self.scan_next(hmap);
+                res
+            }
+            Some(old_entry) => {
+                // the new entry took the bucket of the old one, which was
+                // ours. A new value for the same key counts as a hit
+                let just_inserted = hmap.get_index_mut(new_entry_idx).unwrap();
+                let same_key = old_entry.get_key() == just_inserted.get_key();
+                *just_inserted.get_cache_id_mut() = old_entry.get_cache_id();
+                just_inserted.user_on_insert(Some(old_entry));
+                self._scan.apply_raw(just_inserted.into());
+                if same_key {
+                    self.touch(hmap, new_entry_idx);
+                } else {
+                    self._now = self._now.wrapping_add(1) & STAMP_MASK;
+                    let stamp = self.new_stamp();
+                    hmap.get_index_mut(new_entry_idx)
+                        .unwrap()
+                        .get_cache_id_mut()
+                        .set_stamp(stamp);
+                }
+                self.scan_next(hmap);
+                Removed::new()
+            }
+        }
+    }
+    /// An entry moved here from another cache on the same hashmap
+    ///
+    /// Like [`insert_shared`](Self::insert_shared) without clashes, but the
+    /// on-insert callback is not run, since this is not a new insert
+    pub fn move_in_shared(
+        &mut self,
+        hmap: &mut Hmap,
+        idx: usize,
+    ) -> InsertResultShared {
+        let res = self.push(hmap, idx);
+        self.scan_next(hmap);
+        res
+    }
+    // evict if full, then take the entry at `idx`
+    fn push(&mut self, hmap: &mut Hmap, idx: usize) -> InsertResultShared {
+        // evict first, so that the new entry is not sampled
+        let maybe_evicted = if self._used >= self._capacity {
+            self.evict(hmap)
+        } else {
+            None
+        };
+        self._now = self._now.wrapping_add(1) & STAMP_MASK;
+        let stamp = self.new_stamp();
+        let cid = hmap.get_index_mut(idx).unwrap().get_cache_id_mut();
+        cid.set_cache(self._cache_id);
+        cid.set_stamp(stamp);
+        self._used += 1;
+        match maybe_evicted {
+            None => Removed::new(),
+            Some(evicted) => Removed::single(evicted, RemovalCause::Capacity),
+        }
+    }
+    // stamp of a new entry
+    fn new_stamp(&self) -> u32 {
+        match self._scoring {
+            Scoring::Lfu => 1,
+            Scoring::Lru | Scoring::TtlFirst(_) => self._now,
+        }
+    }
+    // update the stamp of the entry at `idx` after an access
+    fn touch(&mut self, hmap: &mut Hmap, idx: usize) {
+        self._now = self._now.wrapping_add(1) & STAMP_MASK;
+        let cid = hmap.get_index_mut(idx).unwrap().get_cache_id_mut();
+        let stamp = match self._scoring {
+            Scoring::Lfu => ::std::cmp::min(cid.get_stamp() + 1, STAMP_MASK),
+            Scoring::Lru | Scoring::TtlFirst(_) => self._now,
+        };
+        cid.set_stamp(stamp);
+    }
+    // lower scores are evicted first
+    fn score(&self, entry: &E) -> u64 {
+        let stamp = entry.get_cache_id().get_stamp() & STAMP_MASK;
+        match self._scoring {
+            Scoring::Lru => {
+                let age = self._now.wrapping_sub(stamp) & STAMP_MASK;
+                (STAMP_MASK - age) as u64
+            }
+            Scoring::Lfu => stamp as u64,
+            Scoring::TtlFirst(expiration) => expiration(entry),
+        }
+    }
+    // sample up to `samples` entries of this cache and return the index of
+    // the one with the lowest score.
+    // `rng` is advanced only if this is a real eviction
+    fn sample(&self, hmap: &Hmap, rng: &mut u64) -> Option<usize> {
+        if self._used == 0 {
+            return None;
+        }
+        let buckets = hmap.buckets();
+        let mut best: Option<(u64, usize)> = None;
+        let mut found = 0;
+        // if the hashmap is mostly empty or used by other caches, give up
+        // after a while
+        for _ in 0..(8 * self._samples) {
+            let idx = (next_random(rng) % buckets as u64) as usize;
+            let entry = match hmap.get_index(idx) {
+                Some(entry) if entry.get_cache_id() == self._cache_id => entry,
+                _ => continue,
+            };
+            let score = self.score(entry);
+            match best {
+                Some((best_score, _)) if best_score <= score => {}
+                _ => best = Some((score, idx)),
+            }
+            found += 1;
+            if found >= self._samples {
+                break;
+            }
+        }
+        if let Some((_, idx)) = best {
+            return Some(idx);
+        }
+        // no luck: take the first entry after a random bucket
+        let start = (next_random(rng) % buckets as u64) as usize;
+        (0..buckets).map(|i| (start + i) % buckets).find(|&idx| {
+            match hmap.get_index(idx) {
+                Some(entry) => entry.get_cache_id() == self._cache_id,
+                None => false,
+            }
+        })
+    }
+    // evict the sampled entry with the lowest score.
+    // The evicted entry is still in the hashmap but does not belong to this
+    // cache anymore
+    fn evict(&mut self, hmap: &mut Hmap) -> Option<usize> {
+        let mut rng = self._rng;
+        let idx = self.sample(hmap, &mut rng)?;
+        self._rng = rng;
+        hmap.get_index_mut(idx)
+            .unwrap()
+            .get_cache_id_mut()
+            .set_cache(CidT::default());
+        self._used -= 1;
+        Some(idx)
+    }
+    // run the user scan on one more entry
+    fn scan_next(&mut self, hmap: &mut Hmap) {
+        let cache_id = self._cache_id;
+        self._scan.apply_next(hmap, |cid: CidT| cid == cache_id);
+    }
+    /// The index of the entry that will be evicted by the next insert, if
+    /// the cache is full
+    ///
+    /// The next eviction samples the same buckets, so this is exact unless
+    /// the cache changes in the meantime
+    pub fn victim(&self, hmap: &Hmap) -> Option<usize> {
+        if self._used < self._capacity {
+            return None;
+        }
+        let mut rng = self._rng;
+        self.sample(hmap, &mut rng)
+    }
+    /// reset the cache
+    pub fn clear_shared(&mut self) {
+        self._used = 0;
+        self._scan.stop();
+    }
+    /// do not actually remove the element at `idx`, just fix the cache so
+    /// that it is not considered anymore and actual removal is safe
+    pub fn remove_shared(&mut self, _hmap: &mut Hmap, _idx: usize) {
+        self._used -= 1;
+    }
+    /// `old_entry` was in the cache, but the entry at `idx` clashed with it
+    /// and took its place in the hashmap. Forget about the old entry
+    pub fn remove_clashed(
+        &mut self,
+        _hmap: &mut Hmap,
+        _old_entry: &E,
+        _idx: usize,
+    ) {
+        self._used -= 1;
+    }
+    /// Change the number of entries of the cache
+    ///
+    /// When shrinking, the evicted entries are returned.  
+    /// Just like with [`InsertResultShared`] they are still in the
+    /// hashmap and must be removed by the caller
+    pub fn set_capacity(
+        &mut self,
+        hmap: &mut Hmap,
+        entries: usize,
+    ) -> Removed<usize> {
+        self._capacity = entries;
+        let mut evicted = Removed::new();
+        while self._used > self._capacity {
+            match self.evict(hmap) {
+                Some(idx) => evicted.push(idx, RemovalCause::Capacity),
+                None => break,
+            }
+        }
+        evicted
+    }
+    /// The hashmap has been resized, all the entries moved.
+    ///
+    /// Nothing points to the entries, only the scan restarts
+    pub fn relocate(&mut self, _reloc: &hashmap::Relocation<E>) {
+        self._scan.relocate();
+    }
+    /// get the cache id
+    pub fn get_cache_id(&self) -> CidT {
+        self._cache_id
+    }
+    /// Used when composing caches: update the stamp and run the callback on
+    /// the entry at `idx`
+    pub fn on_get(&mut self, hmap: &mut Hmap, idx: usize) {
+        self.touch(hmap, idx);
+        hmap.get_index_mut(idx).unwrap().user_on_get();
+        self.scan_next(hmap);
+    }
+    /// start the scan on the entries of this cache, in bucket order
+    pub fn start_scan(&mut self) {
+        if self._used > 0 {
+            self._scan.start_scan();
+        }
+    }
+    /// check if the scan is still running
+    pub fn is_scan_running(&self) -> bool {
+        self._scan.is_running()
+    }
+    /// get the cache capacity
+    pub fn capacity(&self) -> usize {
+        self._capacity
+    }
+    /// get the cache usage
+    pub fn len(&self) -> usize {
+        self._used
+    }
+    /// check if the cache is empty
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<
+        'a,
+        Hmap: hashmap::HashMap<E, K, V, CidT, Umeta, HB>,
+        E: user::EntryT<K, V, CidT, Umeta>,
+        K: user::Hash,
+        V: user::Val,
+        CidT: CidStamp,
+        Umeta: user::Meta<V>,
+        HB: ::std::hash::BuildHasher + Default,
+    > crate::cache::SharedCache<'a, Hmap, E, K, V, CidT, Umeta, HB>
+    for SampledShared<'a, Hmap, E, K, V, CidT, Umeta, HB>
+{
+    fn insert_shared(
+        &mut self,
+        hmap: &mut Hmap,
+        maybe_old_entry: Option<&mut E>,
+        new_entry_idx: usize,
+    ) -> InsertResultShared {
+        SampledShared::insert_shared(self, hmap, maybe_old_entry, new_entry_idx)
+    }
+    fn move_in_shared(
+        &mut self,
+        hmap: &mut Hmap,
+        idx: usize,
+    ) -> InsertResultShared {
+        SampledShared::move_in_shared(self, hmap, idx)
+    }
+    fn remove_shared(&mut self, hmap: &mut Hmap, idx: usize) {
+        SampledShared::remove_shared(self, hmap, idx)
+    }
+    fn remove_clashed(&mut self, hmap: &mut Hmap, old_entry: &E, idx: usize) {
+        SampledShared::remove_clashed(self, hmap, old_entry, idx)
+    }
+    fn clear_shared(&mut self) {
+        SampledShared::clear_shared(self)
+    }
+    fn on_get(&mut self, hmap: &mut Hmap, idx: usize) {
+        SampledShared::on_get(self, hmap, idx)
+    }
+    fn owns(&self, cid: CidT) -> bool {
+        cid == self._cache_id
+    }
+    fn victim(&self, hmap: &Hmap) -> Option<usize> {
+        SampledShared::victim(self, hmap)
+    }
+    fn relocate(&mut self, reloc: &hashmap::Relocation<E>) {
+        SampledShared::relocate(self, reloc)
+    }
+    fn set_scanf(
+        &mut self,
+        access_scan: Option<&'a dyn Fn(::std::ptr::NonNull<E>)>,
+    ) {
+        SampledShared::set_scanf(self, access_scan)
+    }
+    fn start_scan(&mut self) {
+        SampledShared::start_scan(self)
+    }
+    fn is_scan_running(&self) -> bool {
+        SampledShared::is_scan_running(self)
+    }
+    // hits only update the stamp, there is nothing to move
+    fn set_hit_policy(&mut self, _policy: crate::lru::HitPolicy) {}
+    fn get_hit_policy(&self) -> crate::lru::HitPolicy {
+        crate::lru::HitPolicy::Clock
+    }
+    fn set_capacity(
+        &mut self,
+        hmap: &mut Hmap,
+        entries: usize,
+    ) -> Removed<usize> {
+        SampledShared::set_capacity(self, hmap, entries)
+    }
+    fn capacity(&self) -> usize {
+        SampledShared::capacity(self)
+    }
+    fn len(&self) -> usize {
+        SampledShared::len(self)
+    }
+    // only counts entries
+    fn set_weighted(
+        &mut self,
+        _hmap: &Hmap,
+        weighted: bool,
+    ) -> Result<(), crate::results::Error> {
+        match weighted {
+            false => Ok(()),
+            true => Err(crate::results::Error::WeightUnsupported),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hashmap::user::EntryT;
+    use crate::testing::{Hasher, Key, Val};
+
+    type Cache<'a> = Sampled<'a, Key, Val, user::ZeroMeta, Hasher>;
+
+    // enough samples to see all the entries of a small cache
+    const ALL: usize = 256;
+
+    fn victim(cache: &Cache) -> Key {
+        let idx = cache.shared().victim(cache.hmap()).unwrap();
+        *cache.hmap().get_index(idx).unwrap().get_key()
+    }
+    fn evicted(res: Removed<(Key, Val, user::ZeroMeta)>) -> Vec<Key> {
+        res.iter().map(|(e, _)| e.0).collect()
+    }
+    fn expiration(entry: &SampledEntry<Key, Val, user::ZeroMeta>) -> u64 {
+        entry.get_val().0 as u64
+    }
+
+    #[test]
+    fn victim_is_the_next_eviction() {
+        let mut cache = Cache::new(8, 0, Hasher::default());
+        cache.set_samples(1);
+        for k in 0..8 {
+            cache.insert(Key(k), Val(k));
+        }
+        for k in 8..32 {
+            let expected = victim(&cache);
+            assert_eq!(evicted(cache.insert(Key(k), Val(k))), vec![expected]);
+            assert_eq!(cache.len(), 8);
+        }
+    }
+
+    #[test]
+    fn lru_scoring_evicts_the_oldest_access() {
+        let mut cache = Cache::new(4, 0, Hasher::default());
+        cache.set_samples(ALL);
+        for k in 0..4 {
+            cache.insert(Key(k), Val(k));
+        }
+        for k in [0, 1, 3] {
+            cache.get(&Key(k));
+        }
+        assert_eq!(victim(&cache), Key(2));
+        assert_eq!(evicted(cache.insert(Key(4), Val(4))), vec![Key(2)]);
+        assert_eq!(victim(&cache), Key(0));
+    }
+
+    #[test]
+    fn lfu_scoring_evicts_the_least_used() {
+        let mut cache = Cache::new(4, 0, Hasher::default());
+        cache.set_samples(ALL);
+        cache.set_scoring(Scoring::Lfu);
+        for k in 0..4 {
+            cache.insert(Key(k), Val(k));
+        }
+        for k in [0, 0, 1, 2, 2, 2] {
+            cache.get(&Key(k));
+        }
+        assert_eq!(victim(&cache), Key(3));
+        assert_eq!(evicted(cache.insert(Key(4), Val(4))), vec![Key(3)]);
+        // the new entry was never used
+        assert_eq!(victim(&cache), Key(4));
+    }
+
+    #[test]
+    fn ttl_first_scoring_evicts_the_first_to_expire() {
+        let mut cache = Cache::new(4, 0, Hasher::default());
+        cache.set_samples(ALL);
+        cache.set_scoring(Scoring::TtlFirst(&expiration));
+        for (k, expires) in [(0, 40), (1, 10), (2, 30), (3, 20)] {
+            cache.insert(Key(k), Val(expires));
+        }
+        // accesses do not matter
+        cache.get(&Key(1));
+        assert_eq!(victim(&cache), Key(1));
+        assert_eq!(evicted(cache.insert(Key(4), Val(50))), vec![Key(1)]);
+        assert_eq!(victim(&cache), Key(3));
+        assert!(!cache.shared().is_empty());
+    }
+}
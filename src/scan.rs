@@ -13,41 +13,57 @@
  * See the License for the specific language governing permissions and
  * limitations under the License.
  */
+use crate::cache::SharedCache;
+use crate::expiry;
+use crate::hashmap;
 use crate::hashmap::user;
+use crate::results::{RemovalCause, Removed};
 
+/// Lazy scan of a linked list of entries
+///
+/// Every time `apply_next` is called, "f" is run on one more entry, from
+/// the head towards the tail
 pub(crate) struct Scan<
     'a,
+    Hmap: hashmap::HashMap<E, K, V, Cid, Umeta, HB>,
     E: user::EntryT<K, V, Cid, Umeta>,
     K: user::Hash,
     V: user::Val,
     Cid: user::Cid,
     Umeta: user::Meta<V>,
+    HB: ::std::hash::BuildHasher + Default,
 > {
-    last: Option<::std::ptr::NonNull<E>>,
+    next: Option<E::Link>,
     f: Option<&'a dyn Fn(::std::ptr::NonNull<E>) -> ()>,
+    _hmap: ::std::marker::PhantomData<Hmap>,
     _k: ::std::marker::PhantomData<K>,
     _v: ::std::marker::PhantomData<V>,
     _cid: ::std::marker::PhantomData<Cid>,
     _umeta: ::std::marker::PhantomData<Umeta>,
+    _hb: ::std::marker::PhantomData<HB>,
 }
 
 impl<
         'a,
+        Hmap: hashmap::HashMap<E, K, V, Cid, Umeta, HB>,
         E: user::EntryT<K, V, Cid, Umeta>,
         K: user::Hash,
         V: user::Val,
         Cid: user::Cid,
         Umeta: user::Meta<V>,
-    > Scan<'a, E, K, V, Cid, Umeta>
+        HB: ::std::hash::BuildHasher + Default,
+    > Scan<'a, Hmap, E, K, V, Cid, Umeta, HB>
 {
     pub fn new(f: Option<&'a dyn Fn(::std::ptr::NonNull<E>) -> ()>) -> Self {
         Scan {
-            last: None,
+            next: None,
             f: f,
+            _hmap: ::std::marker::PhantomData,
             _k: ::std::marker::PhantomData,
             _v: ::std::marker::PhantomData,
             _cid: ::std::marker::PhantomData,
             _umeta: ::std::marker::PhantomData,
+            _hb: ::std::marker::PhantomData,
         }
     }
     pub fn set_scanf(
@@ -57,59 +73,164 @@ impl<
         self.f = f
     }
     pub fn is_running(&self) -> bool {
-        self.last != None
+        self.next != None
     }
-    pub fn start_scan(&mut self, entry: ::std::ptr::NonNull<E>) {
+    /// start scanning from `entry`. "f" will be applied on the next
+    /// `apply_next`
+    pub fn start_scan(&mut self, entry: E::Link) {
         if self.f.is_some() {
-            (self.f.unwrap())(entry);
-            self.last = Some(entry);
+            self.next = Some(entry);
         }
     }
     pub fn stop(&mut self) {
-        self.last = None;
+        self.next = None;
     }
     pub fn apply_raw(&self, entry: ::std::ptr::NonNull<E>) {
-        if self.f.is_some() {
-            (self.f.unwrap())(entry);
+        if let Some(f) = self.f {
+            f(entry);
         }
     }
-    /// Apply "f" to the entry in the tail, update the last node
-    pub fn apply_next(&mut self) {
-        if self.last == None || self.f.is_none() {
-            return;
-        }
-        let next_tail = unsafe { self.last.unwrap().as_mut().get_tail_ptr() };
-        match next_tail {
-            None => {
-                self.last = None;
-            }
-            Some(next) => {
-                (self.f.unwrap())(next);
-                self.last = Some(next);
+    /// Apply "f" to the next entry, move towards the tail
+    pub fn apply_next(&mut self, hmap: &mut Hmap) {
+        let (next, f) = match (self.next, self.f) {
+            (Some(next), Some(f)) => (next, f),
+            _ => {
+                self.next = None;
+                return;
             }
+        };
+        let entry = hmap.get_link_mut(next);
+        self.next = entry.get_tail();
+        f(entry.into());
+    }
+    /// When a node is removed or moved, check if it was the next node to scan.
+    /// In that case skip it and go to its tail
+    pub fn check_and_next(&mut self, hmap: &Hmap, entry: E::Link) {
+        if self.next == Some(entry) {
+            self.next = hmap.get_link(entry).get_tail();
         }
     }
-    /// When a node is removed, check if it was the "last" node we scanned.
-    /// In that case we will have to advance to the tail ptr and re-apply "f"
-    pub fn check_and_next(&mut self, entry: ::std::ptr::NonNull<E>) {
-        if self.f.is_none() {
-            return;
+    /// The hashmap has been resized, fix the next entry to scan
+    pub fn relocate(&mut self, reloc: &hashmap::Relocation<E>) {
+        self.next = self.next.and_then(|n| reloc.link(n));
+    }
+}
+
+/// Lazy reclaim of the expired entries
+///
+/// Just like [`Scan`], only one more entry is checked for each operation,
+/// so the expired entries are freed without a background thread.  
+/// The entries are visited in bucket order, so this works with every cache
+/// on the hashmap
+pub(crate) struct ExpiryScan<'a> {
+    next: usize,
+    active: bool,
+    monotonic: expiry::MonotonicTime,
+    time: Option<&'a dyn expiry::TimeSource>,
+}
+
+impl<'a> ExpiryScan<'a> {
+    pub fn new() -> Self {
+        ExpiryScan {
+            next: 0,
+            active: false,
+            monotonic: expiry::MonotonicTime::new(),
+            time: None,
         }
-        match self.last {
-            None => {}
-            Some(mut ptr_e) => {
-                if ptr_e == entry {
-                    match unsafe { ptr_e.as_mut().get_tail_ptr() } {
-                        None => {
-                            self.last = None;
-                        }
-                        Some(ptr_next) => {
-                            (self.f.unwrap())(ptr_next);
-                            self.last = Some(ptr_next);
-                        }
-                    }
-                }
-            }
+    }
+    /// use `time` instead of the monotonic clock
+    pub fn set_time_source(
+        &mut self,
+        time: Option<&'a dyn expiry::TimeSource>,
+    ) {
+        self.time = time;
+    }
+    pub fn now(&self) -> u64 {
+        match self.time {
+            Some(time) => time.now(),
+            None => expiry::TimeSource::now(&self.monotonic),
+        }
+    }
+    /// expiry instant of an entry that lives for `ttl` from now.
+    ///
+    /// Starts reclaiming the expired entries, which is useless until the
+    /// first entry with a TTL
+    pub fn deadline(&mut self, ttl: ::std::time::Duration) -> u64 {
+        self.active = true;
+        self.now().saturating_add(expiry::nanos(ttl))
+    }
+    /// An entry with `expiry` has been inserted: if it can expire, start
+    /// reclaiming the expired entries, like [`deadline`](Self::deadline)
+    pub fn watch(&mut self, expiry: Option<u64>) {
+        if expiry.is_some() {
+            self.active = true;
+        }
+    }
+    pub fn is_expired(&self, expiry: Option<u64>) -> bool {
+        match expiry {
+            None => false,
+            Some(deadline) => deadline <= self.now(),
         }
     }
+    /// If the entry at `idx` is expired, remove it from `cache` and from the
+    /// hashmap
+    pub fn remove_expired<'c, Hmap, E, K, V, Cid, Umeta, HB, C>(
+        &self,
+        hmap: &mut Hmap,
+        cache: &mut C,
+        idx: usize,
+    ) -> Option<(K, V, Umeta)>
+    where
+        Hmap: hashmap::HashMap<E, K, V, Cid, Umeta, HB>,
+        E: user::EntryT<K, V, Cid, Umeta>,
+        K: user::Hash,
+        V: user::Val,
+        Cid: user::Cid,
+        Umeta: user::Meta<V>,
+        HB: ::std::hash::BuildHasher + Default,
+        C: SharedCache<'c, Hmap, E, K, V, Cid, Umeta, HB>,
+    {
+        let expiry = hmap.get_index(idx)?.get_user().get_expiry();
+        if !self.is_expired(expiry) {
+            return None;
+        }
+        cache.remove_shared(hmap, idx);
+        Some(hmap.remove_idx(idx).deconstruct())
+    }
+    /// Check the next bucket, and remove its entry if it is expired
+    pub fn reclaim<'c, Hmap, E, K, V, Cid, Umeta, HB, C>(
+        &mut self,
+        hmap: &mut Hmap,
+        cache: &mut C,
+    ) -> Removed<(K, V, Umeta)>
+    where
+        Hmap: hashmap::HashMap<E, K, V, Cid, Umeta, HB>,
+        E: user::EntryT<K, V, Cid, Umeta>,
+        K: user::Hash,
+        V: user::Val,
+        Cid: user::Cid,
+        Umeta: user::Meta<V>,
+        HB: ::std::hash::BuildHasher + Default,
+        C: SharedCache<'c, Hmap, E, K, V, Cid, Umeta, HB>,
+    {
+        if !self.active || hmap.len() == 0 {
+            return Removed::new();
+        }
+        // one bucket per call, even if it is empty, so that the cost of an
+        // operation does not depend on how sparse the hashmap is
+        if self.next >= hmap.buckets() {
+            self.next = 0;
+        }
+        let idx = self.next;
+        self.next += 1;
+        match self.remove_expired(hmap, cache, idx) {
+            None => Removed::new(),
+            Some(entry) => Removed::single(entry, RemovalCause::Expired),
+        }
+    }
+    /// The hashmap has been resized and the bucket order is lost.  
+    /// Restart from the beginning
+    pub fn relocate(&mut self) {
+        self.next = 0;
+    }
 }
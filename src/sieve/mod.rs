@@ -0,0 +1,483 @@
+/*
+ * Copyright 2021 Luca Fulchir <luca@fenrirproject.org>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! SIEVE cache
+//!
+//! A FIFO queue with a "hand" that moves from the tail to the head.  
+//! A hit only sets the visited bit of the entry, it never moves it.
+//! On eviction the hand skips the visited entries, clearing their bit, and
+//! evicts the first one that was not visited. Unlike CLOCK the skipped
+//! entries stay where they are, so the new entries near the head are
+//! evicted quickly if they are not hit, while the popular ones stay in the
+//! old part of the queue.
+//!
+//! The visited bit is the reference bit of the [`user::Cid`], like
+//! [`ClockCid`](crate::clock::ClockCid).
+//!
+//! See the [original paper](https://www.usenix.org/conference/nsdi24/presentation/zhang-yazhuo)
+
+use crate::clock::ClockCid;
+use crate::hashmap;
+use crate::hashmap::user;
+use crate::owned::Owned;
+use crate::results::{InsertResultShared, RemovalCause, Removed};
+
+type SieveEntry<K, V, Umeta> = user::Entry<K, V, ClockCid, Umeta>;
+type HmapT<K, V, Umeta, HB> =
+    hashmap::SimpleHmap<SieveEntry<K, V, Umeta>, K, V, ClockCid, Umeta, HB>;
+
+/// [SIEVE](https://cachemon.github.io/SIEVE-website/)
+/// implementation that wraps SieveShared, see [`Owned`]
+pub type Sieve<'a, K, V, Umeta, HB> = Owned<
+    'a,
+    K,
+    V,
+    ClockCid,
+    Umeta,
+    HB,
+    SieveShared<
+        'a,
+        HmapT<K, V, Umeta, HB>,
+        SieveEntry<K, V, Umeta>,
+        K,
+        V,
+        ClockCid,
+        Umeta,
+        HB,
+    >,
+>;
+
+impl<
+        'a,
+        K: user::Hash,
+        V: user::Val,
+        Umeta: user::Meta<V>,
+        HB: ::std::hash::BuildHasher + Default,
+    > Sieve<'a, K, V, Umeta, HB>
+{
+    /// new SIEVE with space for `entries` elements
+    pub fn new(
+        entries: usize,
+        extra_hashmap_capacity: usize,
+        hash_builder: HB,
+    ) -> Self {
+        Owned::from_shared(
+            SieveShared::new(entries, ClockCid::new(1), None),
+            extra_hashmap_capacity,
+            hash_builder,
+        )
+    }
+}
+
+/// Actual implementation of SIEVE on a shared hashmap
+///
+/// The queue is an [`LRUShared`](crate::lru::LRUShared) with
+/// [`HitPolicy::Clock`](crate::lru::HitPolicy::Clock), so a hit only sets
+/// the visited bit. The evictions are decided by the hand, not by the queue.
+///
+/// Note that Insert/Remove do not actually insert anything in the hashmap.
+/// That must be done by the caller. We only fix all the pointers and SIEVE
+/// status
+pub struct SieveShared<'a, Hmap, E, K, V, CidT, Umeta, HB>
+where
+    Hmap: hashmap::HashMap<E, K, V, CidT, Umeta, HB>,
+    E: user::EntryT<K, V, CidT, Umeta>,
+    K: user::Hash,
+    V: user::Val,
+    CidT: user::Cid,
+    Umeta: user::Meta<V>,
+    HB: ::std::hash::BuildHasher + Default,
+{
+    _fifo: crate::lru::LRUShared<'a, Hmap, E, K, V, CidT, Umeta, HB>,
+    // next entry checked for eviction. `None` means the tail
+    _hand: Option<usize>,
+}
+
+impl<
+        'a,
+        Hmap: hashmap::HashMap<E, K, V, CidT, Umeta, HB>,
+        E: user::EntryT<K, V, CidT, Umeta>,
+        K: user::Hash,
+        V: user::Val,
+        CidT: user::Cid,
+        Umeta: user::Meta<V>,
+        HB: ::std::hash::BuildHasher + Default,
+    > SieveShared<'a, Hmap, E, K, V, CidT, Umeta, HB>
+{
+    /// new SIEVE with space for `entries` elements, its cache id and an
+    /// optional callback for the lazy scan
+    ///
+    /// The cache id must be able to store the reference bit, used as the
+    /// visited bit. See [`user::Cid`]
+    pub fn new(
+        entries: usize,
+        cache_id: CidT,
+        access_scan: Option<&'a dyn Fn(::std::ptr::NonNull<E>)>,
+    ) -> Self {
+        let mut fifo =
+            crate::lru::LRUShared::new(entries, cache_id, access_scan);
+        fifo.set_hit_policy(crate::lru::HitPolicy::Clock);
+        SieveShared {
+            _fifo: fifo,
+            _hand: None,
+        }
+    }
+    /// change the scan callback
+    pub fn set_scanf(
+        &mut self,
+        access_scan: Option<&'a dyn Fn(::std::ptr::NonNull<E>)>,
+    ) {
+        self._fifo.set_scanf(access_scan)
+    }
+    /// An element has been added by the caller, fix the SIEVE
+    ///
+    /// `maybe_old_entry` must be `!= None` only if the clash happened in
+    /// this cache
+    pub fn insert_shared(
+        &mut self,
+        hmap: &mut Hmap,
+        maybe_old_entry: Option<&mut E>,
+        new_entry_idx: usize,
+    ) -> InsertResultShared {
+        match maybe_old_entry {
+            None => self.push(hmap, new_entry_idx, true),
+            Some(old_entry)
+                if old_entry.get_key()
+                    == hmap.get_index(new_entry_idx).unwrap().get_key() =>
+            {
+                // same key, just a new value. The entry goes to the head of
+                // the queue, so the hand can not point to it anymore
+                if self._hand == Some(new_entry_idx) {
+                    self._hand =
+                        old_entry.get_head().map(|h| hmap.index_from_link(h));
+                }
+                self._fifo
+                    .insert_shared(hmap, Some(old_entry), new_entry_idx)
+            }
+            Some(old_entry) => {
+                // a different key took the place of the old entry, which is
+                // not in the cache anymore
+                self.remove_clashed(hmap, old_entry, new_entry_idx);
+                self.push(hmap, new_entry_idx, true)
+            }
+        }
+    }
+    /// An entry moved here from another cache on the same hashmap.
+    ///
+    /// Like a new insert, but without running the on-insert callback
+    pub fn move_in_shared(
+        &mut self,
+        hmap: &mut Hmap,
+        idx: usize,
+    ) -> InsertResultShared {
+        self.push(hmap, idx, false)
+    }
+    // evict if full, then add the entry at the head of the queue
+    fn push(
+        &mut self,
+        hmap: &mut Hmap,
+        idx: usize,
+        run_callback: bool,
+    ) -> InsertResultShared {
+        let evicted = if self.len() >= self.capacity() {
+            self.evict(hmap)
+        } else {
+            None
+        };
+        match run_callback {
+            true => self._fifo.insert_shared(hmap, None, idx),
+            false => self._fifo.move_in_shared(hmap, idx),
+        };
+        match evicted {
+            None => Removed::new(),
+            Some(evicted) => Removed::single(evicted, RemovalCause::Capacity),
+        }
+    }
+    // move the hand towards the head until an entry that was not visited is
+    // found, clearing the visited bits on the way.
+    // The entry is still in the hashmap, its index is returned
+    fn evict(&mut self, hmap: &mut Hmap) -> Option<usize> {
+        let mut idx = self._hand.or_else(|| self._fifo.tail(hmap))?;
+        // after a full round no entry is visited anymore
+        for _ in 0..self.len() {
+            let cid = hmap.get_index_mut(idx).unwrap().get_cache_id_mut();
+            if !cid.get_reference() {
+                break;
+            }
+            cid.set_reference(false);
+            idx = match self._fifo.next_to_head(hmap, idx) {
+                Some(next) => next,
+                None => self._fifo.tail(hmap)?,
+            };
+        }
+        self._hand = self._fifo.next_to_head(hmap, idx);
+        self._fifo.remove_shared(hmap, idx);
+        Some(idx)
+    }
+    /// The index of the entry that will be evicted by the next insert, if
+    /// the SIEVE is full
+    pub fn victim(&self, hmap: &Hmap) -> Option<usize> {
+        if self.len() < self.capacity() {
+            return None;
+        }
+        let start = self._hand.or_else(|| self._fifo.tail(hmap))?;
+        let mut idx = start;
+        for _ in 0..self.len() {
+            if !hmap.get_index(idx).unwrap().get_cache_id().get_reference() {
+                return Some(idx);
+            }
+            idx = match self._fifo.next_to_head(hmap, idx) {
+                Some(next) => next,
+                None => self._fifo.tail(hmap)?,
+            };
+        }
+        // all visited: the hand will clear them all and come back here
+        Some(start)
+    }
+    /// reset the SIEVE
+    pub fn clear_shared(&mut self) {
+        self._fifo.clear_shared();
+        self._hand = None;
+    }
+    /// do not actually remove the element at `idx`, just fix the SIEVE so
+    /// that it is not considered anymore and actual removal is safe
+    pub fn remove_shared(&mut self, hmap: &mut Hmap, idx: usize) {
+        if self._hand == Some(idx) {
+            self._hand = self._fifo.next_to_head(hmap, idx);
+        }
+        self._fifo.remove_shared(hmap, idx)
+    }
+    /// `old_entry` was in the SIEVE, but the entry at `idx` clashed with it
+    /// and took its place in the hashmap. Forget about the old entry
+    pub fn remove_clashed(
+        &mut self,
+        hmap: &mut Hmap,
+        old_entry: &E,
+        idx: usize,
+    ) {
+        if self._hand == Some(idx) {
+            self._hand = old_entry.get_head().map(|h| hmap.index_from_link(h));
+        }
+        self._fifo.remove_clashed(hmap, old_entry, idx)
+    }
+    /// Change the number of entries of the SIEVE
+    ///
+    /// When shrinking, the evicted entries are returned.  
+    /// Just like with [`InsertResultShared`] they are still in the
+    /// hashmap and must be removed by the caller
+    pub fn set_capacity(
+        &mut self,
+        hmap: &mut Hmap,
+        entries: usize,
+    ) -> Removed<usize> {
+        let mut evicted = Removed::new();
+        while self.len() > entries {
+            match self.evict(hmap) {
+                Some(idx) => evicted.push(idx, RemovalCause::Capacity),
+                None => break,
+            }
+        }
+        // not over the capacity anymore, this does not evict
+        self._fifo.set_capacity(hmap, entries);
+        evicted
+    }
+    /// The hashmap has been resized and all the entries have moved.
+    ///
+    /// Fix the queue and the hand
+    pub fn relocate(&mut self, reloc: &hashmap::Relocation<E>) {
+        self._hand = self._hand.and_then(|h| reloc.index(h));
+        self._fifo.relocate(reloc);
+    }
+    /// get the SIEVE cache id
+    pub fn get_cache_id(&self) -> CidT {
+        self._fifo.get_cache_id()
+    }
+    /// Used when composing caches: set the visited bit and run the callback
+    /// on the entry at `idx`
+    pub fn on_get(&mut self, hmap: &mut Hmap, idx: usize) {
+        self._fifo.on_get(hmap, idx)
+    }
+    /// start the lazy scan  
+    /// The scan will execute on the whole SIEVE but only once
+    pub fn start_scan(&mut self) {
+        self._fifo.start_scan()
+    }
+    /// check if the scan is still running
+    pub fn is_scan_running(&self) -> bool {
+        self._fifo.is_scan_running()
+    }
+    /// get the SIEVE capacity
+    pub fn capacity(&self) -> usize {
+        self._fifo.capacity()
+    }
+    /// get the SIEVE usage
+    pub fn len(&self) -> usize {
+        self._fifo.len()
+    }
+    /// check if the SIEVE is empty
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<
+        'a,
+        Hmap: hashmap::HashMap<E, K, V, CidT, Umeta, HB>,
+        E: user::EntryT<K, V, CidT, Umeta>,
+        K: user::Hash,
+        V: user::Val,
+        CidT: user::Cid,
+        Umeta: user::Meta<V>,
+        HB: ::std::hash::BuildHasher + Default,
+    > crate::cache::SharedCache<'a, Hmap, E, K, V, CidT, Umeta, HB>
+    for SieveShared<'a, Hmap, E, K, V, CidT, Umeta, HB>
+{
+    fn insert_shared(
+        &mut self,
+        hmap: &mut Hmap,
+        maybe_old_entry: Option<&mut E>,
+        new_entry_idx: usize,
+    ) -> InsertResultShared {
+        SieveShared::insert_shared(self, hmap, maybe_old_entry, new_entry_idx)
+    }
+    fn move_in_shared(
+        &mut self,
+        hmap: &mut Hmap,
+        idx: usize,
+    ) -> InsertResultShared {
+        SieveShared::move_in_shared(self, hmap, idx)
+    }
+    fn remove_shared(&mut self, hmap: &mut Hmap, idx: usize) {
+        SieveShared::remove_shared(self, hmap, idx)
+    }
+    fn remove_clashed(&mut self, hmap: &mut Hmap, old_entry: &E, idx: usize) {
+        SieveShared::remove_clashed(self, hmap, old_entry, idx)
+    }
+    fn clear_shared(&mut self) {
+        SieveShared::clear_shared(self)
+    }
+    fn on_get(&mut self, hmap: &mut Hmap, idx: usize) {
+        SieveShared::on_get(self, hmap, idx)
+    }
+    fn owns(&self, cid: CidT) -> bool {
+        cid == self._fifo.get_cache_id()
+    }
+    fn victim(&self, hmap: &Hmap) -> Option<usize> {
+        SieveShared::victim(self, hmap)
+    }
+    fn relocate(&mut self, reloc: &hashmap::Relocation<E>) {
+        SieveShared::relocate(self, reloc)
+    }
+    fn set_scanf(
+        &mut self,
+        access_scan: Option<&'a dyn Fn(::std::ptr::NonNull<E>)>,
+    ) {
+        SieveShared::set_scanf(self, access_scan)
+    }
+    fn start_scan(&mut self) {
+        SieveShared::start_scan(self)
+    }
+    fn is_scan_running(&self) -> bool {
+        SieveShared::is_scan_running(self)
+    }
+    // SIEVE always works with the visited bit
+    fn set_hit_policy(&mut self, _policy: crate::lru::HitPolicy) {}
+    fn get_hit_policy(&self) -> crate::lru::HitPolicy {
+        crate::lru::HitPolicy::Clock
+    }
+    fn set_capacity(
+        &mut self,
+        hmap: &mut Hmap,
+        entries: usize,
+    ) -> Removed<usize> {
+        SieveShared::set_capacity(self, hmap, entries)
+    }
+    fn capacity(&self) -> usize {
+        SieveShared::capacity(self)
+    }
+    fn len(&self) -> usize {
+        SieveShared::len(self)
+    }
+    // only counts entries
+    fn set_weighted(
+        &mut self,
+        _hmap: &Hmap,
+        weighted: bool,
+    ) -> Result<(), crate::results::Error> {
+        match weighted {
+            false => Ok(()),
+            true => Err(crate::results::Error::WeightUnsupported),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hashmap::user::EntryT;
+    use crate::testing::{Hasher, Key, Val};
+
+    type Cache<'a> = Sieve<'a, Key, Val, user::ZeroMeta, Hasher>;
+
+    fn victim(cache: &Cache) -> Key {
+        let idx = cache.shared().victim(cache.hmap()).unwrap();
+        *cache.hmap().get_index(idx).unwrap().get_key()
+    }
+    fn evicted(res: Removed<(Key, Val, user::ZeroMeta)>) -> Vec<Key> {
+        res.iter().map(|(e, _)| e.0).collect()
+    }
+
+    #[test]
+    fn visited_entries_survive_the_hand() {
+        let mut cache = Cache::new(4, 0, Hasher::default());
+        for k in 0..4 {
+            cache.insert(Key(k), Val(k));
+        }
+        cache.get(&Key(1));
+        cache.get(&Key(3));
+        assert_eq!(victim(&cache), Key(0));
+        assert_eq!(evicted(cache.insert(Key(4), Val(4))), vec![Key(0)]);
+        // the hand skips 1 and clears its bit
+        assert_eq!(victim(&cache), Key(2));
+        assert_eq!(evicted(cache.insert(Key(5), Val(5))), vec![Key(2)]);
+        assert!(cache.contains_key(&Key(1)));
+        // the hand does not go back to the tail: 3 is skipped, 4 goes
+        assert_eq!(victim(&cache), Key(4));
+        assert_eq!(evicted(cache.insert(Key(6), Val(6))), vec![Key(4)]);
+        // the hand keeps moving to the head before wrapping to 1
+        assert_eq!(victim(&cache), Key(5));
+        assert_eq!(evicted(cache.insert(Key(7), Val(7))), vec![Key(5)]);
+        assert!(cache.contains_key(&Key(1)));
+        assert_eq!(cache.len(), 4);
+    }
+
+    #[test]
+    fn hand_wraps_around_when_all_are_visited() {
+        let mut cache = Cache::new(4, 0, Hasher::default());
+        for k in 0..4 {
+            cache.insert(Key(k), Val(k));
+            cache.get(&Key(k));
+        }
+        assert_eq!(victim(&cache), Key(0));
+        assert_eq!(evicted(cache.insert(Key(4), Val(4))), vec![Key(0)]);
+        // all the bits were cleared, the next ones go in FIFO order
+        for k in 1..4 {
+            assert_eq!(victim(&cache), Key(k));
+            assert_eq!(evicted(cache.insert(Key(k + 4), Val(k))), vec![Key(k)]);
+        }
+        assert!(!cache.shared().is_empty());
+    }
+}
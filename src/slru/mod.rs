@@ -22,35 +22,40 @@
 //! get/insert  
 //! This means that X elements will be fully scanned only after X get/insert
 
+use crate::expiry::wheel;
 use crate::hashmap;
 use crate::hashmap::user;
-use crate::hashmap::user::EntryT;
-use crate::results::{InsertResult, InsertResultShared};
+use crate::owned::Owned;
+use crate::results::{InsertResultShared, RemovalCause, Removed};
 
+/// entry not in the SLRU yet
 #[derive(Clone, Copy, PartialEq, Eq)]
-struct SLRUCidNone {}
+pub struct SLRUCidNone {}
 impl Default for SLRUCidNone {
     fn default() -> Self {
         SLRUCidNone {}
     }
 }
+/// entry in the probation LRU
 #[derive(Clone, Copy, PartialEq, Eq)]
-struct SLRUCidProbation {}
+pub struct SLRUCidProbation {}
 impl Default for SLRUCidProbation {
     fn default() -> Self {
         SLRUCidProbation {}
     }
 }
+/// entry in the protected LRU
 #[derive(Clone, Copy, PartialEq, Eq)]
-struct SLRUCidProtected {}
+pub struct SLRUCidProtected {}
 impl Default for SLRUCidProtected {
     fn default() -> Self {
         SLRUCidProtected {}
     }
 }
 
+/// cache id of the entries of the owning [`SLRU`]
 #[derive(Clone, Copy, PartialEq, Eq)]
-enum SLRUCid {
+pub enum SLRUCid {
     None(SLRUCidNone),
     Probation(SLRUCidProbation),
     Protected(SLRUCidProtected),
@@ -67,7 +72,7 @@ type HmapT<K, V, Umeta, HB> =
     hashmap::SimpleHmap<SLRUEntry<K, V, Umeta>, K, V, SLRUCid, Umeta, HB>;
 
 /// [SLRU](https://en.wikipedia.org/wiki/Cache_replacement_policies#Segmented_LRU_(SLRU))
-/// implementation
+/// implementation, see [`Owned`]
 ///
 /// SLRU is a Segmented LRU it consists of two LRU:
 ///  * probation LRU: for items that have been just added
@@ -75,15 +80,14 @@ type HmapT<K, V, Umeta, HB> =
 ///
 /// W-TinyLFU specifies an 20-80 split, with 80% for the probation LRU
 /// You can define your own slit here
-pub struct SLRU<'a, K, V, Umeta, HB>
-where
-    K: user::Hash,
-    V: user::Val,
-    Umeta: user::Meta<V>,
-    HB: ::std::hash::BuildHasher + Default,
-{
-    _hmap: HmapT<K, V, Umeta, HB>,
-    _slru: SLRUShared<
+pub type SLRU<'a, K, V, Umeta, HB> = Owned<
+    'a,
+    K,
+    V,
+    SLRUCid,
+    Umeta,
+    HB,
+    SLRUShared<
         'a,
         HmapT<K, V, Umeta, HB>,
         SLRUEntry<K, V, Umeta>,
@@ -93,7 +97,7 @@ where
         Umeta,
         HB,
     >,
-}
+>;
 
 impl<
         'a,
@@ -111,23 +115,8 @@ impl<
         extra_hashmap_capacity: usize,
         hash_builder: HB,
     ) -> Self {
-        SLRU {
-            _hmap: HmapT::<K, V, Umeta, HB>::with_capacity_and_hasher(
-                1 + probation_entries
-                    + protected_entries
-                    + extra_hashmap_capacity,
-                hash_builder,
-            ),
-            _slru: SLRUShared::<
-                'a,
-                HmapT<K, V, Umeta, HB>,
-                SLRUEntry<K, V, Umeta>,
-                K,
-                V,
-                SLRUCid,
-                Umeta,
-                HB,
-            >::new(
+        Owned::from_shared(
+            SLRUShared::new(
                 (
                     probation_entries,
                     SLRUCid::Probation(SLRUCidProbation::default()),
@@ -138,103 +127,24 @@ impl<
                 ),
                 None,
             ),
-        }
-    }
-    /// insert a new element. Can return a clash
-    pub fn insert(&mut self, key: K, val: V) -> InsertResult<(K, V, Umeta)> {
-        self.insert_with_meta(key, val, Umeta::new())
+            extra_hashmap_capacity,
+            hash_builder,
+        )
     }
-    /// insert a new element, but with metadata
-    pub fn insert_with_meta(
+    /// Change the number of entries for the probatory and protected splits
+    ///
+    /// If the SLRU shrinks, the evicted entries are returned
+    pub fn set_split(
         &mut self,
-        key: K,
-        val: V,
-        user_data: Umeta,
-    ) -> InsertResult<(K, V, Umeta)> {
-        let e = user::Entry::<K, V, SLRUCid, Umeta>::new_entry(
-            None,
-            None,
-            key.clone(),
-            val,
-            SLRUCid::default(),
-            user_data,
-        );
-        // insert and get length and a ref to the value just inserted
-        // we will use this ref to fix the linked lists in ll_tail/ll_head
-        // of the various elements
-        let (mut maybe_old_entry, new_entry_idx, _new_entry) =
-            self._hmap.insert(e);
-        let maybe_ref_old = maybe_old_entry.as_mut();
-        match self._slru.insert_shared(
-            &mut self._hmap,
-            maybe_ref_old,
-            new_entry_idx,
-        ) {
-            InsertResultShared::OldEntry { evicted } => {
-                let c = match maybe_old_entry {
-                    None => None,
-                    Some(x) => Some(x.deconstruct()),
-                };
-                let e = match evicted {
-                    None => None,
-                    Some(x) => Some(x.deconstruct()),
-                };
-                InsertResult::OldEntry {
-                    clash: c,
-                    evicted: e,
-                }
-            }
-            InsertResultShared::OldTailPtr { evicted } => {
-                let c = match maybe_old_entry {
-                    None => None,
-                    Some(x) => Some(x.deconstruct()),
-                };
-                let removed = self._hmap.remove(unsafe { &*evicted.as_ptr() });
-                InsertResult::OldTail {
-                    clash: c,
-                    evicted: removed.deconstruct(),
-                }
-            }
-            InsertResultShared::Success => InsertResult::Success,
-        }
-    }
-
-    /// remove an element
-    pub fn remove(&mut self, key: &K) -> Option<(V, Umeta)> {
-        let (idx, entry) = match self._hmap.get_full(key) {
-            None => return None,
-            Some((idx, entry)) => (idx, entry),
-        };
-        self._slru.remove_shared(entry);
-        let (_, val, meta) = self._hmap.remove_idx(idx).deconstruct();
-        Some((val, meta))
-    }
-    /// clear out all the SLRU
-    pub fn clear(&mut self) {
-        self._hmap.clear();
-        self._slru.clear_shared()
-    }
-    /// Get references to the element's data
-    pub fn get(&mut self, key: &K) -> Option<(&V, &Umeta)> {
-        match self._hmap.get_full_mut(key) {
-            None => None,
-            Some((_, entry)) => {
-                self._slru.on_get(entry);
-                Some((entry.get_val(), entry.get_user()))
-            }
-        }
-    }
-    /// get a mutable reference to the element's data
-    pub fn get_mut(&mut self, key: &K) -> Option<(&mut V, &mut Umeta)> {
-        match self._hmap.get_full_mut(key) {
-            None => None,
-            Some((_, entry)) => {
-                self._slru.on_get(entry);
-                Some(entry.get_val_user_mut())
-            }
-        }
+        probation_entries: usize,
+        protected_entries: usize,
+    ) -> Removed<(K, V, Umeta)> {
+        self.resize_with(|hmap, slru| {
+            slru.set_capacity(hmap, probation_entries, protected_entries)
+        })
     }
 }
+
 #[derive(PartialEq, Eq)]
 enum ScanStatus {
     Stopped,
@@ -261,6 +171,9 @@ where
     _probation: crate::lru::LRUShared<'a, Hmap, E, K, V, CidT, Umeta, HB>,
     _protected: crate::lru::LRUShared<'a, Hmap, E, K, V, CidT, Umeta, HB>,
     _scanstatus: ScanStatus,
+    _idle: Option<wheel::TimerWheel>,
+    // time of the current operation, where the idle timers start
+    _now: u64,
 }
 
 impl<
@@ -307,6 +220,8 @@ impl<
                 protected.0, protected.1, access_scan
             ),
             _scanstatus: ScanStatus::Stopped,
+            _idle: None,
+            _now: 0,
         }
     }
     /// change the scan callback
@@ -317,6 +232,15 @@ impl<
         self._probation.set_scanf(access_scan);
         self._protected.set_scanf(access_scan)
     }
+    /// change what happens on a cache hit, in both probation and protected
+    pub fn set_hit_policy(&mut self, policy: crate::lru::HitPolicy) {
+        self._probation.set_hit_policy(policy);
+        self._protected.set_hit_policy(policy);
+    }
+    /// get the current hit policy
+    pub fn get_hit_policy(&self) -> crate::lru::HitPolicy {
+        self._probation.get_hit_policy()
+    }
     /// an itam has been inserted by the caller, fix the SLRU
     ///
     /// `maybe_old_entry` must be `!= None` only if the element is in the SLRU
@@ -325,32 +249,32 @@ impl<
         hmap: &mut Hmap,
         maybe_old_entry: Option<&mut E>,
         new_entry_idx: usize,
-    ) -> InsertResultShared<E> {
-        let just_inserted = hmap.get_index_mut(new_entry_idx).unwrap();
-        if just_inserted.get_cache_id() == self._probation.get_cache_id() {
+    ) -> InsertResultShared {
+        let res = self.insert_segment(hmap, maybe_old_entry, new_entry_idx);
+        self.touch_idle(new_entry_idx, &res);
+        res
+    }
+    // put the new entry in probation or protected
+    fn insert_segment(
+        &mut self,
+        hmap: &mut Hmap,
+        maybe_old_entry: Option<&mut E>,
+        new_entry_idx: usize,
+    ) -> InsertResultShared {
+        let cid = hmap.get_index(new_entry_idx).unwrap().get_cache_id();
+        if cid == self._probation.get_cache_id() {
             // inserted twice. promote to protected
             // Note that since we already found the key, we can not have had any
             // clash (maybe_old_entry == None)
-            self._probation.remove_shared(just_inserted);
-            let res = match self._protected.insert_shared(
-                hmap,
-                None,
-                new_entry_idx,
-            ) {
-                InsertResultShared::OldTailPtr { evicted } => {
-                    // clash is always None
-                    // when an insert causes a tail eviction in the protected
-                    // segment, that has to be re-inserted in the probatory
-                    self._probation.insert_shared(hmap, None, unsafe {
-                        hmap.index_from_entry(&*evicted.as_ptr())
-                    })
-                }
-                r @ _ => r,
-            };
+            self._probation.remove_shared(hmap, new_entry_idx);
+            // when an insert causes tail evictions in the protected
+            // segment, those have to be re-inserted in the probatory
+            let demoted =
+                self._protected.insert_shared(hmap, None, new_entry_idx);
+            let res = self.demote(hmap, demoted);
             self.update_scan_status();
             res
-        } else if just_inserted.get_cache_id() == self._protected.get_cache_id()
-        {
+        } else if cid == self._protected.get_cache_id() {
             // inserted more than once, in protected
             // Note that since we already found the key, we can not have had any
             // clash (maybe_old_entry == None) and there will be no cache
@@ -363,14 +287,20 @@ impl<
             // We might have had a clash, but we will insert into probation
             match maybe_old_entry {
                 None => {
-                    self._probation.insert_shared(hmap, None, new_entry_idx)
+                    let res = self._probation.insert_shared(
+                        hmap,
+                        None,
+                        new_entry_idx,
+                    );
+                    self.update_scan_status();
+                    res
                 }
                 Some(old_entry) => {
                     // old_entry might be either in probation or protected.
-                    // But the only way for us to have an "old_entry" instead of
-                    // None is to have generated a clash on insert.
-                    // This means that "just_inserted" is a new key, not an old
-                    // one, or we would not have had the clash.
+                    // A new value for the same key stays in its segment, a
+                    // new key that clashed goes to probation
+                    let same_key = old_entry.get_key()
+                        == hmap.get_index(new_entry_idx).unwrap().get_key();
                     if old_entry.get_cache_id()
                         == self._probation.get_cache_id()
                     {
@@ -382,8 +312,23 @@ impl<
                         );
                         self.update_scan_status();
                         res
+                    } else if same_key {
+                        // with weights a heavier value can push entries out
+                        // of protected
+                        let demoted = self._protected.insert_shared(
+                            hmap,
+                            Some(old_entry),
+                            new_entry_idx,
+                        );
+                        let res = self.demote(hmap, demoted);
+                        self.update_scan_status();
+                        res
                     } else {
-                        self._protected.remove_shared(&old_entry);
+                        self._protected.remove_clashed(
+                            hmap,
+                            old_entry,
+                            new_entry_idx,
+                        );
                         let res = self._probation.insert_shared(
                             hmap,
                             None,
@@ -396,22 +341,93 @@ impl<
             }
         }
     }
+    /// An entry moved here from another cache on the same hashmap.
+    ///
+    /// It goes in probation, like a new insert, but without running the
+    /// on-insert callback.  
+    /// If probation is full its tail is evicted, even if protected has free
+    /// space: only [`set_capacity`](Self::set_capacity) changes the split
+    pub fn move_in_shared(
+        &mut self,
+        hmap: &mut Hmap,
+        idx: usize,
+    ) -> InsertResultShared {
+        let res = self._probation.move_in_shared(hmap, idx);
+        self.track_idle(idx, &res);
+        self.update_scan_status();
+        res
+    }
+    // move the tails evicted from protected to probation, and return the
+    // entries evicted from probation
+    fn demote(
+        &mut self,
+        hmap: &mut Hmap,
+        demoted: Removed<usize>,
+    ) -> Removed<usize> {
+        let mut evicted = Removed::new();
+        for (idx, _) in demoted {
+            evicted.append(self._probation.move_in_shared(hmap, idx));
+        }
+        evicted
+    }
+    /// The index of the entry that will be evicted by the next insert, if
+    /// probation is full
+    pub fn probation_victim(&self, hmap: &Hmap) -> Option<usize> {
+        if self._probation.weight() < self._probation.capacity() {
+            return None;
+        }
+        self._probation.tail(hmap)
+    }
     /// Reset the SLRU state
     pub fn clear_shared(&mut self) {
         self._probation.clear_shared();
         self._protected.clear_shared();
         self._scanstatus = ScanStatus::Stopped;
+        if let Some(wheel) = self._idle.as_mut() {
+            wheel.clear();
+        }
     }
-    /// do not actually remove the element, just fix the SLRU so that it is not
-    /// considered anymore and actual removal is safe
-    pub fn remove_shared(&mut self, entry: &E) {
-        let res = if entry.get_cache_id() == self._probation.get_cache_id() {
-            self._probation.remove_shared(entry)
+    /// do not actually remove the element at `idx`, just fix the SLRU so that
+    /// it is not considered anymore and actual removal is safe
+    pub fn remove_shared(&mut self, hmap: &mut Hmap, idx: usize) {
+        let cid = hmap.get_index(idx).unwrap().get_cache_id();
+        if cid == self._probation.get_cache_id() {
+            self._probation.remove_shared(hmap, idx)
         } else {
-            self._protected.remove_shared(entry)
+            self._protected.remove_shared(hmap, idx)
         };
+        if let Some(wheel) = self._idle.as_mut() {
+            wheel.cancel(idx);
+        }
+        self.update_scan_status();
+    }
+    /// `old_entry` was in the SLRU, but the entry at `idx` clashed with it and
+    /// took its place in the hashmap. Forget about the old entry
+    pub fn remove_clashed(
+        &mut self,
+        hmap: &mut Hmap,
+        old_entry: &E,
+        idx: usize,
+    ) {
+        if old_entry.get_cache_id() == self._probation.get_cache_id() {
+            self._probation.remove_clashed(hmap, old_entry, idx)
+        } else {
+            self._protected.remove_clashed(hmap, old_entry, idx)
+        };
+        if let Some(wheel) = self._idle.as_mut() {
+            wheel.cancel(idx);
+        }
         self.update_scan_status();
-        res
+    }
+    /// The hashmap has been resized and all the entries have moved.
+    ///
+    /// Fix both the probation and protected LRUs
+    pub fn relocate(&mut self, reloc: &hashmap::Relocation<E>) {
+        self._probation.relocate(reloc);
+        self._protected.relocate(reloc);
+        if let Some(wheel) = self._idle.as_mut() {
+            wheel.relocate(reloc);
+        }
     }
     /// return the cache ids for `(probatory, protected)`
     pub fn get_cache_ids(&self) -> (CidT, CidT) {
@@ -422,14 +438,69 @@ impl<
     }
     /// Should be called only by the parent, run the on-get callback on the
     /// correct LRU
-    pub fn on_get(&mut self, entry: &mut E) {
-        if entry.get_cache_id() == self._probation.get_cache_id() {
-            self._probation.on_get(entry);
+    ///
+    /// A hit in probation promotes the entry to protected, and the protected
+    /// tail is demoted to probation if needed.  
+    /// A get never evicts: if the demoted entries would not fit in probation,
+    /// the entry stays there and the hit is handled by probation
+    pub fn on_get(&mut self, hmap: &mut Hmap, idx: usize) {
+        let cid = hmap.get_index(idx).unwrap().get_cache_id();
+        if cid == self._probation.get_cache_id() && self.can_promote(hmap, idx)
+        {
+            let weight =
+                self._protected.entry_weight(hmap.get_index(idx).unwrap());
+            self._probation.remove_shared(hmap, idx);
+            // make room first, so that protected does not evict anything
+            while self._protected.weight() + weight > self._protected.capacity()
+            {
+                let tail = self._protected.tail(hmap).unwrap();
+                self._protected.remove_shared(hmap, tail);
+                let res = self._probation.move_in_shared(hmap, tail);
+                debug_assert!(res.is_empty(), "demotion evicted on get");
+            }
+            let res = self._protected.move_in_shared(hmap, idx);
+            debug_assert!(res.is_empty(), "promotion evicted on get");
+            hmap.get_index_mut(idx).unwrap().user_on_get();
+        } else if cid == self._probation.get_cache_id() {
+            self._probation.on_get(hmap, idx);
         } else {
-            self._protected.on_get(entry);
+            self._protected.on_get(hmap, idx);
+        }
+        if let Some(wheel) = self._idle.as_mut() {
+            wheel.touch(idx, self._now);
         }
         self.update_scan_status();
     }
+    // the entry at `idx` is in probation. It can be promoted only if it fits
+    // in protected and the protected tail demoted to make room fits in the
+    // space it leaves in probation
+    fn can_promote(&self, hmap: &Hmap, idx: usize) -> bool {
+        let weight = self._protected.entry_weight(hmap.get_index(idx).unwrap());
+        let protected = self._protected.capacity();
+        if weight > protected {
+            return false;
+        }
+        let mut missing =
+            (self._protected.weight() + weight).saturating_sub(protected);
+        let mut free = (self._probation.capacity() + weight)
+            .saturating_sub(self._probation.weight());
+        let mut next = self._protected.tail(hmap);
+        while missing > 0 {
+            let tail = match next {
+                None => break,
+                Some(tail) => tail,
+            };
+            let tail_weight =
+                self._protected.entry_weight(hmap.get_index(tail).unwrap());
+            if tail_weight > free {
+                return false;
+            }
+            free -= tail_weight;
+            missing = missing.saturating_sub(tail_weight);
+            next = self._protected.next_to_head(hmap, tail);
+        }
+        true
+    }
     /// start the scan callbacks on the SLRU
     /// scan will execute only once on the whole SLRU
     pub fn start_scan(&mut self) {
@@ -477,6 +548,31 @@ impl<
             }
         }
     }
+    /// Change the number of entries of the probation and protected LRUs
+    ///
+    /// When the protected LRU shrinks its tail is demoted to probation,
+    /// just like on insert.  
+    /// The entries evicted from the probation tail are returned: they are
+    /// still in the hashmap and must be removed by the caller
+    pub fn set_capacity(
+        &mut self,
+        hmap: &mut Hmap,
+        probation_entries: usize,
+        protected_entries: usize,
+    ) -> Removed<usize> {
+        let mut evicted = self._probation.set_capacity(hmap, probation_entries);
+        let demoted = self._protected.set_capacity(hmap, protected_entries);
+        evicted.append(self.demote(hmap, demoted));
+        if let Some(wheel) = self._idle.as_mut() {
+            wheel.cancel_removed(&evicted);
+        }
+        self.update_scan_status();
+        evicted
+    }
+    /// return the max size of `(probation, protected)`
+    pub fn get_capacities(&self) -> (usize, usize) {
+        (self._probation.capacity(), self._protected.capacity())
+    }
     /// return max SLRU size
     pub fn capacity(&self) -> usize {
         self._probation.capacity() + self._protected.capacity()
@@ -485,4 +581,362 @@ impl<
     pub fn len(&self) -> usize {
         self._probation.len() + self._protected.len()
     }
+    /// Weigh each entry, for both probation and protected.
+    ///
+    /// See [`LRUShared::set_weighted`](crate::lru::LRUShared::set_weighted)
+    pub fn set_weighted(&mut self, hmap: &Hmap, weighted: bool) {
+        self._probation.set_weighted(hmap, weighted);
+        self._protected.set_weighted(hmap, weighted);
+    }
+    /// check if the entries are weighted
+    pub fn is_weighted(&self) -> bool {
+        self._probation.is_weighted()
+    }
+    /// return the total weight of the entries, the same as `len()` when not
+    /// weighted
+    pub fn weight(&self) -> usize {
+        self._probation.weight() + self._protected.weight()
+    }
+    /// Expire the entries that are not accessed for `idle`, in both
+    /// probation and protected
+    ///
+    /// See [`LRUShared::set_time_to_idle`](crate::lru::LRUShared::set_time_to_idle)
+    pub fn set_time_to_idle(
+        &mut self,
+        hmap: &Hmap,
+        idle: Option<::std::time::Duration>,
+        now: u64,
+    ) {
+        let mut wheel = match idle {
+            None => {
+                self._idle = None;
+                return;
+            }
+            Some(idle) => wheel::TimerWheel::new(idle, now),
+        };
+        let (probation, protected) = self.get_cache_ids();
+        for idx in 0..hmap.buckets() {
+            if let Some(entry) = hmap.get_index(idx) {
+                let cid = entry.get_cache_id();
+                if cid == probation || cid == protected {
+                    wheel.touch(idx, now);
+                }
+            }
+        }
+        self._idle = Some(wheel);
+    }
+    /// get the time-to-idle of the entries, if any
+    pub fn get_time_to_idle(&self) -> Option<::std::time::Duration> {
+        self._idle.as_ref().map(|wheel| wheel.idle())
+    }
+    /// Move the time to `now`, and remove the entries that have not been
+    /// accessed for the time-to-idle
+    ///
+    /// The expired entries are still in the hashmap and must be removed by
+    /// the caller
+    pub fn advance(&mut self, hmap: &mut Hmap, now: u64) -> Removed<usize> {
+        let expired = match self._idle.as_mut() {
+            None => return Removed::new(),
+            Some(wheel) => wheel.advance(now),
+        };
+        let mut removed = Removed::new();
+        for idx in expired {
+            self.remove_shared(hmap, idx);
+            removed.push(idx, RemovalCause::Expired);
+        }
+        removed
+    }
+    // (re)start the idle timer of `idx`, and stop the ones of the evicted
+    fn touch_idle(&mut self, idx: usize, evicted: &InsertResultShared) {
+        if let Some(wheel) = self._idle.as_mut() {
+            wheel.touch(idx, self._now);
+            wheel.cancel_removed(evicted);
+        }
+    }
+    // like `touch_idle` for an entry moved in from another cache, which
+    // keeps its timer
+    fn track_idle(&mut self, idx: usize, evicted: &InsertResultShared) {
+        if let Some(wheel) = self._idle.as_mut() {
+            wheel.track(idx, self._now);
+            wheel.cancel_removed(evicted);
+        }
+    }
+    /// the instant after which the entry at `idx` is idle, if there is a
+    /// time-to-idle
+    pub fn idle_deadline(&self, idx: usize) -> Option<u64> {
+        self._idle.as_ref().and_then(|wheel| wheel.deadline(idx))
+    }
+    /// the time of the next inserts and hits, where their idle timers start
+    pub fn set_now(&mut self, now: u64) {
+        self._now = now;
+    }
+}
+
+impl<
+        'a,
+        Hmap: hashmap::HashMap<E, K, V, CidT, Umeta, HB>,
+        E: user::EntryT<K, V, CidT, Umeta>,
+        K: user::Hash,
+        V: user::Val,
+        CidT: user::Cid,
+        Umeta: user::Meta<V>,
+        HB: ::std::hash::BuildHasher + Default,
+    > crate::cache::SharedCache<'a, Hmap, E, K, V, CidT, Umeta, HB>
+    for SLRUShared<'a, Hmap, E, K, V, CidT, Umeta, HB>
+{
+    fn insert_shared(
+        &mut self,
+        hmap: &mut Hmap,
+        maybe_old_entry: Option<&mut E>,
+        new_entry_idx: usize,
+    ) -> InsertResultShared {
+        SLRUShared::insert_shared(self, hmap, maybe_old_entry, new_entry_idx)
+    }
+    fn move_in_shared(
+        &mut self,
+        hmap: &mut Hmap,
+        idx: usize,
+    ) -> InsertResultShared {
+        SLRUShared::move_in_shared(self, hmap, idx)
+    }
+    fn remove_shared(&mut self, hmap: &mut Hmap, idx: usize) {
+        SLRUShared::remove_shared(self, hmap, idx)
+    }
+    fn remove_clashed(&mut self, hmap: &mut Hmap, old_entry: &E, idx: usize) {
+        SLRUShared::remove_clashed(self, hmap, old_entry, idx)
+    }
+    fn clear_shared(&mut self) {
+        SLRUShared::clear_shared(self)
+    }
+    fn on_get(&mut self, hmap: &mut Hmap, idx: usize) {
+        SLRUShared::on_get(self, hmap, idx)
+    }
+    fn owns(&self, cid: CidT) -> bool {
+        cid == self._probation.get_cache_id()
+            || cid == self._protected.get_cache_id()
+    }
+    fn victim(&self, hmap: &Hmap) -> Option<usize> {
+        self.probation_victim(hmap)
+    }
+    fn relocate(&mut self, reloc: &hashmap::Relocation<E>) {
+        SLRUShared::relocate(self, reloc)
+    }
+    fn set_scanf(
+        &mut self,
+        access_scan: Option<&'a dyn Fn(::std::ptr::NonNull<E>)>,
+    ) {
+        SLRUShared::set_scanf(self, access_scan)
+    }
+    fn start_scan(&mut self) {
+        SLRUShared::start_scan(self)
+    }
+    fn is_scan_running(&self) -> bool {
+        SLRUShared::is_scan_running(self)
+    }
+    fn set_hit_policy(&mut self, policy: crate::lru::HitPolicy) {
+        SLRUShared::set_hit_policy(self, policy)
+    }
+    fn get_hit_policy(&self) -> crate::lru::HitPolicy {
+        SLRUShared::get_hit_policy(self)
+    }
+    // keep the current split between probation and protected
+    fn set_capacity(
+        &mut self,
+        hmap: &mut Hmap,
+        entries: usize,
+    ) -> Removed<usize> {
+        // keep the current ratio, or the 20-80 split of W-TinyLFU from
+        // zero. Probation gets at least one entry, protected gets one only
+        // if there is space for both
+        let probation = match SLRUShared::capacity(self) {
+            0 => entries / 5,
+            current => (entries * self._probation.capacity()) / current,
+        };
+        let probation = ::std::cmp::max(
+            ::std::cmp::min(probation, entries.saturating_sub(1)),
+            ::std::cmp::min(1, entries),
+        );
+        SLRUShared::set_capacity(self, hmap, probation, entries - probation)
+    }
+    fn capacity(&self) -> usize {
+        SLRUShared::capacity(self)
+    }
+    fn len(&self) -> usize {
+        SLRUShared::len(self)
+    }
+    fn set_weighted(
+        &mut self,
+        hmap: &Hmap,
+        weighted: bool,
+    ) -> Result<(), crate::results::Error> {
+        SLRUShared::set_weighted(self, hmap, weighted);
+        Ok(())
+    }
+    fn weight(&self) -> usize {
+        SLRUShared::weight(self)
+    }
+    fn is_weighted(&self) -> bool {
+        SLRUShared::is_weighted(self)
+    }
+    fn idle_deadline(&self, idx: usize) -> Option<u64> {
+        SLRUShared::idle_deadline(self, idx)
+    }
+    fn set_now(&mut self, now: u64) {
+        SLRUShared::set_now(self, now)
+    }
+}
+
+impl<
+        'a,
+        Hmap: hashmap::HashMap<E, K, V, CidT, Umeta, HB>,
+        E: user::EntryT<K, V, CidT, Umeta>,
+        K: user::Hash,
+        V: user::Val,
+        CidT: user::Cid,
+        Umeta: user::Meta<V>,
+        HB: ::std::hash::BuildHasher + Default,
+    > crate::cache::IdleCache<'a, Hmap, E, K, V, CidT, Umeta, HB>
+    for SLRUShared<'a, Hmap, E, K, V, CidT, Umeta, HB>
+{
+    fn set_time_to_idle(
+        &mut self,
+        hmap: &Hmap,
+        idle: Option<::std::time::Duration>,
+        now: u64,
+    ) {
+        SLRUShared::set_time_to_idle(self, hmap, idle, now)
+    }
+    fn get_time_to_idle(&self) -> Option<::std::time::Duration> {
+        SLRUShared::get_time_to_idle(self)
+    }
+    fn advance(&mut self, hmap: &mut Hmap, now: u64) -> Removed<usize> {
+        SLRUShared::advance(self, hmap, now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hashmap::user::EntryT;
+    use crate::testing::{Hasher, Key, Val};
+
+    type Cache<'a> = SLRU<'a, Key, Val, user::WeightMeta, Hasher>;
+
+    fn in_probation(cache: &Cache, k: u32) -> bool {
+        let (_, entry) = cache.hmap().get_full(&Key(k)).unwrap();
+        matches!(entry.get_cache_id(), SLRUCid::Probation(_))
+    }
+
+    #[test]
+    fn hits_promote_and_demote() {
+        let mut cache = Cache::new(2, 2, 0, Hasher::default());
+        for k in 0..2 {
+            cache.insert(Key(k), Val(k));
+            assert!(in_probation(&cache, k));
+            cache.get(&Key(k));
+            assert!(!in_probation(&cache, k));
+        }
+        cache.insert(Key(2), Val(2));
+        cache.insert(Key(3), Val(3));
+        // protected is full: its tail goes back to probation
+        cache.get(&Key(2));
+        assert!(!in_probation(&cache, 2));
+        assert!(in_probation(&cache, 0));
+        // protected hits stay there
+        cache.get(&Key(2));
+        assert!(!in_probation(&cache, 2));
+        cache.get_mut(&Key(3)).unwrap().0 .0 = 30;
+        assert!(!in_probation(&cache, 3));
+        assert!(in_probation(&cache, 1));
+        // the demoted entries are evicted from probation, oldest first
+        let res = cache.insert(Key(4), Val(4));
+        let evicted: Vec<_> = res.iter().map(|(e, _)| e.0 .0).collect();
+        assert_eq!(evicted, vec![0]);
+        assert_eq!(cache.get(&Key(3)).unwrap().0, &Val(30));
+        assert_eq!(cache.len(), 4);
+    }
+
+    #[test]
+    fn get_never_evicts() {
+        let mut cache = Cache::new(4, 4, 0, Hasher::default());
+        cache.set_weighted(true).unwrap();
+        for k in 0..2 {
+            cache.insert(Key(k), Val(2));
+            cache.get(&Key(k));
+            assert!(!in_probation(&cache, k));
+        }
+        cache.insert(Key(2), Val(1));
+        cache.insert(Key(3), Val(3));
+        // demoting the protected tail (0, weight 2) would not fit in
+        // probation: 2 stays there
+        cache.get(&Key(2));
+        assert!(in_probation(&cache, 2));
+        assert_eq!((cache.len(), cache.weight()), (4, 8));
+        cache.remove(&Key(3));
+        cache.get(&Key(2));
+        assert!(!in_probation(&cache, 2));
+        assert!(in_probation(&cache, 0));
+        assert_eq!((cache.len(), cache.weight()), (3, 5));
+        assert_eq!(cache.shared().get_capacities(), (4, 4));
+    }
+
+    #[test]
+    fn set_capacity_keeps_the_ratio() {
+        let mut cache = Cache::new(2, 8, 0, Hasher::default());
+        assert!(cache.set_capacity(20).is_empty());
+        assert_eq!(cache.shared().get_capacities(), (4, 16));
+        for k in 0..20 {
+            cache.insert(Key(k), Val(1));
+            if k < 16 {
+                cache.get(&Key(k));
+            }
+        }
+        assert_eq!(cache.len(), 20);
+        let res = cache.set_capacity(5);
+        assert_eq!(res.len(), 15);
+        assert_eq!(cache.shared().get_capacities(), (1, 4));
+        assert_eq!(cache.len(), 5);
+    }
+
+    #[test]
+    fn set_capacity_edge_cases() {
+        let mut cache = Cache::new(2, 8, 0, Hasher::default());
+        for k in 0..4 {
+            cache.insert(Key(k), Val(1));
+            if k < 3 {
+                cache.get(&Key(k));
+            }
+        }
+        assert_eq!(cache.len(), 4);
+        // one entry: probation only, so hits can not promote
+        assert_eq!(cache.set_capacity(1).len(), 3);
+        assert_eq!(cache.shared().get_capacities(), (1, 0));
+        assert_eq!(cache.insert(Key(9), Val(1)).len(), 1);
+        cache.get(&Key(9));
+        assert!(in_probation(&cache, 9));
+        assert_eq!(cache.set_capacity(0).len(), 1);
+        assert_eq!(cache.shared().get_capacities(), (0, 0));
+        assert!(cache.is_empty());
+        // no ratio to keep: back to the 20-80 split
+        assert!(cache.set_capacity(10).is_empty());
+        assert_eq!(cache.shared().get_capacities(), (2, 8));
+        assert!(cache.insert(Key(0), Val(1)).is_empty());
+    }
+
+    #[test]
+    fn protected_update_stays_in_protected() {
+        let mut cache = Cache::new(2, 2, 0, Hasher::default());
+        cache.insert(Key(1), Val(1));
+        cache.get(&Key(1));
+        cache.insert(Key(2), Val(2));
+        cache.insert(Key(3), Val(3));
+        let res = cache.insert(Key(1), Val(10));
+        let removed: Vec<_> =
+            res.iter().map(|(e, c)| (e.0 .0, e.1 .0, *c)).collect();
+        assert_eq!(removed, vec![(1, 1, RemovalCause::Clash)]);
+        assert!(!in_probation(&cache, 1));
+        assert!(in_probation(&cache, 2) && in_probation(&cache, 3));
+        assert_eq!(cache.get(&Key(1)).unwrap().0, &Val(10));
+        assert_eq!(cache.len(), 3);
+    }
 }
@@ -0,0 +1,170 @@
+/*
+ * Copyright 2021 Luca Fulchir <luker@fenrirproject.org>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+//! Hill climbing controller for the Window size
+//!
+//! The hit ratio is sampled every `10 * capacity` get/insert. If the last
+//! change of the window size improved the hit ratio we keep moving in the
+//! same direction, otherwise we go back. The step size decays over time
+//! and is reset when the hit ratio changes a lot, which usually means that
+//! the workload changed.
+
+// percentage of the cache moved on each step before decay
+const INITIAL_STEP_PERCENT: f64 = 6.25;
+// multiplier applied to the step after every sample
+const STEP_DECAY: f64 = 0.98;
+// hit ratio difference that restarts the climb with the initial step
+const RESTART_THRESHOLD: f64 = 0.05;
+// samples are taken every `SAMPLE_MULTIPLIER * capacity` operations
+const SAMPLE_MULTIPLIER: usize = 10;
+
+/// Hill climbing state used to size the Window of the SW-TLFU
+#[derive(Debug, Clone)]
+pub struct HillClimber {
+    _hits: usize,
+    _misses: usize,
+    _sample_size: usize,
+    _initial_step: f64,
+    // signed: positive grows the window, negative shrinks it
+    _step: f64,
+    _previous_hit_ratio: f64,
+}
+
+impl HillClimber {
+    /// New climber for a cache of `entries` elements.  
+    /// The first step will grow the window
+    pub fn new(entries: usize) -> Self {
+        let initial_step =
+            (entries as f64 * INITIAL_STEP_PERCENT / 100.0).max(1.0);
+        HillClimber {
+            _hits: 0,
+            _misses: 0,
+            _sample_size: ::std::cmp::max(1, entries * SAMPLE_MULTIPLIER),
+            _initial_step: initial_step,
+            _step: initial_step,
+            _previous_hit_ratio: 0.0,
+        }
+    }
+    /// record a hit or a miss.  
+    /// returns by how many entries the window should change, which is
+    /// always zero until a full sample has been taken
+    pub fn record(&mut self, hit: bool) -> isize {
+        if hit {
+            self._hits += 1;
+        } else {
+            self._misses += 1;
+        }
+        let total = self._hits + self._misses;
+        if total < self._sample_size {
+            return 0;
+        }
+        let hit_ratio = self._hits as f64 / total as f64;
+        let change = hit_ratio - self._previous_hit_ratio;
+        let amount = if change >= 0.0 {
+            self._step
+        } else {
+            -self._step
+        };
+        self._step = if change.abs() >= RESTART_THRESHOLD {
+            self._initial_step.copysign(amount)
+        } else {
+            amount * STEP_DECAY
+        };
+        self._previous_hit_ratio = hit_ratio;
+        self._hits = 0;
+        self._misses = 0;
+        amount.round() as isize
+    }
+    /// hits in the current sample
+    pub fn hits(&self) -> usize {
+        self._hits
+    }
+    /// misses in the current sample
+    pub fn misses(&self) -> usize {
+        self._misses
+    }
+    /// number of get/insert in a full sample
+    pub fn sample_size(&self) -> usize {
+        self._sample_size
+    }
+    /// next window change, in entries.  
+    /// positive grows the window, negative shrinks it
+    pub fn step(&self) -> f64 {
+        self._step
+    }
+    /// hit ratio of the last full sample
+    pub fn previous_hit_ratio(&self) -> f64 {
+        self._previous_hit_ratio
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // record a full sample, return the window change
+    fn sample(climber: &mut HillClimber, hits: usize) -> isize {
+        let misses = climber.sample_size() - hits;
+        for _ in 0..hits {
+            assert_eq!(climber.record(true), 0);
+        }
+        for _ in 1..misses {
+            assert_eq!(climber.record(false), 0);
+        }
+        climber.record(false)
+    }
+
+    #[test]
+    fn nothing_moves_before_a_full_sample() {
+        let mut climber = HillClimber::new(100);
+        assert_eq!(climber.sample_size(), 1000);
+        for _ in 0..999 {
+            assert_eq!(climber.record(true), 0);
+        }
+        assert_eq!((climber.hits(), climber.misses()), (999, 0));
+        assert_eq!(climber.record(true), 6);
+        assert_eq!((climber.hits(), climber.misses()), (0, 0));
+    }
+
+    #[test]
+    fn climb_direction_and_decay() {
+        let mut climber = HillClimber::new(100);
+        assert_eq!(climber.step(), 6.25);
+        // the first sample always grows the window
+        assert_eq!(sample(&mut climber, 500), 6);
+        assert_eq!(climber.previous_hit_ratio(), 0.5);
+        // small improvements keep the direction, with a smaller step
+        assert_eq!(sample(&mut climber, 510), 6);
+        assert_eq!(sample(&mut climber, 520), 6);
+        assert!((climber.step() - 6.25 * STEP_DECAY * STEP_DECAY).abs() < 1e-9);
+        // a small loss goes back, still decaying
+        assert_eq!(sample(&mut climber, 515), -6);
+        assert!(climber.step() < 0.0);
+        assert!(climber.step() > -6.0);
+        // a small improvement keeps shrinking
+        assert_eq!(sample(&mut climber, 516), -6);
+        // a big loss goes back and restarts with the initial step
+        assert_eq!(sample(&mut climber, 400), 6);
+        assert_eq!(climber.step(), 6.25);
+    }
+
+    #[test]
+    fn small_caches_move_at_least_one_entry() {
+        let mut climber = HillClimber::new(4);
+        assert_eq!(climber.sample_size(), 40);
+        assert_eq!(sample(&mut climber, 20), 1);
+        assert_eq!(sample(&mut climber, 10), -1);
+    }
+}
@@ -13,6 +13,10 @@
  * See the License for the specific language governing permissions and
  * limitations under the License.
  */
+//! Cache ids that also track the access frequency of the entries
+//!
+//! [`Full32`] is the one used by [`SWTLFU`](super::SWTLFU), but any
+//! [`CidCounter`] works with [`SWTLFUShared`](super::SWTLFUShared)
 
 use crate::hashmap::user;
 
@@ -22,7 +26,8 @@ use crate::hashmap::user;
 // There is no "new" and "old" generation, since
 // every X queries the "old" will become the "new"
 // The naming should not give old/new ideas
-#[derive(PartialEq, Eq, Copy, Clone)]
+/// Generation of a counter, flipped periodically to age the counters
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
 pub enum Generation {
     Day,
     Night,
@@ -58,22 +63,42 @@ impl Default for Generation {
     }
 }
 
+/// A [`user::Cid`] that also hides a generation and an access counter
+///
+/// Only [`get_cid`](CidCounter::get_cid) must be used for comparisons
 pub trait CidCounter<Cid>: user::Cid
 where
     Cid: user::Cid,
 {
+    /// new counter for the given cache, starting from zero
     fn new(cid: Cid) -> Self;
+    /// get the actual cache id
     fn get_cid(&self) -> Cid;
+    /// set the actual cache id, leaving the rest untouched
     fn set_cid(&mut self, cid: Cid);
 
+    /// get the generation of the counter
     fn get_generation(&self) -> Generation;
+    /// move the counter to the other generation
     fn flip_generation(&mut self);
 
+    /// get the access counter
     fn get_counter(&self) -> u32;
+    /// count one more access
     fn add(&mut self);
+    /// halve the access counter
     fn halve(&mut self);
+    /// if the counter is from the old generation, halve it and bring it to
+    /// the `current` one
+    fn age(&mut self, current: Generation) {
+        if self.get_generation() != current {
+            self.halve();
+            self.flip_generation();
+        }
+    }
 }
 
+/// The sub-caches of the W-TinyLFU
 #[derive(Debug, PartialEq, Eq, Copy, Clone)]
 pub enum WTLFUCid {
     None = 0,
@@ -103,18 +128,33 @@ impl From<u8> for WTLFUCid {
 
 // This is a Cid, but it hides generation and counters inside
 // make sure it behaves as a Cid first and foremost
+// Layout: cid 1..0, generation 2, counter 30..3, CLOCK reference bit 31
 ::bitfield::bitfield! {
+    /// [`WTLFUCid`] plus generation and a 28 bit counter, all in 32 bits
     #[derive(Copy, Clone)]
     pub struct Full32(u32);
     impl Debug;
     #[inline]
-    pub u8, into WTLFUCid, g_cid, s_cid: 2, 0;
+    pub u8, into WTLFUCid, g_cid, s_cid: 1, 0;
     #[inline]
-    pub into Generation, g_generation, s_generation: 0;
+    pub into Generation, g_generation, s_generation: 2;
     #[inline]
-    pub u32, g_counter, s_counter: 29, 0;
+    pub u32, g_counter, s_counter: 30, 3;
+    #[inline]
+    pub g_reference, s_reference: 31;
+}
+impl user::Cid for Full32 {
+    fn get_reference(&self) -> bool {
+        self.g_reference()
+    }
+    fn set_reference(&mut self, referenced: bool) {
+        self.s_reference(referenced)
+    }
+    fn set_cache(&mut self, cache: Self) {
+        self.s_cid(cache.g_cid() as u8);
+        self.s_reference(false);
+    }
 }
-impl user::Cid for Full32 {}
 
 impl Default for Full32 {
     fn default() -> Self {
@@ -156,7 +196,10 @@ impl CidCounter<WTLFUCid> for Full32 {
     }
     fn add(&mut self) {
         let tmp = self.g_counter();
-        self.s_counter(tmp + 1);
+        // saturate, the counter is only 28 bits
+        if tmp < (1 << 28) - 1 {
+            self.s_counter(tmp + 1);
+        }
     }
     fn halve(&mut self) {
         let tmp = self.g_counter();
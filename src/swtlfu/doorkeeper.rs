@@ -0,0 +1,108 @@
+/*
+ * Copyright 2021 Luca Fulchir <luker@fenrirproject.org>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+//! TinyLFU doorkeeper
+//!
+//! The counters of the SW-TLFU live in the hashmap entries, so a key that
+//! is evicted loses its frequency. The doorkeeper is a small bloom filter
+//! that remembers which keys have been inserted in the current generation,
+//! so that a key that comes back is not treated as a brand new one.
+
+// number of bits set for each key
+const HASHES: u64 = 3;
+
+/// Bloom filter that remembers the keys seen in the current generation
+pub struct Doorkeeper {
+    _bits: Vec<u64>,
+    // number of bits - 1. Always a power of two
+    _mask: u64,
+}
+
+impl Doorkeeper {
+    /// New empty doorkeeper, sized for `entries` keys per generation
+    pub fn new(entries: usize) -> Self {
+        let bits = ::std::cmp::max(64, (entries * 8).next_power_of_two());
+        Doorkeeper {
+            _bits: vec![0; bits / 64],
+            _mask: (bits - 1) as u64,
+        }
+    }
+    // double hashing: derive all the bit positions from a single hash
+    fn positions(&self, hash: u64) -> impl Iterator<Item = usize> {
+        let (h1, h2) = (hash, (hash >> 32) | 1);
+        let mask = self._mask;
+        (0..HASHES)
+            .map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) & mask) as usize)
+    }
+    /// check if the key hash has probably been seen already
+    pub fn contains(&self, hash: u64) -> bool {
+        self.positions(hash)
+            .all(|pos| self._bits[pos / 64] & (1 << (pos % 64)) != 0)
+    }
+    /// record the key hash, return if it had probably been seen already
+    pub fn check_and_set(&mut self, hash: u64) -> bool {
+        let mut seen = true;
+        let positions: Vec<usize> = self.positions(hash).collect();
+        for pos in positions {
+            let bit = 1 << (pos % 64);
+            if self._bits[pos / 64] & bit == 0 {
+                seen = false;
+                self._bits[pos / 64] |= bit;
+            }
+        }
+        seen
+    }
+    /// forget everything, used on every generation flip
+    pub fn clear(&mut self) {
+        for word in self._bits.iter_mut() {
+            *word = 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // spread the test keys like a real hash would
+    fn hash(k: u64) -> u64 {
+        k.wrapping_mul(0x9E37_79B9_7F4A_7C15)
+    }
+
+    #[test]
+    fn no_false_negatives() {
+        let mut doorkeeper = Doorkeeper::new(1000);
+        for k in 0..1000 {
+            doorkeeper.check_and_set(hash(k));
+        }
+        assert!((0..1000).all(|k| doorkeeper.contains(hash(k))));
+        assert!((0..1000).all(|k| doorkeeper.check_and_set(hash(k))));
+        // sized for 1000 keys, most of the others are not there
+        let false_positives = (1000..2000)
+            .filter(|k| doorkeeper.contains(hash(*k)))
+            .count();
+        assert!(false_positives < 100);
+    }
+
+    #[test]
+    fn clear_forgets_everything() {
+        let mut doorkeeper = Doorkeeper::new(16);
+        assert!(!doorkeeper.check_and_set(hash(1)));
+        assert!(doorkeeper.check_and_set(hash(1)));
+        doorkeeper.clear();
+        assert!(!doorkeeper.contains(hash(1)));
+        assert!(!doorkeeper.check_and_set(hash(1)));
+    }
+}
@@ -42,14 +42,114 @@
 //!
 //! The "Scan" part works simply by tracking the generation of the counter
 //! (`Day`/`Night`) and if the generation is not the current one, the counter
-//! is halved. To assure that all counters are halved every X inserts,
-//! every get/insert will scan just one more element.
+//! is halved. The generation flips every X get/insert, and to assure that all
+//! counters are halved every X inserts, every get/insert will scan the
+//! accessed element and just one more.
+//!
+//! An entry evicted from the Window enters the main SLRU only if there is
+//! space or if its counter is higher than the one of the SLRU victim.  
+//! The counters are lost when an entry is evicted. A
+//! `sketch::FrequencyEstimator` can be used to remember the frequency of
+//! evicted keys too, and the admission will then use its estimates.
+//!
+//! The window size can be adapted to the workload with a hill climbing
+//! controller (see `climber::HillClimber`): more recency-heavy workloads get
+//! a bigger window, more frequency-heavy workloads get a bigger main cache.
 
-mod counter;
+pub mod climber;
+pub mod counter;
+pub mod doorkeeper;
+pub mod sketch;
 
+use crate::cache::SharedCache;
+use crate::expiry::wheel;
 use crate::hashmap;
 use crate::hashmap::user;
-use crate::results::{InsertResult, InsertResultShared};
+use crate::owned::Owned;
+use crate::results::{InsertResultShared, RemovalCause, Removed};
+
+type SWTLFUEntry<K, V, Umeta> = user::Entry<K, V, counter::Full32, Umeta>;
+type HmapT<K, V, Umeta, HB> = hashmap::SimpleHmap<
+    SWTLFUEntry<K, V, Umeta>,
+    K,
+    V,
+    counter::Full32,
+    Umeta,
+    HB,
+>;
+type SharedT<'a, K, V, Umeta, HB> = SWTLFUShared<
+    'a,
+    HmapT<K, V, Umeta, HB>,
+    SWTLFUEntry<K, V, Umeta>,
+    K,
+    V,
+    counter::WTLFUCid,
+    counter::Full32,
+    Umeta,
+    HB,
+>;
+
+/// Scan-Window-TinyLFU implementation that wraps SWTLFUShared, see [`Owned`]
+///
+/// The cache uses the standard split (1% window, then 20% probation and 80%
+/// protected) and keeps the counters in the [`counter::Full32`] cache id.
+pub type SWTLFU<'a, K, V, Umeta, HB> =
+    Owned<'a, K, V, counter::Full32, Umeta, HB, SharedT<'a, K, V, Umeta, HB>>;
+
+impl<
+        'a,
+        K: user::Hash + 'a,
+        V: user::Val + 'a,
+        Umeta: user::Meta<V> + 'a,
+        HB: ::std::hash::BuildHasher + Default + 'a,
+    > SWTLFU<'a, K, V, Umeta, HB>
+{
+    /// Create a new empty Scan-W-TinyLFU, with the standard split
+    pub fn new(
+        entries: usize,
+        extra_hashmap_capacity: usize,
+        hash_builder: HB,
+    ) -> Self {
+        // the split can round up the entries, the hashmap follows the cache
+        Owned::from_shared(
+            SharedT::<'a, K, V, Umeta, HB>::new_standard(
+                counter::WTLFUCid::Window,
+                counter::WTLFUCid::SLRUProbation,
+                counter::WTLFUCid::SLRUProtected,
+                entries,
+                None,
+            ),
+            extra_hashmap_capacity,
+            hash_builder,
+        )
+    }
+    /// Enable or disable the doorkeeper bloom filter, see
+    /// [`SWTLFUShared::set_doorkeeper`]
+    pub fn set_doorkeeper(&mut self, enabled: bool) {
+        self.shared_mut().set_doorkeeper(enabled)
+    }
+    /// Set the frequency estimator used for admission, see
+    /// [`SWTLFUShared::set_estimator`]
+    pub fn set_estimator(
+        &mut self,
+        estimator: Option<::std::boxed::Box<dyn sketch::FrequencyEstimator>>,
+    ) {
+        self.shared_mut().set_estimator(estimator)
+    }
+    /// Enable or disable the adaptive window, see
+    /// [`SWTLFUShared::set_adaptive`]
+    pub fn set_adaptive(&mut self, enabled: bool) {
+        self.shared_mut().set_adaptive(enabled)
+    }
+    /// get the state of the hill climber, if the window is adaptive
+    pub fn get_climber(&self) -> Option<&climber::HillClimber> {
+        self.shared().get_climber()
+    }
+    /// return the max size of `(window, main)`
+    pub fn get_split(&self) -> (usize, usize) {
+        self.shared().get_split()
+    }
+}
 
 #[derive(PartialEq, Eq)]
 enum ScanStatus {
@@ -78,8 +178,24 @@ where
 ///
 /// Just like [LRU](crate::lru::LRUShared) and [SLRU](crate::slru::SLRUShared)
 /// the actual adding/deletion must be done by the caller
-pub struct SWTLFUShared<'a, Hmap, E, K, V, CidT, CidCtr, Umeta, HB>
-where
+///
+/// By default the window is an [LRU](crate::lru::LRUShared) and the main
+/// cache an [SLRU](crate::slru::SLRUShared), but any
+/// [`SharedCache`](crate::cache::SharedCache) can be used, see
+/// [`SWTLFUShared::new_with_caches`]
+pub struct SWTLFUShared<
+    'a,
+    Hmap,
+    E,
+    K,
+    V,
+    CidT,
+    CidCtr,
+    Umeta,
+    HB,
+    Window = crate::lru::LRUShared<'a, Hmap, E, K, V, CidCtr, Umeta, HB>,
+    Main = crate::slru::SLRUShared<'a, Hmap, E, K, V, CidCtr, Umeta, HB>,
+> where
     Hmap: hashmap::HashMap<E, K, V, CidCtr, Umeta, HB>,
     E: user::EntryT<K, V, CidCtr, Umeta>,
     K: user::Hash,
@@ -88,17 +204,32 @@ where
     CidCtr: counter::CidCounter<CidT>,
     Umeta: user::Meta<V>,
     HB: ::std::hash::BuildHasher + Default,
+    Window: SharedCache<'a, Hmap, E, K, V, CidCtr, Umeta, HB>,
+    Main: SharedCache<'a, Hmap, E, K, V, CidCtr, Umeta, HB>,
 {
-    _window: crate::lru::LRUShared<'a, Hmap, E, K, V, CidCtr, Umeta, HB>,
-    _slru: crate::slru::SLRUShared<'a, Hmap, E, K, V, CidCtr, Umeta, HB>,
-    _entries: usize,
-    _random: [usize; 2],
+    _window: Window,
+    _main: Main,
     _generation: ::std::boxed::Box<counter::Generation>,
-    _cid_window: CidT,
-    _cid_probation: CidT,
-    _cid_protected: CidT,
+    // get/insert since the last generation flip
+    _ops: usize,
+    _doorkeeper: Option<doorkeeper::Doorkeeper>,
+    _estimator: Option<::std::boxed::Box<dyn sketch::FrequencyEstimator>>,
+    _climber: Option<climber::HillClimber>,
+    // capacity the climber asked to move to the window, applied on insert
+    _resize: isize,
+    _weighted: bool,
+    // expected number of entries when the capacity is a weight
+    _entries: Option<usize>,
+    _idle: Option<wheel::TimerWheel>,
+    // time of the current operation, where the idle timers start
+    _now: u64,
     _hmap: ::std::marker::PhantomData<Hmap>,
+    _k: ::std::marker::PhantomData<K>,
+    _v: ::std::marker::PhantomData<V>,
     _cid: ::std::marker::PhantomData<CidT>,
+    _cidctr: ::std::marker::PhantomData<CidCtr>,
+    _umeta: ::std::marker::PhantomData<Umeta>,
+    _hb: ::std::marker::PhantomData<HB>,
     _scan: ScanScan<'a, dyn Fn(::std::ptr::NonNull<E>) + 'a, E>,
 }
 
@@ -117,6 +248,7 @@ impl<
 {
     /// Build a Scan W-TLFU with standard split between the caches
     ///
+    /// There are at least 3 entries, one for each cache.  
     /// can be given an optional callback for a user-run-scan
     pub fn new_standard(
         window_cid: CidT,
@@ -126,7 +258,7 @@ impl<
         access_scan: Option<&'a dyn Fn(::std::ptr::NonNull<E>) -> ()>,
     ) -> Self {
         // We keep at least one element in each cache
-
+        let entries = ::std::cmp::max(3, entries);
         let floor_window_entries = ((entries as f64) * 0.01) as usize;
         let window_entries = ::std::cmp::max(1, floor_window_entries);
 
@@ -141,7 +273,7 @@ impl<
                         (1, main_entries - 1)
                     }
                 }
-                x @ _ => (x, entries - x),
+                x @ _ => (x, main_entries - x),
             };
         SWTLFUShared::new(
             (window_entries, window_cid),
@@ -157,13 +289,11 @@ impl<
         window: (usize, CidT),
         probation: (usize, CidT),
         protected: (usize, CidT),
-        access_scan: Option<&'a dyn Fn(::std::ptr::NonNull<E>) -> ()>,
+        access_scan: Option<&'a dyn Fn(::std::ptr::NonNull<E>)>,
     ) -> Self {
         // make sure there is at least one element per cache
-        // This assures us that there are at least 3 elements
-        // and thus we can safely generate 3 different indexes during get/insert
         let real_window = if window.0 == 0 { (1, window.1) } else { window };
-        let real_probation = if protected.0 == 0 {
+        let real_probation = if probation.0 == 0 {
             (1, probation.1)
         } else {
             probation
@@ -173,22 +303,63 @@ impl<
         } else {
             protected
         };
+        SWTLFUShared::new_with_caches(
+            crate::lru::LRUShared::<'a, Hmap, E, K, V, CidCtr, Umeta, HB>::new(
+                real_window.0,
+                CidCtr::new(real_window.1),
+                None,
+            ),
+            crate::slru::SLRUShared::<'a, Hmap, E, K, V, CidCtr, Umeta, HB>::new(
+                (real_probation.0, CidCtr::new(real_probation.1)),
+                (real_protected.0, CidCtr::new(real_protected.1)),
+                None,
+            ),
+            access_scan,
+        )
+    }
+    /// return the cache ids, in order:
+    /// * Window
+    /// * probatory
+    /// * protected
+    pub fn get_cache_ids(&self) -> [CidT; 3] {
+        let (probation, protected) = self._main.get_cache_ids();
+        [
+            self._window.get_cache_id().get_cid(),
+            probation.get_cid(),
+            protected.get_cid(),
+        ]
+    }
+}
+
+impl<
+        'a,
+        Hmap: hashmap::HashMap<E, K, V, CidCtr, Umeta, HB> + 'a,
+        E: user::EntryT<K, V, CidCtr, Umeta> + 'a,
+        K: user::Hash + 'a,
+        V: user::Val + 'a,
+        CidT: user::Cid + 'a,
+        CidCtr: counter::CidCounter<CidT> + 'a,
+        Umeta: user::Meta<V> + 'a,
+        HB: ::std::hash::BuildHasher + Default + 'a,
+        Window: SharedCache<'a, Hmap, E, K, V, CidCtr, Umeta, HB> + 'a,
+        Main: SharedCache<'a, Hmap, E, K, V, CidCtr, Umeta, HB> + 'a,
+    > SWTLFUShared<'a, Hmap, E, K, V, CidT, CidCtr, Umeta, HB, Window, Main>
+{
+    /// Build a Scan W-TLFU on top of any window and main cache
+    ///
+    /// The two caches must use different cache ids and have at least one
+    /// element each. Their scan callbacks are replaced by the one that ages
+    /// the counters
+    pub fn new_with_caches(
+        window: Window,
+        main: Main,
+        access_scan: Option<&'a dyn Fn(::std::ptr::NonNull<E>) -> ()>,
+    ) -> Self {
         let gen = ::std::boxed::Box::<counter::Generation>::new(
             counter::Generation::default(),
         );
         let mut sw_tlfu = SWTLFUShared {
-            _window: crate::lru::LRUShared::<
-                'a,
-                Hmap,
-                E,
-                K,
-                V,
-                CidCtr,
-                Umeta,
-                HB,
-            >::new(
-                real_window.0, CidCtr::new(real_window.1), None
-            ),
+            _window: window,
             _scan: ScanScan {
                 wtlfu_scan: ::std::boxed::Box::new(
                     move |_e: ::std::ptr::NonNull<E>| {},
@@ -197,28 +368,24 @@ impl<
                 status: ::std::boxed::Box::new(ScanStatus::Stopped),
                 _entry: ::std::marker::PhantomData,
             },
-            _slru: crate::slru::SLRUShared::<
-                'a,
-                Hmap,
-                E,
-                K,
-                V,
-                CidCtr,
-                Umeta,
-                HB,
-            >::new(
-                (real_probation.0, CidCtr::new(real_probation.1)),
-                (real_protected.0, CidCtr::new(real_protected.1)),
-                None,
-            ),
-            _entries: real_window.0 + real_probation.0 + real_protected.0,
-            _random: [::rand::random::<usize>(), ::rand::random::<usize>()],
+            _main: main,
             _generation: gen,
-            _cid_window: real_window.1,
-            _cid_probation: real_probation.1,
-            _cid_protected: real_protected.1,
+            _ops: 0,
+            _doorkeeper: None,
+            _estimator: None,
+            _climber: None,
+            _resize: 0,
+            _weighted: false,
+            _entries: None,
+            _idle: None,
+            _now: 0,
             _hmap: ::std::marker::PhantomData,
+            _k: ::std::marker::PhantomData,
+            _v: ::std::marker::PhantomData,
             _cid: ::std::marker::PhantomData,
+            _cidctr: ::std::marker::PhantomData,
+            _umeta: ::std::marker::PhantomData,
+            _hb: ::std::marker::PhantomData,
         };
         sw_tlfu.set_main_scanf_once();
         sw_tlfu
@@ -240,7 +407,7 @@ impl<
                 dyn Fn(::std::ptr::NonNull<E>) -> (),
             > = (&*self._scan.wtlfu_scan).into();
             self._window.set_scanf(Some(&*nn_wtlfu_scan.as_ptr()));
-            self._slru.set_scanf(Some(&*nn_wtlfu_scan.as_ptr()));
+            self._main.set_scanf(Some(&*nn_wtlfu_scan.as_ptr()));
         }
         self._window.start_scan();
     }
@@ -251,164 +418,429 @@ impl<
     ) {
         *self._scan.user_scan = access_scan;
     }
-    // return ALL indexes chosen deterministically based on the one in input
-    // make sure they are different and that the initial index is included
-    fn det_idx(&self, idx: usize) -> [usize; 3] {
-        let first = {
-            let tmp = (idx ^ self._random[0]) % self._entries;
-            if tmp != idx {
-                tmp
-            } else {
-                (tmp + 1) % self._entries
-            }
-        };
-        let second: usize = {
-            let mut tmp = (idx ^ self._random[1]) % self._entries;
-            loop {
-                if tmp != idx && tmp != first {
-                    break tmp;
-                }
-                tmp = (tmp - 1) % self._entries;
-            }
-        };
-        [idx, first, second]
-    }
-    // choose the entry to evict
-    fn choose_evict(&self, hmap: &mut Hmap, idx: usize) -> usize {
-        let (mut toevict, mut minfreq) = (idx, usize::MAX);
-        for idx in self.det_idx(idx).iter() {
-            match hmap.get_index(*idx) {
-                None => {}
-                Some(entry) => {
-                    let freq = entry.get_cache_id().get_counter() as usize;
-                    if freq < minfreq {
-                        minfreq = freq;
-                        toevict = *idx;
+    /// change what happens on a cache hit, in both the window and the main
+    /// cache
+    pub fn set_hit_policy(&mut self, policy: crate::lru::HitPolicy) {
+        self._window.set_hit_policy(policy);
+        self._main.set_hit_policy(policy);
+    }
+    /// get the current hit policy
+    pub fn get_hit_policy(&self) -> crate::lru::HitPolicy {
+        self._window.get_hit_policy()
+    }
+    // TinyLFU admission: the window victim enters the main cache only if
+    // there is space or if it is more frequent than the main victim
+    fn admit(&self, hmap: &Hmap, candidate: usize) -> bool {
+        match self._main.victim(hmap) {
+            None => true,
+            Some(victim) => {
+                let freq = |idx: usize| match &self._estimator {
+                    Some(estimator) => {
+                        // like TinyLFU, the doorkeeper counts as one more
+                        // access of the keys seen in this generation
+                        let hash = Self::key_hash(hmap, idx);
+                        let seen = match &self._doorkeeper {
+                            Some(doorkeeper) => doorkeeper.contains(hash),
+                            None => false,
+                        };
+                        estimator.estimate(hash) + seen as u32
                     }
-                }
+                    None => {
+                        // the counter might not have been halved yet
+                        let mut cid =
+                            hmap.get_index(idx).unwrap().get_cache_id();
+                        cid.age(*self._generation);
+                        cid.get_counter()
+                    }
+                };
+                freq(candidate) > freq(victim)
             }
         }
-        toevict
+    }
+    fn key_hash(hmap: &Hmap, idx: usize) -> u64 {
+        let key = hmap.get_index(idx).unwrap().get_key();
+        ::std::hash::BuildHasher::hash_one(hmap.hasher(), key)
+    }
+    // count one more access of the entry at `idx` in the estimator
+    fn estimator_add(&mut self, hmap: &Hmap, idx: usize) {
+        if let Some(estimator) = self._estimator.as_mut() {
+            estimator.increment(Self::key_hash(hmap, idx));
+        }
+    }
+    /// Use a frequency estimator for the admission in the main cache
+    /// instead of the counters of the entries.
+    ///
+    /// The counters are still kept, but the estimator can also remember
+    /// keys that have already been evicted
+    pub fn set_estimator(
+        &mut self,
+        estimator: Option<::std::boxed::Box<dyn sketch::FrequencyEstimator>>,
+    ) {
+        let entries = self.entries();
+        self._estimator = estimator;
+        if let Some(estimator) = self._estimator.as_mut() {
+            estimator.set_capacity(entries);
+        }
     }
     /// An element has been added by the caller, fix the various sub-caches
     ///
     /// Note that `maybe_old_entry` is ` != None` only if the clash happened in
-    /// this cache
+    /// this cache.  
+    /// A new value for a key that is already in the cache stays in the same
+    /// segment, with the same frequency
     pub fn insert_shared(
         &mut self,
         hmap: &mut Hmap,
         maybe_old_entry: Option<&mut E>,
         new_entry_idx: usize,
-    ) -> InsertResultShared<E> {
-        // TODO: scan one more entry
-        let just_inserted = hmap.get_index_mut(new_entry_idx).unwrap();
-        let cid_j_i = just_inserted.get_cache_id().get_cid();
-        if cid_j_i == self._cid_window {
-            // promote it to main
-            self._window.remove_shared(just_inserted);
-            match maybe_old_entry {
-                None => self._slru.insert_shared(hmap, None, new_entry_idx),
-                Some(old_entry) => {
-                    if old_entry.get_cache_id().get_cid() == self._cid_window {
-                        self._slru.insert_shared(
-                            hmap,
-                            Some(old_entry),
-                            new_entry_idx,
-                        )
-                    } else {
-                        self._slru.insert_shared(hmap, None, new_entry_idx)
-                    }
-                }
+    ) -> InsertResultShared {
+        let same_key = match &maybe_old_entry {
+            None => false,
+            Some(old_entry) => {
+                let new_key = hmap.get_index(new_entry_idx).unwrap().get_key();
+                old_entry.get_key() == new_key
             }
-        } else if (cid_j_i == self._cid_probation)
-            || (cid_j_i == self._cid_protected)
-        {
-            match maybe_old_entry {
-                None => self._slru.insert_shared(hmap, None, new_entry_idx),
-                Some(old_entry) => {
-                    let old_cid = old_entry.get_cache_id().get_cid();
-                    if (old_cid == self._cid_probation)
-                        || (old_cid == self._cid_protected)
-                    {
-                        self._slru.insert_shared(
-                            hmap,
-                            Some(old_entry),
-                            new_entry_idx,
-                        )
-                    } else {
-                        self._slru.insert_shared(hmap, None, new_entry_idx)
-                    }
-                }
+        };
+        // a new key might have been here before, ask the doorkeeper
+        let seen = !same_key && self.doorkeeper_check(hmap, new_entry_idx);
+        self.estimator_add(hmap, new_entry_idx);
+        let cid = hmap
+            .get_index_mut(new_entry_idx)
+            .unwrap()
+            .get_cache_id_mut();
+        match &maybe_old_entry {
+            Some(old_entry) if same_key => {
+                // same key, just a new value: keep the frequency
+                *cid = old_entry.get_cache_id();
+                cid.set_cid(CidT::default());
+                cid.set_reference(false);
+            }
+            _ => self.new_generation(cid),
+        }
+        if seen {
+            cid.add();
+        }
+        cid.add();
+        let res = self.insert_clashed(hmap, maybe_old_entry, new_entry_idx);
+        self.tick();
+        if !same_key {
+            self.climb(false);
+        }
+        let mut res = res;
+        res.append(self.resize_window(hmap));
+        self.touch_idle(new_entry_idx, &res);
+        self.update_scan_status();
+        res
+    }
+    /// An entry moved here from another cache on the same hashmap.
+    ///
+    /// It goes in the window like a new insert, keeping its counter, but
+    /// without running the on-insert callback
+    pub fn move_in_shared(
+        &mut self,
+        hmap: &mut Hmap,
+        idx: usize,
+    ) -> InsertResultShared {
+        let res_window = self._window.move_in_shared(hmap, idx);
+        let res = self.window_result(hmap, res_window);
+        self.track_idle(idx, &res);
+        self.update_scan_status();
+        res
+    }
+    // a new entry starts from the current generation, so that the scan does
+    // not halve it
+    fn new_generation(&self, cid: &mut CidCtr) {
+        if cid.get_generation() != *self._generation {
+            cid.flip_generation();
+        }
+    }
+    // count one more get/insert. Every `entries()` operations the
+    // generation flips, the scan will halve all the counters and the
+    // doorkeeper starts anew
+    fn tick(&mut self) {
+        self._ops += 1;
+        if self._ops >= self.entries() {
+            self._ops = 0;
+            *self._generation = !*self._generation;
+            if let Some(doorkeeper) = self._doorkeeper.as_mut() {
+                doorkeeper.clear();
             }
+            if let Some(estimator) = self._estimator.as_mut() {
+                estimator.halve();
+            }
+        }
+    }
+    // record the key of the entry at `idx` in the doorkeeper, and return if
+    // it had already been seen in this generation
+    fn doorkeeper_check(&mut self, hmap: &Hmap, idx: usize) -> bool {
+        match self._doorkeeper.as_mut() {
+            None => false,
+            Some(doorkeeper) => {
+                doorkeeper.check_and_set(Self::key_hash(hmap, idx))
+            }
+        }
+    }
+    /// Enable or disable the doorkeeper bloom filter.
+    ///
+    /// With the doorkeeper, a new key that was already inserted or read in
+    /// the current generation gets an extra count, so it has a better chance to
+    /// be admitted in the main cache
+    pub fn set_doorkeeper(&mut self, enabled: bool) {
+        self._doorkeeper = if enabled {
+            Some(doorkeeper::Doorkeeper::new(self.entries()))
         } else {
-            // put in window
-            // evicted from window get a second chance
-            let res_window = match maybe_old_entry {
-                None => self._window.insert_shared(hmap, None, new_entry_idx),
-                Some(old_entry) => {
-                    if old_entry.get_cache_id().get_cid() == self._cid_window {
-                        self._window.insert_shared(
-                            hmap,
-                            Some(old_entry),
-                            new_entry_idx,
-                        )
-                    } else {
-                        self._window.insert_shared(hmap, None, new_entry_idx)
-                    }
-                }
-            };
-            match res_window {
-                InsertResultShared::OldEntry { evicted } => {
-                    // This means that we had a clash and we are forced to
-                    // report the evicted as such, it makes
-                    // no sense to try to add it again to the
-                    // SLRU since it would benerate more clashes
-                    InsertResultShared::OldEntry { evicted }
-                }
-                InsertResultShared::OldTailPtr { evicted } => {
-                    let evicted_idx =
-                        unsafe { hmap.index_from_entry(&*evicted.as_ptr()) };
-                    let to_evict_idx = self.choose_evict(hmap, evicted_idx);
-                    let to_evict = hmap.get_index(to_evict_idx).unwrap();
-                    if to_evict.get_cache_id().get_cid() == self._cid_window {
-                        return InsertResultShared::Success;
-                    }
-                    self._slru.insert_shared(hmap, None, evicted_idx)
+            None
+        };
+    }
+    /// Enable or disable the adaptive window.
+    ///
+    /// When enabled the hit ratio is sampled and capacity is moved between
+    /// the window and the main cache with a hill climbing controller.  
+    /// Disabling it keeps the current split
+    pub fn set_adaptive(&mut self, enabled: bool) {
+        self._climber = if enabled {
+            Some(climber::HillClimber::new(self.capacity()))
+        } else {
+            None
+        };
+    }
+    /// get the state of the hill climber, if the window is adaptive
+    pub fn get_climber(&self) -> Option<&climber::HillClimber> {
+        self._climber.as_ref()
+    }
+    /// return the max size of `(window, main)`
+    pub fn get_split(&self) -> (usize, usize) {
+        (self._window.capacity(), self._main.capacity())
+    }
+    // record a hit or a miss in the climber. The capacity it asks for is
+    // moved between the window and the main cache on the next insert, since
+    // with weights that might evict
+    fn climb(&mut self, hit: bool) {
+        if let Some(climber) = self._climber.as_mut() {
+            self._resize += climber.record(hit);
+        }
+    }
+    // grow (or shrink, if negative) the window by the capacity asked by the
+    // climber, and the main cache by the opposite.
+    // Returns the entries that did not fit anywhere, if any
+    fn resize_window(&mut self, hmap: &mut Hmap) -> Removed<usize> {
+        let amount = ::std::mem::replace(&mut self._resize, 0);
+        let total = self.capacity();
+        // the main cache might be an SLRU, keep at least 2 entries there
+        if amount == 0 || total < 3 {
+            return Removed::new();
+        }
+        let window = self._window.capacity();
+        let new_window =
+            (window as isize + amount).clamp(1, total as isize - 2) as usize;
+        let weighted = self._weighted;
+        if new_window < window {
+            move_capacity(
+                hmap,
+                &mut self._window,
+                &mut self._main,
+                window - new_window,
+                weighted,
+            )
+        } else if new_window > window {
+            move_capacity(
+                hmap,
+                &mut self._main,
+                &mut self._window,
+                new_window - window,
+                weighted,
+            )
+        } else {
+            Removed::new()
+        }
+    }
+    /// Weigh each entry, for both the window and the main cache.
+    /// The capacity becomes the max total weight.
+    ///
+    /// The doorkeeper, the estimator and the counters count keys, not
+    /// weight: when weighted they are sized for `entries` keys.  
+    /// Fails if the window or the main cache can only count entries.  
+    /// See [`LRUShared::set_weighted`](crate::lru::LRUShared::set_weighted)
+    pub fn set_weighted(
+        &mut self,
+        hmap: &Hmap,
+        weighted: bool,
+        entries: usize,
+    ) -> Result<(), crate::results::Error> {
+        self._window.set_weighted(hmap, weighted)?;
+        if let Err(err) = self._main.set_weighted(hmap, weighted) {
+            // the window accepted the old setting before
+            let _ = self._window.set_weighted(hmap, self._weighted);
+            return Err(err);
+        }
+        self._weighted = weighted;
+        self._entries = match weighted {
+            false => None,
+            true => Some(::std::cmp::max(1, entries)),
+        };
+        let entries = self.entries();
+        if self._doorkeeper.is_some() {
+            self._doorkeeper = Some(doorkeeper::Doorkeeper::new(entries));
+        }
+        if let Some(estimator) = self._estimator.as_mut() {
+            estimator.set_capacity(entries);
+        }
+        Ok(())
+    }
+    /// check if the entries are weighted
+    pub fn is_weighted(&self) -> bool {
+        self._weighted
+    }
+    // number of keys for the doorkeeper, the estimator and the generations
+    fn entries(&self) -> usize {
+        self._entries.unwrap_or_else(|| self.capacity())
+    }
+    /// check if the doorkeeper is enabled
+    pub fn has_doorkeeper(&self) -> bool {
+        self._doorkeeper.is_some()
+    }
+    // put a new entry in the window, or replace the old entry with the same
+    // key in its own segment, so that an update does not demote an entry of
+    // the main cache.
+    // Evicted from window get a second chance
+    fn insert_clashed(
+        &mut self,
+        hmap: &mut Hmap,
+        maybe_old_entry: Option<&mut E>,
+        new_entry_idx: usize,
+    ) -> InsertResultShared {
+        let res_window = match maybe_old_entry {
+            None => self._window.insert_shared(hmap, None, new_entry_idx),
+            Some(old_entry) => {
+                if self._window.owns(old_entry.get_cache_id()) {
+                    self._window.insert_shared(
+                        hmap,
+                        Some(old_entry),
+                        new_entry_idx,
+                    )
+                } else if old_entry.get_key()
+                    == hmap.get_index(new_entry_idx).unwrap().get_key()
+                {
+                    // same key in the main cache, keep it there
+                    return self._main.insert_shared(
+                        hmap,
+                        Some(old_entry),
+                        new_entry_idx,
+                    );
+                } else {
+                    // the clash was in the main cache
+                    self._main.remove_clashed(hmap, old_entry, new_entry_idx);
+                    // the caller reports the clash, we only need to handle
+                    // the window eviction
+                    self._window.insert_shared(hmap, None, new_entry_idx)
                 }
-                InsertResultShared::Success => InsertResultShared::Success,
             }
+        };
+        self.window_result(hmap, res_window)
+    }
+    // an entry went in the window, maybe evicting its tail
+    fn window_result(
+        &mut self,
+        hmap: &mut Hmap,
+        res_window: InsertResultShared,
+    ) -> InsertResultShared {
+        // with weights the window can evict more than one entry,
+        // each one competes with the main victim of the moment
+        let mut evicted = Removed::new();
+        for (candidate, cause) in res_window {
+            if !self.admit(hmap, candidate) {
+                // the window tail lost, drop it
+                evicted.push(candidate, cause);
+                continue;
+            }
+            evicted.append(self._main.move_in_shared(hmap, candidate));
         }
+        evicted
     }
     /// reset the cache
     pub fn clear_shared(&mut self) {
         self._window.clear_shared();
-        self._slru.clear_shared();
+        self._main.clear_shared();
+        if let Some(wheel) = self._idle.as_mut() {
+            wheel.clear();
+        }
     }
-    /// fix the sub-caches so that it is safe to remove from the hashmap
-    pub fn remove_shared(&mut self, entry: &E) {
-        let res = if entry.get_cache_id().get_cid() == self._cid_window {
-            self._window.remove_shared(entry)
+    /// fix the sub-caches so that it is safe to remove the element at `idx`
+    /// from the hashmap
+    pub fn remove_shared(&mut self, hmap: &mut Hmap, idx: usize) {
+        let cid = hmap.get_index(idx).unwrap().get_cache_id();
+        let res = if self._window.owns(cid) {
+            self._window.remove_shared(hmap, idx)
         } else {
-            self._slru.remove_shared(entry)
+            self._main.remove_shared(hmap, idx)
         };
+        if let Some(wheel) = self._idle.as_mut() {
+            wheel.cancel(idx);
+        }
         self.update_scan_status();
         res
     }
-    /// return the cache ids, in order:
-    /// * Window
-    /// * probatory
-    /// * protected
-    pub fn get_cache_ids(&self) -> [CidT; 3] {
-        [self._cid_window, self._cid_probation, self._cid_protected]
+    /// `old_entry` was in this cache, but the entry at `idx` clashed with it
+    /// and took its place in the hashmap. Forget about the old entry
+    pub fn remove_clashed(
+        &mut self,
+        hmap: &mut Hmap,
+        old_entry: &E,
+        idx: usize,
+    ) {
+        if self._window.owns(old_entry.get_cache_id()) {
+            self._window.remove_clashed(hmap, old_entry, idx)
+        } else {
+            self._main.remove_clashed(hmap, old_entry, idx)
+        };
+        if let Some(wheel) = self._idle.as_mut() {
+            wheel.cancel(idx);
+        }
+        self.update_scan_status();
+    }
+    /// The index of the entry that will be evicted by the next insert, if the
+    /// cache is full
+    pub fn victim(&self, hmap: &Hmap) -> Option<usize> {
+        let window_victim = self._window.victim(hmap)?;
+        if self.admit(hmap, window_victim) {
+            self._main.victim(hmap)
+        } else {
+            Some(window_victim)
+        }
+    }
+    /// The hashmap has been resized and all the entries have moved.
+    ///
+    /// Fix the window and the main cache
+    pub fn relocate(&mut self, reloc: &hashmap::Relocation<E>) {
+        self._window.relocate(reloc);
+        self._main.relocate(reloc);
+        if let Some(wheel) = self._idle.as_mut() {
+            wheel.relocate(reloc);
+        }
     }
     /// if a higher-level cache is using this one, call this to make sure
     /// that the right cache will handle the on-get callback
-    pub fn on_get(&mut self, entry: &mut E) {
-        if entry.get_cache_id().get_cid() == self._cid_window {
-            self._window.on_get(entry);
+    pub fn on_get(&mut self, hmap: &mut Hmap, idx: usize) {
+        // the hit entries are always moved away from the scan, so they
+        // must be aged here
+        let cid_ctr = hmap.get_index_mut(idx).unwrap().get_cache_id_mut();
+        cid_ctr.age(*self._generation);
+        cid_ctr.add();
+        let cid = *cid_ctr;
+        self.estimator_add(hmap, idx);
+        // remember the hit, so the key is known if it comes back after an
+        // eviction
+        self.doorkeeper_check(hmap, idx);
+        self.tick();
+        if self._window.owns(cid) {
+            self._window.on_get(hmap, idx);
         } else {
-            self._slru.on_get(entry);
+            self._main.on_get(hmap, idx);
         }
+        if let Some(wheel) = self._idle.as_mut() {
+            wheel.touch(idx, self._now);
+        }
+        self.climb(true);
         self.update_scan_status();
     }
     /// start the user-scan
@@ -430,10 +862,10 @@ impl<
         match self._window.is_scan_running() {
             true => {}
             false => {
-                self._slru.start_scan();
+                self._main.start_scan();
             }
         };
-        match self._slru.is_scan_running() {
+        match self._main.is_scan_running() {
             true => {}
             false => {
                 self._window.start_scan();
@@ -441,13 +873,126 @@ impl<
             }
         }
     }
+    /// Change the total number of entries of the cache
+    ///
+    /// The window and the main cache are rebalanced so that they keep the
+    /// current proportions, with at least one element each.  
+    /// The entries evicted by a shrink are returned: they are still in the
+    /// hashmap and must be removed by the caller
+    pub fn set_capacity(
+        &mut self,
+        hmap: &mut Hmap,
+        entries: usize,
+    ) -> Removed<usize> {
+        let window_entries = ::std::cmp::max(
+            1,
+            (entries * self._window.capacity()) / self.capacity(),
+        );
+        let main_entries =
+            ::std::cmp::max(1, entries.saturating_sub(window_entries));
+        let mut evicted = self._window.set_capacity(hmap, window_entries);
+        evicted.append(self._main.set_capacity(hmap, main_entries));
+        let keys = self.entries();
+        if self._doorkeeper.is_some() {
+            self._doorkeeper = Some(doorkeeper::Doorkeeper::new(keys));
+        }
+        if let Some(estimator) = self._estimator.as_mut() {
+            estimator.set_capacity(keys);
+        }
+        if self._climber.is_some() {
+            self._climber = Some(climber::HillClimber::new(self.capacity()));
+            self._resize = 0;
+        }
+        if let Some(wheel) = self._idle.as_mut() {
+            wheel.cancel_removed(&evicted);
+        }
+        self.update_scan_status();
+        evicted
+    }
     /// get the cache max capacity
     pub fn capacity(&self) -> usize {
-        self._entries
+        self._window.capacity() + self._main.capacity()
     }
     /// get the current elements in the cache
     pub fn len(&self) -> usize {
-        self._window.len() + self._slru.len()
+        self._window.len() + self._main.len()
+    }
+    /// get the total weight of the entries, the same as `len()` when not
+    /// weighted
+    pub fn weight(&self) -> usize {
+        self._window.weight() + self._main.weight()
+    }
+    /// Expire the entries that are not accessed for `idle`, both in the
+    /// window and in the main cache
+    ///
+    /// See [`LRUShared::set_time_to_idle`](crate::lru::LRUShared::set_time_to_idle)
+    pub fn set_time_to_idle(
+        &mut self,
+        hmap: &Hmap,
+        idle: Option<::std::time::Duration>,
+        now: u64,
+    ) {
+        let mut wheel = match idle {
+            None => {
+                self._idle = None;
+                return;
+            }
+            Some(idle) => wheel::TimerWheel::new(idle, now),
+        };
+        for idx in 0..hmap.buckets() {
+            if let Some(entry) = hmap.get_index(idx) {
+                let cid = entry.get_cache_id();
+                if self._window.owns(cid) || self._main.owns(cid) {
+                    wheel.touch(idx, now);
+                }
+            }
+        }
+        self._idle = Some(wheel);
+    }
+    /// get the time-to-idle of the entries, if any
+    pub fn get_time_to_idle(&self) -> Option<::std::time::Duration> {
+        self._idle.as_ref().map(|wheel| wheel.idle())
+    }
+    /// Move the time to `now`, and remove the entries that have not been
+    /// accessed for the time-to-idle
+    ///
+    /// The expired entries are still in the hashmap and must be removed by
+    /// the caller
+    pub fn advance(&mut self, hmap: &mut Hmap, now: u64) -> Removed<usize> {
+        let expired = match self._idle.as_mut() {
+            None => return Removed::new(),
+            Some(wheel) => wheel.advance(now),
+        };
+        let mut removed = Removed::new();
+        for idx in expired {
+            self.remove_shared(hmap, idx);
+            removed.push(idx, RemovalCause::Expired);
+        }
+        removed
+    }
+    // (re)start the idle timer of `idx`, and stop the ones of the evicted
+    fn touch_idle(&mut self, idx: usize, evicted: &InsertResultShared) {
+        if let Some(wheel) = self._idle.as_mut() {
+            wheel.touch(idx, self._now);
+            wheel.cancel_removed(evicted);
+        }
+    }
+    // like `touch_idle` for an entry moved in from another cache, which
+    // keeps its timer
+    fn track_idle(&mut self, idx: usize, evicted: &InsertResultShared) {
+        if let Some(wheel) = self._idle.as_mut() {
+            wheel.track(idx, self._now);
+            wheel.cancel_removed(evicted);
+        }
+    }
+    /// the instant after which the entry at `idx` is idle, if there is a
+    /// time-to-idle
+    pub fn idle_deadline(&self, idx: usize) -> Option<u64> {
+        self._idle.as_ref().and_then(|wheel| wheel.deadline(idx))
+    }
+    /// the time of the next inserts and hits, where their idle timers start
+    pub fn set_now(&mut self, now: u64) {
+        self._now = now;
     }
     fn continuous_scan(
         &self,
@@ -458,14 +1003,9 @@ impl<
             (&*self._generation).into();
         move |entry: ::std::ptr::NonNull<E>| -> () {
             unsafe {
-                if *generation.as_ptr()
-                    != (*entry.as_ref()).get_cache_id().get_generation()
-                {
-                    entry.as_ref().get_cache_id().halve();
-                    entry.as_ref().get_cache_id().flip_generation();
-                } else {
-                    // do nothing
-                }
+                (*entry.as_ptr())
+                    .get_cache_id_mut()
+                    .age(*generation.as_ptr());
             }
             if fscan.is_some() && *status == ScanStatus::Running {
                 (fscan.unwrap())(entry)
@@ -473,3 +1013,425 @@ impl<
         }
     }
 }
+
+impl<
+        'a,
+        Hmap: hashmap::HashMap<E, K, V, CidCtr, Umeta, HB> + 'a,
+        E: user::EntryT<K, V, CidCtr, Umeta> + 'a,
+        K: user::Hash + 'a,
+        V: user::Val + 'a,
+        CidT: user::Cid + 'a,
+        CidCtr: counter::CidCounter<CidT> + 'a,
+        Umeta: user::Meta<V> + 'a,
+        HB: ::std::hash::BuildHasher + Default + 'a,
+        Window: SharedCache<'a, Hmap, E, K, V, CidCtr, Umeta, HB> + 'a,
+        Main: SharedCache<'a, Hmap, E, K, V, CidCtr, Umeta, HB> + 'a,
+    > SharedCache<'a, Hmap, E, K, V, CidCtr, Umeta, HB>
+    for SWTLFUShared<'a, Hmap, E, K, V, CidT, CidCtr, Umeta, HB, Window, Main>
+{
+    fn insert_shared(
+        &mut self,
+        hmap: &mut Hmap,
+        maybe_old_entry: Option<&mut E>,
+        new_entry_idx: usize,
+    ) -> InsertResultShared {
+        SWTLFUShared::insert_shared(self, hmap, maybe_old_entry, new_entry_idx)
+    }
+    fn move_in_shared(
+        &mut self,
+        hmap: &mut Hmap,
+        idx: usize,
+    ) -> InsertResultShared {
+        SWTLFUShared::move_in_shared(self, hmap, idx)
+    }
+    fn remove_shared(&mut self, hmap: &mut Hmap, idx: usize) {
+        SWTLFUShared::remove_shared(self, hmap, idx)
+    }
+    fn remove_clashed(&mut self, hmap: &mut Hmap, old_entry: &E, idx: usize) {
+        SWTLFUShared::remove_clashed(self, hmap, old_entry, idx)
+    }
+    fn clear_shared(&mut self) {
+        SWTLFUShared::clear_shared(self)
+    }
+    fn on_get(&mut self, hmap: &mut Hmap, idx: usize) {
+        SWTLFUShared::on_get(self, hmap, idx)
+    }
+    fn owns(&self, cid: CidCtr) -> bool {
+        self._window.owns(cid) || self._main.owns(cid)
+    }
+    fn victim(&self, hmap: &Hmap) -> Option<usize> {
+        SWTLFUShared::victim(self, hmap)
+    }
+    fn relocate(&mut self, reloc: &hashmap::Relocation<E>) {
+        SWTLFUShared::relocate(self, reloc)
+    }
+    fn set_scanf(
+        &mut self,
+        access_scan: Option<&'a dyn Fn(::std::ptr::NonNull<E>)>,
+    ) {
+        SWTLFUShared::set_scanf(self, access_scan)
+    }
+    fn start_scan(&mut self) {
+        SWTLFUShared::start_scan(self)
+    }
+    fn is_scan_running(&self) -> bool {
+        SWTLFUShared::is_scan_running(self)
+    }
+    fn set_hit_policy(&mut self, policy: crate::lru::HitPolicy) {
+        SWTLFUShared::set_hit_policy(self, policy)
+    }
+    fn get_hit_policy(&self) -> crate::lru::HitPolicy {
+        SWTLFUShared::get_hit_policy(self)
+    }
+    fn set_capacity(
+        &mut self,
+        hmap: &mut Hmap,
+        entries: usize,
+    ) -> Removed<usize> {
+        SWTLFUShared::set_capacity(self, hmap, entries)
+    }
+    fn capacity(&self) -> usize {
+        SWTLFUShared::capacity(self)
+    }
+    fn len(&self) -> usize {
+        SWTLFUShared::len(self)
+    }
+    // keep the current number of expected entries
+    fn set_weighted(
+        &mut self,
+        hmap: &Hmap,
+        weighted: bool,
+    ) -> Result<(), crate::results::Error> {
+        let entries = self.entries();
+        SWTLFUShared::set_weighted(self, hmap, weighted, entries)
+    }
+    fn weight(&self) -> usize {
+        SWTLFUShared::weight(self)
+    }
+    fn is_weighted(&self) -> bool {
+        SWTLFUShared::is_weighted(self)
+    }
+    fn idle_deadline(&self, idx: usize) -> Option<u64> {
+        SWTLFUShared::idle_deadline(self, idx)
+    }
+    fn set_now(&mut self, now: u64) {
+        SWTLFUShared::set_now(self, now)
+    }
+}
+
+// move `amount` of capacity from `from` to `to`.
+// Entries do not leave the cache: the ones that do not fit anymore in
+// `from` are moved to `to`, and with weights they might need more than
+// `amount`: in that case that is the capacity that moves.
+// `to` might still evict while taking them, like an SLRU with a full
+// probation: the new capacities are kept anyway, or the window could never
+// shrink, and its victims are returned, still in the hashmap
+fn move_capacity<'a, Hmap, E, K, V, CidCtr, Umeta, HB, From, To>(
+    hmap: &mut Hmap,
+    from: &mut From,
+    to: &mut To,
+    amount: usize,
+    weighted: bool,
+) -> Removed<usize>
+where
+    Hmap: hashmap::HashMap<E, K, V, CidCtr, Umeta, HB>,
+    E: user::EntryT<K, V, CidCtr, Umeta>,
+    K: user::Hash,
+    V: user::Val,
+    CidCtr: user::Cid,
+    Umeta: user::Meta<V>,
+    HB: ::std::hash::BuildHasher + Default,
+    From: SharedCache<'a, Hmap, E, K, V, CidCtr, Umeta, HB>,
+    To: SharedCache<'a, Hmap, E, K, V, CidCtr, Umeta, HB>,
+{
+    let (from_entries, to_entries) = (from.capacity(), to.capacity());
+    let weight_of = |hmap: &Hmap, displaced: &Removed<usize>| -> usize {
+        match weighted {
+            false => displaced.len(),
+            true => displaced
+                .iter()
+                .map(|(idx, _)| {
+                    hmap.get_index(*idx).unwrap().get_user().weight()
+                })
+                .sum(),
+        }
+    };
+    let mut shift = amount;
+    let mut displaced = from.set_capacity(hmap, from_entries - shift);
+    loop {
+        let weight = weight_of(hmap, &displaced);
+        if weight <= shift {
+            break;
+        }
+        // `from` already lost that much weight, this only evicts if its
+        // sub-caches rebalance
+        shift = weight;
+        displaced.append(
+            from.set_capacity(hmap, from_entries.saturating_sub(shift)),
+        );
+    }
+    // growing never evicts
+    to.set_capacity(hmap, to_entries + shift);
+    let mut bounced = Removed::new();
+    for (idx, _) in displaced {
+        bounced.append(to.move_in_shared(hmap, idx));
+    }
+    bounced
+}
+
+impl<
+        'a,
+        Hmap: hashmap::HashMap<E, K, V, CidCtr, Umeta, HB> + 'a,
+        E: user::EntryT<K, V, CidCtr, Umeta> + 'a,
+        K: user::Hash + 'a,
+        V: user::Val + 'a,
+        CidT: user::Cid + 'a,
+        CidCtr: counter::CidCounter<CidT> + 'a,
+        Umeta: user::Meta<V> + 'a,
+        HB: ::std::hash::BuildHasher + Default + 'a,
+        Window: SharedCache<'a, Hmap, E, K, V, CidCtr, Umeta, HB> + 'a,
+        Main: SharedCache<'a, Hmap, E, K, V, CidCtr, Umeta, HB> + 'a,
+    > crate::cache::IdleCache<'a, Hmap, E, K, V, CidCtr, Umeta, HB>
+    for SWTLFUShared<'a, Hmap, E, K, V, CidT, CidCtr, Umeta, HB, Window, Main>
+{
+    fn set_time_to_idle(
+        &mut self,
+        hmap: &Hmap,
+        idle: Option<::std::time::Duration>,
+        now: u64,
+    ) {
+        SWTLFUShared::set_time_to_idle(self, hmap, idle, now)
+    }
+    fn get_time_to_idle(&self) -> Option<::std::time::Duration> {
+        SWTLFUShared::get_time_to_idle(self)
+    }
+    fn advance(&mut self, hmap: &mut Hmap, now: u64) -> Removed<usize> {
+        SWTLFUShared::advance(self, hmap, now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hashmap::user::EntryT;
+    use crate::testing::{Hasher, Key, Val};
+    use counter::CidCounter;
+
+    type Cache<'a> = SWTLFU<'a, Key, Val, user::ZeroMeta, Hasher>;
+
+    fn in_main(cache: &Cache, k: u32) -> bool {
+        let (_, entry) = cache.hmap().get_full(&Key(k)).unwrap();
+        cache.shared()._main.owns(entry.get_cache_id())
+    }
+
+    fn in_protected(cache: &Cache, k: u32) -> bool {
+        let (_, entry) = cache.hmap().get_full(&Key(k)).unwrap();
+        entry.get_cache_id().get_cid() == counter::WTLFUCid::SLRUProtected
+    }
+
+    #[test]
+    fn update_stays_in_main() {
+        let mut cache = Cache::new(100, 0, Hasher::default());
+        assert_eq!(cache.get_split().0, 1);
+        cache.insert(Key(0), Val(0));
+        assert!(!in_main(&cache, 0));
+        // the window holds one entry, the next insert moves 0 to main
+        cache.insert(Key(1), Val(1));
+        assert!(in_main(&cache, 0));
+        let res = cache.insert(Key(0), Val(10));
+        let old: Vec<_> = res.iter().map(|(e, c)| (e.0, *c)).collect();
+        assert_eq!(old, vec![(Key(0), RemovalCause::Clash)]);
+        assert!(in_main(&cache, 0));
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get(&Key(0)).unwrap().0, &Val(10));
+        // the hit promoted 0 to protected, an update keeps it there
+        assert!(in_protected(&cache, 0));
+        assert_eq!(cache.insert(Key(0), Val(20)).len(), 1);
+        assert!(in_protected(&cache, 0));
+        // an update in the window stays in the window
+        cache.insert(Key(1), Val(11));
+        assert!(!in_main(&cache, 1));
+        assert_eq!(cache.len(), 2);
+    }
+
+    #[test]
+    fn cold_cache_keeps_the_main_split() {
+        let mut cache = Cache::new(100, 0, Hasher::default());
+        let split = cache.shared()._main.get_capacities();
+        // no hits: everything goes through probation only
+        for k in 0..1000 {
+            cache.insert(Key(k), Val(k));
+            assert!(cache.len() <= cache.capacity());
+        }
+        assert_eq!(cache.shared()._main.get_capacities(), split);
+        assert_eq!(cache.len(), cache.get_split().0 + split.0);
+    }
+
+    #[test]
+    fn tiny_caches_keep_one_entry_per_segment() {
+        for entries in 0..4 {
+            let mut cache = Cache::new(entries, 0, Hasher::default());
+            assert_eq!(cache.capacity(), 3);
+            for k in 0..10 {
+                cache.insert(Key(k), Val(k));
+            }
+            assert!(cache.len() <= 3);
+        }
+    }
+
+    fn set_counter(
+        hmap: &mut HmapT<Key, Val, user::ZeroMeta, Hasher>,
+        idx: usize,
+        counter: u32,
+        generation: counter::Generation,
+    ) {
+        let cid = hmap.get_index_mut(idx).unwrap().get_cache_id_mut();
+        cid.s_counter(counter);
+        cid.s_generation(generation.into());
+    }
+
+    #[test]
+    fn admission_ages_the_victim() {
+        let mut cache = Cache::new(10, 0, Hasher::default());
+        for k in 0..10 {
+            cache.insert(Key(k), Val(k));
+        }
+        let (hmap, shared) = cache.parts_mut();
+        let victim = shared._main.victim(hmap).unwrap();
+        let candidate = shared._window.tail(hmap).unwrap();
+        let now = *shared._generation;
+        set_counter(hmap, candidate, 5, now);
+        set_counter(hmap, victim, 8, now);
+        assert!(!shared.admit(hmap, candidate));
+        // the victim was last counted before the flip: it is worth 4
+        set_counter(hmap, victim, 8, !now);
+        assert!(shared.admit(hmap, candidate));
+        // the candidate is aged too
+        set_counter(hmap, candidate, 5, !now);
+        assert!(!shared.admit(hmap, candidate));
+    }
+
+    #[test]
+    fn counters_are_halved_after_a_flip() {
+        let mut cache = Cache::new(10, 0, Hasher::default());
+        cache.insert(Key(0), Val(0));
+        for _ in 0..6 {
+            cache.get(&Key(0));
+        }
+        let counter = |cache: &Cache| {
+            let (_, e) = cache.hmap().get_full(&Key(0)).unwrap();
+            e.get_cache_id().get_counter()
+        };
+        assert_eq!(counter(&cache), 7);
+        let generation = *cache.shared()._generation;
+        // 10 operations flip the generation
+        for k in 1..4 {
+            cache.insert(Key(k), Val(k));
+        }
+        assert!(*cache.shared()._generation != generation);
+        // the next hit halves the old counter before counting
+        cache.get(&Key(0));
+        assert_eq!(counter(&cache), 4);
+    }
+
+    #[test]
+    fn doorkeeper_records_hits_until_the_flip() {
+        let mut cache = Cache::new(10, 0, Hasher::default());
+        cache.set_doorkeeper(true);
+        let hash =
+            ::std::hash::BuildHasher::hash_one(cache.hmap().hasher(), &Key(0));
+        let seen = |cache: &Cache| {
+            cache.shared()._doorkeeper.as_ref().unwrap().contains(hash)
+        };
+        cache.insert(Key(0), Val(0));
+        assert!(seen(&cache));
+        // the 10th operation flips the generation and clears the doorkeeper
+        for _ in 0..9 {
+            cache.get(&Key(0));
+        }
+        assert!(!seen(&cache));
+        cache.get(&Key(0));
+        assert!(seen(&cache));
+    }
+
+    // get the key, insert it on a miss
+    fn access(cache: &mut Cache, k: u32) {
+        if cache.get(&Key(k)).is_none() {
+            cache.insert(Key(k), Val(k));
+        }
+    }
+
+    #[test]
+    fn adaptive_window_follows_the_workload() {
+        let mut cache = Cache::new(100, 0, Hasher::default());
+        cache.set_adaptive(true);
+        cache.set_estimator(Some(Box::new(sketch::CountMinSketch::new(100))));
+        assert_eq!(cache.get_split(), (1, 99));
+        // recency: every key is used again shortly after, then never
+        for i in 0..10_000 {
+            access(&mut cache, i);
+            if i >= 10 {
+                access(&mut cache, i - 10);
+            }
+        }
+        let (window, main) = cache.get_split();
+        assert_eq!(window + main, 100);
+        assert!(window > 75, "window did not grow: {}", window);
+        // frequency: a hot set, with one-time keys in between
+        for i in 0..10_000 {
+            access(&mut cache, i % 80);
+            access(&mut cache, 1_000_000 + i);
+        }
+        let (window, main) = cache.get_split();
+        assert_eq!(window + main, 100);
+        assert!(window < 40, "window did not shrink: {}", window);
+    }
+
+    #[test]
+    fn adaptive_window_bounds() {
+        let mut cache = Cache::new(10, 0, Hasher::default());
+        cache.set_adaptive(true);
+        // a steady hit ratio keeps growing the window, up to leaving two
+        // entries for the main cache. The window only changes on insert.
+        for i in 0..2_000 {
+            match i % 10 {
+                9 => access(&mut cache, 100 + i),
+                _ => access(&mut cache, i % 2),
+            }
+        }
+        assert_eq!(cache.get_split(), (8, 2));
+        // all misses: the first drop reverses the climb, then the steady
+        // hit ratio keeps shrinking the window, down to one entry
+        for i in 0..2_000 {
+            access(&mut cache, 10_000 + i);
+        }
+        assert_eq!(cache.get_split(), (1, 9));
+    }
+
+    #[test]
+    fn owning_api() {
+        let mut cache = Cache::new(10, 0, Hasher::default());
+        for k in 0..10 {
+            assert!(cache.insert(Key(k), Val(k)).is_empty());
+            // hits move the entries out of the probation segment
+            for j in 0..=k {
+                cache.get(&Key(j));
+            }
+        }
+        assert_eq!(cache.len(), 10);
+        let res = cache.insert(Key(10), Val(10));
+        let causes: Vec<_> = res.iter().map(|(_, c)| *c).collect();
+        assert_eq!(causes, vec![RemovalCause::Capacity]);
+        assert_eq!(cache.len(), 10);
+        cache.get_mut(&Key(0)).unwrap().0 .0 = 100;
+        assert_eq!(cache.get(&Key(0)).unwrap().0, &Val(100));
+        let res = cache.remove(&Key(0));
+        let old: Vec<_> = res.iter().map(|(e, c)| (e.1 .0, *c)).collect();
+        assert_eq!(old, vec![(100, RemovalCause::Explicit)]);
+        assert!(!cache.contains_key(&Key(0)));
+        assert!(cache.remove(&Key(0)).is_empty());
+        cache.clear();
+        assert!(cache.is_empty());
+        assert!(!cache.contains_key(&Key(1)));
+    }
+}
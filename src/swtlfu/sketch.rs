@@ -0,0 +1,165 @@
+/*
+ * Copyright 2021 Luca Fulchir <luker@fenrirproject.org>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+//! Frequency estimators for keys that are not in the cache
+//!
+//! The `CidCounter` lives in the hashmap entry, so it can only count the
+//! accesses of resident keys. A `FrequencyEstimator` works on the key hash
+//! and can remember keys that have already been evicted, so that the TinyLFU
+//! admission can compare the candidate and the victim as the W-TinyLFU
+//! paper intends.
+
+/// Approximate access counter indexed by the key hash
+pub trait FrequencyEstimator {
+    /// record one more access of the key
+    fn increment(&mut self, hash: u64);
+    /// estimated number of accesses of the key
+    fn estimate(&self, hash: u64) -> u32;
+    /// halve all the frequencies. Called on every generation flip
+    fn halve(&mut self);
+    /// forget everything
+    fn clear(&mut self);
+    /// the cache has been resized.  
+    /// The estimator can be rebuilt and forget everything
+    fn set_capacity(&mut self, entries: usize);
+}
+
+// number of rows in the sketch
+const DEPTH: u64 = 4;
+// 4-bit counters, 16 per word
+const COUNTERS_PER_WORD: usize = 16;
+const COUNTER_MAX: u64 = 0x0F;
+// mask to clear the bit that ends up in the next counter when halving
+const HALVE_MASK: u64 = 0x7777_7777_7777_7777;
+
+/// Count-Min Sketch with 4-bit saturating counters
+pub struct CountMinSketch {
+    _table: Vec<u64>,
+    // counters per row - 1. Always a power of two
+    _mask: u64,
+}
+
+impl CountMinSketch {
+    /// New empty sketch, sized for a cache of `entries` elements
+    pub fn new(entries: usize) -> Self {
+        let width =
+            ::std::cmp::max(COUNTERS_PER_WORD, entries.next_power_of_two());
+        CountMinSketch {
+            _table: vec![0; (width * DEPTH as usize) / COUNTERS_PER_WORD],
+            _mask: (width - 1) as u64,
+        }
+    }
+    // position of the counter (word, shift) for each row.
+    // double hashing: derive all the rows from a single hash
+    fn counters(&self, hash: u64) -> impl Iterator<Item = (usize, u64)> {
+        let (h1, h2) = (hash, (hash >> 32) | 1);
+        let mask = self._mask;
+        (0..DEPTH).map(move |row| {
+            let col = h1.wrapping_add(row.wrapping_mul(h2)) & mask;
+            let pos = (row * (mask + 1) + col) as usize;
+            (
+                pos / COUNTERS_PER_WORD,
+                ((pos % COUNTERS_PER_WORD) * 4) as u64,
+            )
+        })
+    }
+}
+
+impl FrequencyEstimator for CountMinSketch {
+    fn increment(&mut self, hash: u64) {
+        let counters: Vec<(usize, u64)> = self.counters(hash).collect();
+        for (word, shift) in counters {
+            if (self._table[word] >> shift) & COUNTER_MAX < COUNTER_MAX {
+                self._table[word] += 1 << shift;
+            }
+        }
+    }
+    fn estimate(&self, hash: u64) -> u32 {
+        self.counters(hash)
+            .map(|(word, shift)| (self._table[word] >> shift) & COUNTER_MAX)
+            .min()
+            .unwrap_or(0) as u32
+    }
+    fn halve(&mut self) {
+        for word in self._table.iter_mut() {
+            *word = (*word >> 1) & HALVE_MASK;
+        }
+    }
+    fn clear(&mut self) {
+        for word in self._table.iter_mut() {
+            *word = 0;
+        }
+    }
+    fn set_capacity(&mut self, entries: usize) {
+        *self = CountMinSketch::new(entries);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // spread the test keys like a real hash would
+    fn hash(k: u64) -> u64 {
+        k.wrapping_mul(0x9E37_79B9_7F4A_7C15)
+    }
+
+    #[test]
+    fn add_and_estimate() {
+        let mut sketch = CountMinSketch::new(64);
+        assert_eq!(sketch.estimate(hash(1)), 0);
+        for _ in 0..3 {
+            sketch.increment(hash(1));
+        }
+        sketch.increment(hash(2));
+        // count-min never underestimates
+        assert!(sketch.estimate(hash(1)) >= 3);
+        assert!(sketch.estimate(hash(2)) >= 1);
+        // and with few keys it is exact
+        assert_eq!(sketch.estimate(hash(1)), 3);
+        assert_eq!(sketch.estimate(hash(2)), 1);
+        sketch.clear();
+        assert_eq!(sketch.estimate(hash(1)), 0);
+    }
+
+    #[test]
+    fn counters_saturate() {
+        let mut sketch = CountMinSketch::new(64);
+        for _ in 0..100 {
+            sketch.increment(hash(1));
+        }
+        assert_eq!(sketch.estimate(hash(1)), COUNTER_MAX as u32);
+        // a saturated counter must not overflow into its neighbour
+        assert_eq!(sketch.estimate(hash(2)), 0);
+    }
+
+    #[test]
+    fn halve_on_reset() {
+        let mut sketch = CountMinSketch::new(64);
+        for _ in 0..100 {
+            sketch.increment(hash(1));
+        }
+        for _ in 0..5 {
+            sketch.increment(hash(2));
+        }
+        sketch.halve();
+        assert_eq!(sketch.estimate(hash(1)), 7);
+        assert_eq!(sketch.estimate(hash(2)), 2);
+        sketch.halve();
+        sketch.halve();
+        assert_eq!(sketch.estimate(hash(1)), 1);
+        assert_eq!(sketch.estimate(hash(2)), 0);
+    }
+}
@@ -0,0 +1,36 @@
+/*
+ * Copyright 2021 Luca Fulchir <luker@fenrirproject.org>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+//! Key and value types for the unit tests
+
+use crate::hashmap::user;
+
+#[derive(Clone, Copy, Debug, Default, Hash, PartialEq, Eq)]
+pub struct Key(pub u32);
+impl user::Hash for Key {}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Val(pub u32);
+impl user::Val for Val {}
+
+/// deterministic hasher, so that twin caches behave the same
+pub type Hasher = ::std::hash::BuildHasherDefault<
+    ::std::collections::hash_map::DefaultHasher,
+>;
+impl user::Weigher for Val {
+    fn weight(&self) -> usize {
+        self.0 as usize
+    }
+}
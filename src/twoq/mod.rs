@@ -0,0 +1,588 @@
+/*
+ * Copyright 2021 Luca Fulchir <luca@fenrirproject.org>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *   http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! 2Q cache
+//!
+//! Like the SLRU, 2Q has two parts:
+//!  * A1in: a FIFO queue for the entries that have been seen only once
+//!  * Am: an LRU for the entries that have been seen again later
+//!
+//! A hit in A1in does nothing: correlated accesses that happen in a short
+//! time do not make an entry "hot". Instead the keys of the entries evicted
+//! from A1in are remembered in the A1out ghost queue, and if a key comes
+//! back while still in A1out, it goes straight to Am.  
+//! Entries seen only once, like long scans, never reach Am, so they can not
+//! push out the frequently used ones.
+//!
+//! As suggested in the paper, A1in gets 25% of the capacity and A1out
+//! remembers as many keys as 50% of the capacity.
+//!
+//! See the [original paper](https://www.vldb.org/conf/1994/P439.PDF)
+
+use crate::ghost::Ghost;
+use crate::hashmap;
+use crate::hashmap::user;
+use crate::owned::Owned;
+use crate::results::{InsertResultShared, RemovalCause, Removed};
+
+/// cache id of the entries of the owning [`TwoQ`]
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum TwoQCid {
+    None,
+    A1in,
+    Am,
+}
+impl Default for TwoQCid {
+    fn default() -> Self {
+        TwoQCid::None
+    }
+}
+impl user::Cid for TwoQCid {}
+
+type TwoQEntry<K, V, Umeta> = user::Entry<K, V, TwoQCid, Umeta>;
+type HmapT<K, V, Umeta, HB> =
+    hashmap::SimpleHmap<TwoQEntry<K, V, Umeta>, K, V, TwoQCid, Umeta, HB>;
+
+/// [2Q](https://www.vldb.org/conf/1994/P439.PDF)
+/// implementation that wraps TwoQShared, see [`Owned`]
+pub type TwoQ<'a, K, V, Umeta, HB> = Owned<
+    'a,
+    K,
+    V,
+    TwoQCid,
+    Umeta,
+    HB,
+    TwoQShared<
+        'a,
+        HmapT<K, V, Umeta, HB>,
+        TwoQEntry<K, V, Umeta>,
+        K,
+        V,
+        TwoQCid,
+        Umeta,
+        HB,
+    >,
+>;
+
+impl<
+        'a,
+        K: user::Hash,
+        V: user::Val,
+        Umeta: user::Meta<V>,
+        HB: ::std::hash::BuildHasher + Default,
+    > TwoQ<'a, K, V, Umeta, HB>
+{
+    /// new 2Q with space for `entries` elements
+    pub fn new(
+        entries: usize,
+        extra_hashmap_capacity: usize,
+        hash_builder: HB,
+    ) -> Self {
+        Owned::from_shared(
+            TwoQShared::new(entries, TwoQCid::A1in, TwoQCid::Am, None),
+            extra_hashmap_capacity,
+            hash_builder,
+        )
+    }
+    /// return the current size of `(A1in, Am)`
+    pub fn get_lens(&self) -> (usize, usize) {
+        self.shared().get_lens()
+    }
+}
+
+#[derive(PartialEq, Eq)]
+enum ScanStatus {
+    Stopped,
+    RunningA1in,
+    RunningAm,
+}
+
+// A1in gets 25% of the entries
+fn a1in_size(entries: usize) -> usize {
+    ::std::cmp::max(1, entries / 4)
+}
+// A1out remembers as many keys as 50% of the entries
+fn a1out_size(entries: usize) -> usize {
+    ::std::cmp::max(1, entries / 2)
+}
+
+/// Actual implementation of the 2Q on a shared hashmap
+///
+/// A1in and Am are two LRUs on the hashmap, with different cache ids.
+/// A1in uses [`HitPolicy::Fifo`](crate::lru::HitPolicy::Fifo).  
+/// A1out only keeps the keys of the evicted entries, outside of the hashmap.
+///
+/// Note that Insert/Remove do not actually insert anything in the hashmap.
+/// That must be done by the caller. We only fix all the pointers and 2Q
+/// status
+pub struct TwoQShared<'a, Hmap, E, K, V, CidT, Umeta, HB>
+where
+    Hmap: hashmap::HashMap<E, K, V, CidT, Umeta, HB>,
+    E: user::EntryT<K, V, CidT, Umeta>,
+    K: user::Hash,
+    V: user::Val,
+    CidT: user::Cid,
+    Umeta: user::Meta<V>,
+    HB: ::std::hash::BuildHasher + Default,
+{
+    // A1in and Am can each grow up to the whole capacity, the evictions are
+    // decided by the 2Q, not by the LRUs
+    _a1in: crate::lru::LRUShared<'a, Hmap, E, K, V, CidT, Umeta, HB>,
+    _am: crate::lru::LRUShared<'a, Hmap, E, K, V, CidT, Umeta, HB>,
+    _a1out: Ghost<K, HB>,
+    _capacity: usize,
+    _scanstatus: ScanStatus,
+}
+
+impl<
+        'a,
+        Hmap: hashmap::HashMap<E, K, V, CidT, Umeta, HB>,
+        E: user::EntryT<K, V, CidT, Umeta>,
+        K: user::Hash,
+        V: user::Val,
+        CidT: user::Cid,
+        Umeta: user::Meta<V>,
+        HB: ::std::hash::BuildHasher + Default,
+    > TwoQShared<'a, Hmap, E, K, V, CidT, Umeta, HB>
+{
+    /// new 2Q with space for `entries` elements, the cache ids of A1in and
+    /// Am plus an optional callback for the lazy scan
+    pub fn new(
+        entries: usize,
+        a1in_cid: CidT,
+        am_cid: CidT,
+        access_scan: Option<&'a dyn Fn(::std::ptr::NonNull<E>)>,
+    ) -> Self {
+        let mut a1in =
+            crate::lru::LRUShared::new(entries, a1in_cid, access_scan);
+        a1in.set_hit_policy(crate::lru::HitPolicy::Fifo);
+        TwoQShared {
+            _a1in: a1in,
+            _am: crate::lru::LRUShared::new(entries, am_cid, access_scan),
+            _a1out: Ghost::new(),
+            _capacity: entries,
+            _scanstatus: ScanStatus::Stopped,
+        }
+    }
+    /// change the scan callback
+    pub fn set_scanf(
+        &mut self,
+        access_scan: Option<&'a dyn Fn(::std::ptr::NonNull<E>)>,
+    ) {
+        self._a1in.set_scanf(access_scan);
+        self._am.set_scanf(access_scan)
+    }
+    /// change what happens on a cache hit in Am.  
+    /// A1in is always a FIFO
+    pub fn set_hit_policy(&mut self, policy: crate::lru::HitPolicy) {
+        self._am.set_hit_policy(policy);
+    }
+    /// get the current hit policy of Am
+    pub fn get_hit_policy(&self) -> crate::lru::HitPolicy {
+        self._am.get_hit_policy()
+    }
+    /// An element has been added by the caller, fix the 2Q
+    ///
+    /// `maybe_old_entry` must be `!= None` only if the clash happened in
+    /// this cache
+    pub fn insert_shared(
+        &mut self,
+        hmap: &mut Hmap,
+        maybe_old_entry: Option<&mut E>,
+        new_entry_idx: usize,
+    ) -> InsertResultShared {
+        let res = match maybe_old_entry {
+            None => self.miss(hmap, new_entry_idx, true),
+            Some(old_entry)
+                if old_entry.get_key()
+                    == hmap.get_index(new_entry_idx).unwrap().get_key() =>
+            {
+                // same key, just a new value: the entry stays in its queue
+                if old_entry.get_cache_id() == self._a1in.get_cache_id() {
+                    self._a1in.insert_shared(
+                        hmap,
+                        Some(old_entry),
+                        new_entry_idx,
+                    )
+                } else {
+                    self._am.insert_shared(hmap, Some(old_entry), new_entry_idx)
+                }
+            }
+            Some(old_entry) => {
+                // a different key took the place of the old entry, which is
+                // not in the cache anymore
+                if old_entry.get_cache_id() == self._a1in.get_cache_id() {
+                    self._a1in.remove_clashed(hmap, old_entry, new_entry_idx);
+                } else {
+                    self._am.remove_clashed(hmap, old_entry, new_entry_idx);
+                }
+                self.miss(hmap, new_entry_idx, true)
+            }
+        };
+        self.update_scan_status();
+        res
+    }
+    /// An entry moved here from another cache on the same hashmap.
+    ///
+    /// Like a new insert, but without running the on-insert callback
+    pub fn move_in_shared(
+        &mut self,
+        hmap: &mut Hmap,
+        idx: usize,
+    ) -> InsertResultShared {
+        let res = self.miss(hmap, idx, false);
+        self.update_scan_status();
+        res
+    }
+    // the key at `idx` was not in the cache: make room, then add the entry
+    // to Am if the key was still in A1out, or to A1in otherwise
+    fn miss(
+        &mut self,
+        hmap: &mut Hmap,
+        idx: usize,
+        run_callback: bool,
+    ) -> InsertResultShared {
+        let key = hmap.get_index(idx).unwrap().get_key();
+        let in_a1out = self._a1out.remove(key);
+        let evicted = self.reclaim(hmap);
+        let lru = if in_a1out {
+            &mut self._am
+        } else {
+            &mut self._a1in
+        };
+        match run_callback {
+            true => lru.insert_shared(hmap, None, idx),
+            false => lru.move_in_shared(hmap, idx),
+        };
+        match evicted {
+            None => Removed::new(),
+            Some(evicted) => Removed::single(evicted, RemovalCause::Capacity),
+        }
+    }
+    // if the cache is full, evict the tail of A1in if it is over its size,
+    // remembering its key in A1out, or the tail of Am.
+    // The entry is still in the hashmap, its index is returned
+    fn reclaim(&mut self, hmap: &mut Hmap) -> Option<usize> {
+        if self.len() < self._capacity {
+            return None;
+        }
+        if self._a1in.len() > a1in_size(self._capacity) || self._am.len() == 0 {
+            let tail = self._a1in.tail(hmap)?;
+            self._a1in.remove_shared(hmap, tail);
+            self._a1out
+                .push(hmap.get_index(tail).unwrap().get_key().clone());
+            while self._a1out.len() > a1out_size(self._capacity) {
+                self._a1out.pop_lru();
+            }
+            Some(tail)
+        } else {
+            let tail = self._am.tail(hmap)?;
+            self._am.remove_shared(hmap, tail);
+            Some(tail)
+        }
+    }
+    /// The index of the entry that will be evicted by the next insert, if
+    /// the 2Q is full
+    pub fn victim(&self, hmap: &Hmap) -> Option<usize> {
+        if self.len() < self._capacity {
+            return None;
+        }
+        if self._a1in.len() > a1in_size(self._capacity) || self._am.len() == 0 {
+            self._a1in.tail(hmap)
+        } else {
+            self._am.tail(hmap)
+        }
+    }
+    /// Reset the 2Q state, A1out included
+    pub fn clear_shared(&mut self) {
+        self._a1in.clear_shared();
+        self._am.clear_shared();
+        self._a1out.clear();
+        self._scanstatus = ScanStatus::Stopped;
+    }
+    /// do not actually remove the element at `idx`, just fix the 2Q so that
+    /// it is not considered anymore and actual removal is safe
+    pub fn remove_shared(&mut self, hmap: &mut Hmap, idx: usize) {
+        let cid = hmap.get_index(idx).unwrap().get_cache_id();
+        if cid == self._a1in.get_cache_id() {
+            self._a1in.remove_shared(hmap, idx)
+        } else {
+            self._am.remove_shared(hmap, idx)
+        };
+        self.update_scan_status();
+    }
+    /// `old_entry` was in the 2Q, but the entry at `idx` clashed with it and
+    /// took its place in the hashmap. Forget about the old entry
+    pub fn remove_clashed(
+        &mut self,
+        hmap: &mut Hmap,
+        old_entry: &E,
+        idx: usize,
+    ) {
+        if old_entry.get_cache_id() == self._a1in.get_cache_id() {
+            self._a1in.remove_clashed(hmap, old_entry, idx)
+        } else {
+            self._am.remove_clashed(hmap, old_entry, idx)
+        };
+        self.update_scan_status();
+    }
+    /// The hashmap has been resized and all the entries have moved.
+    ///
+    /// Fix both A1in and Am. A1out only has keys
+    pub fn relocate(&mut self, reloc: &hashmap::Relocation<E>) {
+        self._a1in.relocate(reloc);
+        self._am.relocate(reloc);
+    }
+    /// return the cache ids for `(A1in, Am)`
+    pub fn get_cache_ids(&self) -> (CidT, CidT) {
+        (self._a1in.get_cache_id(), self._am.get_cache_id())
+    }
+    /// Should be called only by the parent, run the on-get callback on the
+    /// correct LRU
+    ///
+    /// Only a hit in Am moves the entry
+    pub fn on_get(&mut self, hmap: &mut Hmap, idx: usize) {
+        let cid = hmap.get_index(idx).unwrap().get_cache_id();
+        if cid == self._a1in.get_cache_id() {
+            self._a1in.on_get(hmap, idx);
+        } else {
+            self._am.on_get(hmap, idx);
+        }
+        self.update_scan_status();
+    }
+    /// start the scan callbacks on the 2Q
+    /// scan will execute only once on the whole 2Q
+    pub fn start_scan(&mut self) {
+        self._a1in.start_scan();
+        if self._a1in.is_scan_running() {
+            self._scanstatus = ScanStatus::RunningA1in;
+            return;
+        }
+        self._am.start_scan();
+        self._scanstatus = if self._am.is_scan_running() {
+            ScanStatus::RunningAm
+        } else {
+            ScanStatus::Stopped
+        };
+    }
+    /// return scan status
+    pub fn is_scan_running(&self) -> bool {
+        self._scanstatus != ScanStatus::Stopped
+    }
+    fn update_scan_status(&mut self) {
+        match self._scanstatus {
+            ScanStatus::Stopped => {}
+            ScanStatus::RunningA1in => {
+                if !self._a1in.is_scan_running() {
+                    self._am.start_scan();
+                    self._scanstatus = ScanStatus::RunningAm;
+                }
+            }
+            ScanStatus::RunningAm => {
+                if !self._am.is_scan_running() {
+                    self._scanstatus = ScanStatus::Stopped;
+                }
+            }
+        }
+    }
+    /// Change the number of entries of the 2Q
+    ///
+    /// The entries evicted while shrinking are chosen just like on insert,
+    /// and their indexes are returned: they are still in the hashmap and
+    /// must be removed by the caller
+    pub fn set_capacity(
+        &mut self,
+        hmap: &mut Hmap,
+        entries: usize,
+    ) -> Removed<usize> {
+        self._capacity = entries;
+        let mut evicted = Removed::new();
+        while self.len() > entries {
+            match self.reclaim(hmap) {
+                Some(idx) => evicted.push(idx, RemovalCause::Capacity),
+                None => break,
+            }
+        }
+        while self._a1out.len() > a1out_size(entries) {
+            self._a1out.pop_lru();
+        }
+        // A1in and Am are not over the capacity anymore, this does not evict
+        self._a1in.set_capacity(hmap, entries);
+        self._am.set_capacity(hmap, entries);
+        self.update_scan_status();
+        evicted
+    }
+    /// return the current size of `(A1in, Am)`
+    pub fn get_lens(&self) -> (usize, usize) {
+        (self._a1in.len(), self._am.len())
+    }
+    /// return max 2Q size
+    pub fn capacity(&self) -> usize {
+        self._capacity
+    }
+    /// return used 2Q size
+    pub fn len(&self) -> usize {
+        self._a1in.len() + self._am.len()
+    }
+    /// check if the 2Q is empty
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<
+        'a,
+        Hmap: hashmap::HashMap<E, K, V, CidT, Umeta, HB>,
+        E: user::EntryT<K, V, CidT, Umeta>,
+        K: user::Hash,
+        V: user::Val,
+        CidT: user::Cid,
+        Umeta: user::Meta<V>,
+        HB: ::std::hash::BuildHasher + Default,
+    > crate::cache::SharedCache<'a, Hmap, E, K, V, CidT, Umeta, HB>
+    for TwoQShared<'a, Hmap, E, K, V, CidT, Umeta, HB>
+{
+    fn insert_shared(
+        &mut self,
+        hmap: &mut Hmap,
+        maybe_old_entry: Option<&mut E>,
+        new_entry_idx: usize,
+    ) -> InsertResultShared {
+        TwoQShared::insert_shared(self, hmap, maybe_old_entry, new_entry_idx)
+    }
+    fn move_in_shared(
+        &mut self,
+        hmap: &mut Hmap,
+        idx: usize,
+    ) -> InsertResultShared {
+        TwoQShared::move_in_shared(self, hmap, idx)
+    }
+    fn remove_shared(&mut self, hmap: &mut Hmap, idx: usize) {
+        TwoQShared::remove_shared(self, hmap, idx)
+    }
+    fn remove_clashed(&mut self, hmap: &mut Hmap, old_entry: &E, idx: usize) {
+        TwoQShared::remove_clashed(self, hmap, old_entry, idx)
+    }
+    fn clear_shared(&mut self) {
+        TwoQShared::clear_shared(self)
+    }
+    fn on_get(&mut self, hmap: &mut Hmap, idx: usize) {
+        TwoQShared::on_get(self, hmap, idx)
+    }
+    fn owns(&self, cid: CidT) -> bool {
+        cid == self._a1in.get_cache_id() || cid == self._am.get_cache_id()
+    }
+    fn victim(&self, hmap: &Hmap) -> Option<usize> {
+        TwoQShared::victim(self, hmap)
+    }
+    fn relocate(&mut self, reloc: &hashmap::Relocation<E>) {
+        TwoQShared::relocate(self, reloc)
+    }
+    fn set_scanf(
+        &mut self,
+        access_scan: Option<&'a dyn Fn(::std::ptr::NonNull<E>)>,
+    ) {
+        TwoQShared::set_scanf(self, access_scan)
+    }
+    fn start_scan(&mut self) {
+        TwoQShared::start_scan(self)
+    }
+    fn is_scan_running(&self) -> bool {
+        TwoQShared::is_scan_running(self)
+    }
+    fn set_hit_policy(&mut self, policy: crate::lru::HitPolicy) {
+        TwoQShared::set_hit_policy(self, policy)
+    }
+    fn get_hit_policy(&self) -> crate::lru::HitPolicy {
+        TwoQShared::get_hit_policy(self)
+    }
+    fn set_capacity(
+        &mut self,
+        hmap: &mut Hmap,
+        entries: usize,
+    ) -> Removed<usize> {
+        TwoQShared::set_capacity(self, hmap, entries)
+    }
+    fn capacity(&self) -> usize {
+        TwoQShared::capacity(self)
+    }
+    fn len(&self) -> usize {
+        TwoQShared::len(self)
+    }
+    // only counts entries
+    fn set_weighted(
+        &mut self,
+        _hmap: &Hmap,
+        weighted: bool,
+    ) -> Result<(), crate::results::Error> {
+        match weighted {
+            false => Ok(()),
+            true => Err(crate::results::Error::WeightUnsupported),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{Hasher, Key, Val};
+
+    type Cache<'a> = TwoQ<'a, Key, Val, user::ZeroMeta, Hasher>;
+
+    fn keys(res: Removed<(Key, Val, user::ZeroMeta)>) -> Vec<u32> {
+        res.iter().map(|(e, _)| e.0 .0).collect()
+    }
+
+    #[test]
+    fn a1out_hit_goes_to_am() {
+        let mut cache = Cache::new(8, 0, Hasher::default());
+        for k in 0..8 {
+            cache.insert(Key(k), Val(k));
+        }
+        // a hit in A1in does not promote
+        cache.get(&Key(7));
+        assert_eq!(cache.get_lens(), (8, 0));
+        // A1out remembers the last 4 keys evicted from A1in
+        for k in 8..13 {
+            assert_eq!(keys(cache.insert(Key(k), Val(k))), vec![k - 8]);
+        }
+        // 0 was forgotten, and evicting 5 forgets 1
+        assert_eq!(keys(cache.insert(Key(0), Val(0))), vec![5]);
+        assert_eq!(cache.get_lens(), (8, 0));
+        assert_eq!(keys(cache.insert(Key(2), Val(2))), vec![6]);
+        assert_eq!(cache.get_lens(), (7, 1));
+        assert_eq!(keys(cache.insert(Key(1), Val(1))), vec![7]);
+        assert_eq!(cache.get_lens(), (7, 1));
+    }
+
+    #[test]
+    fn am_tail_goes_when_a1in_is_small() {
+        let mut cache = Cache::new(4, 0, Hasher::default());
+        for k in 0..6 {
+            cache.insert(Key(k), Val(k));
+        }
+        assert_eq!(keys(cache.insert(Key(0), Val(0))), vec![2]);
+        assert_eq!(keys(cache.insert(Key(1), Val(1))), vec![3]);
+        assert_eq!(keys(cache.insert(Key(2), Val(2))), vec![4]);
+        assert_eq!(cache.get_lens(), (1, 3));
+        // A1in is at its size: the Am tail goes, and is not remembered
+        assert_eq!(keys(cache.insert(Key(7), Val(7))), vec![0]);
+        assert_eq!(cache.get_lens(), (2, 2));
+        assert_eq!(keys(cache.insert(Key(0), Val(0))), vec![5]);
+        assert_eq!(cache.get_lens(), (2, 2));
+        assert!(!cache.shared().is_empty());
+    }
+}